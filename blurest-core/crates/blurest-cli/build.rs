@@ -0,0 +1,37 @@
+fn main() {
+    // The `grpc` feature generates the service from its definition below rather than
+    // from `proto/blurest.proto`, so building does not need `protoc`. Keep both in sync.
+    #[cfg(feature = "grpc")]
+    {
+        use tonic_build::manual::{Builder, Method, Service};
+
+        let method = |name: &str, route: &str, input: &str, output: &str| {
+            Method::builder()
+                .name(name)
+                .route_name(route)
+                .input_type(format!("crate::grpc::proto::{input}"))
+                .output_type(format!("crate::grpc::proto::{output}"))
+                .codec_path("tonic_prost::ProstCodec")
+                .build()
+        };
+        let service = Service::builder()
+            .name("Blurest")
+            .package("blurest.v1")
+            .method(method(
+                "get_blurhash",
+                "GetBlurhash",
+                "GetBlurhashRequest",
+                "BlurhashReply",
+            ))
+            .method(method("warm", "Warm", "WarmRequest", "WarmReply"))
+            .method(method(
+                "invalidate",
+                "Invalidate",
+                "InvalidateRequest",
+                "InvalidateReply",
+            ))
+            .method(method("stats", "Stats", "StatsRequest", "StatsReply"))
+            .build();
+        Builder::new().build_client(false).compile(&[service]);
+    }
+}
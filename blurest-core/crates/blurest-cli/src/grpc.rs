@@ -0,0 +1,287 @@
+//! gRPC front end to a [`CacheStore`], the `blurest.v1.Blurest` service of
+//! `proto/blurest.proto`, for services that speak gRPC rather than HTTP and JSON.
+//!
+//! - `GetBlurhash` returns the blurhash of an image, generating it if needed. A
+//!   `traceparent` metadata entry makes the spans of the lookup (`otel` feature) part
+//!   of the caller's trace.
+//! - `Warm` generates every listed image and reports those that failed.
+//! - `Invalidate` forgets an image, as [`invalidate`] does.
+//! - `Stats` returns entry counts and the cache metrics.
+//!
+//! Lookups block, so they run on the runtime's blocking threads. Failed lookups are
+//! `PERMISSION_DENIED` for [`BlurestError`]s, whose code is set in the
+//! `blurest-error-code` metadata entry, and `INVALID_ARGUMENT` otherwise.
+//!
+//! [`BlurestError`]: blurest_core::BlurestError
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use blurest_core::{
+    CacheStore, CacheValidation, RequestOptions, core::BatchItem, error::error_code,
+    maintenance::cache_stats, manifest::invalidate,
+};
+use tonic::{Code, Request, Response, Status, metadata::MetadataMap, transport::Server};
+
+use crate::TIMESTAMP_FORMAT;
+
+/// Messages of `proto/blurest.proto` and the generated service.
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GetBlurhashRequest {
+        #[prost(string, tag = "1")]
+        pub path: String,
+        #[prost(string, tag = "2")]
+        pub validation: String,
+        #[prost(bool, tag = "3")]
+        pub force: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BlurhashReply {
+        #[prost(string, tag = "1")]
+        pub key: String,
+        #[prost(string, tag = "2")]
+        pub blurhash: String,
+        #[prost(int32, tag = "3")]
+        pub width: i32,
+        #[prost(int32, tag = "4")]
+        pub height: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WarmRequest {
+        #[prost(string, repeated, tag = "1")]
+        pub paths: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WarmFailure {
+        #[prost(string, tag = "1")]
+        pub path: String,
+        #[prost(string, tag = "2")]
+        pub error: String,
+        #[prost(string, optional, tag = "3")]
+        pub code: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WarmReply {
+        #[prost(uint64, tag = "1")]
+        pub warmed: u64,
+        #[prost(message, repeated, tag = "2")]
+        pub failed: Vec<WarmFailure>,
+        #[prost(string, repeated, tag = "3")]
+        pub skipped: Vec<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InvalidateRequest {
+        #[prost(string, tag = "1")]
+        pub path: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct InvalidateReply {
+        #[prost(string, optional, tag = "1")]
+        pub key: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsRequest {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsReply {
+        #[prost(int64, tag = "1")]
+        pub entries: i64,
+        #[prost(int64, tag = "2")]
+        pub content_keyed: i64,
+        #[prost(string, optional, tag = "3")]
+        pub oldest_update: Option<String>,
+        #[prost(string, optional, tag = "4")]
+        pub newest_update: Option<String>,
+        #[prost(uint64, tag = "5")]
+        pub hits: u64,
+        #[prost(uint64, tag = "6")]
+        pub misses: u64,
+        #[prost(uint64, tag = "7")]
+        pub stale_refreshes: u64,
+        #[prost(uint64, tag = "8")]
+        pub db_errors: u64,
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/blurest.v1.Blurest.rs"));
+}
+
+use proto::{
+    BlurhashReply, GetBlurhashRequest, InvalidateReply, InvalidateRequest, StatsReply,
+    StatsRequest, WarmFailure, WarmReply, WarmRequest,
+    blurest_server::{Blurest, BlurestServer},
+};
+
+/// Serves `store` on `address` until the process is interrupted, running up to
+/// `threads` lookups at once.
+pub fn serve(store: CacheStore, address: &str, threads: usize) -> Result<()> {
+    let address = address
+        .parse()
+        .with_context(|| format!("Invalid listen address {address}"))?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(threads.max(1))
+        .enable_all()
+        .build()
+        .context("Failed to start the gRPC runtime")?;
+
+    runtime.block_on(async {
+        eprintln!("Listening on grpc://{address}");
+        Server::builder()
+            .add_service(BlurestServer::new(Service { store }))
+            .serve_with_shutdown(address, async {
+                // Without a signal handler, the process is simply terminated.
+                let _ = tokio::signal::ctrl_c().await;
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to serve on {address}: {e}"))
+    })
+}
+
+struct Service {
+    store: CacheStore,
+}
+
+impl Service {
+    /// Runs `f` with the store on a blocking thread.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&CacheStore) -> Result<T, Status> + Send + 'static,
+    ) -> Result<Response<T>, Status> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .map_err(|e| Status::internal(format!("Request handler failed: {e}")))?
+            .map(Response::new)
+    }
+}
+
+#[tonic::async_trait]
+impl Blurest for Service {
+    async fn get_blurhash(
+        &self,
+        request: Request<GetBlurhashRequest>,
+    ) -> Result<Response<BlurhashReply>, Status> {
+        let traceparent = request
+            .metadata()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let request = request.into_inner();
+        if request.path.is_empty() {
+            return Err(Status::invalid_argument("Missing `path`"));
+        }
+        let mut options = RequestOptions {
+            traceparent,
+            force: request.force,
+            ..RequestOptions::default()
+        };
+        if !request.validation.is_empty() {
+            let validation = CacheValidation::parse(&request.validation)
+                .map_err(|e| Status::invalid_argument(format!("{e:#}")))?;
+            options.validation = Some(validation);
+        }
+
+        self.blocking(move |store| {
+            let data = store
+                .get_blurhash_with_options(Path::new(&request.path), &options)
+                .map_err(|e| generation_error(&e))?;
+            Ok(BlurhashReply {
+                key: data.key,
+                blurhash: data.blurhash,
+                width: data.width,
+                height: data.height,
+            })
+        })
+        .await
+    }
+
+    async fn warm(&self, request: Request<WarmRequest>) -> Result<Response<WarmReply>, Status> {
+        let paths: Vec<_> = request
+            .into_inner()
+            .paths
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        self.blocking(move |store| {
+            let mut reply = WarmReply::default();
+            store
+                .get_blurhashes(paths, |path, result| match result {
+                    Ok(BatchItem::Image(_)) => reply.warmed += 1,
+                    Ok(BatchItem::Skipped(_)) => {
+                        reply.skipped.push(path.to_string_lossy().into_owned())
+                    }
+                    Err(e) => reply.failed.push(WarmFailure {
+                        path: path.to_string_lossy().into_owned(),
+                        error: format!("{e:#}"),
+                        code: error_code(&e).map(str::to_string),
+                    }),
+                })
+                .map_err(|e| Status::internal(format!("{e:#}")))?;
+            Ok(reply)
+        })
+        .await
+    }
+
+    async fn invalidate(
+        &self,
+        request: Request<InvalidateRequest>,
+    ) -> Result<Response<InvalidateReply>, Status> {
+        let path = request.into_inner().path;
+        if path.is_empty() {
+            return Err(Status::invalid_argument("Missing `path`"));
+        }
+        self.blocking(move |store| {
+            let key = store
+                .with_context(|context| invalidate(context, Path::new(&path)))
+                .map_err(|e| Status::internal(format!("{e:#}")))?;
+            Ok(InvalidateReply { key })
+        })
+        .await
+    }
+
+    async fn stats(&self, _: Request<StatsRequest>) -> Result<Response<StatsReply>, Status> {
+        self.blocking(|store| {
+            let (stats, metrics) = store
+                .with_context(|context| Ok((cache_stats(context)?, context.metrics.snapshot())))
+                .map_err(|e| Status::internal(format!("{e:#}")))?;
+            Ok(StatsReply {
+                entries: stats.entries,
+                content_keyed: stats.content_keyed,
+                oldest_update: stats
+                    .oldest_update
+                    .map(|t| t.format(TIMESTAMP_FORMAT).to_string()),
+                newest_update: stats
+                    .newest_update
+                    .map(|t| t.format(TIMESTAMP_FORMAT).to_string()),
+                hits: metrics.hits,
+                misses: metrics.misses,
+                stale_refreshes: metrics.stale_refreshes,
+                db_errors: metrics.db_errors,
+            })
+        })
+        .await
+    }
+}
+
+/// Maps a failed generation to a status: policy violations are `PERMISSION_DENIED`,
+/// with their code in the metadata, anything else (missing or undecodable images)
+/// `INVALID_ARGUMENT`.
+fn generation_error(error: &anyhow::Error) -> Status {
+    let message = format!("{error:#}");
+    let Some(code) = error_code(error) else {
+        return Status::invalid_argument(message);
+    };
+    let mut metadata = MetadataMap::new();
+    metadata.insert(
+        "blurest-error-code",
+        code.parse().expect("error codes are ASCII"),
+    );
+    Status::with_metadata(Code::PermissionDenied, message, metadata)
+}
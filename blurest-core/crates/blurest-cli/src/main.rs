@@ -0,0 +1,647 @@
+//! # blurest
+//!
+//! Command-line access to a blurest cache database, sharing the engine of the
+//! Node.js module through `blurest-core`.
+//!
+//! ```text
+//! blurest --database cache.sqlite3 --root public get public/hero.jpg
+//! blurest --database cache.sqlite3 --root public warm 'public/**/*.{jpg,png}'
+//! blurest --database cache.sqlite3 --root public prune --dry-run
+//! blurest --database cache.sqlite3 --root public maintain --prune-orphans --vacuum --analyze
+//! blurest --database cache.sqlite3 --root public duplicates --verify-size
+//! blurest --database cache.sqlite3 stats
+//! blurest --database cache.sqlite3 export > cache.json
+//! blurest --database cache.sqlite3 --root public serve --listen 127.0.0.1:4000
+//! blurest --database cache.sqlite3 --root public grpc --listen 127.0.0.1:50051
+//! blurest --database cache.sqlite3 --root public daemon --socket /tmp/blurest.sock
+//! blurest --config blurest.config.toml warm 'public/**/*.jpg'
+//! blurest --root public get public/hero.jpg
+//! ```
+//!
+//! Settings may come from a `blurest.config.json` or `blurest.config.toml` file, the
+//! one shared with the Node.js module; it is picked up from the current directory when
+//! `--config` is not given. `BLUREST_*` environment variables take precedence over the
+//! file, and command-line flags over both. Without any database, the cache in
+//! `.blurest` of the first root is used, see [`blurest_core::project`].
+//!
+//! `serve` requires the `server` feature, `grpc` the `grpc` feature and
+//! `--otlp-endpoint` the `otel` feature.
+
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "server")]
+mod server;
+
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use blurest_core::ipc::IpcServer;
+use blurest_core::{
+    CacheStore, CacheValidation, RequestOptions, Revalidate,
+    config::{Config, resolve_config},
+    core::BatchItem,
+    keys::{OutsideRootPolicy, relative_key},
+    logging::{LogFormat, init_logger},
+    maintenance::{
+        MaintenanceOptions, cache_composition, cache_stats, export_entries, find_duplicates,
+        maintain, prune_cache, repair_cache, staleness_report,
+    },
+    snapshot::write_snapshot,
+    verify::verify_entries,
+};
+use clap::{Parser, Subcommand};
+use serde_json::json;
+
+/// Timestamp format used in JSON output.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+#[derive(Parser)]
+#[command(
+    name = "blurest",
+    version,
+    about = "Inspect and maintain a blurest cache"
+)]
+struct Cli {
+    /// Config file to read settings from. Defaults to BLUREST_CONFIG, then to
+    /// blurest.config.json or blurest.config.toml in the current directory, if present.
+    #[arg(long, short)]
+    config: Option<PathBuf>,
+    /// Path of the SQLite cache database; created if missing. Defaults to the config
+    /// file or BLUREST_DATABASE_URL, then to `.blurest/cache.sqlite3` in the first
+    /// root, locked while the command runs.
+    #[arg(long, short)]
+    database: Option<String>,
+    /// Seconds to wait for another process to release the cache in `.blurest`.
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    lock_timeout: u64,
+    /// Project root images are keyed against; may be repeated. Defaults to the roots of
+    /// the config file, then to the current directory.
+    #[arg(long = "root", short)]
+    roots: Vec<PathBuf>,
+    /// How cached entries are validated: auto, mtime, hash, always or never.
+    #[arg(long, value_parser = CacheValidation::parse)]
+    validation: Option<CacheValidation>,
+    /// How images outside every root are keyed: error, absolute or hash.
+    #[arg(long, value_parser = OutsideRootPolicy::parse)]
+    outside_root: Option<OutsideRootPolicy>,
+    /// Log output on stderr: text (the default), or json lines with structured fields.
+    /// The level is taken from RUST_LOG (a plain level such as `debug` for json), then
+    /// from BLUREST_LOG or the config file.
+    #[arg(long, value_parser = LogFormat::parse)]
+    log_format: Option<LogFormat>,
+    /// Export OpenTelemetry spans to this OTLP/HTTP traces endpoint.
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the blurhash of an image, generating and caching it if needed.
+    Get {
+        path: PathBuf,
+        /// Regenerate the blurhash and overwrite the cached entry even if it is current.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Generate and cache blurhashes for every image matching the glob patterns.
+    Warm {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+        /// Number of images processed in parallel, the size of the worker pool.
+        /// Defaults to `workers` from the config, or the number of CPUs.
+        #[arg(long, short)]
+        jobs: Option<usize>,
+        /// Seconds between progress lines on stderr, with the throughput and an
+        /// estimated time left; 0 disables them. Lines are JSON with `--log-format json`.
+        #[arg(long, default_value_t = 10)]
+        progress_interval: u64,
+    },
+    /// Remove entries whose image no longer exists under any root.
+    Prune {
+        /// Only list the entries that would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Run the selected maintenance steps, e.g. from a nightly cron job, and print a
+    /// report. Entries are removed first, then the database is vacuumed and analyzed.
+    Maintain {
+        /// Remove entries whose image no longer exists under any root.
+        #[arg(long)]
+        prune_orphans: bool,
+        /// Remove entries not written within this many days.
+        #[arg(long, value_name = "DAYS")]
+        prune_older_than: Option<u64>,
+        /// Rebuild the database file to reclaim the space of deleted entries.
+        #[arg(long)]
+        vacuum: bool,
+        /// Refresh the statistics of SQLite's query planner.
+        #[arg(long)]
+        analyze: bool,
+    },
+    /// Regenerate entries with a malformed blurhash or impossible dimensions from their
+    /// file, delete those that cannot be regenerated, and print a report.
+    Repair,
+    /// Print the groups of cached images with identical content as a JSON array.
+    Duplicates {
+        /// Also compare file sizes on disk, skipping images whose file is gone.
+        #[arg(long)]
+        verify_size: bool,
+    },
+    /// Print statistics about the cache.
+    Stats,
+    /// Print how many entries match their file's mtime, are stale or lost their file.
+    Staleness {
+        /// Only check entries whose cache key starts with this.
+        prefix: Option<String>,
+    },
+    /// Recompute cached entries from their files and print those that differ as JSON.
+    /// Exits unsuccessfully if an entry of an unchanged file differs or fails.
+    Verify {
+        /// Only check entries whose cache key starts with this.
+        prefix: Option<String>,
+        /// Recompute only this many entries, picked at random.
+        #[arg(long, value_name = "N")]
+        sample: Option<usize>,
+    },
+    /// Print entry counts, file sizes and average dimensions by extension and
+    /// top-level directory as JSON.
+    Composition,
+    /// Print every cached entry as a JSON array.
+    Export,
+    /// Write a memory-mapped snapshot of the cache for read-only processes, replacing
+    /// the file, and print its entry count and size.
+    Snapshot { path: PathBuf },
+    /// Serve the cache over HTTP.
+    #[cfg(feature = "server")]
+    Serve {
+        /// Address to listen on.
+        #[arg(long, short, default_value = "127.0.0.1:4000")]
+        listen: String,
+        /// Number of requests handled in parallel. Defaults to the number of CPUs.
+        #[arg(long, short)]
+        threads: Option<usize>,
+    },
+    /// Serve the cache on a Unix domain socket to local processes.
+    #[cfg(unix)]
+    Daemon {
+        /// Path of the socket.
+        #[arg(long, short, value_name = "PATH")]
+        socket: PathBuf,
+    },
+    /// Serve the cache over gRPC.
+    #[cfg(feature = "grpc")]
+    Grpc {
+        /// Address to listen on.
+        #[arg(long, short, default_value = "127.0.0.1:50051")]
+        listen: String,
+        /// Number of lookups run in parallel. Defaults to the number of CPUs.
+        #[arg(long, short)]
+        threads: Option<usize>,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = resolve_config(cli.config.as_deref()).and_then(|config| {
+        init_logging(cli.log_format, &config)?;
+        run(cli, config)
+    });
+    #[cfg(feature = "otel")]
+    if let Err(e) = blurest_core::telemetry::shutdown() {
+        log::warn!("Failed to flush telemetry: {e:#}");
+    }
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Sets up logging in `format`, or else the configured one. `RUST_LOG` takes precedence
+/// over the configured level.
+fn init_logging(format: Option<LogFormat>, config: &Config) -> Result<()> {
+    let format = format.or(config.log_format).unwrap_or_default();
+    let rust_log = std::env::var("RUST_LOG").ok();
+    if format == LogFormat::Text && config.log_file.is_none() {
+        let mut builder = env_logger::Builder::from_default_env();
+        if rust_log.is_none()
+            && let Some(level) = config.log_level
+        {
+            builder.filter_level(level);
+        }
+        builder.init();
+        return Ok(());
+    }
+
+    init_logger(format, config.log_file.as_deref())?;
+    let level = rust_log
+        .and_then(|level| level.parse().ok())
+        .or(config.log_level)
+        .unwrap_or(log::LevelFilter::Warn);
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn run(cli: Cli, config: Config) -> Result<ExitCode> {
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        blurest_core::telemetry::init_otlp(&blurest_core::telemetry::OtlpOptions {
+            endpoint: Some(endpoint.clone()),
+            service_name: "blurest".to_string(),
+        })?;
+    }
+    let mut options = config.options.clone();
+    if let Some(validation) = cli.validation {
+        options.validation = validation;
+    }
+    if let Some(outside_root) = cli.outside_root {
+        options.outside_root = outside_root;
+    }
+    if let Command::Warm {
+        jobs: Some(jobs), ..
+    } = cli.command
+    {
+        options.workers = jobs.max(1);
+    }
+    // One-shot commands exit before a background refresh could be stored.
+    if is_one_shot(&cli.command) {
+        options.revalidate = Revalidate::Blocking;
+    }
+    let roots = if !cli.roots.is_empty() {
+        cli.roots
+    } else if !config.project_roots.is_empty() {
+        config.project_roots.clone()
+    } else {
+        vec![std::env::current_dir().context("Failed to read the current directory")?]
+    };
+    let store = match cli.database.or_else(|| config.database_url.clone()) {
+        Some(database) => CacheStore::open(&database, &roots, options)?,
+        None => CacheStore::open_project(&roots, options, Duration::from_secs(cli.lock_timeout))?,
+    };
+
+    match cli.command {
+        Command::Get { path, force } => {
+            let request = RequestOptions {
+                force,
+                ..RequestOptions::default()
+            };
+            let data = store.get_blurhash_with_options(&path, &request)?;
+            println!(
+                "{}",
+                json!({
+                    "blurhash": data.blurhash,
+                    "width": data.width,
+                    "height": data.height,
+                })
+            );
+        }
+        Command::Warm {
+            patterns,
+            progress_interval,
+            ..
+        } => {
+            let progress = WarmProgress {
+                interval: Duration::from_secs(progress_interval),
+                format: cli.log_format.or(config.log_format).unwrap_or_default(),
+            };
+            return warm(&store, &config, &patterns, &progress);
+        }
+        Command::Prune { dry_run } => {
+            let keys = store.with_context(|context| prune_cache(context, dry_run))?;
+            for key in &keys {
+                println!("{key}");
+            }
+            let verb = if dry_run { "Would prune" } else { "Pruned" };
+            eprintln!("{verb} {} entries", keys.len());
+        }
+        Command::Maintain {
+            prune_orphans,
+            prune_older_than,
+            vacuum,
+            analyze,
+        } => {
+            let options = MaintenanceOptions {
+                prune_orphans,
+                max_age: prune_older_than
+                    .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+                vacuum,
+                analyze,
+            };
+            let report = store.with_context(|context| maintain(context, &options))?;
+            println!(
+                "{:#}",
+                json!({
+                    "orphansPruned": report.orphans.len(),
+                    "expiredPruned": report.expired.len(),
+                    "vacuumed": report.vacuumed,
+                    "analyzed": report.analyzed,
+                    "sizeBefore": report.size_before,
+                    "sizeAfter": report.size_after,
+                    "durationMs": report.duration.as_millis() as u64,
+                })
+            );
+        }
+        Command::Duplicates { verify_size } => {
+            let groups = store.with_context(|context| find_duplicates(context, verify_size))?;
+            let groups: Vec<_> = groups
+                .into_iter()
+                .map(|group| {
+                    json!({
+                        "xxhash": group.xxhash,
+                        "fileSize": group.file_size,
+                        "keys": group.keys,
+                    })
+                })
+                .collect();
+            println!("{:#}", serde_json::Value::Array(groups));
+        }
+        Command::Repair => {
+            let report = store.with_context(repair_cache)?;
+            println!(
+                "{:#}",
+                json!({
+                    "checked": report.checked,
+                    "regenerated": report.regenerated,
+                    "deleted": report.deleted,
+                    "durationMs": report.duration.as_secs_f64() * 1000.0,
+                })
+            );
+        }
+        Command::Stats => {
+            let stats = store.with_context(cache_stats)?;
+            let oldest = stats
+                .oldest_update
+                .map(|t| t.format(TIMESTAMP_FORMAT).to_string());
+            let newest = stats
+                .newest_update
+                .map(|t| t.format(TIMESTAMP_FORMAT).to_string());
+            println!(
+                "{:#}",
+                json!({
+                    "entries": stats.entries,
+                    "contentKeyed": stats.content_keyed,
+                    "oldestUpdate": oldest,
+                    "newestUpdate": newest,
+                })
+            );
+        }
+        Command::Staleness { prefix } => {
+            let report =
+                store.with_context(|context| staleness_report(context, prefix.as_deref()))?;
+            println!(
+                "{:#}",
+                json!({
+                    "fresh": report.fresh,
+                    "stale": report.stale,
+                    "missing": report.missing,
+                })
+            );
+        }
+        Command::Verify { prefix, sample } => {
+            let report =
+                store.with_context(|context| verify_entries(context, prefix.as_deref(), sample))?;
+            let mismatched: Vec<_> = report
+                .mismatched
+                .iter()
+                .map(|mismatch| {
+                    json!({
+                        "key": mismatch.key,
+                        "cachedBlurhash": mismatch.cached_blurhash,
+                        "cachedWidth": mismatch.cached_width,
+                        "cachedHeight": mismatch.cached_height,
+                        "blurhash": mismatch.blurhash,
+                        "width": mismatch.width,
+                        "height": mismatch.height,
+                        "contentChanged": mismatch.content_changed,
+                    })
+                })
+                .collect();
+            println!(
+                "{:#}",
+                json!({
+                    "checked": report.checked,
+                    "matched": report.matched,
+                    "mismatched": mismatched,
+                    "missing": report.missing,
+                    "failed": report.failed,
+                    "skipped": report.skipped,
+                    "durationMs": report.duration.as_secs_f64() * 1000.0,
+                })
+            );
+            let corrupted = report
+                .mismatched
+                .iter()
+                .any(|mismatch| !mismatch.content_changed);
+            if corrupted || !report.failed.is_empty() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Composition => {
+            let composition = store.with_context(cache_composition)?;
+            println!("{:#}", composition.to_json());
+        }
+        Command::Export => {
+            let entries = store.with_context(export_entries)?;
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|entry| {
+                    json!({
+                        "key": entry.relative_path,
+                        "xxhash": entry.xxhash,
+                        "mtimeMs": entry.mtime_ms,
+                        "blurhash": entry.blurhash,
+                        "width": entry.width,
+                        "height": entry.height,
+                        "createdAt": entry.created_at.format(TIMESTAMP_FORMAT).to_string(),
+                        "updatedAt": entry.updated_at.format(TIMESTAMP_FORMAT).to_string(),
+                    })
+                })
+                .collect();
+            println!("{:#}", serde_json::Value::Array(entries));
+        }
+        Command::Snapshot { path } => {
+            let report = store.with_context(|context| write_snapshot(context, &path))?;
+            println!(
+                "{:#}",
+                json!({ "entries": report.entries, "bytes": report.bytes })
+            );
+        }
+        #[cfg(feature = "server")]
+        Command::Serve { listen, threads } => {
+            let threads = threads
+                .or_else(|| thread::available_parallelism().ok().map(usize::from))
+                .unwrap_or(1);
+            server::serve(store, &listen, threads)?;
+        }
+        #[cfg(unix)]
+        Command::Daemon { socket } => {
+            let server = IpcServer::bind(&socket)?;
+            eprintln!("Listening on {}", server.path().display());
+            server.serve(&store)?;
+        }
+        #[cfg(feature = "grpc")]
+        Command::Grpc { listen, threads } => {
+            let threads = threads
+                .or_else(|| thread::available_parallelism().ok().map(usize::from))
+                .unwrap_or(1);
+            grpc::serve(store, &listen, threads)?;
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Whether `command` exits once done, unlike the servers.
+fn is_one_shot(command: &Command) -> bool {
+    match command {
+        #[cfg(feature = "server")]
+        Command::Serve { .. } => false,
+        #[cfg(unix)]
+        Command::Daemon { .. } => false,
+        #[cfg(feature = "grpc")]
+        Command::Grpc { .. } => false,
+        _ => true,
+    }
+}
+
+/// How `warm` reports its progress.
+struct WarmProgress {
+    /// Time between reports, or zero for none.
+    interval: Duration,
+    format: LogFormat,
+}
+
+impl WarmProgress {
+    /// Prints how far a warm of `total` images got after `elapsed`, with the
+    /// throughput so far and the time left at that rate.
+    fn report(&self, processed: usize, failed: usize, total: usize, elapsed: Duration) {
+        let rate = processed as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let eta = (rate > 0.0).then(|| Duration::from_secs_f64((total - processed) as f64 / rate));
+        match self.format {
+            LogFormat::Json => eprintln!(
+                "{}",
+                json!({
+                    "event": "progress",
+                    "processed": processed,
+                    "failed": failed,
+                    "total": total,
+                    "imagesPerSecond": (rate * 10.0).round() / 10.0,
+                    "etaSeconds": eta.map(|eta| eta.as_secs()),
+                })
+            ),
+            LogFormat::Text => eprintln!(
+                "Warmed {processed}/{total} images ({failed} failed), {rate:.1}/s, {} left",
+                eta.map_or_else(|| "unknown time".to_string(), format_duration)
+            ),
+        }
+    }
+}
+
+/// Formats `duration` in whole seconds for humans, e.g. `1h 02m 05s` or `42s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m {seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// Expands `patterns` and fills the cache for every matching file on the store's
+/// worker pool, skipping files matching the `ignore` patterns of `config` and files
+/// that are not images. Failures are reported per file and make the command exit
+/// unsuccessfully; `progress` is reported on a separate thread while the workers run.
+fn warm(
+    store: &CacheStore,
+    config: &Config,
+    patterns: &[String],
+    progress: &WarmProgress,
+) -> Result<ExitCode> {
+    let roots = store.with_context(|context| Ok(context.project_roots.clone()))?;
+    let is_ignored = |path: &PathBuf| {
+        path.canonicalize()
+            .ok()
+            .and_then(|path| relative_key(&path, &roots).ok())
+            .is_some_and(|key| config.is_ignored(&key))
+    };
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        for entry in glob::glob(pattern).with_context(|| format!("Invalid pattern `{pattern}`"))? {
+            let path = entry?;
+            if path.is_file() && !is_ignored(&path) {
+                paths.push(path);
+            }
+        }
+    }
+
+    let total = paths.len();
+    let processed = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let mut skipped = 0;
+    let started = Instant::now();
+
+    thread::scope(|scope| {
+        let (done, finished) = mpsc::channel::<()>();
+        if !progress.interval.is_zero() {
+            let (processed, failed) = (&processed, &failed);
+            scope.spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(progress.interval)
+                {
+                    progress.report(
+                        processed.load(Ordering::Relaxed),
+                        failed.load(Ordering::Relaxed),
+                        total,
+                        started.elapsed(),
+                    );
+                }
+            });
+        }
+        let warmed = store.get_blurhashes(paths, |path, result| {
+            match result {
+                Ok(BatchItem::Image(_)) => {}
+                Ok(BatchItem::Skipped(warning)) => {
+                    eprintln!("{}: {warning}", path.display());
+                    skipped += 1;
+                }
+                Err(e) => {
+                    eprintln!("{}: {e:#}", path.display());
+                    failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            processed.fetch_add(1, Ordering::Relaxed);
+        });
+        drop(done);
+        warmed
+    })?;
+
+    // Jobs that panicked never reported back.
+    let failed = failed.into_inner() + (total - processed.into_inner());
+    eprintln!(
+        "Warmed {} images ({failed} failed, {skipped} skipped)",
+        total - failed - skipped
+    );
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
@@ -0,0 +1,203 @@
+//! HTTP front end to a [`CacheStore`], so services outside Node.js can share one cache.
+//!
+//! - `GET /blurhash?path=<path>[&validation=<mode>]` returns `{blurhash, width, height}`.
+//!   A `traceparent` header makes the spans of the lookup (`otel` feature) part of the
+//!   caller's trace.
+//! - `POST /warm` with `{"paths": [...]}` generates every listed image and returns
+//!   `{warmed, failed: [{path, error, code}], skipped}`, where `skipped` lists the
+//!   paths that are not images.
+//! - `GET /metrics` returns cache metrics in the Prometheus text format.
+//!
+//! Errors are returned as `{error, code}`, where `code` is set for [`BlurestError`]s.
+//!
+//! [`BlurestError`]: blurest_core::BlurestError
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{Result, anyhow};
+use blurest_core::{
+    CacheStore, CacheValidation, RequestOptions, core::BatchItem, error::error_code,
+    metrics::render_prometheus,
+};
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest accepted request body, in bytes.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+type JsonResponse = (u16, Value);
+
+/// Serves `store` on `address` until the process is terminated, handling requests on
+/// `threads` threads.
+pub fn serve(store: CacheStore, address: &str, threads: usize) -> Result<()> {
+    let server =
+        Arc::new(Server::http(address).map_err(|e| anyhow!("Failed to listen on {address}: {e}"))?);
+    eprintln!("Listening on http://{}", server.server_addr());
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    handle(&store, request);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(store: &CacheStore, mut request: Request) {
+    let (path, query) = match request.url().split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (request.url().to_string(), String::new()),
+    };
+
+    if (request.method(), path.as_str()) == (&Method::Get, "/metrics") {
+        return metrics(store, request);
+    }
+
+    let (status, body) = match (request.method(), path.as_str()) {
+        (Method::Get, "/blurhash") => {
+            let traceparent = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("traceparent"))
+                .map(|header| header.value.to_string());
+            get_blurhash(store, &query, traceparent)
+        }
+        (Method::Post, "/warm") => match read_json(&mut request) {
+            Ok(body) => warm(store, &body),
+            Err(e) => error_response(400, &e),
+        },
+        (_, "/blurhash" | "/warm" | "/metrics") => {
+            error_response(405, &anyhow!("Method not allowed"))
+        }
+        _ => error_response(404, &anyhow!("Not found")),
+    };
+
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("static header is valid");
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    if let Err(e) = request.respond(response) {
+        log::warn!("Failed to send response: {e}");
+    }
+}
+
+/// Responds with the store's metrics in the Prometheus text format.
+fn metrics(store: &CacheStore, request: Request) {
+    let response = match store.with_context(|context| Ok(context.metrics.snapshot())) {
+        Ok(snapshot) => {
+            let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                .expect("static header is valid");
+            Response::from_string(render_prometheus(&[("default", snapshot)]))
+                .with_header(content_type)
+        }
+        Err(e) => Response::from_string(format!("{e:#}")).with_status_code(500),
+    };
+    if let Err(e) = request.respond(response) {
+        log::warn!("Failed to send response: {e}");
+    }
+}
+
+fn get_blurhash(store: &CacheStore, query: &str, traceparent: Option<String>) -> JsonResponse {
+    let mut image_path = None;
+    let mut request = RequestOptions {
+        traceparent,
+        ..RequestOptions::default()
+    };
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "path" => image_path = Some(value.into_owned()),
+            "validation" => match CacheValidation::parse(&value) {
+                Ok(validation) => request.validation = Some(validation),
+                Err(e) => return error_response(400, &e),
+            },
+            "force" => request.force = matches!(value.as_ref(), "1" | "true"),
+            _ => {}
+        }
+    }
+    let Some(image_path) = image_path else {
+        return error_response(400, &anyhow!("Missing `path` query parameter"));
+    };
+
+    match store.get_blurhash_with_options(Path::new(&image_path), &request) {
+        Ok(data) => (
+            200,
+            json!({
+                "blurhash": data.blurhash,
+                "width": data.width,
+                "height": data.height,
+            }),
+        ),
+        Err(e) => generation_error(&e),
+    }
+}
+
+fn warm(store: &CacheStore, body: &Value) -> JsonResponse {
+    let Some(paths) = body.get("paths").and_then(Value::as_array) else {
+        return error_response(400, &anyhow!("Expected a JSON body with a `paths` array"));
+    };
+
+    let mut warmed = 0;
+    let mut failed = Vec::new();
+    let mut skipped = Vec::new();
+    let mut valid = Vec::new();
+    for path in paths {
+        match path.as_str() {
+            Some(path) => valid.push(PathBuf::from(path)),
+            None => failed.push(json!({ "path": path, "error": "Path must be a string" })),
+        }
+    }
+    let warming = store.get_blurhashes(valid, |path, result| match result {
+        Ok(BatchItem::Image(_)) => warmed += 1,
+        Ok(BatchItem::Skipped(_)) => skipped.push(path.to_string_lossy().into_owned()),
+        Err(e) => failed.push(json!({
+            "path": path.to_string_lossy(),
+            "error": format!("{e:#}"),
+            "code": error_code(&e),
+        })),
+    });
+    if let Err(e) = warming {
+        return error_response(500, &e);
+    }
+
+    (
+        200,
+        json!({ "warmed": warmed, "failed": failed, "skipped": skipped }),
+    )
+}
+
+fn read_json(request: &mut Request) -> Result<Value> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES)
+        .read_to_string(&mut body)?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+/// Maps a failed generation to a response: policy violations are `403`, anything else
+/// (missing or undecodable images) `422`.
+fn generation_error(error: &anyhow::Error) -> JsonResponse {
+    let status = if error_code(error).is_some() {
+        403
+    } else {
+        422
+    };
+    error_response(status, error)
+}
+
+fn error_response(status: u16, error: &anyhow::Error) -> JsonResponse {
+    (
+        status,
+        json!({ "error": format!("{error:#}"), "code": error_code(error) }),
+    )
+}
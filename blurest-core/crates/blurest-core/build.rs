@@ -0,0 +1,15 @@
+fn main() {
+    // The `turbojpeg` feature links the TurboJPEG API of the system's libjpeg-turbo.
+    #[cfg(feature = "turbojpeg")]
+    if let Err(e) = pkg_config::Config::new().probe("libturbojpeg") {
+        panic!("The `turbojpeg` feature needs libjpeg-turbo, found with pkg-config: {e}");
+    }
+    // The `vips` feature links the system's libvips along with GLib.
+    #[cfg(feature = "vips")]
+    if let Err(e) = pkg_config::Config::new()
+        .atleast_version("8.8")
+        .probe("vips")
+    {
+        panic!("The `vips` feature needs libvips 8.8 or later, found with pkg-config: {e}");
+    }
+}
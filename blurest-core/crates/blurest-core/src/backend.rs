@@ -0,0 +1,169 @@
+//! Libraries images are decoded with. The `image` crate is always built in; the
+//! `turbojpeg` and `vips` features add faster ones, which the cache then prefers
+//! unless [`ContextOptions::decoder`](crate::options::ContextOptions::decoder) picks
+//! another.
+
+use anyhow::{Result, anyhow};
+
+use crate::encode::{DecodedImage, decode_image_with_metadata};
+
+/// Shortest the longer side of a scaled decode may get, in pixels. Blurhashes average
+/// the whole image over a few cosines, so this leaves plenty of detail.
+pub const SCALED_DECODE_MIN_SIZE: u32 = 64;
+
+/// A library turning image files into pixels and their metadata.
+pub trait DecodeBackend: Send + Sync {
+    /// Short name of the library, e.g. `"image"`.
+    fn name(&self) -> &'static str;
+
+    /// Decodes `file_bytes`. Given `min_size`, the backend may decode the image at a
+    /// reduced size whose longer side is at least that long, or the full size if
+    /// smaller; [`DecodedImage::full_size`] still reports the dimensions as stored.
+    fn decode(&self, file_bytes: &[u8], min_size: Option<u32>) -> Result<DecodedImage>;
+
+    /// Whether [`DecodeBackend::decode`] decodes at a reduced size given `min_size`,
+    /// making the pixels depend on it.
+    fn scales(&self) -> bool {
+        false
+    }
+}
+
+/// How an image decoded by `backend` with `min_size` is recorded in the cache, e.g.
+/// `"image"` or `"turbojpeg@64"`. Backends and scales produce slightly different
+/// pixels, so entries recorded otherwise are regenerated; entries cached before this
+/// was recorded count as `"image"`.
+pub fn decode_identity(backend: &dyn DecodeBackend, min_size: Option<u32>) -> String {
+    match min_size.filter(|_| backend.scales()) {
+        Some(min_size) => format!("{}@{min_size}", backend.name()),
+        None => backend.name().to_string(),
+    }
+}
+
+/// The decoders of the `image` crate, always at full size.
+pub struct ImageCrate;
+
+impl DecodeBackend for ImageCrate {
+    fn name(&self) -> &'static str {
+        "image"
+    }
+
+    fn decode(&self, file_bytes: &[u8], _min_size: Option<u32>) -> Result<DecodedImage> {
+        decode_image_with_metadata(file_bytes)
+    }
+}
+
+/// libjpeg-turbo for JPEG files, see [`crate::jpeg`].
+#[cfg(feature = "turbojpeg")]
+pub struct TurboJpeg;
+
+#[cfg(feature = "turbojpeg")]
+impl DecodeBackend for TurboJpeg {
+    fn name(&self) -> &'static str {
+        "turbojpeg"
+    }
+
+    fn decode(&self, file_bytes: &[u8], min_size: Option<u32>) -> Result<DecodedImage> {
+        crate::jpeg::decode_jpeg_with_metadata(file_bytes, min_size)
+    }
+
+    fn scales(&self) -> bool {
+        true
+    }
+}
+
+/// libvips for every format it reads, see [`crate::vips`].
+#[cfg(feature = "vips")]
+pub struct Vips;
+
+#[cfg(feature = "vips")]
+impl DecodeBackend for Vips {
+    fn name(&self) -> &'static str {
+        "vips"
+    }
+
+    fn decode(&self, file_bytes: &[u8], min_size: Option<u32>) -> Result<DecodedImage> {
+        crate::vips::decode_vips_with_metadata(file_bytes, min_size)
+    }
+
+    fn scales(&self) -> bool {
+        true
+    }
+}
+
+/// The backend [`Decoder::Auto`] decodes images with: libvips when built with the
+/// `vips` feature, otherwise libjpeg-turbo with `turbojpeg`, otherwise the `image`
+/// crate.
+pub fn active_backend() -> &'static dyn DecodeBackend {
+    #[cfg(feature = "vips")]
+    return &Vips;
+    #[cfg(all(feature = "turbojpeg", not(feature = "vips")))]
+    return &TurboJpeg;
+    #[cfg(not(any(feature = "turbojpeg", feature = "vips")))]
+    &ImageCrate
+}
+
+/// Every backend built in, the `image` crate first.
+pub fn available_backends() -> Vec<&'static dyn DecodeBackend> {
+    vec![
+        &ImageCrate,
+        #[cfg(feature = "turbojpeg")]
+        &TurboJpeg,
+        #[cfg(feature = "vips")]
+        &Vips,
+    ]
+}
+
+/// Backend a context decodes images with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Decoder {
+    /// The fastest backend built in, see [`active_backend`].
+    #[default]
+    Auto,
+    /// The decoders of the `image` crate.
+    Image,
+    /// libjpeg-turbo for JPEG files, with the `turbojpeg` feature.
+    #[cfg(feature = "turbojpeg")]
+    TurboJpeg,
+    /// libvips, with the `vips` feature.
+    #[cfg(feature = "vips")]
+    Vips,
+}
+
+impl Decoder {
+    /// Parses a decoder name as accepted by the JavaScript API. Names of backends
+    /// left out of the build are refused.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(Self::Auto),
+            "image" => Ok(Self::Image),
+            #[cfg(feature = "turbojpeg")]
+            "turbojpeg" => Ok(Self::TurboJpeg),
+            #[cfg(feature = "vips")]
+            "vips" => Ok(Self::Vips),
+            #[cfg(not(feature = "turbojpeg"))]
+            "turbojpeg" => Err(not_built(name)),
+            #[cfg(not(feature = "vips"))]
+            "vips" => Err(not_built(name)),
+            other => Err(anyhow!(
+                "Unknown decoder `{other}`, expected \"auto\", \"image\", \"turbojpeg\" or \"vips\""
+            )),
+        }
+    }
+
+    /// The backend decoding images.
+    pub fn backend(self) -> &'static dyn DecodeBackend {
+        match self {
+            Self::Auto => active_backend(),
+            Self::Image => &ImageCrate,
+            #[cfg(feature = "turbojpeg")]
+            Self::TurboJpeg => &TurboJpeg,
+            #[cfg(feature = "vips")]
+            Self::Vips => &Vips,
+        }
+    }
+}
+
+#[cfg(not(all(feature = "turbojpeg", feature = "vips")))]
+fn not_built(name: &str) -> anyhow::Error {
+    anyhow!("Decoder `{name}` is not available: built without the `{name}` feature")
+}
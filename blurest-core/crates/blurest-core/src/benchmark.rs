@@ -0,0 +1,211 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use image::GenericImageView;
+
+use crate::{
+    backend::{SCALED_DECODE_MIN_SIZE, available_backends},
+    core::{AppContext, get_blurhash_with_cache},
+    encode::{decode_image, encode_rgba, hash_file_bytes},
+    options::DEFAULT_COMPONENTS,
+};
+
+/// Summary statistics for one measured operation, in milliseconds.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub mean_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Timing {
+    fn from_samples(samples: &[Duration]) -> Self {
+        let to_ms = |d: &Duration| d.as_secs_f64() * 1000.0;
+        let total: f64 = samples.iter().map(to_ms).sum();
+        Self {
+            mean_ms: total / samples.len() as f64,
+            min_ms: samples.iter().map(to_ms).fold(f64::INFINITY, f64::min),
+            max_ms: samples.iter().map(to_ms).fold(0.0, f64::max),
+        }
+    }
+}
+
+/// Timings of the individual stages of an uncached generation.
+#[derive(Debug, Clone)]
+pub struct StageTimings {
+    /// Reading the file from disk.
+    pub read: Timing,
+    /// Computing the xxh3 content hash.
+    pub hash: Timing,
+    /// Decoding the image.
+    pub decode: Timing,
+    /// Converting the decoded image to RGBA8.
+    pub to_rgba: Timing,
+    /// Encoding the blurhash.
+    pub encode: Timing,
+}
+
+/// Measurements of the uncached generation path.
+#[derive(Debug, Clone)]
+pub struct ColdPathReport {
+    pub width: u32,
+    pub height: u32,
+    pub file_size: u64,
+    /// Full generation without the cache: read, hash, decode and encode.
+    pub total: Timing,
+    pub stages: StageTimings,
+}
+
+/// Decoding time of one backend, see [`crate::backend`].
+#[derive(Debug, Clone)]
+pub struct DecoderTiming {
+    /// Name of the backend, e.g. `"turbojpeg"`.
+    pub name: &'static str,
+    /// Decoding the image as generation does, scaled down where the backend can.
+    pub decode: Timing,
+}
+
+/// Structured result of [`run_benchmark`].
+#[derive(Debug, Clone)]
+pub struct BenchmarkReport {
+    pub iterations: usize,
+    pub cold: ColdPathReport,
+    /// Cached lookup of an entry that is already up to date.
+    pub warm: Timing,
+    /// Every backend built in, the `image` crate first.
+    pub decoders: Vec<DecoderTiming>,
+}
+
+/// Times the cold path, warm path and individual stages for one image.
+///
+/// # Arguments
+/// * `context` - Application context used for the warm path
+/// * `image_path` - Path to the image file
+/// * `iterations` - Number of measured runs per operation (at least 1)
+pub fn run_benchmark(
+    context: &mut AppContext,
+    image_path: &Path,
+    iterations: usize,
+) -> Result<BenchmarkReport> {
+    let cold = measure_cold_path(image_path, iterations)?;
+    let warm = measure_warm_path(context, image_path, iterations)?;
+    let decoders = measure_decoders(image_path, iterations)?;
+    Ok(BenchmarkReport {
+        iterations,
+        cold,
+        warm,
+        decoders,
+    })
+}
+
+/// Times full uncached generations of an image, stage by stage.
+///
+/// Bypasses the cache entirely and needs no context, so it can run without holding any lock.
+pub fn measure_cold_path(image_path: &Path, iterations: usize) -> Result<ColdPathReport> {
+    if iterations == 0 {
+        return Err(anyhow!("Benchmark iterations must be at least 1"));
+    }
+
+    let mut read = Vec::with_capacity(iterations);
+    let mut hash = Vec::with_capacity(iterations);
+    let mut decode = Vec::with_capacity(iterations);
+    let mut to_rgba = Vec::with_capacity(iterations);
+    let mut encode = Vec::with_capacity(iterations);
+    let mut total = Vec::with_capacity(iterations);
+    let mut dimensions = (0, 0);
+    let mut file_size = 0;
+
+    for _ in 0..iterations {
+        let cold_start = Instant::now();
+
+        let start = Instant::now();
+        let file_bytes = fs::read(image_path)?;
+        read.push(start.elapsed());
+        file_size = file_bytes.len() as u64;
+
+        let start = Instant::now();
+        let _ = hash_file_bytes(&file_bytes);
+        hash.push(start.elapsed());
+
+        let start = Instant::now();
+        let img = decode_image(&file_bytes)?;
+        decode.push(start.elapsed());
+        dimensions = img.dimensions();
+
+        let start = Instant::now();
+        let rgba_data = img.to_rgba8().into_vec();
+        to_rgba.push(start.elapsed());
+
+        let start = Instant::now();
+        encode_rgba(DEFAULT_COMPONENTS, dimensions.0, dimensions.1, &rgba_data)?;
+        encode.push(start.elapsed());
+
+        total.push(cold_start.elapsed());
+    }
+
+    Ok(ColdPathReport {
+        width: dimensions.0,
+        height: dimensions.1,
+        file_size,
+        total: Timing::from_samples(&total),
+        stages: StageTimings {
+            read: Timing::from_samples(&read),
+            hash: Timing::from_samples(&hash),
+            decode: Timing::from_samples(&decode),
+            to_rgba: Timing::from_samples(&to_rgba),
+            encode: Timing::from_samples(&encode),
+        },
+    })
+}
+
+/// Times decoding an image with each backend built in.
+///
+/// Needs no context, like [`measure_cold_path`].
+pub fn measure_decoders(image_path: &Path, iterations: usize) -> Result<Vec<DecoderTiming>> {
+    if iterations == 0 {
+        return Err(anyhow!("Benchmark iterations must be at least 1"));
+    }
+
+    let file_bytes = fs::read(image_path)?;
+    available_backends()
+        .into_iter()
+        .map(|backend| {
+            let mut decode = Vec::with_capacity(iterations);
+            for _ in 0..iterations {
+                let start = Instant::now();
+                backend.decode(&file_bytes, Some(SCALED_DECODE_MIN_SIZE))?;
+                decode.push(start.elapsed());
+            }
+            Ok(DecoderTiming {
+                name: backend.name(),
+                decode: Timing::from_samples(&decode),
+            })
+        })
+        .collect()
+}
+
+/// Times cached lookups of an image, populating its entry first if needed.
+pub fn measure_warm_path(
+    context: &mut AppContext,
+    image_path: &Path,
+    iterations: usize,
+) -> Result<Timing> {
+    if iterations == 0 {
+        return Err(anyhow!("Benchmark iterations must be at least 1"));
+    }
+
+    // Make sure the entry is cached so every measured run is a hit.
+    get_blurhash_with_cache(context, image_path)?;
+
+    let mut warm = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        get_blurhash_with_cache(context, image_path)?;
+        warm.push(start.elapsed());
+    }
+    Ok(Timing::from_samples(&warm))
+}
@@ -0,0 +1,211 @@
+//! Blurhashes of raw bitmaps, such as Electron `nativeImage` captures, which have no
+//! file to key them by. Bitmaps are cached under a hash of their pixels, like images
+//! outside every root under [`OutsideRootPolicy::Hash`], so the same capture is only
+//! encoded once.
+//!
+//! [`OutsideRootPolicy::Hash`]: crate::keys::OutsideRootPolicy::Hash
+
+use std::{path::PathBuf, time::Instant};
+
+use anyhow::{Result, anyhow};
+use image::{DynamicImage, RgbaImage};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{
+    core::{
+        AppContext, BlurhashData, CacheLookup, ComputedEntry, PendingEntry, ResolvedImage,
+        encode_decoded, evaluate_cached, find_cached, store_blurhash,
+    },
+    encode::DecodedImage,
+    keys::{CONTENT_KEY_PREFIX, CacheKey},
+    options::RequestOptions,
+    refresh::apply_refreshes,
+};
+
+/// Channel order of the pixels of a [`RawBitmap`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Blue, green, red, alpha, as returned by Electron's `nativeImage.toBitmap()`
+    /// on little-endian platforms.
+    #[default]
+    Bgra8,
+    /// Red, green, blue, alpha.
+    Rgba8,
+}
+
+impl PixelFormat {
+    /// Parses a pixel format name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "bgra" => Ok(Self::Bgra8),
+            "rgba" => Ok(Self::Rgba8),
+            other => Err(anyhow!(
+                "Unknown pixel format `{other}`, expected \"bgra\" or \"rgba\""
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Bgra8 => "bgra",
+            Self::Rgba8 => "rgba",
+        }
+    }
+}
+
+/// Uncompressed pixels with four 8-bit channels, row by row from the top.
+#[derive(Debug, Clone, Copy)]
+pub struct RawBitmap<'a> {
+    pub data: &'a [u8],
+    pub width: u32,
+    pub height: u32,
+    /// Bytes from the start of one row to the next, at least `width * 4`. Padding at
+    /// the end of rows is ignored.
+    pub row_stride: usize,
+    pub format: PixelFormat,
+}
+
+impl<'a> RawBitmap<'a> {
+    /// Tightly packed bitmap of `width` by `height` pixels.
+    pub fn new(data: &'a [u8], width: u32, height: u32, format: PixelFormat) -> Self {
+        Self {
+            data,
+            width,
+            height,
+            row_stride: width as usize * 4,
+            format,
+        }
+    }
+
+    /// The pixel bytes of each row, without padding, after checking that the
+    /// dimensions fit the data.
+    fn rows(&self) -> Result<impl Iterator<Item = &'a [u8]>> {
+        if self.width == 0 || self.height == 0 {
+            return Err(anyhow!(
+                "Bitmap dimensions must be positive, got {}x{}",
+                self.width,
+                self.height
+            ));
+        }
+        let row_len = self.width as usize * 4;
+        if self.row_stride < row_len {
+            return Err(anyhow!(
+                "Row stride {} is shorter than a row of {} pixels",
+                self.row_stride,
+                self.width
+            ));
+        }
+        let needed = self
+            .row_stride
+            .checked_mul(self.height as usize - 1)
+            .and_then(|size| size.checked_add(row_len))
+            .filter(|&needed| needed <= self.data.len())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Bitmap of {} bytes is too small for {}x{} pixels with a row stride of {}",
+                    self.data.len(),
+                    self.width,
+                    self.height,
+                    self.row_stride
+                )
+            })?;
+        let (data, stride) = (&self.data[..needed], self.row_stride);
+        Ok((0..self.height as usize).map(move |row| &data[row * stride..][..row_len]))
+    }
+
+    /// Hex-encoded xxh3 hash of the dimensions, channel order and pixels, the
+    /// content hash the bitmap is cached under.
+    pub fn content_hash(&self) -> Result<String> {
+        let mut hasher = Xxh3::new();
+        hasher.update(&self.width.to_le_bytes());
+        hasher.update(&self.height.to_le_bytes());
+        hasher.update(self.format.name().as_bytes());
+        for row in self.rows()? {
+            hasher.update(row);
+        }
+        Ok(hex::encode(hasher.digest().to_be_bytes()))
+    }
+
+    /// Copies the pixels into an RGBA image.
+    pub fn to_image(&self) -> Result<DynamicImage> {
+        let mut pixels = Vec::with_capacity(self.width as usize * self.height as usize * 4);
+        for row in self.rows()? {
+            match self.format {
+                PixelFormat::Rgba8 => pixels.extend_from_slice(row),
+                PixelFormat::Bgra8 => {
+                    for pixel in row.chunks_exact(4) {
+                        pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+                    }
+                }
+            }
+        }
+        let image = RgbaImage::from_raw(self.width, self.height, pixels)
+            .ok_or_else(|| anyhow!("Bitmap pixels do not fill its dimensions"))?;
+        Ok(DynamicImage::ImageRgba8(image))
+    }
+}
+
+/// Gets the blurhash of `bitmap`, encoding and caching it unless an entry for the
+/// same pixels exists. `request` applies as to file lookups; its crop and aspect
+/// ratio select a region of the bitmap, and `project_root` is ignored.
+pub fn get_blurhash_for_bitmap(
+    context: &mut AppContext,
+    bitmap: &RawBitmap,
+    request: &RequestOptions,
+) -> Result<BlurhashData> {
+    match lookup_bitmap(context, bitmap, request)? {
+        CacheLookup::Hit(data) => Ok(data),
+        CacheLookup::Pending(pending) => {
+            let computed = compute_bitmap(&pending, bitmap)?;
+            store_blurhash(context, *pending, computed)
+        }
+    }
+}
+
+/// Hashes the pixels of `bitmap` and checks the cache for them, like
+/// [`lookup_blurhash`](crate::core::lookup_blurhash) does for files.
+pub fn lookup_bitmap(
+    context: &mut AppContext,
+    bitmap: &RawBitmap,
+    request: &RequestOptions,
+) -> Result<CacheLookup> {
+    apply_refreshes(context)?;
+    let key = CacheKey::Content(format!("{CONTENT_KEY_PREFIX}{}", bitmap.content_hash()?));
+    let image = ResolvedImage {
+        absolute_path: PathBuf::new(),
+        path_key: key.as_str().to_string(),
+        key: key.for_request(request),
+        mtime_ms: 0,
+        file_size: bitmap.data.len() as u64,
+    };
+    let cached = find_cached(
+        &mut context.db_conn,
+        image.key(),
+        context.options.case_insensitive_keys,
+    )
+    .inspect_err(|_| context.metrics.record_db_error())?;
+    evaluate_cached(context, image, cached, request)
+}
+
+/// Encodes `bitmap` for an entry found missing by [`lookup_bitmap`]. Needs no
+/// context, so it can run on any thread.
+pub fn compute_bitmap(pending: &PendingEntry, bitmap: &RawBitmap) -> Result<ComputedEntry> {
+    let began = Instant::now();
+    let xxhash = pending
+        .path_key
+        .strip_prefix(CONTENT_KEY_PREFIX)
+        .unwrap_or_default()
+        .to_string();
+    let decoded = DecodedImage {
+        image: bitmap.to_image()?,
+        full_size: (bitmap.width, bitmap.height),
+        orientation: 1,
+        format: None,
+        bit_depth: 8,
+        color_space: "RGB".to_string(),
+        color_profile: None,
+    };
+    let computed = encode_decoded(pending, decoded, xxhash, bitmap.data.len() as u64, began)?;
+    pending.metrics.record_decode(began.elapsed());
+    Ok(computed)
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::{
+    core::{
+        AppContext, BlurhashData, CacheLookup, evaluate_cached, find_cached_many, lookup_blurhash,
+        resolve_image,
+    },
+    options::RequestOptions,
+    refresh::apply_refreshes,
+};
+
+/// Most keys fetched per query by [`lookup_many_cached`], below SQLite's historical
+/// limit of 999 bound parameters.
+const KEYS_PER_QUERY: usize = 900;
+
+/// Outcome of a generation scheduled after a cache miss.
+pub struct Completion {
+    /// Path the generation was scheduled for, as passed by the caller.
+    pub path: PathBuf,
+    pub result: Result<BlurhashData>,
+}
+
+/// Generations scheduled by non-blocking lookups, and their results until collected.
+///
+/// A path is scheduled at most once while its generation is in flight, so callers can
+/// poll the same missing image repeatedly without queueing duplicate work.
+#[derive(Default)]
+pub struct CompletionQueue {
+    state: Mutex<CompletionQueueState>,
+    idle: Condvar,
+}
+
+#[derive(Default)]
+struct CompletionQueueState {
+    in_flight: HashSet<PathBuf>,
+    done: Vec<Completion>,
+}
+
+impl CompletionQueue {
+    /// Marks `path` as in flight. Returns `false` if it already was, in which case
+    /// the caller must not generate it again.
+    pub fn schedule(&self, path: &Path) -> bool {
+        match self.state.lock() {
+            Ok(mut state) => state.in_flight.insert(path.to_path_buf()),
+            Err(_) => false,
+        }
+    }
+
+    /// Stores the result of a generation started with [`CompletionQueue::schedule`].
+    pub fn complete(&self, path: PathBuf, result: Result<BlurhashData>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.in_flight.remove(&path);
+            state.done.push(Completion { path, result });
+        }
+        self.idle.notify_all();
+    }
+
+    /// Takes every stored result, oldest first.
+    pub fn drain(&self) -> Vec<Completion> {
+        match self.state.lock() {
+            Ok(mut state) => std::mem::take(&mut state.done),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Blocks until no generation is in flight.
+    pub fn wait_idle(&self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        while !state.in_flight.is_empty() {
+            state = match self.idle.wait(state) {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+        }
+    }
+
+    /// Number of generations in flight.
+    pub fn in_flight(&self) -> usize {
+        self.state
+            .lock()
+            .map(|state| state.in_flight.len())
+            .unwrap_or(0)
+    }
+}
+
+/// Returns the cached blurhash for an image if it is valid without reading the
+/// image, or `None` on a miss.
+///
+/// Only file metadata and the database are touched (plus the file content under
+/// [`crate::keys::OutsideRootPolicy::Hash`], to derive the key). Entries that would
+/// need a content hash comparison to be confirmed count as misses.
+pub fn lookup_cached(
+    context: &mut AppContext,
+    image_path: &Path,
+    request: &RequestOptions,
+) -> Result<Option<BlurhashData>> {
+    match lookup_blurhash(context, image_path, request)? {
+        CacheLookup::Hit(data) => Ok(Some(data)),
+        CacheLookup::Pending(_) => Ok(None),
+    }
+}
+
+/// Outcome of [`lookup_many_cached`], by image path as passed by the caller.
+#[derive(Debug, Default)]
+pub struct CachedBatch {
+    /// Images with a valid cached entry.
+    pub hits: BTreeMap<PathBuf, BlurhashData>,
+    /// Images without a valid cached entry, in the order they were passed.
+    pub misses: Vec<PathBuf>,
+    /// Images that could not be looked up, e.g. missing or disallowed files.
+    pub errors: BTreeMap<PathBuf, anyhow::Error>,
+}
+
+/// Looks up many images like [`lookup_cached`], fetching their cache rows in one query
+/// (per [`KEYS_PER_QUERY`] images) rather than one per image.
+///
+/// Never reads an image and never schedules a generation; repeated paths are looked
+/// up once. Fails only when the rows cannot be fetched.
+pub fn lookup_many_cached(
+    context: &mut AppContext,
+    image_paths: &[PathBuf],
+    request: &RequestOptions,
+) -> Result<CachedBatch> {
+    apply_refreshes(context)?;
+    let mut batch = CachedBatch::default();
+    let mut seen = HashSet::new();
+    let mut resolved = Vec::new();
+    for path in image_paths {
+        if !seen.insert(path) {
+            continue;
+        }
+        match resolve_image(context, path, request) {
+            Ok(image) => resolved.push((path, image)),
+            Err(e) => {
+                batch.errors.insert(path.clone(), e);
+            }
+        }
+    }
+
+    let case_insensitive = context.options.case_insensitive_keys;
+    let fold = |key: &str| {
+        if case_insensitive {
+            key.to_ascii_lowercase()
+        } else {
+            key.to_string()
+        }
+    };
+    let mut rows = HashMap::new();
+    for chunk in resolved.chunks(KEYS_PER_QUERY) {
+        let keys: Vec<&str> = chunk.iter().map(|(_, image)| image.key()).collect();
+        let found = find_cached_many(&mut context.db_conn, &keys, case_insensitive)
+            .inspect_err(|_| context.metrics.record_db_error())?;
+        // Rows come oldest first, and like `find_cached` the oldest match wins.
+        for row in found {
+            rows.entry(fold(&row.relative_path)).or_insert(row);
+        }
+    }
+
+    for (path, image) in resolved {
+        let cached = rows.get(&fold(image.key())).cloned();
+        match evaluate_cached(context, image, cached, request) {
+            Ok(CacheLookup::Hit(data)) => {
+                batch.hits.insert(path.clone(), data);
+            }
+            Ok(CacheLookup::Pending(_)) => batch.misses.push(path.clone()),
+            Err(e) => {
+                batch.errors.insert(path.clone(), e);
+            }
+        }
+    }
+    Ok(batch)
+}
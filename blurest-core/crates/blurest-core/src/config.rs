@@ -0,0 +1,434 @@
+//! Loading context settings from a `blurest.config.json` or `blurest.config.toml` file
+//! and `BLUREST_*` environment variables, so the Node.js module, the CLI and its server
+//! share one configuration.
+//!
+//! Keys use the names of the JavaScript options:
+//!
+//! ```json
+//! {
+//!   "databaseUrl": ".cache/blurhash.sqlite3",
+//!   "projectRoot": ["apps/web/public", "apps/docs/public"],
+//!   "componentsX": 4,
+//!   "componentsY": 3,
+//!   "maxConcurrentDecodes": 4,
+//!   "encodeRules": [{ "pattern": "png", "componentsX": 3, "componentsY": 3 }],
+//!   "ignore": ["**/drafts/**", "*.svg"]
+//! }
+//! ```
+//!
+//! Relative paths are resolved against the directory of the config file, which is also
+//! the project root if `projectRoot` is not set. Each key can also be set through the
+//! environment variable listed in [`FIELDS`], e.g. `BLUREST_DATABASE_URL`, which takes
+//! precedence over the file; see [`resolve_config`]. A `quality` preset is applied
+//! before the other keys of the file or the environment, which override it.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, anyhow};
+use glob::{MatchOptions, Pattern};
+use log::LevelFilter;
+use serde_json::Value;
+
+use crate::{
+    backend::Decoder,
+    encode::MAX_PREVIEW_SIZE,
+    keys::{NonUtf8Policy, OutsideRootPolicy},
+    logging::LogFormat,
+    options::{CacheValidation, ContextOptions, EncodeRule, KeyPattern, Quality, Revalidate},
+};
+
+/// Keys accepted in config files, with the environment variables that set them.
+pub const FIELDS: [(&str, &str); 32] = [
+    ("databaseUrl", "BLUREST_DATABASE_URL"),
+    ("projectRoot", "BLUREST_PROJECT_ROOT"),
+    ("workers", "BLUREST_WORKERS"),
+    ("quality", "BLUREST_QUALITY"),
+    ("componentsX", "BLUREST_COMPONENTS_X"),
+    ("componentsY", "BLUREST_COMPONENTS_Y"),
+    ("encodeSize", "BLUREST_ENCODE_SIZE"),
+    ("encodeRules", "BLUREST_ENCODE_RULES"),
+    ("maxConcurrentDecodes", "BLUREST_MAX_CONCURRENT_DECODES"),
+    ("decodeMemoryBudget", "BLUREST_DECODE_MEMORY_BUDGET"),
+    ("decoder", "BLUREST_DECODER"),
+    ("mtimeToleranceMs", "BLUREST_MTIME_TOLERANCE_MS"),
+    ("ioRetries", "BLUREST_IO_RETRIES"),
+    ("validation", "BLUREST_VALIDATION"),
+    ("revalidate", "BLUREST_REVALIDATE"),
+    ("outsideRoot", "BLUREST_OUTSIDE_ROOT"),
+    ("nonUtf8Keys", "BLUREST_NON_UTF8_KEYS"),
+    ("caseInsensitiveKeys", "BLUREST_CASE_INSENSITIVE_KEYS"),
+    ("sandbox", "BLUREST_SANDBOX"),
+    ("blurDataUrl", "BLUREST_BLUR_DATA_URL"),
+    ("persistWebpPreviews", "BLUREST_PERSIST_WEBP_PREVIEWS"),
+    ("microPixels", "BLUREST_MICRO_PIXELS"),
+    ("previewSize", "BLUREST_PREVIEW_SIZE"),
+    ("thumbnailSize", "BLUREST_THUMBNAIL_SIZE"),
+    ("applyOrientation", "BLUREST_APPLY_ORIENTATION"),
+    ("allowedExtensions", "BLUREST_ALLOWED_EXTENSIONS"),
+    ("allowedMimeTypes", "BLUREST_ALLOWED_MIME_TYPES"),
+    ("renditionWidths", "BLUREST_RENDITION_WIDTHS"),
+    ("ignore", "BLUREST_IGNORE"),
+    ("logLevel", "BLUREST_LOG"),
+    ("logFormat", "BLUREST_LOG_FORMAT"),
+    ("logFile", "BLUREST_LOG_FILE"),
+];
+
+/// File names looked up by [`find_config`], in order of preference.
+pub const CONFIG_FILE_NAMES: [&str; 2] = ["blurest.config.json", "blurest.config.toml"];
+
+/// Settings read from a config file.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Path of the cache database, if configured.
+    pub database_url: Option<String>,
+    /// Project roots, not yet canonicalized.
+    pub project_roots: Vec<PathBuf>,
+    /// Context options, defaults for any key that is not set.
+    pub options: ContextOptions,
+    /// Patterns of cache keys (paths relative to their project root) that bulk
+    /// operations such as warming skip.
+    pub ignore: Vec<Pattern>,
+    /// Maximum level of log records, if configured.
+    pub log_level: Option<LevelFilter>,
+    /// Log output format, if configured.
+    pub log_format: Option<LogFormat>,
+    /// File to append log records to instead of stderr, if configured.
+    pub log_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Whether the image keyed `key` matches one of the [`Config::ignore`] patterns.
+    pub fn is_ignored(&self, key: &str) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: false,
+            ..MatchOptions::new()
+        };
+        self.ignore
+            .iter()
+            .any(|pattern| pattern.matches_with(key, options))
+    }
+}
+
+/// Returns the first of [`CONFIG_FILE_NAMES`] that exists in `dir`.
+pub fn find_config(dir: &Path) -> Option<PathBuf> {
+    CONFIG_FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Reads and validates the config file at `path`, parsed as TOML if its extension is
+/// `.toml` and as JSON otherwise.
+pub fn load_config(path: &Path) -> Result<Config> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read config file {path:?}"))?;
+    let value: Value = if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        toml::from_str(&text).with_context(|| format!("Failed to parse config file {path:?}"))?
+    } else {
+        serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse config file {path:?}"))?
+    };
+    let base = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    parse_config(value, base).with_context(|| format!("Invalid config file {path:?}"))
+}
+
+/// Builds a [`Config`] from the parsed file, resolving relative paths against `base`.
+fn parse_config(value: Value, base: &Path) -> Result<Config> {
+    let Value::Object(fields) = value else {
+        return Err(anyhow!("Expected an object at the top level"));
+    };
+
+    let mut config = Config::default();
+    // The quality preset goes first, so the settings it covers override it.
+    let (quality, others): (Vec<_>, Vec<_>) = fields.iter().partition(|(key, _)| *key == "quality");
+    for (key, value) in quality.into_iter().chain(others) {
+        if key == "$schema" {
+            continue;
+        }
+        if !FIELDS.iter().any(|(name, _)| name == key) {
+            let names: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+            return Err(anyhow!(
+                "Unknown key `{key}`, expected one of {}",
+                names.join(", ")
+            ));
+        }
+        set_field(&mut config, key, value, base)?;
+    }
+    if config.project_roots.is_empty() {
+        config.project_roots.push(base.to_path_buf());
+    }
+    Ok(config)
+}
+
+/// Overwrites the fields of `config` that are set through `BLUREST_*` environment
+/// variables (see [`FIELDS`]), so containers can be configured without a file.
+/// Relative paths are resolved against the current directory.
+///
+/// Values are parsed like their JSON counterparts; lists are comma-separated, except
+/// `BLUREST_PROJECT_ROOT`, which is separated like `PATH`, and `BLUREST_ENCODE_RULES`,
+/// which holds the JSON array.
+pub fn apply_env(config: &mut Config) -> Result<()> {
+    for (key, var) in FIELDS {
+        let Some(raw) = env::var_os(var) else {
+            continue;
+        };
+        let raw = raw
+            .into_string()
+            .map_err(|_| anyhow!("Environment variable {var} is not valid UTF-8"))?;
+        let value = match key {
+            "projectRoot" => env::split_paths(&raw)
+                .map(|path| Value::String(path.to_string_lossy().into_owned()))
+                .collect(),
+            "allowedExtensions" | "allowedMimeTypes" | "ignore" => raw
+                .split(',')
+                .map(|item| Value::String(item.trim().to_string()))
+                .collect(),
+            "encodeRules" => serde_json::from_str(&raw)
+                .with_context(|| format!("Invalid environment variable {var}"))?,
+            "renditionWidths" => raw
+                .split(',')
+                .map(|item| {
+                    serde_json::from_str(item.trim())
+                        .unwrap_or_else(|_| Value::String(item.trim().to_string()))
+                })
+                .collect(),
+            _ => match serde_json::from_str(&raw) {
+                Ok(value @ (Value::Number(_) | Value::Bool(_) | Value::Null)) => value,
+                _ => Value::String(raw),
+            },
+        };
+        set_field(config, key, &value, Path::new("."))
+            .with_context(|| format!("Invalid environment variable {var}"))?;
+    }
+    Ok(())
+}
+
+/// Loads the config file at `path`, or named by `BLUREST_CONFIG`, or found in the
+/// current directory, and applies the environment on top with [`apply_env`].
+///
+/// Without any config file, only the environment is used.
+pub fn resolve_config(path: Option<&Path>) -> Result<Config> {
+    let path = match path {
+        Some(path) => Some(path.to_path_buf()),
+        None => match env::var_os("BLUREST_CONFIG") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => find_config(Path::new(".")),
+        },
+    };
+    let mut config = match path {
+        Some(path) => load_config(&path)?,
+        None => Config::default(),
+    };
+    apply_env(&mut config)?;
+    Ok(config)
+}
+
+/// Sets the field named `key` from `value`.
+fn set_field(config: &mut Config, key: &str, value: &Value, base: &Path) -> Result<()> {
+    let options = &mut config.options;
+    match key {
+        "databaseUrl" => {
+            let url = string(key, value)?;
+            config.database_url = Some(if url == ":memory:" || Path::new(url).is_absolute() {
+                url.to_string()
+            } else {
+                base.join(url).to_string_lossy().into_owned()
+            });
+        }
+        "projectRoot" => {
+            config.project_roots = strings(key, value)?
+                .into_iter()
+                .map(|root| base.join(root))
+                .collect();
+            if config.project_roots.is_empty() {
+                return Err(anyhow!("`{key}` must list at least one project root"));
+            }
+        }
+        "workers" => options.workers = integer(key, value, 1, u64::MAX)? as usize,
+        "quality" => Quality::parse(string(key, value)?)?.apply(options),
+        "componentsX" => options.components.0 = integer(key, value, 1, 9)? as u32,
+        "componentsY" => options.components.1 = integer(key, value, 1, 9)? as u32,
+        "encodeSize" => {
+            options.encode_size = Some(integer(key, value, 1, u64::from(u32::MAX))? as u32);
+        }
+        "encodeRules" => {
+            options.encode_rules = match value {
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| encode_rule(key, item))
+                    .collect::<Result<_>>()?,
+                _ => return Err(anyhow!("`{key}` must be an array of rules, found {value}")),
+            };
+        }
+        "maxConcurrentDecodes" => {
+            options.max_concurrent_decodes = limit(key, value)?.map(|max| max as usize);
+        }
+        "decodeMemoryBudget" => options.decode_memory_budget = limit(key, value)?,
+        "decoder" => options.decoder = Decoder::parse(string(key, value)?)?,
+        "mtimeToleranceMs" => options.mtime_tolerance_ms = integer(key, value, 0, u64::MAX)?,
+        "ioRetries" => options.io_retries = integer(key, value, 0, u64::from(u32::MAX))? as u32,
+        "validation" => options.validation = CacheValidation::parse(string(key, value)?)?,
+        "revalidate" => options.revalidate = Revalidate::parse(string(key, value)?)?,
+        "outsideRoot" => options.outside_root = OutsideRootPolicy::parse(string(key, value)?)?,
+        "nonUtf8Keys" => options.non_utf8_keys = NonUtf8Policy::parse(string(key, value)?)?,
+        "caseInsensitiveKeys" => options.case_insensitive_keys = boolean(key, value)?,
+        "sandbox" => options.sandbox = boolean(key, value)?,
+        "blurDataUrl" => options.blur_data_url = boolean(key, value)?,
+        "persistWebpPreviews" => options.persist_webp_previews = boolean(key, value)?,
+        "microPixels" => options.micro_pixels = boolean(key, value)?,
+        "previewSize" => {
+            options.preview_size = integer(key, value, 1, u64::from(MAX_PREVIEW_SIZE))? as u32;
+        }
+        "thumbnailSize" => {
+            options.thumbnail_size =
+                Some(integer(key, value, 1, u64::from(MAX_PREVIEW_SIZE))? as u32);
+        }
+        "applyOrientation" => options.apply_orientation = boolean(key, value)?,
+        "allowedExtensions" => {
+            options.allowed_extensions = Some(
+                strings(key, value)?
+                    .iter()
+                    .map(|extension| extension.trim_start_matches('.').to_ascii_lowercase())
+                    .collect(),
+            );
+        }
+        "allowedMimeTypes" => {
+            options.allowed_mime_types = Some(
+                strings(key, value)?
+                    .iter()
+                    .map(|mime_type| mime_type.to_ascii_lowercase())
+                    .collect(),
+            );
+        }
+        "renditionWidths" => {
+            options.rendition_widths = match value {
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| integer(key, item, 1, u64::from(u32::MAX)).map(|w| w as u32))
+                    .collect::<Result<_>>()?,
+                _ => return Err(anyhow!("`{key}` must be an array of widths, found {value}")),
+            };
+        }
+        "ignore" => {
+            config.ignore = strings(key, value)?
+                .iter()
+                .map(|pattern| {
+                    Pattern::new(pattern)
+                        .with_context(|| format!("Invalid pattern `{pattern}` in `{key}`"))
+                })
+                .collect::<Result<_>>()?;
+        }
+        "logLevel" => {
+            let level = string(key, value)?;
+            config.log_level = Some(level.parse().map_err(|_| {
+                anyhow!(
+                    "Unknown logLevel `{level}`, expected \"off\", \"error\", \"warn\", \"info\", \"debug\" or \"trace\""
+                )
+            })?);
+        }
+        "logFormat" => config.log_format = Some(LogFormat::parse(string(key, value)?)?),
+        "logFile" => config.log_file = Some(base.join(string(key, value)?)),
+        _ => unreachable!("`{key}` is not listed in FIELDS"),
+    }
+    Ok(())
+}
+
+fn string<'a>(key: &str, value: &'a Value) -> Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| anyhow!("`{key}` must be a string, found {value}"))
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| anyhow!("`{key}` must be a boolean, found {value}"))
+}
+
+/// Reads an integer between `min` and `max`, inclusive.
+fn integer(key: &str, value: &Value, min: u64, max: u64) -> Result<u64> {
+    value
+        .as_u64()
+        .filter(|number| (min..=max).contains(number))
+        .ok_or_else(|| match max {
+            u64::MAX => anyhow!("`{key}` must be an integer of at least {min}, found {value}"),
+            _ => anyhow!("`{key}` must be an integer between {min} and {max}, found {value}"),
+        })
+}
+
+/// Reads one of the `encodeRules`, an object with a `pattern` and the settings it
+/// overrides.
+fn encode_rule(key: &str, value: &Value) -> Result<EncodeRule> {
+    let Value::Object(fields) = value else {
+        return Err(anyhow!(
+            "`{key}` must be an array of objects, found {value}"
+        ));
+    };
+    let setting = |name: &str, max: u32| {
+        fields
+            .get(name)
+            .map(|value| integer(&format!("{key}.{name}"), value, 1, u64::from(max)))
+            .transpose()
+            .map(|value| value.map(|value| value as u32))
+    };
+    let rule = EncodeRule {
+        pattern: KeyPattern::parse(string(
+            &format!("{key}.pattern"),
+            fields.get("pattern").unwrap_or(&Value::Null),
+        )?)?,
+        components_x: setting("componentsX", 9)?,
+        components_y: setting("componentsY", 9)?,
+        encode_size: setting("encodeSize", u32::MAX)?,
+        preview_size: setting("previewSize", MAX_PREVIEW_SIZE)?,
+    };
+    if let Some(name) = fields.keys().find(|name| {
+        ![
+            "pattern",
+            "componentsX",
+            "componentsY",
+            "encodeSize",
+            "previewSize",
+        ]
+        .contains(&name.as_str())
+    }) {
+        return Err(anyhow!(
+            "Unknown key `{name}` in `{key}`, expected pattern, componentsX, componentsY, encodeSize or previewSize"
+        ));
+    }
+    Ok(rule)
+}
+
+/// Reads an optional limit, where `null` means no limit.
+fn limit(key: &str, value: &Value) -> Result<Option<u64>> {
+    match value {
+        Value::Null => Ok(None),
+        _ => integer(key, value, 1, u64::MAX).map(Some),
+    }
+}
+
+/// Reads a string or an array of strings.
+fn strings(key: &str, value: &Value) -> Result<Vec<String>> {
+    match value {
+        Value::String(single) => Ok(vec![single.clone()]),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| {
+                item.as_str().map(str::to_string).ok_or_else(|| {
+                    anyhow!("`{key}` must be a string or an array of strings, found {item}")
+                })
+            })
+            .collect(),
+        _ => Err(anyhow!(
+            "`{key}` must be a string or an array of strings, found {value}"
+        )),
+    }
+}
@@ -0,0 +1,1840 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as AnyhowContext, Result};
+use diesel::{
+    SqliteConnection,
+    dsl::sql,
+    prelude::*,
+    sql_types::{Bool, Text},
+};
+use image::{ImageDecoder, ImageReader, metadata::Orientation};
+use log::{debug, info, warn};
+
+use crate::{
+    backend::{Decoder, SCALED_DECODE_MIN_SIZE, decode_identity},
+    completions::CompletionQueue,
+    encode::{
+        ALGO_VERSION, DecodedImage, average_color, blur_data_url, blur_webp, blurhash_components,
+        crop_image, difference_hash, encode_image, encode_thumbnail, focal_region,
+        has_transparency, hash_file_bytes, micro_pixels, orientation_swaps_axes,
+        overlay_text_color,
+    },
+    error::{BlurestError, BlurestWarning, error_code},
+    keys::{CacheKey, derive_key, is_within_roots},
+    maintenance::{MaintenanceSchedule, normalize_keys},
+    manifest::Manifest,
+    metrics::Metrics,
+    migrations::run_migrations,
+    models::{
+        BlurhashCache, NewBlurhashCache, NewPlaceholderCache, NewThumbnailCache, PlaceholderCache,
+    },
+    options::{
+        CacheValidation, ContextOptions, Crop, FocalPoint, Output, RequestOptions, Revalidate,
+    },
+    placeholder::{Payloads, PlaceholderGenerator, check_generators, generate_placeholders},
+    pool::WorkerPool,
+    project::ProjectLock,
+    refresh::{RefreshQueue, apply_refreshes, schedule_refresh},
+    responsive::{Responsive, responsive},
+    retry::retry_io,
+    sandbox::{check_allowed, check_traversal, is_supported_image},
+    schema::{blurhash_cache, placeholder_cache, thumbnail_cache},
+    snapshot::SnapshotSchedule,
+    telemetry::Span,
+};
+
+/// Application context containing database connection and project root paths
+pub struct AppContext {
+    pub db_conn: SqliteConnection,
+    /// Database `db_conn` was opened from, for [`AppContext::reconnect`]; `None` for
+    /// contexts created from a connection.
+    pub database_url: Option<String>,
+    /// Canonical project roots. Cache keys are relative to whichever root contains the image.
+    pub project_roots: Vec<PathBuf>,
+    pub options: ContextOptions,
+    pub pool: Arc<WorkerPool>,
+    pub decode_limiter: Arc<DecodeLimiter>,
+    /// Blurhashes handed out through [`crate::manifest::get_or_generate`].
+    pub manifest: Manifest,
+    /// Every blurhash handed out since [`crate::manifest::begin_session`], until
+    /// [`crate::manifest::finalize_session`]; `None` outside a session.
+    pub session: Option<Manifest>,
+    /// Generations scheduled after misses of [`crate::completions::lookup_cached`].
+    pub completions: Arc<CompletionQueue>,
+    /// Cache hit, miss and decode counters, for [`crate::metrics::render_prometheus`].
+    pub metrics: Arc<Metrics>,
+    /// Regenerations of stale entries served under [`Revalidate::Background`].
+    pub refreshes: Arc<RefreshQueue>,
+    /// Receives errors no caller sees, such as failed background refreshes; they are
+    /// logged either way.
+    pub error_hook: Option<ErrorHook>,
+    /// Thread running [`ContextOptions::maintenance`], stopped when dropped; see
+    /// [`crate::maintenance::schedule_maintenance`].
+    pub maintenance_schedule: Option<MaintenanceSchedule>,
+    /// Thread writing [`ContextOptions::snapshot`], stopped when dropped; see
+    /// [`crate::snapshot::schedule_snapshots`].
+    pub snapshot_schedule: Option<SnapshotSchedule>,
+    /// Lock on the project-local cache of [`AppContext::open_project`], held for as
+    /// long as the context lives.
+    pub project_lock: Option<ProjectLock>,
+}
+
+/// Callback for errors that are not returned to any caller, with the cache key of
+/// the image they concern. Called from worker threads and while the context is held,
+/// so it should only hand the error off.
+pub type ErrorHook = Arc<dyn Fn(&str, &anyhow::Error) + Send + Sync>;
+
+impl AppContext {
+    /// Creates a context and spawns its worker pool according to `options`, and
+    /// rewrites keys stored in a legacy form with [`normalize_keys`].
+    ///
+    /// `project_roots` must contain at least one canonical path.
+    pub fn new(
+        db_conn: SqliteConnection,
+        project_roots: Vec<PathBuf>,
+        options: ContextOptions,
+    ) -> Result<Self> {
+        if project_roots.is_empty() {
+            return Err(anyhow::anyhow!("At least one project root is required"));
+        }
+        check_generators(&options.generators)?;
+        let pool = Arc::new(WorkerPool::new(options.workers)?);
+        let decode_limiter = Arc::new(DecodeLimiter::new(
+            options.max_concurrent_decodes,
+            options.decode_memory_budget,
+        ));
+        let mut context = Self {
+            db_conn,
+            database_url: None,
+            project_roots,
+            options,
+            pool,
+            decode_limiter,
+            manifest: Manifest::default(),
+            session: None,
+            completions: Arc::default(),
+            metrics: Arc::default(),
+            refreshes: Arc::default(),
+            error_hook: None,
+            maintenance_schedule: None,
+            snapshot_schedule: None,
+            project_lock: None,
+        };
+        normalize_keys(&mut context).context("Failed to normalize cache keys")?;
+        Ok(context)
+    }
+
+    /// Opens (creating and migrating if needed) the database at `database_url` and
+    /// creates a context on it like [`AppContext::new`], able to reconnect later.
+    pub fn open(
+        database_url: &str,
+        project_roots: Vec<PathBuf>,
+        options: ContextOptions,
+    ) -> Result<Self> {
+        let db_conn = initialize_and_connect_db(database_url)?;
+        let mut context = Self::new(db_conn, project_roots, options)?;
+        context.database_url = Some(database_url.to_string());
+        Ok(context)
+    }
+
+    /// Opens the cache of the first project root in its `.blurest` directory, see
+    /// [`crate::project`], once no other process holds it, waiting up to
+    /// `lock_timeout` for it to be released. Fails with
+    /// [`crate::project::ProjectLocked`] if it is not.
+    pub fn open_project(
+        project_roots: Vec<PathBuf>,
+        options: ContextOptions,
+        lock_timeout: Duration,
+    ) -> Result<Self> {
+        let root = project_roots
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("At least one project root is required"))?;
+        let lock = ProjectLock::acquire(root, lock_timeout)?;
+        let database_url = lock.database_path().to_string_lossy().into_owned();
+        let mut context = Self::open(&database_url, project_roots, options)?;
+        context.project_lock = Some(lock);
+        Ok(context)
+    }
+
+    /// Replaces the database connection with a fresh one to the same database, e.g.
+    /// after the database was briefly unreachable, keeping the options, worker pool,
+    /// metrics and manifests. The old connection is kept if reconnecting fails.
+    pub fn reconnect(&mut self) -> Result<()> {
+        let database_url = self.database_url.as_deref().ok_or_else(|| {
+            anyhow::anyhow!("The context was created from a connection and cannot reconnect")
+        })?;
+        self.db_conn = initialize_and_connect_db(database_url)?;
+        if let Some(schedule) = &mut self.snapshot_schedule {
+            schedule.forget_written();
+        }
+        Ok(())
+    }
+
+    /// Applies new options to the live context without touching the database or cache.
+    ///
+    /// The worker pool is replaced only when the worker count changes; jobs already
+    /// queued on the old pool still run to completion. Disabling
+    /// [`ContextOptions::maintenance`] or [`ContextOptions::snapshot`] stops its
+    /// thread; enabling or changing one takes a call to
+    /// [`crate::maintenance::schedule_maintenance`] or
+    /// [`crate::snapshot::schedule_snapshots`].
+    pub fn reconfigure(&mut self, options: ContextOptions) -> Result<()> {
+        check_generators(&options.generators)?;
+        if options.workers != self.pool.size() {
+            self.pool = Arc::new(WorkerPool::new(options.workers)?);
+        }
+        self.decode_limiter
+            .set_limits(options.max_concurrent_decodes, options.decode_memory_budget);
+        if options.maintenance.is_none() {
+            self.maintenance_schedule = None;
+        }
+        if options.snapshot.is_none() {
+            self.snapshot_schedule = None;
+        }
+        self.options = options;
+        Ok(())
+    }
+}
+
+/// Admission control bounding how many images are decoded at the same time.
+///
+/// Limits both the number of concurrent decodes and, optionally, the sum of their
+/// estimated decoded sizes. Shared by every API of a context (sync, async and batch),
+/// so peak memory stays bounded no matter how many callers or worker threads are active.
+pub struct DecodeLimiter {
+    state: Mutex<DecodeLimiterState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct DecodeLimiterState {
+    max_concurrent: Option<usize>,
+    memory_budget: Option<u64>,
+    active: usize,
+    reserved_bytes: u64,
+}
+
+impl DecodeLimiter {
+    /// Creates a limiter admitting at most `max_concurrent` decodes whose estimated sizes
+    /// sum to at most `memory_budget` bytes. `None` disables the respective limit.
+    pub fn new(max_concurrent: Option<usize>, memory_budget: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(DecodeLimiterState {
+                max_concurrent,
+                memory_budget,
+                ..Default::default()
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Replaces both limits. Decodes already in progress keep their permits; waiting
+    /// decodes are re-checked against the new limits immediately.
+    pub fn set_limits(&self, max_concurrent: Option<usize>, memory_budget: Option<u64>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.max_concurrent = max_concurrent;
+            state.memory_budget = memory_budget;
+        }
+        self.released.notify_all();
+    }
+
+    /// Blocks until a decode of `estimated_bytes` can be admitted and returns a permit holding it.
+    ///
+    /// A decode larger than the whole budget is admitted once nothing else is decoding,
+    /// so oversized images are serialized instead of failing.
+    pub fn acquire(&self, estimated_bytes: u64) -> Result<DecodePermit<'_>> {
+        let poisoned = || anyhow::anyhow!("Decode limiter lock was poisoned");
+        let mut state = self.state.lock().map_err(|_| poisoned())?;
+        while !Self::admits(&state, estimated_bytes) {
+            state = self.released.wait(state).map_err(|_| poisoned())?;
+        }
+        state.active += 1;
+        state.reserved_bytes += estimated_bytes;
+        Ok(DecodePermit {
+            limiter: self,
+            bytes: estimated_bytes,
+        })
+    }
+
+    fn admits(state: &DecodeLimiterState, estimated_bytes: u64) -> bool {
+        if state.max_concurrent.is_some_and(|max| state.active >= max) {
+            return false;
+        }
+        match state.memory_budget {
+            Some(budget) => state.active == 0 || state.reserved_bytes + estimated_bytes <= budget,
+            None => true,
+        }
+    }
+
+    /// Number of decodes currently in progress.
+    pub fn active(&self) -> usize {
+        self.state.lock().map(|state| state.active).unwrap_or(0)
+    }
+
+    /// Estimated bytes reserved by decodes currently in progress.
+    pub fn reserved_bytes(&self) -> u64 {
+        self.state
+            .lock()
+            .map(|state| state.reserved_bytes)
+            .unwrap_or(0)
+    }
+}
+
+/// A held decode slot, released when dropped.
+pub struct DecodePermit<'a> {
+    limiter: &'a DecodeLimiter,
+    bytes: u64,
+}
+
+impl Drop for DecodePermit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.limiter.state.lock() {
+            state.active -= 1;
+            state.reserved_bytes -= self.bytes;
+        }
+        self.limiter.released.notify_all();
+    }
+}
+
+/// Estimates the peak memory needed to decode an image by probing its header.
+///
+/// Accounts for the decoded buffer plus the RGBA copy handed to the encoder.
+/// Returns 0 when the header cannot be parsed; the decode itself will then report the error.
+pub fn estimate_decoded_size(file_bytes: &[u8]) -> u64 {
+    let decoder = ImageReader::new(Cursor::new(file_bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok());
+    match decoder {
+        Some(decoder) => {
+            let (width, height) = decoder.dimensions();
+            decoder
+                .total_bytes()
+                .saturating_add(u64::from(width) * u64::from(height) * 4)
+        }
+        None => 0,
+    }
+}
+
+#[derive(Debug)]
+pub struct BlurhashData {
+    /// Cache key the image is stored under.
+    pub key: String,
+    /// Key derived from the requested path, before matching stored keys regardless
+    /// of case and without the suffixes of a cropped entry.
+    pub path_key: String,
+    pub blurhash: String,
+    pub width: i32,
+    pub height: i32,
+    /// Tiny PNG data URL of the blurhash, when [`ContextOptions::blur_data_url`] is set
+    /// or [`Output::DataUri`] was requested.
+    pub blur_data_url: Option<String>,
+    /// Tiny WebP of the blurhash, when [`Output::WebP`] was requested.
+    pub webp_preview: Option<Vec<u8>>,
+    /// RGB pixels of the blurhash decoded at 8x8, see [`micro_pixels`], when
+    /// [`ContextOptions::micro_pixels`] is set.
+    pub micro_pixels: Option<Vec<u8>>,
+    /// Payloads of each generator in [`ContextOptions::generators`], by generator name.
+    pub placeholders: BTreeMap<String, Payloads>,
+    /// Rendition sizes, when rendition widths are configured or requested.
+    pub responsive: Option<Responsive>,
+    /// EXIF orientation of the file, from 1 (upright) to 8, or `None` for entries
+    /// cached before orientations were recorded.
+    pub orientation: Option<u8>,
+    /// Whether the orientation was applied before encoding, in which case `width` and
+    /// `height` are the displayed dimensions rather than the stored ones.
+    pub orientation_applied: bool,
+    /// Whether the encoded image, or its cropped region, has transparent pixels, or
+    /// `None` for entries cached before transparency was recorded.
+    pub has_alpha: Option<bool>,
+    /// Container format detected from the file contents, e.g. `"jpeg"`, or `None` for
+    /// entries cached before formats were recorded.
+    pub format: Option<String>,
+    /// Bits per channel of the file, e.g. 8 or 16, or `None` for entries cached before
+    /// bit depths were recorded.
+    pub bit_depth: Option<u8>,
+    /// Difference hash of the encoded image, see [`difference_hash`], or `None` for
+    /// entries cached before perceptual hashes were recorded.
+    pub perceptual_hash: Option<u64>,
+    /// Color space of the file, e.g. `"RGB"` or `"CMYK"`, or `None` for entries cached
+    /// before color spaces were recorded.
+    pub color_space: Option<String>,
+    /// Name of the ICC profile embedded in the file, e.g. `"Display P3"`, or `None` if
+    /// the file is untagged or was cached before profiles were recorded.
+    pub color_profile: Option<String>,
+    /// Conditions noticed while looking up this image that did not stop it.
+    pub warnings: Vec<BlurestWarning>,
+}
+
+impl BlurhashData {
+    /// Dimensions of the pixels as stored in the file, before any EXIF orientation.
+    pub fn raw_dimensions(&self) -> (i32, i32) {
+        match self.orientation {
+            Some(orientation)
+                if self.orientation_applied && orientation_swaps_axes(orientation) =>
+            {
+                (self.height, self.width)
+            }
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// Dimensions of the image as displayed, with its EXIF orientation applied.
+    pub fn display_dimensions(&self) -> (i32, i32) {
+        let (width, height) = self.raw_dimensions();
+        match self.orientation {
+            Some(orientation) if orientation_swaps_axes(orientation) => (height, width),
+            _ => (width, height),
+        }
+    }
+
+    /// Text color for captions overlaid on the image, `#000` or `#fff`, whichever
+    /// contrasts more with its average color. `None` for a malformed blurhash.
+    pub fn overlay_color(&self) -> Option<&'static str> {
+        average_color(&self.blurhash).map(overlay_text_color)
+    }
+}
+
+/// Opens (creating if needed) the database and returns a connection.
+/// Applies any pending schema migrations, so both new and existing files end up current.
+pub fn initialize_and_connect_db(database_url: &str) -> Result<SqliteConnection> {
+    let mut conn = SqliteConnection::establish(database_url)
+        .with_context(|| format!("Error connecting to or creating database at {database_url}"))?;
+
+    run_migrations(&mut conn).with_context(|| "Failed to run database migrations")?;
+    debug!("Database schema is up to date");
+
+    Ok(conn)
+}
+
+/// Converts SystemTime to Unix timestamp in milliseconds
+pub(crate) fn time_to_ms(time: SystemTime) -> Result<i64> {
+    let duration = time.duration_since(UNIX_EPOCH)?;
+    Ok(duration.as_millis() as i64)
+}
+
+/// Outcome of the database phase of a cache lookup.
+// Hits are the hot path, so their data stays unboxed.
+#[allow(clippy::large_enum_variant)]
+pub enum CacheLookup {
+    /// The cached entry is valid and can be returned as is.
+    Hit(BlurhashData),
+    /// The file has to be read (and possibly decoded) before an answer is known.
+    Pending(Box<PendingEntry>),
+}
+
+/// A cache lookup that needs file content to be resolved.
+///
+/// Produced by [`lookup_blurhash`] while holding the context, consumed by
+/// [`compute_blurhash`] without it, so the expensive part can run on any thread.
+pub struct PendingEntry {
+    pub relative_key: String,
+    /// Key derived from the requested path, see [`BlurhashData::path_key`].
+    pub path_key: String,
+    pub absolute_path: PathBuf,
+    pub mtime_ms: i64,
+    /// Size of the file in bytes when it was looked up.
+    pub file_size: u64,
+    pub cached: Option<BlurhashCache>,
+    pub validation: CacheValidation,
+    /// Blurhash components `(x, y)` to encode with.
+    pub components: (u32, u32),
+    /// Longer side to shrink the image to before encoding, if any.
+    pub encode_size: Option<u32>,
+    /// Region of the image to encode, or `None` for the full frame.
+    pub crop: Option<Crop>,
+    /// Aspect ratio to crop to around the focal point after `crop`, if any.
+    pub aspect_ratio: Option<(f64, FocalPoint)>,
+    /// Custom placeholder generators to run on the decoded image.
+    pub generators: Vec<Arc<dyn PlaceholderGenerator>>,
+    /// Target widths of the renditions to report in the result.
+    pub rendition_widths: Vec<u32>,
+    /// Whether to apply the EXIF orientation before encoding.
+    pub apply_orientation: bool,
+    /// Preview images to include in the result.
+    pub previews: Previews,
+    /// Longer side of the raster thumbnail to store, if any.
+    pub thumbnail_size: Option<u32>,
+    pub decode_limiter: Arc<DecodeLimiter>,
+    pub metrics: Arc<Metrics>,
+    /// Trace context of the request, for the generation span.
+    pub traceparent: Option<String>,
+    /// Longest the generation may run, see [`RequestOptions::timeout`].
+    pub timeout: Option<Duration>,
+    /// Retries of a read failing with a transient error.
+    pub io_retries: u32,
+    /// Library to decode the image with.
+    pub decoder: Decoder,
+    /// [`decode_identity`] of the decoder and scale, recorded with the entry; `None`
+    /// for content-addressed entries, which are not decoded from a file.
+    pub decoded_with: Option<String>,
+    /// Warnings of the lookup, handed on to the result.
+    pub warnings: Vec<BlurestWarning>,
+}
+
+/// Preview images a result carries, resolved from the request and the context options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Previews {
+    /// Include a PNG data URL, see [`BlurhashData::blur_data_url`].
+    pub data_url: bool,
+    /// Include a WebP preview, see [`BlurhashData::webp_preview`].
+    pub webp: bool,
+    /// Longer side of the preview images in pixels.
+    pub size: u32,
+}
+
+/// Result of reading and, if needed, decoding a pending entry.
+// Generated is the common case, and is moved straight into the store.
+#[allow(clippy::large_enum_variant)]
+pub enum ComputedEntry {
+    /// Content hash matches the cached entry; only the mtime needs refreshing.
+    Unchanged,
+    /// The image was decoded and a new blurhash was generated.
+    Generated {
+        xxhash: String,
+        /// Size of the file in bytes.
+        file_size: u64,
+        blurhash: String,
+        width: u32,
+        height: u32,
+        /// Payloads of each generator, by generator name.
+        placeholders: BTreeMap<String, Payloads>,
+        /// EXIF orientation of the file.
+        orientation: u8,
+        /// Whether the orientation was applied before encoding.
+        orientation_applied: bool,
+        /// Whether the encoded image has transparent pixels.
+        has_alpha: bool,
+        /// Container format detected from the file contents.
+        format: Option<&'static str>,
+        /// Bits per channel of the file.
+        bit_depth: u8,
+        /// Difference hash of the encoded image.
+        perceptual_hash: u64,
+        /// Color space of the file.
+        color_space: String,
+        /// Name of the ICC profile embedded in the file.
+        color_profile: Option<String>,
+        /// Raster thumbnail of the encoded image, if enabled.
+        thumbnail: Option<Vec<u8>>,
+        /// Warnings of decoding the image.
+        warnings: Vec<BlurestWarning>,
+    },
+}
+
+/// Pixel count above which decoding an image is reported as
+/// [`BlurestWarning::LargeImage`], that of a 50 megapixel camera.
+pub const LARGE_IMAGE_PIXELS: u64 = 50_000_000;
+
+/// Gets the blurhash for an image with intelligent caching.
+///
+/// With the default [`CacheValidation::MtimeThenHash`] strategy this implements a
+/// two-tier caching strategy:
+/// 1. First checks modification time (mtime) for quick validation
+/// 2. Falls back to content hash (xxhash) and file size verification if mtime differs
+///
+/// The context's `validation` option selects a different strategy.
+///
+/// # Arguments
+/// * `context` - Application context containing database connection and project root
+/// * `image_path` - Path to the image file
+///
+/// # Returns
+/// * `Result<BlurhashData>` - A struct containing the blurhash string, width, and height, or an error
+pub fn get_blurhash_with_cache(
+    context: &mut AppContext,
+    image_path: &Path,
+) -> Result<BlurhashData> {
+    get_blurhash_with_options(context, image_path, &RequestOptions::default())
+}
+
+/// Same as [`get_blurhash_with_cache`], applying per-call overrides from `request`.
+pub fn get_blurhash_with_options(
+    context: &mut AppContext,
+    image_path: &Path,
+    request: &RequestOptions,
+) -> Result<BlurhashData> {
+    match lookup_blurhash(context, image_path, request)? {
+        CacheLookup::Hit(data) => Ok(data),
+        CacheLookup::Pending(pending) => {
+            let computed = compute_blurhash(&pending)?;
+            store_blurhash(context, *pending, computed)
+        }
+    }
+}
+
+/// Outcome of one file of a bulk operation such as warming a directory, which skips
+/// files that are not images instead of failing on them.
+#[derive(Debug)]
+// Like `CacheLookup`, images are the common case.
+#[allow(clippy::large_enum_variant)]
+pub enum BatchItem {
+    /// The blurhash data of an image.
+    Image(BlurhashData),
+    /// The file was skipped, with [`BlurestWarning::UnsupportedFile`].
+    Skipped(BlurestWarning),
+}
+
+/// Turns `result`, the lookup of `image_path` by a bulk operation, into its
+/// [`BatchItem`]: a failed lookup of a file that does not start like an image is a
+/// skip, while other failures, such as of corrupt images, stay errors.
+pub fn skip_unsupported(
+    image_path: &Path,
+    request: &RequestOptions,
+    result: Result<BlurhashData>,
+) -> Result<BatchItem> {
+    let error = match result {
+        Ok(data) => return Ok(BatchItem::Image(data)),
+        Err(e) => e,
+    };
+    // Policy violations are reported even for files that are not images.
+    if error_code(&error).is_some() {
+        return Err(error);
+    }
+    let path = match &request.project_root {
+        Some(root) => root.join(image_path),
+        None => image_path.to_path_buf(),
+    };
+    if is_supported_image(&path) {
+        return Err(error);
+    }
+    let shown = path.to_string_lossy();
+    warn!(path = &*shown; "Skipping {shown}: not a supported image");
+    Ok(BatchItem::Skipped(BlurestWarning::UnsupportedFile))
+}
+
+/// Resolves the cache key for `image_path` and checks the cache by modification time.
+///
+/// Only touches file metadata and the database; file content is never read here,
+/// except to key images outside every root under [`crate::keys::OutsideRootPolicy::Hash`].
+pub fn lookup_blurhash(
+    context: &mut AppContext,
+    image_path: &Path,
+    request: &RequestOptions,
+) -> Result<CacheLookup> {
+    let mut span = Span::start("blurest.lookup", request.traceparent.as_deref());
+    span.set_str("blurest.path", &image_path.to_string_lossy());
+    let lookup = find_entry(context, image_path, request);
+    match &lookup {
+        Ok(CacheLookup::Hit(data)) => {
+            span.set_str("blurest.key", &data.key);
+            span.set_str("blurest.cache_status", "hit");
+        }
+        Ok(CacheLookup::Pending(pending)) => {
+            span.set_str("blurest.key", &pending.relative_key);
+            span.set_str("blurest.cache_status", "pending");
+        }
+        Err(e) => span.set_error(e),
+    }
+    lookup
+}
+
+/// An image path resolved to the cache key it is looked up under.
+pub(crate) struct ResolvedImage {
+    pub(crate) absolute_path: PathBuf,
+    /// Key derived from the path, see [`BlurhashData::path_key`].
+    pub(crate) path_key: String,
+    pub(crate) key: CacheKey,
+    pub(crate) mtime_ms: i64,
+    pub(crate) file_size: u64,
+}
+
+impl ResolvedImage {
+    /// Key of the cache row for this request, cropped entries included.
+    pub(crate) fn key(&self) -> &str {
+        self.key.as_str()
+    }
+}
+
+/// Body of [`lookup_blurhash`], without the tracing span.
+fn find_entry(
+    context: &mut AppContext,
+    image_path: &Path,
+    request: &RequestOptions,
+) -> Result<CacheLookup> {
+    apply_refreshes(context)?;
+    let image = resolve_image(context, image_path, request)?;
+    let cached = find_cached(
+        &mut context.db_conn,
+        image.key(),
+        context.options.case_insensitive_keys,
+    )
+    .inspect_err(|_| context.metrics.record_db_error())?;
+    evaluate_cached(context, image, cached, request)
+}
+
+/// Canonicalizes `image_path`, applies the sandbox and allow-lists, and derives the
+/// cache key for `request`. Only touches file metadata.
+pub(crate) fn resolve_image(
+    context: &AppContext,
+    image_path: &Path,
+    request: &RequestOptions,
+) -> Result<ResolvedImage> {
+    let override_root = match &request.project_root {
+        Some(root) => Some(
+            fs::canonicalize(root)
+                .with_context(|| format!("Failed to resolve project root override: {root:?}"))?,
+        ),
+        None => None,
+    };
+
+    if context.options.sandbox {
+        check_traversal(image_path)?;
+    }
+
+    let image_path = match &override_root {
+        Some(root) if image_path.is_relative() => root.join(image_path),
+        _ => image_path.to_path_buf(),
+    };
+    let retries = context.options.io_retries;
+    let absolute_path = retry_io(retries, || fs::canonicalize(&image_path))
+        .with_context(|| format!("Failed to find file at: {image_path:?}"))?;
+
+    let roots = match &override_root {
+        Some(root) => std::slice::from_ref(root),
+        None => context.project_roots.as_slice(),
+    };
+    if context.options.sandbox && !is_within_roots(&absolute_path, roots) {
+        return Err(BlurestError::PathEscapesRoot(image_path).into());
+    }
+    check_allowed(&absolute_path, &context.options)?;
+    let key = derive_key(&absolute_path, roots, &context.options)?;
+    let path_key = key.as_str().to_string();
+    let key = key.for_request(request);
+
+    let metadata = retry_io(retries, || fs::metadata(&absolute_path))?;
+    let mtime_ms = time_to_ms(metadata.modified()?)?;
+    Ok(ResolvedImage {
+        absolute_path,
+        path_key,
+        key,
+        mtime_ms,
+        file_size: metadata.len(),
+    })
+}
+
+/// Decides whether `cached`, the row found under the key of `image`, can be handed
+/// out as is or the image has to be read.
+pub(crate) fn evaluate_cached(
+    context: &mut AppContext,
+    image: ResolvedImage,
+    cached: Option<BlurhashCache>,
+    request: &RequestOptions,
+) -> Result<CacheLookup> {
+    let ResolvedImage {
+        absolute_path,
+        path_key,
+        key,
+        mtime_ms,
+        file_size,
+    } = image;
+    // Keep writing to the existing row when it was matched with different case.
+    let relative_key = match &cached {
+        Some(cache) => cache.relative_path.clone(),
+        None => key.as_str().to_string(),
+    };
+
+    // Forced lookups regenerate like `Always`, whatever the revalidation policy.
+    let validation = if request.force {
+        CacheValidation::Always
+    } else {
+        request.validation.unwrap_or(context.options.validation)
+    };
+    let revalidate = request.revalidate.unwrap_or(context.options.revalidate);
+    let rendition_widths = request
+        .rendition_widths
+        .clone()
+        .unwrap_or_else(|| context.options.rendition_widths.clone());
+    let settings = context.options.encode_settings(&path_key);
+    let previews = Previews {
+        data_url: context.options.blur_data_url || request.output == Output::DataUri,
+        webp: request.output == Output::WebP,
+        size: request.preview_size.unwrap_or(settings.preview_size),
+    };
+    // Entries encoded with other components, by another encoder version, from pixels
+    // of another decoder or scale, with the other orientation handling, or missing
+    // the current output of a generator, are stale whatever the validation strategy.
+    let mut warnings = Vec::new();
+    if let Some(cache) = &cached
+        && mtime_ms < cache.mtime_ms
+        && !mtime_matches(mtime_ms, cache.mtime_ms, context.options.mtime_tolerance_ms)
+    {
+        warn!(path = relative_key.as_str(); "{relative_key} is older than its cached entry");
+        warnings.push(BlurestWarning::MtimeRegression {
+            cached_ms: cache.mtime_ms,
+            current_ms: mtime_ms,
+        });
+    }
+    let decoded_with = (!key.is_content_addressed()).then(|| {
+        let full_frame = request.crop.is_none()
+            && request.aspect_ratio.is_none()
+            && context.options.generators.is_empty();
+        let min_size = decode_min_size(
+            full_frame,
+            context.options.thumbnail_size,
+            settings.encode_size,
+        );
+        decode_identity(context.options.decoder.backend(), min_size)
+    });
+    let found = cached.is_some();
+    let cached = cached.filter(|cache| {
+        cache.algo_version == ALGO_VERSION
+            && decoded_with
+                .as_deref()
+                .is_none_or(|identity| cache.decoded_with.as_deref().unwrap_or("image") == identity)
+            && blurhash_components(&cache.blurhash) == Some(settings.components)
+            && (cache.orientation_applied == context.options.apply_orientation
+                || cache.orientation == Some(1))
+    });
+    let placeholders = match &cached {
+        Some(cache) => {
+            find_placeholders(&mut context.db_conn, cache.id, &context.options.generators)
+                .inspect_err(|_| context.metrics.record_db_error())?
+        }
+        None => None,
+    };
+    let cached = cached.filter(|_| placeholders.is_some());
+    let mut stale = None;
+    if request.force {
+        info!(path = relative_key.as_str(), cache_status = "forced"; "Cache bypassed: regenerating {relative_key} on request");
+    } else if let (Some(cache), Some(placeholders)) = (&cached, placeholders) {
+        if key.is_content_addressed() || validation == CacheValidation::Never {
+            debug!(path = relative_key.as_str(), cache_status = "hit"; "Cache hit: trusted entry for {relative_key}");
+            let mut data = cached_hit(
+                context,
+                relative_key,
+                path_key,
+                cache,
+                placeholders,
+                &rendition_widths,
+                previews,
+            )?;
+            data.warnings = warnings;
+            return Ok(CacheLookup::Hit(data));
+        }
+        if validation.trusts_mtime()
+            && mtime_matches(mtime_ms, cache.mtime_ms, context.options.mtime_tolerance_ms)
+        {
+            debug!(path = relative_key.as_str(), cache_status = "hit"; "Cache hit: mtime match for {relative_key}");
+            return cached_hit(
+                context,
+                relative_key,
+                path_key,
+                cache,
+                placeholders,
+                &rendition_widths,
+                previews,
+            )
+            .map(CacheLookup::Hit);
+        }
+        // A touched file is hashed before its entry is served as stale: reading it is
+        // cheap next to the decode a refresh would cost, and an unchanged file is a hit.
+        if revalidate != Revalidate::Blocking
+            && validation.compares_content()
+            && content_unchanged(&absolute_path, file_size, cache, context.options.io_retries)?
+        {
+            debug!(path = relative_key.as_str(), cache_status = "revalidated"; "Cache hit: content unchanged, updating mtime for {relative_key}");
+            diesel::update(cache)
+                .set((
+                    blurhash_cache::mtime_ms.eq(mtime_ms),
+                    blurhash_cache::file_size.eq(file_size as i64),
+                ))
+                .execute(&mut context.db_conn)
+                .inspect_err(|_| context.metrics.record_db_error())?;
+            let mut data = cached_hit(
+                context,
+                relative_key,
+                path_key,
+                cache,
+                placeholders,
+                &rendition_widths,
+                previews,
+            )?;
+            data.warnings = warnings;
+            return Ok(CacheLookup::Hit(data));
+        }
+        if revalidate != Revalidate::Blocking {
+            info!(path = relative_key.as_str(), cache_status = "stale"; "Cache stale: serving {relative_key} without regenerating it first");
+            let mut data = cached_hit(
+                context,
+                relative_key.clone(),
+                path_key.clone(),
+                cache,
+                placeholders,
+                &rendition_widths,
+                previews,
+            )?;
+            data.warnings = warnings.clone();
+            data.warnings.push(BlurestWarning::StaleEntry);
+            stale = Some(data);
+        }
+    } else if found {
+        info!(path = relative_key.as_str(), cache_status = "stale"; "Cache stale: {relative_key} was encoded with other settings");
+    } else {
+        info!(path = relative_key.as_str(), cache_status = "miss"; "Cache miss: new file {relative_key}");
+    }
+
+    let pending = PendingEntry {
+        relative_key,
+        path_key,
+        absolute_path,
+        mtime_ms,
+        file_size,
+        cached,
+        validation,
+        components: settings.components,
+        encode_size: settings.encode_size,
+        crop: request.crop,
+        aspect_ratio: request
+            .aspect_ratio
+            .map(|ratio| (ratio, request.focal_point.unwrap_or_default())),
+        generators: context.options.generators.clone(),
+        rendition_widths,
+        apply_orientation: context.options.apply_orientation,
+        previews,
+        thumbnail_size: context.options.thumbnail_size,
+        decode_limiter: Arc::clone(&context.decode_limiter),
+        metrics: Arc::clone(&context.metrics),
+        traceparent: request.traceparent.clone(),
+        timeout: request.timeout,
+        io_retries: context.options.io_retries,
+        decoder: context.options.decoder,
+        decoded_with,
+        warnings,
+    };
+    match stale {
+        Some(data) => {
+            if revalidate == Revalidate::Background {
+                schedule_refresh(context, pending);
+            }
+            Ok(CacheLookup::Hit(data))
+        }
+        None => Ok(CacheLookup::Pending(Box::new(pending))),
+    }
+}
+
+/// Whether the file at `path`, `file_size` bytes long, still has the content `cache`
+/// was encoded from, compared like [`generate_from_bytes`] does.
+fn content_unchanged(
+    path: &Path,
+    file_size: u64,
+    cache: &BlurhashCache,
+    io_retries: u32,
+) -> Result<bool> {
+    if cache.xxhash.is_empty() || cache.file_size.is_some_and(|size| size != file_size as i64) {
+        return Ok(false);
+    }
+    let file_bytes = retry_io(io_retries, || fs::read(path))?;
+    Ok(hash_file_bytes(&file_bytes) == cache.xxhash)
+}
+
+/// Compares modification times, treating differences up to `tolerance_ms` as equal.
+pub(crate) fn mtime_matches(current_ms: i64, cached_ms: i64, tolerance_ms: u64) -> bool {
+    current_ms.abs_diff(cached_ms) <= tolerance_ms
+}
+
+/// Builds the result for a valid cached row, filling in the blur data URL or WebP
+/// preview first if one is wanted but was not stored yet, and records it as a hit.
+fn cached_hit(
+    context: &mut AppContext,
+    key: String,
+    path_key: String,
+    cache: &BlurhashCache,
+    placeholders: BTreeMap<String, Payloads>,
+    rendition_widths: &[u32],
+    previews: Previews,
+) -> Result<BlurhashData> {
+    // Stored previews of another size are rendered anew.
+    let current = cache.preview_size == Some(previews.size as i32);
+    let stored_url = cache.blur_data_url.as_ref().filter(|_| current);
+    let stored_webp = cache.webp_preview.as_ref().filter(|_| current);
+    let blur_data_url = match (stored_url, previews.data_url) {
+        (_, false) => None,
+        (Some(url), true) => Some(url.clone()),
+        (None, true) => Some(blur_data_url(
+            &cache.blurhash,
+            cache.width,
+            cache.height,
+            previews.size,
+        )?),
+    };
+    let webp_preview = match (stored_webp, previews.webp) {
+        (_, false) => None,
+        (Some(webp), true) => Some(webp.clone()),
+        (None, true) => Some(blur_webp(
+            &cache.blurhash,
+            cache.width,
+            cache.height,
+            previews.size,
+        )?),
+    };
+    let micro_pixels = match (&cache.micro_pixels, context.options.micro_pixels) {
+        (_, false) => None,
+        (Some(pixels), true) => Some(pixels.clone()),
+        (None, true) => Some(micro_pixels(&cache.blurhash)?),
+    };
+    if cache.micro_pixels.is_none() && micro_pixels.is_some() {
+        diesel::update(cache)
+            .set(blurhash_cache::micro_pixels.eq(&micro_pixels))
+            .execute(&mut context.db_conn)
+            .inspect_err(|_| context.metrics.record_db_error())?;
+    }
+    // The row records a single preview size, so storing new previews drops those of
+    // another size.
+    let persist_webp = context.options.persist_webp_previews;
+    let new_url = blur_data_url.is_some() && stored_url.is_none();
+    let new_webp = persist_webp && webp_preview.is_some() && stored_webp.is_none();
+    if new_url || new_webp {
+        diesel::update(cache)
+            .set((
+                blurhash_cache::blur_data_url.eq(blur_data_url.as_ref().or(stored_url)),
+                blurhash_cache::webp_preview.eq(webp_preview
+                    .as_ref()
+                    .filter(|_| persist_webp)
+                    .or(stored_webp)),
+                blurhash_cache::preview_size.eq(previews.size as i32),
+            ))
+            .execute(&mut context.db_conn)
+            .inspect_err(|_| context.metrics.record_db_error())?;
+    }
+    let data = BlurhashData {
+        key,
+        path_key,
+        blurhash: cache.blurhash.clone(),
+        width: cache.width,
+        height: cache.height,
+        blur_data_url,
+        webp_preview,
+        placeholders,
+        responsive: responsive(cache.width, cache.height, rendition_widths),
+        orientation: cache.orientation.map(|orientation| orientation as u8),
+        orientation_applied: cache.orientation_applied,
+        has_alpha: cache.has_alpha,
+        format: cache.format.clone(),
+        bit_depth: cache.bit_depth.map(|bit_depth| bit_depth as u8),
+        perceptual_hash: cache.perceptual_hash.map(|hash| hash as u64),
+        color_space: cache.color_space.clone(),
+        color_profile: cache.color_profile.clone(),
+        micro_pixels,
+        warnings: Vec::new(),
+    };
+    context.metrics.record_hit();
+    record_session(context, &data);
+    Ok(data)
+}
+
+/// Records `data` in the context's build session, if one is in progress.
+fn record_session(context: &mut AppContext, data: &BlurhashData) {
+    if let Some(session) = &mut context.session {
+        session.record(data);
+    }
+}
+
+/// Loads the payloads of `generators` cached for the entry `entry_id`, or `None` if
+/// any of them is missing or was stored by another version of its generator.
+fn find_placeholders(
+    conn: &mut SqliteConnection,
+    entry_id: i32,
+    generators: &[Arc<dyn PlaceholderGenerator>],
+) -> Result<Option<BTreeMap<String, Payloads>>> {
+    if generators.is_empty() {
+        return Ok(Some(BTreeMap::new()));
+    }
+    let rows = placeholder_cache::table
+        .filter(placeholder_cache::entry_id.eq(entry_id))
+        .select(PlaceholderCache::as_select())
+        .load(conn)?;
+    let mut placeholders = BTreeMap::new();
+    for generator in generators {
+        let Some(row) = rows
+            .iter()
+            .find(|row| row.generator == generator.name() && row.version == generator.version())
+        else {
+            return Ok(None);
+        };
+        let payloads = serde_json::from_str(&row.payloads).with_context(|| {
+            format!(
+                "Invalid cached payloads of placeholder generator `{}`",
+                row.generator
+            )
+        })?;
+        placeholders.insert(row.generator.clone(), payloads);
+    }
+    Ok(Some(placeholders))
+}
+
+/// Fetches the cache rows stored under any of `keys` in one query, oldest first.
+///
+/// With `case_insensitive` set, keys are compared like in [`find_cached`]; pair the
+/// rows with keys using [`str::eq_ignore_ascii_case`] then.
+pub(crate) fn find_cached_many(
+    conn: &mut SqliteConnection,
+    keys: &[&str],
+    case_insensitive: bool,
+) -> Result<Vec<BlurhashCache>> {
+    let query = blurhash_cache::table
+        .select(BlurhashCache::as_select())
+        .order(blurhash_cache::id.asc());
+    let rows = if case_insensitive {
+        query
+            .filter(sql::<Text>("relative_path COLLATE NOCASE").eq_any(keys))
+            .load(conn)?
+    } else {
+        query
+            .filter(blurhash_cache::relative_path.eq_any(keys))
+            .load(conn)?
+    };
+    Ok(rows)
+}
+
+/// Fetches the cache row stored under `key`.
+///
+/// With `case_insensitive` set, keys are compared with SQLite's `NOCASE` collation
+/// (ASCII case folding) and the oldest matching row wins.
+pub(crate) fn find_cached(
+    conn: &mut SqliteConnection,
+    key: &str,
+    case_insensitive: bool,
+) -> Result<Option<BlurhashCache>> {
+    let query = blurhash_cache::table
+        .select(BlurhashCache::as_select())
+        .order(blurhash_cache::id.asc());
+    let cached = if case_insensitive {
+        query
+            .filter(
+                sql::<Bool>("relative_path = ")
+                    .bind::<Text, _>(key)
+                    .sql(" COLLATE NOCASE"),
+            )
+            .first::<BlurhashCache>(conn)
+            .optional()?
+    } else {
+        query
+            .filter(blurhash_cache::relative_path.eq(key))
+            .first::<BlurhashCache>(conn)
+            .optional()?
+    };
+    Ok(cached)
+}
+
+/// Reads the file behind a pending entry and generates a blurhash if its content changed.
+///
+/// Does not need the application context, so callers can run it without holding any lock.
+pub fn compute_blurhash(pending: &PendingEntry) -> Result<ComputedEntry> {
+    let mut span = Span::start("blurest.generate", pending.traceparent.as_deref());
+    span.set_str("blurest.path", &pending.absolute_path.to_string_lossy());
+    span.set_str("blurest.key", &pending.relative_key);
+    let started = Instant::now();
+    let computed = generate_entry(pending, &mut span);
+    let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match &computed {
+        Ok(ComputedEntry::Unchanged) => span.set_str("blurest.cache_status", "revalidated"),
+        Ok(ComputedEntry::Generated { width, height, .. }) => {
+            let status = if pending.cached.is_some() {
+                "stale"
+            } else {
+                "miss"
+            };
+            span.set_str("blurest.cache_status", status);
+            span.set_i64("blurest.width", i64::from(*width));
+            span.set_i64("blurest.height", i64::from(*height));
+            debug!(
+                path = pending.relative_key.as_str(), cache_status = status, duration_ms;
+                "Generated blurhash for {} in {duration_ms:.1} ms",
+                pending.relative_key
+            );
+        }
+        Err(e) => {
+            span.set_error(e);
+            warn!(
+                path = pending.relative_key.as_str(), duration_ms;
+                "Failed to generate blurhash for {}: {e:#}",
+                pending.relative_key
+            );
+        }
+    }
+    computed
+}
+
+/// Body of [`compute_blurhash`], recording the file size on `span`.
+fn generate_entry(pending: &PendingEntry, span: &mut Span) -> Result<ComputedEntry> {
+    let began = Instant::now();
+    let check_timeout = || match pending.timeout {
+        Some(timeout) if began.elapsed() > timeout => Err(BlurestError::Timeout(timeout)),
+        _ => Ok(()),
+    };
+    let file_bytes = retry_io(pending.io_retries, || fs::read(&pending.absolute_path))?;
+    span.set_i64("blurest.file_size", file_bytes.len() as i64);
+    check_timeout()?;
+    generate_from_bytes(pending, &file_bytes, began)
+}
+
+/// Compares the read content of the file of `pending` with its cached entry and
+/// encodes it unless unchanged. The timeout of `pending` counts from `began`.
+pub(crate) fn generate_from_bytes(
+    pending: &PendingEntry,
+    file_bytes: &[u8],
+    began: Instant,
+) -> Result<ComputedEntry> {
+    let check_timeout = || match pending.timeout {
+        Some(timeout) if began.elapsed() > timeout => Err(BlurestError::Timeout(timeout)),
+        _ => Ok(()),
+    };
+
+    // In mtime-only mode content is never hashed; rows are stored without a hash.
+    let xxhash = match pending.validation {
+        CacheValidation::MtimeOnly => None,
+        _ => Some(hash_file_bytes(file_bytes)),
+    };
+
+    if let Some(cache) = &pending.cached {
+        // A differing size settles it without comparing hashes; both have to match
+        // for a content hit, so a 64-bit hash collision alone cannot produce one.
+        // Entries cached before sizes were stored are matched by hash alone.
+        let size_matches = cache
+            .file_size
+            .is_none_or(|size| size == file_bytes.len() as i64);
+        match &xxhash {
+            Some(current_xxhash)
+                if pending.validation.compares_content()
+                    && size_matches
+                    && *current_xxhash == cache.xxhash =>
+            {
+                debug!(
+                    path = pending.relative_key.as_str(), cache_status = "revalidated";
+                    "Cache hit: content unchanged, updating mtime for {}",
+                    pending.relative_key
+                );
+                return Ok(ComputedEntry::Unchanged);
+            }
+            _ if pending.validation == CacheValidation::Always => {
+                debug!(path = pending.relative_key.as_str(), cache_status = "stale"; "Regenerating {} as requested", pending.relative_key)
+            }
+            Some(_) => warn!(
+                path = pending.relative_key.as_str(), cache_status = "stale";
+                "Cache stale: content changed for {}",
+                pending.relative_key
+            ),
+            None => debug!(
+                path = pending.relative_key.as_str(), cache_status = "stale";
+                "Cache stale: mtime changed, regenerating {}",
+                pending.relative_key
+            ),
+        }
+    }
+
+    // Reserves a full-size decode: backends that honor `min_size` need less, and
+    // those that ignore it, such as the `image` crate, exactly that.
+    let _permit = pending
+        .decode_limiter
+        .acquire(estimate_decoded_size(file_bytes))?;
+    check_timeout()?;
+    let started = Instant::now();
+    let decoded = decode_entry(pending, file_bytes)?;
+    check_timeout()?;
+    let computed = encode_decoded(
+        pending,
+        decoded,
+        xxhash.unwrap_or_default(),
+        file_bytes.len() as u64,
+        began,
+    )?;
+    pending.metrics.record_decode(started.elapsed());
+    Ok(computed)
+}
+
+/// Decodes the file of `pending` with its decoder, scaled down unless a crop or a
+/// placeholder generator needs every pixel.
+fn decode_entry(pending: &PendingEntry, file_bytes: &[u8]) -> Result<DecodedImage> {
+    let full_frame =
+        pending.crop.is_none() && pending.aspect_ratio.is_none() && pending.generators.is_empty();
+    let min_size = decode_min_size(full_frame, pending.thumbnail_size, pending.encode_size);
+    pending.decoder.backend().decode(file_bytes, min_size)
+}
+
+/// Size images may be scaled down to while decoding: `None` unless the whole frame
+/// is encoded, as crops and placeholder generators need every pixel.
+fn decode_min_size(
+    full_frame: bool,
+    thumbnail_size: Option<u32>,
+    encode_size: Option<u32>,
+) -> Option<u32> {
+    // The thumbnail and the encoded image are cut from the decoded pixels, so they
+    // must cover their sizes.
+    full_frame.then(|| {
+        [thumbnail_size, encode_size]
+            .into_iter()
+            .flatten()
+            .fold(SCALED_DECODE_MIN_SIZE, u32::max)
+    })
+}
+
+/// Turns the decoded image of `pending` into a new entry: applies its orientation
+/// and crops, then runs the encoder, the placeholder generators and the thumbnail.
+/// The timeout of `pending` counts from `began`.
+pub(crate) fn encode_decoded(
+    pending: &PendingEntry,
+    decoded: DecodedImage,
+    xxhash: String,
+    file_size: u64,
+    began: Instant,
+) -> Result<ComputedEntry> {
+    let check_timeout = || match pending.timeout {
+        Some(timeout) if began.elapsed() > timeout => Err(BlurestError::Timeout(timeout)),
+        _ => Ok(()),
+    };
+    let DecodedImage {
+        mut image,
+        full_size,
+        orientation,
+        format,
+        bit_depth,
+        color_space,
+        color_profile,
+    } = decoded;
+    let mut warnings = Vec::new();
+    let scaled = full_size != (image.width(), image.height());
+    let (width, height) = full_size;
+    if u64::from(width) * u64::from(height) > LARGE_IMAGE_PIXELS {
+        warn!(
+            path = pending.relative_key.as_str();
+            "Decoded an unusually large {width}x{height} image from {}",
+            pending.relative_key
+        );
+        warnings.push(BlurestWarning::LargeImage { width, height });
+    }
+    let orientation_applied = pending.apply_orientation && orientation != 1;
+    if orientation_applied && let Some(transform) = Orientation::from_exif(orientation) {
+        image.apply_orientation(transform);
+    }
+    if let Some(crop) = pending.crop {
+        image = crop_image(&image, crop)?;
+    }
+    if let Some((aspect_ratio, focal_point)) = pending.aspect_ratio {
+        let region = focal_region(image.width(), image.height(), aspect_ratio, focal_point)?;
+        image = crop_image(&image, region)?;
+    }
+    check_timeout()?;
+    let (blurhash, mut width, mut height) = match pending.encode_size {
+        Some(size) if image.width().max(image.height()) > size => {
+            let (blurhash, ..) = encode_image(pending.components, &image.thumbnail(size, size))?;
+            (blurhash, image.width(), image.height())
+        }
+        _ => encode_image(pending.components, &image)?,
+    };
+    // Scaled decodes are only made of full frames, whose dimensions as stored count.
+    if scaled {
+        (width, height) = full_size;
+        if orientation_applied && orientation_swaps_axes(orientation) {
+            (width, height) = (height, width);
+        }
+    }
+    check_timeout()?;
+    let has_alpha = has_transparency(&image);
+    let perceptual_hash = difference_hash(&image);
+    let placeholders = generate_placeholders(&pending.generators, &image)?;
+    let thumbnail = match pending.thumbnail_size {
+        Some(size) => Some(encode_thumbnail(&image, size)?),
+        None => None,
+    };
+    Ok(ComputedEntry::Generated {
+        xxhash,
+        file_size,
+        blurhash,
+        width,
+        height,
+        placeholders,
+        orientation,
+        orientation_applied,
+        has_alpha,
+        format,
+        bit_depth,
+        perceptual_hash,
+        color_space,
+        color_profile,
+        thumbnail,
+        warnings,
+    })
+}
+
+/// Writes the outcome of [`compute_blurhash`] back to the cache and returns the final data.
+pub fn store_blurhash(
+    context: &mut AppContext,
+    pending: PendingEntry,
+    computed: ComputedEntry,
+) -> Result<BlurhashData> {
+    match computed {
+        ComputedEntry::Unchanged => {
+            let cache = pending
+                .cached
+                .ok_or_else(|| anyhow::anyhow!("Unchanged entry without a cached row"))?;
+            diesel::update(&cache)
+                .set((
+                    blurhash_cache::mtime_ms.eq(pending.mtime_ms),
+                    blurhash_cache::file_size.eq(pending.file_size as i64),
+                ))
+                .execute(&mut context.db_conn)
+                .inspect_err(|_| context.metrics.record_db_error())?;
+            let placeholders =
+                find_placeholders(&mut context.db_conn, cache.id, &pending.generators)
+                    .inspect_err(|_| context.metrics.record_db_error())?
+                    .unwrap_or_default();
+            let mut data = cached_hit(
+                context,
+                pending.relative_key,
+                pending.path_key,
+                &cache,
+                placeholders,
+                &pending.rendition_widths,
+                pending.previews,
+            )?;
+            data.warnings = pending.warnings;
+            Ok(data)
+        }
+        ComputedEntry::Generated {
+            xxhash,
+            file_size,
+            blurhash,
+            width,
+            height,
+            placeholders,
+            orientation,
+            orientation_applied,
+            has_alpha,
+            format,
+            bit_depth,
+            perceptual_hash,
+            color_space,
+            color_profile,
+            thumbnail,
+            warnings,
+        } => {
+            let previews = pending.previews;
+            let blur_data_url = if previews.data_url {
+                Some(blur_data_url(
+                    &blurhash,
+                    width as i32,
+                    height as i32,
+                    previews.size,
+                )?)
+            } else {
+                None
+            };
+            let webp_preview = if previews.webp {
+                Some(blur_webp(
+                    &blurhash,
+                    width as i32,
+                    height as i32,
+                    previews.size,
+                )?)
+            } else {
+                None
+            };
+            let persisted_webp = webp_preview
+                .as_deref()
+                .filter(|_| context.options.persist_webp_previews);
+            let micro_pixels = if context.options.micro_pixels {
+                Some(micro_pixels(&blurhash)?)
+            } else {
+                None
+            };
+            let new_cache_entry = NewBlurhashCache {
+                relative_path: &pending.relative_key,
+                xxhash: &xxhash,
+                mtime_ms: pending.mtime_ms,
+                blurhash: &blurhash,
+                width: width as i32,
+                height: height as i32,
+                blur_data_url: blur_data_url.as_deref(),
+                webp_preview: persisted_webp,
+                preview_size: Some(previews.size as i32),
+                algo_version: ALGO_VERSION,
+                orientation: Some(i32::from(orientation)),
+                orientation_applied,
+                has_alpha: Some(has_alpha),
+                format,
+                bit_depth: Some(i32::from(bit_depth)),
+                perceptual_hash: Some(perceptual_hash as i64),
+                color_space: Some(&color_space),
+                color_profile: color_profile.as_deref(),
+                micro_pixels: micro_pixels.as_deref(),
+                file_size: Some(file_size as i64),
+                decoded_with: pending.decoded_with.as_deref(),
+            };
+
+            // Upsert, since another worker may have inserted the same key while this
+            // entry was being computed without the context held.
+            context
+                .db_conn
+                .transaction(|conn| {
+                    let entry_id = diesel::insert_into(blurhash_cache::table)
+                        .values(&new_cache_entry)
+                        .on_conflict(blurhash_cache::relative_path)
+                        .do_update()
+                        .set((
+                            blurhash_cache::xxhash.eq(&xxhash),
+                            blurhash_cache::mtime_ms.eq(pending.mtime_ms),
+                            blurhash_cache::blurhash.eq(&blurhash),
+                            blurhash_cache::width.eq(width as i32),
+                            blurhash_cache::height.eq(height as i32),
+                            blurhash_cache::blur_data_url.eq(&blur_data_url),
+                            blurhash_cache::algo_version.eq(ALGO_VERSION),
+                            blurhash_cache::orientation.eq(i32::from(orientation)),
+                            blurhash_cache::orientation_applied.eq(orientation_applied),
+                            blurhash_cache::has_alpha.eq(has_alpha),
+                            blurhash_cache::format.eq(format),
+                            blurhash_cache::bit_depth.eq(i32::from(bit_depth)),
+                            blurhash_cache::perceptual_hash.eq(perceptual_hash as i64),
+                            blurhash_cache::color_space.eq(&color_space),
+                            blurhash_cache::color_profile.eq(&color_profile),
+                            blurhash_cache::micro_pixels.eq(&micro_pixels),
+                            blurhash_cache::file_size.eq(file_size as i64),
+                            blurhash_cache::decoded_with.eq(&pending.decoded_with),
+                            blurhash_cache::webp_preview.eq(persisted_webp),
+                            blurhash_cache::preview_size.eq(previews.size as i32),
+                        ))
+                        .returning(blurhash_cache::id)
+                        .get_result::<i32>(conn)?;
+                    store_placeholders(conn, entry_id, &pending.generators, &placeholders)?;
+                    store_thumbnail(
+                        conn,
+                        entry_id,
+                        pending.thumbnail_size.zip(thumbnail.as_deref()),
+                    )
+                })
+                .inspect_err(|_| context.metrics.record_db_error())?;
+
+            if pending.cached.is_some() {
+                context.metrics.record_stale_refresh();
+            } else {
+                context.metrics.record_miss();
+            }
+
+            let data = BlurhashData {
+                key: pending.relative_key,
+                path_key: pending.path_key,
+                blurhash,
+                width: width as i32,
+                height: height as i32,
+                blur_data_url,
+                webp_preview,
+                placeholders,
+                responsive: responsive(width as i32, height as i32, &pending.rendition_widths),
+                orientation: Some(orientation),
+                orientation_applied,
+                has_alpha: Some(has_alpha),
+                format: format.map(str::to_owned),
+                bit_depth: Some(bit_depth),
+                perceptual_hash: Some(perceptual_hash),
+                color_space: Some(color_space),
+                color_profile,
+                micro_pixels,
+                warnings: pending.warnings.into_iter().chain(warnings).collect(),
+            };
+            record_session(context, &data);
+            Ok(data)
+        }
+    }
+}
+
+/// Replaces the raster thumbnail cached for the entry `entry_id`, or removes it.
+fn store_thumbnail(
+    conn: &mut SqliteConnection,
+    entry_id: i32,
+    thumbnail: Option<(u32, &[u8])>,
+) -> Result<()> {
+    diesel::delete(thumbnail_cache::table.filter(thumbnail_cache::entry_id.eq(entry_id)))
+        .execute(conn)?;
+    if let Some((size, thumbnail)) = thumbnail {
+        diesel::insert_into(thumbnail_cache::table)
+            .values(&NewThumbnailCache {
+                entry_id,
+                size: size as i32,
+                thumbnail,
+            })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Replaces the placeholder payloads cached for the entry `entry_id`.
+fn store_placeholders(
+    conn: &mut SqliteConnection,
+    entry_id: i32,
+    generators: &[Arc<dyn PlaceholderGenerator>],
+    placeholders: &BTreeMap<String, Payloads>,
+) -> Result<()> {
+    diesel::delete(placeholder_cache::table.filter(placeholder_cache::entry_id.eq(entry_id)))
+        .execute(conn)?;
+    for generator in generators {
+        let Some(payloads) = placeholders.get(generator.name()) else {
+            continue;
+        };
+        let payloads = serde_json::to_string(payloads)?;
+        diesel::insert_into(placeholder_cache::table)
+            .values(&NewPlaceholderCache {
+                entry_id,
+                generator: generator.name(),
+                version: generator.version(),
+                payloads: &payloads,
+            })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use image::{ImageFormat, Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// What a lookup of a changed file ends in.
+    #[derive(Debug, PartialEq, Eq)]
+    enum Outcome {
+        /// Served from the cache without reading the file.
+        Hit,
+        /// Read, found unchanged by content, and kept.
+        Unchanged,
+        /// Read and encoded anew.
+        Generated,
+    }
+
+    /// How the file changes after it was cached.
+    #[derive(Debug, Clone, Copy)]
+    enum Change {
+        /// A newer mtime with the same content.
+        Touch,
+        /// Different content of the same size, with the cached mtime.
+        Content,
+        /// Content of another size, with the cached mtime.
+        Size,
+    }
+
+    /// Bitmaps, so images of equal dimensions have files of equal size.
+    fn write_bitmap(path: &Path, size: u32, rgb: [u8; 3]) {
+        RgbImage::from_pixel(size, size, Rgb(rgb))
+            .save_with_format(path, ImageFormat::Bmp)
+            .unwrap();
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    /// Caches an image with `validation`, applies `change` and looks it up again.
+    fn outcome(validation: CacheValidation, change: Change) -> Outcome {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let options = ContextOptions {
+            validation,
+            ..ContextOptions::default()
+        };
+        let conn = initialize_and_connect_db(":memory:").unwrap();
+        let mut context = AppContext::new(conn, vec![root.clone()], options).unwrap();
+        let path = root.join("image.bmp");
+        write_bitmap(&path, 4, [200, 30, 30]);
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        get_blurhash_with_cache(&mut context, &path).unwrap();
+
+        match change {
+            Change::Touch => set_mtime(&path, mtime + Duration::from_secs(5)),
+            Change::Content => {
+                write_bitmap(&path, 4, [30, 30, 200]);
+                set_mtime(&path, mtime);
+            }
+            Change::Size => {
+                write_bitmap(&path, 8, [200, 30, 30]);
+                set_mtime(&path, mtime);
+            }
+        }
+
+        match lookup_blurhash(&mut context, &path, &RequestOptions::default()).unwrap() {
+            CacheLookup::Hit(_) => Outcome::Hit,
+            CacheLookup::Pending(pending) => match compute_blurhash(&pending).unwrap() {
+                ComputedEntry::Unchanged => Outcome::Unchanged,
+                ComputedEntry::Generated { .. } => Outcome::Generated,
+            },
+        }
+    }
+
+    fn assert_outcomes(validation: CacheValidation, expected: [Outcome; 3]) {
+        let outcomes = [Change::Touch, Change::Content, Change::Size]
+            .map(|change| outcome(validation, change));
+        assert_eq!(
+            outcomes, expected,
+            "{validation:?} after touch, content, size"
+        );
+    }
+
+    #[test]
+    fn mtime_then_hash_trusts_mtimes_and_hashes_touched_files() {
+        assert_outcomes(
+            CacheValidation::MtimeThenHash,
+            [Outcome::Unchanged, Outcome::Hit, Outcome::Hit],
+        );
+    }
+
+    #[test]
+    fn mtime_only_regenerates_touched_files() {
+        assert_outcomes(
+            CacheValidation::MtimeOnly,
+            [Outcome::Generated, Outcome::Hit, Outcome::Hit],
+        );
+    }
+
+    #[test]
+    fn hash_only_ignores_mtimes() {
+        assert_outcomes(
+            CacheValidation::HashOnly,
+            [Outcome::Unchanged, Outcome::Generated, Outcome::Generated],
+        );
+    }
+
+    #[test]
+    fn always_regenerates() {
+        assert_outcomes(
+            CacheValidation::Always,
+            [Outcome::Generated, Outcome::Generated, Outcome::Generated],
+        );
+    }
+
+    #[test]
+    fn never_always_trusts_the_cache() {
+        assert_outcomes(
+            CacheValidation::Never,
+            [Outcome::Hit, Outcome::Hit, Outcome::Hit],
+        );
+    }
+
+    #[test]
+    fn touched_files_are_not_served_as_stale() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let options = ContextOptions {
+            revalidate: Revalidate::Never,
+            ..ContextOptions::default()
+        };
+        let conn = initialize_and_connect_db(":memory:").unwrap();
+        let mut context = AppContext::new(conn, vec![root.clone()], options).unwrap();
+        let path = root.join("image.bmp");
+        write_bitmap(&path, 4, [200, 30, 30]);
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        get_blurhash_with_cache(&mut context, &path).unwrap();
+        let warnings = |context: &mut AppContext| match lookup_blurhash(
+            context,
+            &path,
+            &RequestOptions::default(),
+        )
+        .unwrap()
+        {
+            CacheLookup::Hit(data) => data.warnings,
+            CacheLookup::Pending(_) => panic!("lookup did not serve the cached entry"),
+        };
+
+        set_mtime(&path, mtime + Duration::from_secs(5));
+        assert_eq!(warnings(&mut context), []);
+        let cached_ms: i64 = blurhash_cache::table
+            .select(blurhash_cache::mtime_ms)
+            .first(&mut context.db_conn)
+            .unwrap();
+        assert_eq!(
+            cached_ms,
+            time_to_ms(mtime + Duration::from_secs(5)).unwrap()
+        );
+
+        write_bitmap(&path, 4, [30, 30, 200]);
+        set_mtime(&path, mtime + Duration::from_secs(10));
+        assert_eq!(warnings(&mut context), [BlurestWarning::StaleEntry]);
+    }
+
+    #[test]
+    fn batches_skip_files_that_are_not_images() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let conn = initialize_and_connect_db(":memory:").unwrap();
+        let mut context =
+            AppContext::new(conn, vec![root.clone()], ContextOptions::default()).unwrap();
+        let mut batch_item = |path: &Path| {
+            let request = RequestOptions::default();
+            let result = get_blurhash_with_options(&mut context, path, &request);
+            skip_unsupported(path, &request, result)
+        };
+
+        let image = root.join("image.bmp");
+        write_bitmap(&image, 4, [200, 30, 30]);
+        assert!(matches!(batch_item(&image), Ok(BatchItem::Image(_))));
+
+        let notes = root.join("notes.txt");
+        fs::write(&notes, "not an image").unwrap();
+        assert!(matches!(
+            batch_item(&notes),
+            Ok(BatchItem::Skipped(BlurestWarning::UnsupportedFile))
+        ));
+
+        // A truncated bitmap is an image that fails to decode, not a skipped file.
+        let truncated = root.join("truncated.bmp");
+        fs::write(&truncated, &fs::read(&image).unwrap()[..40]).unwrap();
+        assert!(batch_item(&truncated).is_err());
+        assert!(batch_item(&root.join("missing.bmp")).is_err());
+    }
+
+    #[test]
+    fn entries_of_another_decoder_are_regenerated() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let conn = initialize_and_connect_db(":memory:").unwrap();
+        let mut context =
+            AppContext::new(conn, vec![root.clone()], ContextOptions::default()).unwrap();
+        let path = root.join("image.bmp");
+        write_bitmap(&path, 4, [200, 30, 30]);
+        let is_hit = |context: &mut AppContext, decoder: Decoder| {
+            context.options.decoder = decoder;
+            let request = RequestOptions::default();
+            match lookup_blurhash(context, &path, &request).unwrap() {
+                CacheLookup::Hit(_) => true,
+                CacheLookup::Pending(pending) => {
+                    let computed = compute_blurhash(&pending).unwrap();
+                    store_blurhash(context, *pending, computed).unwrap();
+                    false
+                }
+            }
+        };
+
+        let set_decoded_with = |context: &mut AppContext, decoded_with: Option<&str>| {
+            diesel::update(blurhash_cache::table)
+                .set(blurhash_cache::decoded_with.eq(decoded_with))
+                .execute(&mut context.db_conn)
+                .unwrap();
+        };
+
+        assert!(!is_hit(&mut context, Decoder::Image));
+        assert!(is_hit(&mut context, Decoder::Image));
+        set_decoded_with(&mut context, Some("turbojpeg@64"));
+        assert!(!is_hit(&mut context, Decoder::Image));
+        let decoded_with: Option<String> = blurhash_cache::table
+            .select(blurhash_cache::decoded_with)
+            .first(&mut context.db_conn)
+            .unwrap();
+        assert_eq!(decoded_with.as_deref(), Some("image"));
+
+        // Entries cached before the decoder was recorded came from the `image` crate.
+        set_decoded_with(&mut context, None);
+        assert!(is_hit(&mut context, Decoder::Image));
+    }
+}
@@ -0,0 +1,100 @@
+//! Pure-CSS approximations of blurhashes, for placeholders that need no decoder on
+//! the client.
+//!
+//! The blurhash is sampled on a small grid matching the aspect ratio of the image.
+//! Each row of the grid becomes a horizontal `linear-gradient` through its cell
+//! colors, and the rows are stacked as overlapping background layers:
+//!
+//! ```text
+//! background-image: linear-gradient(90deg, #6c7b8a 6.25%, ...), ...;
+//! background-position: 0 0%, 0 20%, ...;
+//! background-size: 100% 21%;
+//! background-repeat: no-repeat;
+//! ```
+
+use anyhow::Result;
+
+use crate::encode::{color_to_hex, decode_rgba};
+
+/// Cells along the longer side of the grid a blurhash is sampled on.
+pub const CSS_GRID_SIZE: u32 = 8;
+
+/// CSS background properties approximating a blurhash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CssBackground {
+    pub background_image: String,
+    pub background_position: String,
+    pub background_size: String,
+    pub background_repeat: String,
+}
+
+impl CssBackground {
+    /// The properties as CSS declarations, e.g. for a `style` attribute.
+    pub fn to_declarations(&self) -> String {
+        format!(
+            "background-image:{};background-position:{};background-size:{};background-repeat:{}",
+            self.background_image,
+            self.background_position,
+            self.background_size,
+            self.background_repeat
+        )
+    }
+}
+
+/// Approximates `blurhash` with CSS backgrounds for an element with the aspect ratio
+/// of a `width` by `height` image.
+pub fn blurhash_to_css(blurhash: &str, width: u32, height: u32) -> Result<CssBackground> {
+    let (width, height) = (width.max(1), height.max(1));
+    let scale = |side: u32| {
+        ((u64::from(side) * u64::from(CSS_GRID_SIZE)) / u64::from(width.max(height))).max(1) as u32
+    };
+    let (columns, rows) = (scale(width), scale(height));
+    let pixels = decode_rgba(blurhash, columns, rows, 1.0)?;
+
+    let gradients: Vec<String> = pixels
+        .chunks_exact(columns as usize * 4)
+        .map(|row| {
+            let stops: Vec<String> = row
+                .chunks_exact(4)
+                .enumerate()
+                .map(|(column, rgba)| {
+                    let color = color_to_hex([rgba[0], rgba[1], rgba[2]]);
+                    let center = (column as f64 + 0.5) * 100.0 / f64::from(columns);
+                    format!("{color} {}%", format_number(center))
+                })
+                .collect();
+            match stops.as_slice() {
+                [single] => format!("linear-gradient(90deg, {single}, {single})"),
+                _ => format!("linear-gradient(90deg, {})", stops.join(", ")),
+            }
+        })
+        .collect();
+    // Layers are positioned as a fraction of the space they leave free, and made one
+    // percent taller than a row so that rounding never leaves gaps between them.
+    let positions: Vec<String> = (0..rows)
+        .map(|row| match rows {
+            1 => "0 0".to_string(),
+            _ => format!(
+                "0 {}%",
+                format_number(f64::from(row) * 100.0 / f64::from(rows - 1))
+            ),
+        })
+        .collect();
+    let row_height = (100.0 / f64::from(rows) + 1.0).min(100.0);
+
+    Ok(CssBackground {
+        background_image: gradients.join(", "),
+        background_position: positions.join(", "),
+        background_size: format!("100% {}%", format_number(row_height)),
+        background_repeat: "no-repeat".to_string(),
+    })
+}
+
+/// Formats `value` with at most two decimals and without trailing zeros.
+fn format_number(value: f64) -> String {
+    let formatted = format!("{value:.2}");
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
@@ -0,0 +1,502 @@
+//! Blurhash encoding and decoding, independent of the cache.
+//!
+//! Everything here builds without the `cache` feature, e.g. for WebAssembly.
+
+use std::{
+    any::Any,
+    io::Cursor,
+    panic::{self, AssertUnwindSafe},
+};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use image::{
+    DynamicImage, GenericImageView, ImageDecoder, ImageFormat, ImageReader, RgbaImage,
+    imageops::FilterType,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    error::BlurestError,
+    options::{Crop, FocalPoint},
+};
+
+/// Version of the encoder and its parameters, stored with every cached entry.
+///
+/// Bump it with any change that makes fresh generations differ from what older
+/// builds cached for the same file (resizing before encoding, color handling, ...);
+/// cached entries of other versions are then regenerated on their next lookup.
+pub const ALGO_VERSION: i32 = 1;
+
+/// Longest side, in pixels, of the images behind [`blur_data_url`] and [`blur_webp`]
+/// unless configured otherwise.
+pub const DEFAULT_PREVIEW_SIZE: u32 = 32;
+
+/// Largest accepted preview size; larger sizes are capped to it.
+pub const MAX_PREVIEW_SIZE: u32 = 256;
+
+/// Largest width or height [`decode_rgba`] decodes a blurhash to.
+pub const MAX_DECODE_SIZE: u32 = 2048;
+
+/// Digits of the base 83 encoding used by blurhash.
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Helper function that encapsulates blurhash and dimension calculation logic
+///
+/// # Arguments
+/// * `file_bytes` - Raw image file bytes
+///
+/// # Returns
+/// * `Result<(String, u32, u32)>` - Tuple of (blurhash, width, height) or error
+pub fn calculate_blurhash(components: (u32, u32), file_bytes: &[u8]) -> Result<(String, u32, u32)> {
+    encode_image(components, &decode_image(file_bytes)?)
+}
+
+/// An image decoded by [`decode_image_with_metadata`], with what the file says about
+/// it beyond the pixels.
+pub struct DecodedImage {
+    /// The pixels as stored, without applying the orientation.
+    pub image: DynamicImage,
+    /// Width and height of the image as stored, larger than those of `image` after a
+    /// scaled decode.
+    pub full_size: (u32, u32),
+    /// EXIF orientation, from 1 (upright) to 8.
+    pub orientation: u8,
+    /// Container format detected from the file contents, e.g. `"jpeg"` or `"png"`.
+    pub format: Option<&'static str>,
+    /// Bits per channel of the file, before conversion to the decoded pixel type.
+    pub bit_depth: u8,
+    /// Color space of the pixels, e.g. `"RGB"` or `"CMYK"`, from the embedded ICC
+    /// profile or, without one, from the pixel type.
+    pub color_space: String,
+    /// Description of the embedded ICC profile, e.g. `"Display P3"`.
+    pub color_profile: Option<String>,
+}
+
+/// Decodes raw image file bytes along with their orientation, format and bit depth.
+pub fn decode_image_with_metadata(file_bytes: &[u8]) -> Result<DecodedImage> {
+    decode_with_metadata(file_bytes, |decoder, _| {
+        Ok(DynamicImage::from_decoder(decoder)?)
+    })
+}
+
+/// Reads the metadata of the image in `file_bytes` from its headers, then has
+/// `decode` produce the pixels from the decoder and the detected format.
+pub(crate) fn decode_with_metadata(
+    file_bytes: &[u8],
+    decode: impl FnOnce(Box<dyn ImageDecoder + '_>, Option<&'static str>) -> Result<DynamicImage>,
+) -> Result<DecodedImage> {
+    catch_panic(|| {
+        let reader = ImageReader::new(Cursor::new(file_bytes)).with_guessed_format()?;
+        let format = reader.format().map(format_name);
+        let mut decoder = reader.into_decoder()?;
+        let orientation = decoder.orientation()?;
+        let color_type = decoder.original_color_type();
+        let bit_depth = color_type.bits_per_pixel() / u16::from(color_type.channel_count().max(1));
+        let icc_profile = decoder.icc_profile()?;
+        let full_size = decoder.dimensions();
+        let image = decode(Box::new(decoder), format)?;
+        let color_space = icc_profile
+            .as_deref()
+            .and_then(icc_color_space)
+            .unwrap_or_else(|| {
+                if image.color().has_color() {
+                    "RGB".to_string()
+                } else {
+                    "GRAY".to_string()
+                }
+            });
+        Ok(DecodedImage {
+            image,
+            full_size,
+            orientation: orientation.to_exif(),
+            format,
+            bit_depth: bit_depth as u8,
+            color_space,
+            color_profile: icc_profile.as_deref().and_then(icc_profile_name),
+        })
+    })
+}
+
+/// Data color space of an ICC profile, e.g. `"RGB"`, `"GRAY"` or `"CMYK"`, read from
+/// its header.
+pub fn icc_color_space(profile: &[u8]) -> Option<String> {
+    let signature = std::str::from_utf8(profile.get(16..20)?).ok()?.trim_end();
+    (!signature.is_empty()).then(|| signature.to_string())
+}
+
+/// Description of an ICC profile, e.g. `"sRGB IEC61966-2.1"` or `"Display P3"`, read
+/// from its `desc` tag in either the version 2 or version 4 encoding.
+pub fn icc_profile_name(profile: &[u8]) -> Option<String> {
+    let read_u32 = |offset: usize| -> Option<usize> {
+        let bytes = profile.get(offset..offset + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+    };
+    let tag_count = read_u32(128)?;
+    let (offset, size) = (0..tag_count.min(1024)).find_map(|index| {
+        let entry = 132 + index * 12;
+        (profile.get(entry..entry + 4)? == b"desc")
+            .then(|| Some((read_u32(entry + 4)?, read_u32(entry + 8)?)))?
+    })?;
+    let tag = profile.get(offset..offset.checked_add(size)?)?;
+    let name = match tag.get(0..4)? {
+        // textDescriptionType: an ASCII string with its length, NUL included.
+        b"desc" => {
+            let length = u32::from_be_bytes(tag.get(8..12)?.try_into().ok()?) as usize;
+            let text = tag.get(12..12usize.checked_add(length)?)?;
+            String::from_utf8_lossy(text)
+                .trim_end_matches('\0')
+                .to_string()
+        }
+        // multiLocalizedUnicodeType: UTF-16BE records; the first one is used.
+        b"mluc" => {
+            let length = u32::from_be_bytes(tag.get(20..24)?.try_into().ok()?) as usize;
+            let start = u32::from_be_bytes(tag.get(24..28)?.try_into().ok()?) as usize;
+            let text = tag.get(start..start.checked_add(length)?)?;
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+                .trim_end_matches('\0')
+                .to_string()
+        }
+        _ => return None,
+    };
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Lowercase name of an image format as reported in results, e.g. `"jpeg"`.
+pub fn format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "jpeg",
+        ImageFormat::OpenExr => "exr",
+        other => other.extensions_str().first().copied().unwrap_or("unknown"),
+    }
+}
+
+/// Names, as given by [`format_name`], of the image formats this build can decode.
+pub fn decodable_formats() -> Vec<&'static str> {
+    ImageFormat::all()
+        .filter(|format| format.reading_enabled())
+        .map(format_name)
+        .collect()
+}
+
+/// Whether EXIF `orientation` swaps the width and height of the displayed image.
+pub fn orientation_swaps_axes(orientation: u8) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// Whether `image` has an alpha channel and at least one pixel that is not fully
+/// opaque. Images whose alpha channel is opaque throughout count as opaque.
+pub fn has_transparency(image: &DynamicImage) -> bool {
+    if !image.color().has_alpha() {
+        return false;
+    }
+    match image {
+        DynamicImage::ImageLumaA8(image) => image.pixels().any(|pixel| pixel.0[1] != u8::MAX),
+        DynamicImage::ImageRgba8(image) => image.pixels().any(|pixel| pixel.0[3] != u8::MAX),
+        DynamicImage::ImageLumaA16(image) => image.pixels().any(|pixel| pixel.0[1] != u16::MAX),
+        DynamicImage::ImageRgba16(image) => image.pixels().any(|pixel| pixel.0[3] != u16::MAX),
+        DynamicImage::ImageRgba32F(image) => image.pixels().any(|pixel| pixel.0[3] < 1.0),
+        _ => image.pixels().any(|(_, _, pixel)| pixel.0[3] != u8::MAX),
+    }
+}
+
+/// 64-bit difference hash (dHash) of `image`: each bit tells whether a pixel of the
+/// image shrunk to 9x8 grayscale pixels is brighter than its right neighbour.
+///
+/// Resizing, recompression and small edits change few bits, so the Hamming distance
+/// between two hashes measures how different two images look.
+pub fn difference_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y).0[0];
+            let right = small.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+    hash
+}
+
+/// Encodes a decoded image, returning the blurhash and the image dimensions.
+pub fn encode_image(components: (u32, u32), image: &DynamicImage) -> Result<(String, u32, u32)> {
+    let (width, height) = image.dimensions();
+    let rgba_data = image.to_rgba8().into_vec();
+
+    let blurhash_str = encode_rgba(components, width, height, &rgba_data)?;
+
+    Ok((blurhash_str, width, height))
+}
+
+/// Cuts `crop` out of `image`, failing with [`BlurestError::CropOutOfBounds`] if the
+/// region is empty or extends past the image.
+pub fn crop_image(image: &DynamicImage, crop: Crop) -> Result<DynamicImage> {
+    let (width, height) = image.dimensions();
+    let fits = |offset: u32, size: u32, limit: u32| {
+        size > 0 && offset.checked_add(size).is_some_and(|end| end <= limit)
+    };
+    if !fits(crop.x, crop.width, width) || !fits(crop.y, crop.height, height) {
+        return Err(BlurestError::CropOutOfBounds {
+            crop,
+            width,
+            height,
+        }
+        .into());
+    }
+    Ok(image.crop_imm(crop.x, crop.y, crop.width, crop.height))
+}
+
+/// The largest region of a `width` by `height` image with `aspect_ratio` (width to
+/// height), positioned as close to centered on `focal_point` as the edges allow.
+pub fn focal_region(
+    width: u32,
+    height: u32,
+    aspect_ratio: f64,
+    focal_point: FocalPoint,
+) -> Result<Crop> {
+    if !aspect_ratio.is_finite() || aspect_ratio <= 0.0 {
+        return Err(anyhow!(
+            "Aspect ratio must be a positive number, found {aspect_ratio}"
+        ));
+    }
+    let FocalPoint { fx, fy } = focal_point;
+    if !(0.0..=1.0).contains(&fx) || !(0.0..=1.0).contains(&fy) {
+        return Err(anyhow!(
+            "Focal point coordinates must be between 0 and 1, found ({fx}, {fy})"
+        ));
+    }
+
+    let (crop_width, crop_height) = if f64::from(width) / f64::from(height) > aspect_ratio {
+        let crop_width = (f64::from(height) * aspect_ratio).round() as u32;
+        (crop_width.clamp(1, width), height)
+    } else {
+        let crop_height = (f64::from(width) / aspect_ratio).round() as u32;
+        (width, crop_height.clamp(1, height))
+    };
+    let offset = |size: u32, crop_size: u32, focus: f64| {
+        let start = (f64::from(size) * focus - f64::from(crop_size) / 2.0).round();
+        start.clamp(0.0, f64::from(size - crop_size)) as u32
+    };
+    Ok(Crop {
+        x: offset(width, crop_width, fx),
+        y: offset(height, crop_height, fy),
+        width: crop_width,
+        height: crop_height,
+    })
+}
+
+/// Computes the hex-encoded xxh3 content hash stored in the cache.
+pub fn hash_file_bytes(file_bytes: &[u8]) -> String {
+    hex::encode(xxh3_64(file_bytes).to_be_bytes())
+}
+
+/// Decodes raw image file bytes.
+pub fn decode_image(file_bytes: &[u8]) -> Result<DynamicImage> {
+    catch_panic(|| Ok(image::load_from_memory(file_bytes)?))
+}
+
+/// Encodes an RGBA8 pixel buffer into a blurhash string with `components` `(x, y)`.
+pub fn encode_rgba(
+    components: (u32, u32),
+    width: u32,
+    height: u32,
+    rgba_data: &[u8],
+) -> Result<String> {
+    catch_panic(|| {
+        Ok(blurhash::encode(
+            components.0,
+            components.1,
+            width,
+            height,
+            rgba_data,
+        )?)
+    })
+}
+
+/// Reads the `(x, y)` component counts from the size flag at the start of a blurhash.
+pub fn blurhash_components(blurhash: &str) -> Option<(u32, u32)> {
+    let size_flag = base83_digit(*blurhash.as_bytes().first()?)?;
+    Some((size_flag % 9 + 1, size_flag / 9 + 1))
+}
+
+/// Whether `blurhash` is well-formed: base83 digits only, as many as its size flag
+/// calls for.
+pub fn is_valid_blurhash(blurhash: &str) -> bool {
+    blurhash_components(blurhash).is_some_and(|(x, y)| blurhash.len() == 4 + 2 * (x * y) as usize)
+        && blurhash.bytes().all(|c| base83_digit(c).is_some())
+}
+
+/// Decodes `blurhash` into an RGBA8 pixel buffer of `width` by `height`, each at most
+/// [`MAX_DECODE_SIZE`].
+///
+/// `punch` scales the contrast of the decoded image; `1.0` keeps it unchanged.
+///
+/// Every pixel is opaque, so the buffer reads the same with straight or premultiplied
+/// alpha and can back an `ImageData`, which expects straight alpha, without conversion.
+pub fn decode_rgba(blurhash: &str, width: u32, height: u32, punch: f32) -> Result<Vec<u8>> {
+    if width.max(height) > MAX_DECODE_SIZE {
+        return Err(anyhow!(
+            "Cannot decode to {width}x{height} pixels, sides are limited to {MAX_DECODE_SIZE}"
+        ));
+    }
+    catch_panic(|| Ok(blurhash::decode(blurhash, width, height, punch)?))
+}
+
+/// Renders `blurhash` as a tiny PNG data URL with the aspect ratio of a `width` by
+/// `height` image and `size` pixels on its longer side, as expected by `blurDataURL`
+/// of Next.js `next/image`.
+pub fn blur_data_url(blurhash: &str, width: i32, height: i32, size: u32) -> Result<String> {
+    let mut png = Vec::new();
+    blur_preview(blurhash, width, height, size)?
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(format!("data:image/png;base64,{}", BASE64.encode(png)))
+}
+
+/// Side, in pixels, of the grid [`micro_pixels`] decodes blurhashes at.
+pub const MICRO_PIXELS_SIZE: u32 = 8;
+
+/// Decodes `blurhash` at [`MICRO_PIXELS_SIZE`] pixels square and returns the RGB bytes
+/// row by row, small enough to ship raw for clients to upscale with CSS.
+pub fn micro_pixels(blurhash: &str) -> Result<Vec<u8>> {
+    let pixels = decode_rgba(blurhash, MICRO_PIXELS_SIZE, MICRO_PIXELS_SIZE, 1.0)?;
+    Ok(pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect())
+}
+
+/// Quality, from 0 to 100, of lossy WebP previews. Blurred pixels have no detail to
+/// lose, so low qualities look the same and keep previews small.
+#[cfg(feature = "libwebp")]
+pub const WEBP_PREVIEW_QUALITY: f32 = 50.0;
+
+/// Renders `blurhash` as a tiny WebP with the aspect ratio of a `width` by `height`
+/// image and `size` pixels on its longer side, with the pixels of [`blur_data_url`].
+///
+/// With the `libwebp` feature the preview is lossy, at [`WEBP_PREVIEW_QUALITY`], and
+/// well below the size of the PNG. Otherwise the `image` crate encodes it losslessly,
+/// about as large as the PNG.
+pub fn blur_webp(blurhash: &str, width: i32, height: i32, size: u32) -> Result<Vec<u8>> {
+    let preview = blur_preview(blurhash, width, height, size)?;
+    #[cfg(feature = "libwebp")]
+    let encoded = webp::Encoder::from_rgba(&preview, preview.width(), preview.height())
+        .encode_simple(false, WEBP_PREVIEW_QUALITY)
+        .map_err(|e| anyhow!("Failed to encode WebP preview: {e:?}"))?
+        .to_vec();
+    #[cfg(not(feature = "libwebp"))]
+    let encoded = {
+        let mut encoded = Vec::new();
+        preview.write_to(&mut Cursor::new(&mut encoded), ImageFormat::WebP)?;
+        encoded
+    };
+    Ok(encoded)
+}
+
+/// Encodes `image` downscaled to [`preview_dimensions`] as a lossless WebP, a raster
+/// thumbnail of the image itself rather than of its blurhash.
+pub fn encode_thumbnail(image: &DynamicImage, size: u32) -> Result<Vec<u8>> {
+    let (width, height) = preview_dimensions(image.width() as i32, image.height() as i32, size);
+    let thumbnail = DynamicImage::ImageRgba8(image.thumbnail_exact(width, height).to_rgba8());
+    let mut encoded = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut encoded), ImageFormat::WebP)?;
+    Ok(encoded)
+}
+
+/// Dimensions of a preview with `size` pixels on its longer side, capped to
+/// [`MAX_PREVIEW_SIZE`], and the aspect ratio of a `width` by `height` image.
+pub fn preview_dimensions(width: i32, height: i32, size: u32) -> (u32, u32) {
+    let (width, height) = (width.max(1) as u32, height.max(1) as u32);
+    let size = size.clamp(1, MAX_PREVIEW_SIZE);
+    let scale = |side: u32| ((side as u64 * size as u64) / width.max(height) as u64).max(1) as u32;
+    (scale(width), scale(height))
+}
+
+/// Decodes `blurhash` at [`preview_dimensions`].
+fn blur_preview(blurhash: &str, width: i32, height: i32, size: u32) -> Result<RgbaImage> {
+    let (tiny_width, tiny_height) = preview_dimensions(width, height, size);
+
+    let pixels = decode_rgba(blurhash, tiny_width, tiny_height, 1.0)?;
+    RgbaImage::from_raw(tiny_width, tiny_height, pixels)
+        .ok_or_else(|| anyhow!("Decoded blurhash has an unexpected size"))
+}
+
+/// Reads the average color of the image, stored as the DC component of `blurhash`.
+///
+/// Only the DC digits are decoded, so this is far cheaper than decoding pixels.
+/// Returns `None` if `blurhash` is malformed.
+pub fn average_color(blurhash: &str) -> Option<[u8; 3]> {
+    let (x, y) = blurhash_components(blurhash)?;
+    if blurhash.len() != 4 + 2 * (x * y) as usize {
+        return None;
+    }
+    let value = blurhash
+        .get(2..6)?
+        .bytes()
+        .try_fold(0u32, |value, c| Some(value * 83 + base83_digit(c)?))?;
+    Some([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
+
+/// Reads the average color of the image like [`average_color`], as a CSS hex color.
+pub fn average_color_hex(blurhash: &str) -> Option<String> {
+    average_color(blurhash).map(color_to_hex)
+}
+
+/// WCAG relative luminance of an sRGB color, from 0 for black to 1 for white.
+pub fn relative_luminance([r, g, b]: [u8; 3]) -> f64 {
+    let linear = |channel: u8| {
+        let channel = f64::from(channel) / 255.0;
+        if channel <= 0.04045 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+}
+
+/// Text color to overlay on `background`: `#000` or `#fff`, whichever has the higher
+/// WCAG contrast ratio with it.
+pub fn overlay_text_color(background: [u8; 3]) -> &'static str {
+    let luminance = relative_luminance(background);
+    // Contrast with black is (L + 0.05) / 0.05, with white 1.05 / (L + 0.05).
+    if (luminance + 0.05).powi(2) >= 0.05 * 1.05 {
+        "#000"
+    } else {
+        "#fff"
+    }
+}
+
+/// Formats an RGB color as a CSS hex color, e.g. `#1a2b3c`.
+pub fn color_to_hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Runs `f`, turning a panic into a [`BlurestError::DecodePanic`] instead of letting it
+/// unwind into the host, where it would take down e.g. the whole Node.js process.
+pub(crate) fn catch_panic<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(BlurestError::DecodePanic(panic_message(&*payload)).into()))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+fn base83_digit(c: u8) -> Option<u32> {
+    BASE83
+        .iter()
+        .position(|&digit| digit == c)
+        .map(|digit| digit as u32)
+}
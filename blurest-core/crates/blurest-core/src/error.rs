@@ -0,0 +1,156 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use crate::options::Crop;
+
+/// Errors that callers are expected to tell apart, each with a stable code.
+///
+/// Returned inside `anyhow::Error`; the JavaScript bindings expose [`BlurestError::code`]
+/// as the `code` field of error results.
+#[derive(Debug)]
+pub enum BlurestError {
+    /// The path leaves the project roots, lexically through `..` or after resolving symlinks.
+    PathEscapesRoot(PathBuf),
+    /// The file extension is not on the configured allow-list.
+    ExtensionNotAllowed(PathBuf),
+    /// The detected image type is not on the configured allow-list.
+    MimeTypeNotAllowed { path: PathBuf, mime_type: String },
+    /// Decoding or encoding panicked, typically on a malformed file; holds the panic message.
+    DecodePanic(String),
+    /// The requested crop region is empty or extends past the image.
+    CropOutOfBounds { crop: Crop, width: u32, height: u32 },
+    /// Generation ran past the timeout of the call; holds the timeout.
+    Timeout(Duration),
+    /// An error with a code, one of [`ERROR_CODES`], reported by the cache daemon a
+    /// lookup was forwarded to; see [`crate::ipc::IpcClient`].
+    Daemon { code: &'static str, message: String },
+}
+
+/// Defines [`BlurestError::code`] and [`ERROR_CODES`] from one table. The match is
+/// exhaustive, so a new variant does not compile until its code is listed.
+macro_rules! error_codes {
+    ($($variant:ident $fields:tt => $code:literal,)*) => {
+        /// Every code [`BlurestError::code`] returns, in variant order, for bindings that
+        /// publish the table so callers can match codes without hardcoding them.
+        pub const ERROR_CODES: [&str; [$($code),*].len()] = [$($code),*];
+
+        impl BlurestError {
+            /// Stable machine-readable code of the error, one of [`ERROR_CODES`].
+            pub fn code(&self) -> &'static str {
+                match self {
+                    $(Self::$variant $fields => $code,)*
+                    Self::Daemon { code, .. } => code,
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    PathEscapesRoot(_) => "PATH_ESCAPES_ROOT",
+    ExtensionNotAllowed(_) => "EXTENSION_NOT_ALLOWED",
+    MimeTypeNotAllowed { .. } => "MIME_TYPE_NOT_ALLOWED",
+    DecodePanic(_) => "DECODE_PANIC",
+    CropOutOfBounds { .. } => "CROP_OUT_OF_BOUNDS",
+    Timeout(_) => "TIMEOUT",
+}
+
+impl fmt::Display for BlurestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PathEscapesRoot(path) => {
+                write!(f, "Path escapes the project root: {path:?}")
+            }
+            Self::ExtensionNotAllowed(path) => {
+                write!(f, "File extension is not allowed: {path:?}")
+            }
+            Self::MimeTypeNotAllowed { path, mime_type } => {
+                write!(f, "Image type {mime_type} is not allowed: {path:?}")
+            }
+            Self::DecodePanic(message) => write!(f, "Image decoding panicked: {message}"),
+            Self::CropOutOfBounds {
+                crop,
+                width,
+                height,
+            } => write!(
+                f,
+                "Crop region {}x{} at ({}, {}) does not fit the {width}x{height} image",
+                crop.width, crop.height, crop.x, crop.y
+            ),
+            Self::Timeout(timeout) => write!(
+                f,
+                "Generation exceeded its timeout of {} ms",
+                timeout.as_millis()
+            ),
+            Self::Daemon { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for BlurestError {}
+
+/// Conditions worth a caller's attention that do not stop a lookup, returned in
+/// [`crate::core::BlurhashData::warnings`] besides being logged, or in
+/// [`crate::core::BatchItem::Skipped`] for files bulk operations skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlurestWarning {
+    /// The cached entry of a changed image was served without regenerating it first,
+    /// see [`crate::options::Revalidate`].
+    StaleEntry,
+    /// The file is older than its cached entry, as after restoring a backup or a
+    /// clock change, so its modification time cannot tell whether it changed.
+    MtimeRegression { cached_ms: i64, current_ms: i64 },
+    /// The image has more pixels than [`crate::core::LARGE_IMAGE_PIXELS`], which makes
+    /// decoding it slow and memory hungry for a placeholder.
+    LargeImage { width: u32, height: u32 },
+    /// A bulk operation skipped a file that is not an image format it can decode,
+    /// such as a text file in a warmed directory, instead of failing on it.
+    UnsupportedFile,
+}
+
+/// Every code [`BlurestWarning::code`] returns, in variant order.
+pub const WARNING_CODES: [&str; 4] = [
+    "STALE_ENTRY",
+    "MTIME_REGRESSION",
+    "LARGE_IMAGE",
+    "UNSUPPORTED_FILE",
+];
+
+impl BlurestWarning {
+    /// Stable machine-readable code of the warning, one of [`WARNING_CODES`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::StaleEntry => "STALE_ENTRY",
+            Self::MtimeRegression { .. } => "MTIME_REGRESSION",
+            Self::LargeImage { .. } => "LARGE_IMAGE",
+            Self::UnsupportedFile => "UNSUPPORTED_FILE",
+        }
+    }
+}
+
+impl fmt::Display for BlurestWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StaleEntry => write!(f, "Served an outdated cached entry"),
+            Self::MtimeRegression {
+                cached_ms,
+                current_ms,
+            } => write!(
+                f,
+                "File modification time went back {} ms since it was cached",
+                cached_ms.saturating_sub(*current_ms)
+            ),
+            Self::LargeImage { width, height } => {
+                write!(f, "Decoded an unusually large {width}x{height} image")
+            }
+            Self::UnsupportedFile => write!(f, "Skipped a file that is not a supported image"),
+        }
+    }
+}
+
+/// Returns the code of the first [`BlurestError`] in the chain of `error`, if any.
+pub fn error_code(error: &anyhow::Error) -> Option<&'static str> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<BlurestError>())
+        .map(BlurestError::code)
+}
@@ -0,0 +1,105 @@
+//! Blurhashes of files opened by the caller, for servers that hold a handle (e.g. from
+//! a security-scoped open) but cannot or should not pass a path. The caller names the
+//! cache key; entries are validated like those of paths, by the modification time and
+//! content of the open file.
+
+use std::{fs::File, io, path::PathBuf, time::Instant};
+
+use anyhow::{Context as AnyhowContext, Result};
+
+use crate::{
+    core::{
+        AppContext, BlurhashData, CacheLookup, ComputedEntry, PendingEntry, ResolvedImage,
+        evaluate_cached, find_cached, generate_from_bytes, store_blurhash, time_to_ms,
+    },
+    keys::caller_key,
+    options::{RequestOptions, Revalidate},
+    refresh::apply_refreshes,
+};
+
+/// Gets the blurhash of the open `file`, cached under `cache_key`. `request` applies
+/// as to path lookups, except that `project_root` is ignored and stale entries are
+/// always regenerated before returning, as there is no path to refresh them from later.
+pub fn get_blurhash_for_file(
+    context: &mut AppContext,
+    file: &File,
+    cache_key: &str,
+    request: &RequestOptions,
+) -> Result<BlurhashData> {
+    match lookup_file(context, file, cache_key, request)? {
+        CacheLookup::Hit(data) => Ok(data),
+        CacheLookup::Pending(pending) => {
+            let computed = compute_file(&pending, file)?;
+            store_blurhash(context, *pending, computed)
+        }
+    }
+}
+
+/// Checks the cache for `cache_key` against the metadata of `file`, like
+/// [`lookup_blurhash`](crate::core::lookup_blurhash) does for paths.
+pub fn lookup_file(
+    context: &mut AppContext,
+    file: &File,
+    cache_key: &str,
+    request: &RequestOptions,
+) -> Result<CacheLookup> {
+    apply_refreshes(context)?;
+    let key = caller_key(cache_key)?;
+    let metadata = file
+        .metadata()
+        .with_context(|| format!("Failed to read metadata of the file for {cache_key}"))?;
+    let image = ResolvedImage {
+        absolute_path: PathBuf::new(),
+        path_key: key.as_str().to_string(),
+        key: key.for_request(request),
+        mtime_ms: time_to_ms(metadata.modified()?)?,
+        file_size: metadata.len(),
+    };
+    let cached = find_cached(
+        &mut context.db_conn,
+        image.key(),
+        context.options.case_insensitive_keys,
+    )
+    .inspect_err(|_| context.metrics.record_db_error())?;
+    let request = RequestOptions {
+        revalidate: Some(Revalidate::Blocking),
+        ..request.clone()
+    };
+    evaluate_cached(context, image, cached, &request)
+}
+
+/// Reads `file` from its start and encodes it for an entry found missing or stale by
+/// [`lookup_file`]. Needs no context, so it can run on any thread. On Unix the read
+/// does not move the file offset.
+pub fn compute_file(pending: &PendingEntry, file: &File) -> Result<ComputedEntry> {
+    let began = Instant::now();
+    let file_bytes = read_from_start(file)
+        .with_context(|| format!("Failed to read the file for {}", pending.relative_key))?;
+    generate_from_bytes(pending, &file_bytes, began)
+}
+
+#[cfg(unix)]
+fn read_from_start(file: &File) -> io::Result<Vec<u8>> {
+    use std::os::unix::fs::FileExt;
+
+    let mut bytes = Vec::with_capacity(file.metadata().map_or(0, |m| m.len() as usize));
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        match file.read_at(&mut chunk, bytes.len() as u64) {
+            Ok(0) => return Ok(bytes),
+            Ok(read) => bytes.extend_from_slice(&chunk[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn read_from_start(mut file: &File) -> io::Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
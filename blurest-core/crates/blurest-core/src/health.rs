@@ -0,0 +1,128 @@
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use diesel::prelude::*;
+
+use crate::{
+    core::AppContext,
+    encode::{average_color, decode_rgba, encode_rgba},
+    options::DEFAULT_COMPONENTS,
+    schema::blurhash_cache,
+};
+
+/// Side of the image encoded by the encoder check.
+const PROBE_SIZE: u32 = 8;
+
+/// Color of the image encoded by the encoder check.
+const PROBE_COLOR: [u8; 3] = [96, 160, 224];
+
+/// Largest difference per channel between the probe color and the average color of
+/// its blurhash that still counts as a round trip.
+const PROBE_TOLERANCE: u8 = 2;
+
+/// Outcome of one check of a [`HealthReport`].
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    /// Why the check failed, or `None` if it passed.
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+impl HealthCheck {
+    fn run(check: impl FnOnce() -> Result<()>) -> Self {
+        let started = Instant::now();
+        let error = check().err().map(|e| format!("{e:#}"));
+        Self {
+            error,
+            duration: started.elapsed(),
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Readiness of a context, from [`health_check`].
+#[derive(Debug, Clone)]
+pub struct HealthReport {
+    /// The cache table answers a query.
+    pub database: HealthCheck,
+    /// Each project root can be listed, in the order of the context's roots.
+    pub project_roots: Vec<(PathBuf, HealthCheck)>,
+    /// A tiny image encodes to a blurhash that decodes back to its color.
+    pub encoder: HealthCheck,
+}
+
+impl HealthReport {
+    /// Whether every check passed.
+    pub fn healthy(&self) -> bool {
+        self.database.ok()
+            && self.project_roots.iter().all(|(_, check)| check.ok())
+            && self.encoder.ok()
+    }
+}
+
+/// Checks that `context` can serve lookups: its database answers a query, its project
+/// roots are readable and the encoder round-trips an image held in memory.
+///
+/// Unlike a lookup, this touches neither image files nor cached entries, so it is
+/// cheap enough for frequent readiness probes.
+pub fn health_check(context: &mut AppContext) -> HealthReport {
+    let database = HealthCheck::run(|| {
+        blurhash_cache::table
+            .select(blurhash_cache::id)
+            .first::<i32>(&mut context.db_conn)
+            .optional()?;
+        Ok(())
+    });
+    let project_roots = context
+        .project_roots
+        .iter()
+        .map(|root| {
+            let check = HealthCheck::run(|| {
+                fs::read_dir(root)?.next().transpose()?;
+                Ok(())
+            });
+            (root.clone(), check)
+        })
+        .collect();
+    let encoder = HealthCheck::run(probe_encoder);
+    HealthReport {
+        database,
+        project_roots,
+        encoder,
+    }
+}
+
+/// Encodes a solid image and checks that its blurhash decodes back to its color.
+fn probe_encoder() -> Result<()> {
+    let pixels: Vec<u8> = (0..PROBE_SIZE * PROBE_SIZE)
+        .flat_map(|_| [PROBE_COLOR[0], PROBE_COLOR[1], PROBE_COLOR[2], u8::MAX])
+        .collect();
+    let blurhash = encode_rgba(DEFAULT_COMPONENTS, PROBE_SIZE, PROBE_SIZE, &pixels)?;
+    let decoded = decode_rgba(&blurhash, PROBE_SIZE, PROBE_SIZE, 1.0)?;
+    if decoded.len() != pixels.len() {
+        return Err(anyhow!(
+            "Decoded {} bytes of pixels instead of {}",
+            decoded.len(),
+            pixels.len()
+        ));
+    }
+    let color = average_color(&blurhash)
+        .ok_or_else(|| anyhow!("Encoded an invalid blurhash `{blurhash}`"))?;
+    let matches = color
+        .iter()
+        .zip(PROBE_COLOR)
+        .all(|(&channel, expected)| channel.abs_diff(expected) <= PROBE_TOLERANCE);
+    if !matches {
+        return Err(anyhow!(
+            "Blurhash `{blurhash}` of color {PROBE_COLOR:?} decodes to {color:?}"
+        ));
+    }
+    Ok(())
+}
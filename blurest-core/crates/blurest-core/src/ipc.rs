@@ -0,0 +1,418 @@
+//! A cache daemon listening on a Unix domain socket, so several local processes, such
+//! as Node.js cluster workers or a Python thumbnailer, share one cache without network
+//! configuration; see [`IpcServer`].
+//!
+//! Every message, in either direction, is a 4-byte big-endian length followed by that
+//! many bytes of UTF-8 JSON. A connection carries any number of requests, answered in
+//! order:
+//!
+//! ```json
+//! { "method": "getBlurhash", "params": { "path": "/srv/site/public/hero.jpg" } }
+//! { "result": { "key": "hero.jpg", "blurhash": "…", "width": 1200, "height": 800, … } }
+//! { "error": "Failed to find file at …", "code": null }
+//! ```
+//!
+//! Methods:
+//!
+//! - `getBlurhash` with `{ path, force?, validation? }` returns the entry, see
+//!   [`blurhash_json`].
+//! - `warm` with `{ paths }` returns `{ warmed, failed: [{ path, error, code }], skipped }`,
+//!   where `skipped` lists the paths that are not images.
+//! - `invalidate` with `{ path }` returns `{ key }`, see [`invalidate`].
+//! - `stats` returns `{ entries, contentKeyed, hits, misses, staleRefreshes, dbErrors }`.
+//! - `ping` returns `{}`.
+//!
+//! Relative paths are resolved against the daemon's working directory.
+//!
+//! [`IpcClient`] is the other end, for processes that forward their lookups to a
+//! daemon rather than opening the database themselves.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+};
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, warn};
+use serde_json::{Map, Value, json};
+
+use crate::{
+    BlurestError, BlurhashData, CacheStore, CacheValidation, Output, RequestOptions,
+    core::BatchItem,
+    error::{ERROR_CODES, error_code},
+    maintenance::cache_stats,
+    manifest::invalidate,
+};
+
+/// Largest accepted message, in bytes.
+pub const MAX_MESSAGE_BYTES: u32 = 16 * 1024 * 1024;
+
+/// A bound daemon socket, removed again when dropped.
+pub struct IpcServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl IpcServer {
+    /// Binds the socket at `path`. A socket file left behind by a daemon that is no
+    /// longer running is replaced; one with a live daemon behind it is an error.
+    pub fn bind(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("Another daemon is listening on {}", path.display()));
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves `store` until the process is terminated, each connection on a thread
+    /// of its own.
+    pub fn serve(&self, store: &CacheStore) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a connection: {e}");
+                    continue;
+                }
+            };
+            let store = store.clone();
+            thread::Builder::new()
+                .name("blurest-ipc".to_string())
+                .spawn(move || {
+                    if let Err(e) = handle_connection(&store, stream) {
+                        debug!("Connection closed: {e:#}");
+                    }
+                })
+                .context("Failed to start a connection thread")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A client of an [`IpcServer`]. Safe to share between threads: each call takes an
+/// idle connection, or opens one, so concurrent calls do not wait for each other.
+pub struct IpcClient {
+    path: PathBuf,
+    idle: Mutex<Vec<UnixStream>>,
+}
+
+impl IpcClient {
+    /// Connects to the daemon listening at `path`, failing if none answers.
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        let client = Self {
+            path: path.as_ref().to_path_buf(),
+            idle: Mutex::new(Vec::new()),
+        };
+        client.call("ping", json!({}))?;
+        Ok(client)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sends one request and returns its `result`. Errors the daemon reports keep their
+    /// code, as [`BlurestError::Daemon`].
+    ///
+    /// A connection that went idle may have been closed by a restarted daemon, so a
+    /// request failing on one is sent once more on a new connection.
+    pub fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let request = json!({ "method": method, "params": params });
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let (stream, response) = match idle.map(|stream| exchange(stream, &request)) {
+            Some(Ok(exchanged)) => exchanged,
+            Some(Err(e)) => {
+                debug!("Reconnecting to {}: {e:#}", self.path.display());
+                exchange(self.open()?, &request)?
+            }
+            None => exchange(self.open()?, &request)?,
+        };
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.push(stream);
+        }
+
+        if let Some(message) = response.get("error") {
+            let message = message
+                .as_str()
+                .unwrap_or("Daemon request failed")
+                .to_string();
+            let code = response.get("code").and_then(Value::as_str);
+            return Err(
+                match ERROR_CODES.into_iter().find(|&known| Some(known) == code) {
+                    Some(code) => BlurestError::Daemon { code, message }.into(),
+                    None => anyhow!(message),
+                },
+            );
+        }
+        response
+            .get("result")
+            .cloned()
+            .ok_or_else(|| anyhow!("Daemon response has neither a result nor an error"))
+    }
+
+    fn open(&self) -> Result<UnixStream> {
+        UnixStream::connect(&self.path)
+            .with_context(|| format!("Failed to connect to the daemon at {}", self.path.display()))
+    }
+
+    /// Looks `image_path` up through the daemon. Relative paths are resolved against
+    /// the working directory of this process.
+    ///
+    /// Only `force`, `validation` and `traceparent` are forwarded; other request
+    /// options are rejected, as the daemon would ignore them.
+    pub fn get_blurhash(
+        &self,
+        image_path: &Path,
+        request: &RequestOptions,
+    ) -> Result<BlurhashData> {
+        if request.project_root.is_some()
+            || request.crop.is_some()
+            || request.aspect_ratio.is_some()
+            || request.focal_point.is_some()
+            || request.rendition_widths.is_some()
+            || request.preview_size.is_some()
+            || request.output != Output::default()
+        {
+            return Err(anyhow!(
+                "Lookups forwarded to a daemon only take `force`, `validation` and `traceparent`"
+            ));
+        }
+        let mut params = json!({
+            "path": std::path::absolute(image_path)?,
+            "force": request.force,
+            "traceparent": request.traceparent,
+        });
+        if let Some(validation) = request.validation {
+            params["validation"] = json!(validation.name());
+        }
+        blurhash_from_json(&self.call("getBlurhash", params)?)
+    }
+
+    /// Forgets `image_path` on the daemon, see [`invalidate`]. Returns its cache key,
+    /// or `None` if no key can be derived for it.
+    pub fn invalidate(&self, image_path: &Path) -> Result<Option<String>> {
+        let params = json!({ "path": std::path::absolute(image_path)? });
+        let result = self.call("invalidate", params)?;
+        Ok(result
+            .get("key")
+            .and_then(Value::as_str)
+            .map(str::to_string))
+    }
+}
+
+/// Sends `request` on `stream` and reads the response, handing the stream back.
+fn exchange(mut stream: UnixStream, request: &Value) -> Result<(UnixStream, Value)> {
+    write_message(&mut stream, request)?;
+    let response =
+        read_message(&mut stream)?.ok_or_else(|| anyhow!("Daemon closed the connection"))?;
+    Ok((stream, response))
+}
+
+/// Answers the requests of one client until it disconnects.
+fn handle_connection(store: &CacheStore, mut stream: UnixStream) -> Result<()> {
+    while let Some(request) = read_message(&mut stream)? {
+        let response = match dispatch(store, &request) {
+            Ok(result) => json!({ "result": result }),
+            Err(e) => json!({ "error": format!("{e:#}"), "code": error_code(&e) }),
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+fn dispatch(store: &CacheStore, request: &Value) -> Result<Value> {
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Expected a request with a `method` string"))?;
+    let empty = Map::new();
+    let params = match request.get("params") {
+        None | Some(Value::Null) => &empty,
+        Some(Value::Object(params)) => params,
+        Some(_) => return Err(anyhow!("`params` must be an object")),
+    };
+
+    match method {
+        "getBlurhash" => {
+            let path = path_param(params)?;
+            let mut options = RequestOptions {
+                force: params
+                    .get("force")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+                traceparent: params
+                    .get("traceparent")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                ..RequestOptions::default()
+            };
+            if let Some(validation) = params.get("validation").and_then(Value::as_str) {
+                options.validation = Some(CacheValidation::parse(validation)?);
+            }
+            let data = store.get_blurhash_with_options(&path, &options)?;
+            Ok(blurhash_json(&data))
+        }
+        "warm" => {
+            let paths = params
+                .get("paths")
+                .and_then(Value::as_array)
+                .ok_or_else(|| anyhow!("Expected a `paths` array"))?
+                .iter()
+                .map(|path| path.as_str().map(PathBuf::from))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| anyhow!("Paths must be strings"))?;
+            let mut warmed = 0;
+            let mut failed = Vec::new();
+            let mut skipped = Vec::new();
+            store.get_blurhashes(paths, |path, result| match result {
+                Ok(BatchItem::Image(_)) => warmed += 1,
+                Ok(BatchItem::Skipped(_)) => skipped.push(path.to_string_lossy().into_owned()),
+                Err(e) => failed.push(json!({
+                    "path": path.to_string_lossy(),
+                    "error": format!("{e:#}"),
+                    "code": error_code(&e),
+                })),
+            })?;
+            Ok(json!({ "warmed": warmed, "failed": failed, "skipped": skipped }))
+        }
+        "invalidate" => {
+            let path = path_param(params)?;
+            let key = store.with_context(|context| invalidate(context, &path))?;
+            Ok(json!({ "key": key }))
+        }
+        "stats" => {
+            let (stats, metrics) = store
+                .with_context(|context| Ok((cache_stats(context)?, context.metrics.snapshot())))?;
+            Ok(json!({
+                "entries": stats.entries,
+                "contentKeyed": stats.content_keyed,
+                "hits": metrics.hits,
+                "misses": metrics.misses,
+                "staleRefreshes": metrics.stale_refreshes,
+                "dbErrors": metrics.db_errors,
+            }))
+        }
+        "ping" => Ok(json!({})),
+        _ => Err(anyhow!("Unknown method `{method}`")),
+    }
+}
+
+fn path_param(params: &Map<String, Value>) -> Result<PathBuf> {
+    params
+        .get("path")
+        .and_then(Value::as_str)
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("Expected a `path` string"))
+}
+
+/// The fields of `data` a daemon sends for `getBlurhash`, camel-cased. Generator
+/// payloads, renditions, previews and warnings stay on the daemon.
+pub fn blurhash_json(data: &BlurhashData) -> Value {
+    json!({
+        "key": data.key,
+        "pathKey": data.path_key,
+        "blurhash": data.blurhash,
+        "width": data.width,
+        "height": data.height,
+        "blurDataUrl": data.blur_data_url,
+        "orientation": data.orientation,
+        "orientationApplied": data.orientation_applied,
+        "hasAlpha": data.has_alpha,
+        "format": data.format,
+        "bitDepth": data.bit_depth,
+        "colorSpace": data.color_space,
+        "colorProfile": data.color_profile,
+    })
+}
+
+/// Parses an entry sent by [`blurhash_json`]. The fields the daemon does not send are
+/// left empty.
+pub fn blurhash_from_json(value: &Value) -> Result<BlurhashData> {
+    let string = |name: &str| value.get(name).and_then(Value::as_str).map(str::to_string);
+    let integer = |name: &str| value.get(name).and_then(Value::as_i64);
+    let dimension = |name: &str| {
+        integer(name)
+            .and_then(|value| i32::try_from(value).ok())
+            .ok_or_else(|| anyhow!("Daemon response lacks `{name}`"))
+    };
+    let key = string("key").ok_or_else(|| anyhow!("Daemon response lacks `key`"))?;
+    Ok(BlurhashData {
+        path_key: string("pathKey").unwrap_or_else(|| key.clone()),
+        key,
+        blurhash: string("blurhash").ok_or_else(|| anyhow!("Daemon response lacks `blurhash`"))?,
+        width: dimension("width")?,
+        height: dimension("height")?,
+        blur_data_url: string("blurDataUrl"),
+        webp_preview: None,
+        micro_pixels: None,
+        placeholders: BTreeMap::new(),
+        responsive: None,
+        orientation: integer("orientation").and_then(|value| u8::try_from(value).ok()),
+        orientation_applied: value
+            .get("orientationApplied")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        has_alpha: value.get("hasAlpha").and_then(Value::as_bool),
+        format: string("format"),
+        bit_depth: integer("bitDepth").and_then(|value| u8::try_from(value).ok()),
+        perceptual_hash: None,
+        color_space: string("colorSpace"),
+        color_profile: string("colorProfile"),
+        warnings: Vec::new(),
+    })
+}
+
+/// Reads one message, or `None` if the peer closed the connection before sending one.
+pub(crate) fn read_message(stream: &mut impl Read) -> Result<Option<Value>> {
+    let mut length = [0; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(length);
+    if length > MAX_MESSAGE_BYTES {
+        return Err(anyhow!(
+            "Message of {length} bytes exceeds the limit of {MAX_MESSAGE_BYTES}"
+        ));
+    }
+    let mut body = vec![0; length as usize];
+    stream.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+pub(crate) fn write_message(stream: &mut impl Write, message: &Value) -> Result<()> {
+    let body = serde_json::to_vec(message)?;
+    let length = u32::try_from(body.len())
+        .ok()
+        .filter(|&length| length <= MAX_MESSAGE_BYTES)
+        .ok_or_else(|| anyhow!("Message of {} bytes is too large", body.len()))?;
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+    Ok(())
+}
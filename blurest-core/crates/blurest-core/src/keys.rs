@@ -0,0 +1,473 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::{
+    encode::hash_file_bytes,
+    options::{ContextOptions, Crop, RequestOptions},
+    retry::retry_io,
+};
+
+/// Prefix of cache keys derived from file content rather than from a path.
+pub(crate) const CONTENT_KEY_PREFIX: &str = "xxh3:";
+/// Separates the key of an image from the region of an entry cropped with
+/// [`RequestOptions::crop`].
+const CROP_KEY_SEPARATOR: &str = "#crop=";
+/// Separates the key of an image from the aspect ratio and focal point of an entry
+/// cropped with [`RequestOptions::aspect_ratio`].
+const ASPECT_KEY_SEPARATOR: &str = "#aspect=";
+
+/// Prefix of hex-encoded keys of paths that are not valid UTF-8.
+const BYTES_KEY_PREFIX: &str = "bytes:";
+
+/// How images outside every project root are keyed in the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutsideRootPolicy {
+    /// Refuse to process the image.
+    #[default]
+    Error,
+    /// Key the image by its canonical absolute path.
+    Absolute,
+    /// Key the image by the hash of its content, so identical files share one entry.
+    Hash,
+}
+
+impl OutsideRootPolicy {
+    /// Parses a policy name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "error" => Ok(Self::Error),
+            "absolute" => Ok(Self::Absolute),
+            "hash" => Ok(Self::Hash),
+            other => Err(anyhow!(
+                "Unknown outsideRoot policy `{other}`, expected \"error\", \"absolute\" or \"hash\""
+            )),
+        }
+    }
+}
+
+/// How paths that are not valid UTF-8 are turned into keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonUtf8Policy {
+    /// Refuse to process the image.
+    #[default]
+    Error,
+    /// Replace invalid sequences with U+FFFD. Distinct names may share a key.
+    Lossy,
+    /// Hex-encode the raw path bytes behind a `bytes:` prefix, which is unambiguous
+    /// but not readable.
+    Bytes,
+}
+
+impl NonUtf8Policy {
+    /// Parses a policy name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "error" => Ok(Self::Error),
+            "lossy" => Ok(Self::Lossy),
+            "bytes" => Ok(Self::Bytes),
+            other => Err(anyhow!(
+                "Unknown nonUtf8Keys policy `{other}`, expected \"error\", \"lossy\" or \"bytes\""
+            )),
+        }
+    }
+}
+
+/// Cache key of an image, as stored in the `relative_path` column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheKey {
+    /// Path relative to the project root containing the image, `/`-separated on every platform.
+    Relative(String),
+    /// Canonical absolute path of an image outside every root.
+    Absolute(String),
+    /// Content hash of an image outside every root. Such entries never go stale,
+    /// because a different content yields a different key.
+    Content(String),
+}
+
+impl CacheKey {
+    /// The key as stored in the database.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Relative(key) | Self::Absolute(key) | Self::Content(key) => key,
+        }
+    }
+
+    /// Whether the entry is addressed by content, so a stored row is always valid.
+    pub fn is_content_addressed(&self) -> bool {
+        matches!(self, Self::Content(_))
+    }
+
+    /// The key of the part of the image selected by `request`, e.g.
+    /// `a.png#crop=0,0,64,32` or `a.png#aspect=1.5,0.5,0.25`, or the key itself when
+    /// the request encodes the full frame.
+    pub fn for_request(self, request: &RequestOptions) -> Self {
+        let mut suffix = String::new();
+        if let Some(Crop {
+            x,
+            y,
+            width,
+            height,
+        }) = request.crop
+        {
+            suffix.push_str(&format!("{CROP_KEY_SEPARATOR}{x},{y},{width},{height}"));
+        }
+        if let Some(aspect_ratio) = request.aspect_ratio {
+            let focal_point = request.focal_point.unwrap_or_default();
+            suffix.push_str(&format!(
+                "{ASPECT_KEY_SEPARATOR}{aspect_ratio},{},{}",
+                focal_point.fx, focal_point.fy
+            ));
+        }
+        if suffix.is_empty() {
+            return self;
+        }
+        match self {
+            Self::Relative(key) => Self::Relative(key + &suffix),
+            Self::Absolute(key) => Self::Absolute(key + &suffix),
+            Self::Content(key) => Self::Content(key + &suffix),
+        }
+    }
+}
+
+/// Derives the cache key of the canonical `absolute_path`.
+///
+/// Paths inside one of `roots` are keyed relative to it; anything else is handled
+/// according to `options.outside_root`. [`OutsideRootPolicy::Hash`] reads the whole file.
+pub fn derive_key(
+    absolute_path: &Path,
+    roots: &[PathBuf],
+    options: &ContextOptions,
+) -> Result<CacheKey> {
+    if let Some(relative) = strip_root(absolute_path, roots) {
+        return Ok(CacheKey::Relative(relative_path_to_key(
+            relative,
+            options.non_utf8_keys,
+        )?));
+    }
+
+    match options.outside_root {
+        OutsideRootPolicy::Error => Err(anyhow!("Image path is not within the project root.")),
+        OutsideRootPolicy::Absolute => Ok(CacheKey::Absolute(path_to_key(
+            absolute_path,
+            options.non_utf8_keys,
+        )?)),
+        OutsideRootPolicy::Hash => {
+            let file_bytes = retry_io(options.io_retries, || fs::read(absolute_path))?;
+            Ok(CacheKey::Content(format!(
+                "{CONTENT_KEY_PREFIX}{}",
+                hash_file_bytes(&file_bytes)
+            )))
+        }
+    }
+}
+
+/// Takes a cache key chosen by the caller for an image without a path to derive one
+/// from, such as a file opened elsewhere. Keys that are reserved for content hashes
+/// or byte-encoded paths, or end in the suffix of a cropped entry, are refused.
+pub fn caller_key(key: &str) -> Result<CacheKey> {
+    if key.is_empty() {
+        return Err(anyhow!("Cache key must not be empty"));
+    }
+    if key.starts_with(CONTENT_KEY_PREFIX) || key.starts_with(BYTES_KEY_PREFIX) {
+        return Err(anyhow!(
+            "Cache key `{key}` uses a prefix reserved for derived keys"
+        ));
+    }
+    if image_key(key) != key {
+        return Err(anyhow!(
+            "Cache key `{key}` ends in the suffix of a cropped entry"
+        ));
+    }
+    if Path::new(key).is_absolute() {
+        Ok(CacheKey::Absolute(key.to_string()))
+    } else {
+        Ok(CacheKey::Relative(key.to_string()))
+    }
+}
+
+/// Derives the cache key of `absolute_path` relative to the root that contains it.
+///
+/// When several roots contain the path, the most specific (deepest) one wins, and
+/// among equally deep roots the one listed first. Roots sharing relative paths also
+/// share cache keys.
+pub fn relative_key(absolute_path: &Path, roots: &[PathBuf]) -> Result<String> {
+    let relative = strip_root(absolute_path, roots)
+        .ok_or_else(|| anyhow!("Image path is not within the project root."))?;
+    relative_path_to_key(relative, NonUtf8Policy::Error)
+}
+
+/// Whether any of `roots` contains the canonical `absolute_path`.
+pub fn is_within_roots(absolute_path: &Path, roots: &[PathBuf]) -> bool {
+    strip_root(absolute_path, roots).is_some()
+}
+
+/// Resolves a cache key back to the paths it may have been derived from.
+///
+/// Relative keys yield one candidate per root, absolute keys their own path. Returns
+/// `None` for content-addressed keys, and for byte-encoded keys on platforms where
+/// they cannot be decoded, as those do not name a file.
+pub fn key_paths(key: &str, roots: &[PathBuf]) -> Option<Vec<PathBuf>> {
+    let path = key_path(key)?;
+    if path.is_absolute() {
+        return Some(vec![path]);
+    }
+    Some(roots.iter().map(|root| root.join(&path)).collect())
+}
+
+/// The path a cache key names, relative to its root for relative keys; see
+/// [`key_paths`].
+pub(crate) fn key_path(key: &str) -> Option<PathBuf> {
+    if key.starts_with(CONTENT_KEY_PREFIX) {
+        return None;
+    }
+    let key = image_key(key);
+    match key.strip_prefix(BYTES_KEY_PREFIX) {
+        Some(encoded) => decode_bytes_key(encoded),
+        None => Some(PathBuf::from(key)),
+    }
+}
+
+/// The `/`-separated form of a relative key written with backslashes, as by versions
+/// that kept Windows separators, or `None` if `key` needs no rewriting. Absolute keys
+/// keep the separators of their platform and are left alone.
+#[cfg(feature = "cache")]
+pub(crate) fn normalized_legacy_key(key: &str) -> Option<String> {
+    let bytes = key.as_bytes();
+    let absolute = matches!(bytes.first(), Some(b'/' | b'\\'))
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':');
+    (!absolute && key.contains('\\')).then(|| key.replace('\\', "/"))
+}
+
+/// SQLite `GLOB` pattern matching the keys of every cropped entry of the image `key`,
+/// along with some other keys; filter the matches with [`image_key`].
+#[cfg(feature = "cache")]
+pub(crate) fn cropped_key_pattern(key: &str) -> String {
+    let mut pattern = String::with_capacity(key.len() + 2);
+    for c in key.chars() {
+        match c {
+            '*' | '?' | '[' => {
+                pattern.push('[');
+                pattern.push(c);
+                pattern.push(']');
+            }
+            _ => pattern.push(c),
+        }
+    }
+    pattern.push_str("#*");
+    pattern
+}
+
+/// The key of the full image behind `key`, dropping the suffixes of a cropped entry.
+pub(crate) fn image_key(mut key: &str) -> &str {
+    while let Some((prefix, suffix)) = key.rsplit_once('#') {
+        let suffix = format!("#{suffix}");
+        let is_region = if let Some(region) = suffix.strip_prefix(CROP_KEY_SEPARATOR) {
+            is_number_list::<u32>(region, 4)
+        } else if let Some(region) = suffix.strip_prefix(ASPECT_KEY_SEPARATOR) {
+            is_number_list::<f64>(region, 3)
+        } else {
+            false
+        };
+        if !is_region {
+            break;
+        }
+        key = prefix;
+    }
+    key
+}
+
+/// Whether `list` consists of exactly `count` comma-separated numbers of type `T`.
+fn is_number_list<T: std::str::FromStr>(list: &str, count: usize) -> bool {
+    list.split(',').count() == count && list.split(',').all(|n| n.parse::<T>().is_ok())
+}
+
+#[cfg(unix)]
+fn decode_bytes_key(encoded: &str) -> Option<PathBuf> {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    hex::decode(encoded)
+        .ok()
+        .map(|bytes| PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn decode_bytes_key(_encoded: &str) -> Option<PathBuf> {
+    None
+}
+
+fn strip_root<'a>(absolute_path: &'a Path, roots: &[PathBuf]) -> Option<&'a Path> {
+    let mut best: Option<(usize, &Path)> = None;
+    for root in roots {
+        if let Ok(relative) = absolute_path.strip_prefix(root) {
+            let depth = root.components().count();
+            if best.is_none_or(|(best_depth, _)| depth > best_depth) {
+                best = Some((depth, relative));
+            }
+        }
+    }
+    best.map(|(_, relative)| relative)
+}
+
+/// Joins the components of a relative path with `/` on every platform, so keys
+/// written on Windows match keys written elsewhere.
+fn relative_path_to_key(path: &Path, non_utf8: NonUtf8Policy) -> Result<String> {
+    let parts: Vec<&OsStr> = path
+        .components()
+        .map(|component| component.as_os_str())
+        .collect();
+    encode_key(&parts, non_utf8)
+}
+
+fn path_to_key(path: &Path, non_utf8: NonUtf8Policy) -> Result<String> {
+    encode_key(&[path.as_os_str()], non_utf8)
+}
+
+/// Joins `parts` with `/` into a key, applying `non_utf8` if any part is not valid UTF-8.
+fn encode_key(parts: &[&OsStr], non_utf8: NonUtf8Policy) -> Result<String> {
+    if let Some(parts) = parts
+        .iter()
+        .map(|part| part.to_str())
+        .collect::<Option<Vec<_>>>()
+    {
+        return Ok(parts.join("/"));
+    }
+
+    match non_utf8 {
+        NonUtf8Policy::Error => Err(anyhow!("Path contains non-UTF8 characters")),
+        NonUtf8Policy::Lossy => Ok(parts
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")),
+        NonUtf8Policy::Bytes => {
+            let mut bytes = Vec::new();
+            for (index, part) in parts.iter().enumerate() {
+                if index > 0 {
+                    bytes.push(b'/');
+                }
+                bytes.extend_from_slice(part.as_encoded_bytes());
+            }
+            Ok(format!("{BYTES_KEY_PREFIX}{}", hex::encode(bytes)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root() -> PathBuf {
+        std::env::temp_dir().join("blurest-keys")
+    }
+
+    #[test]
+    fn relative_keys_use_forward_slashes() {
+        let roots = [root()];
+        // `join` inserts the platform separator, a backslash on Windows.
+        let image = roots[0].join("a").join("b").join("c.png");
+        assert_eq!(relative_key(&image, &roots).unwrap(), "a/b/c.png");
+        let image = roots[0].join("a/b/c.png");
+        assert_eq!(relative_key(&image, &roots).unwrap(), "a/b/c.png");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn backslash_relative_paths_become_slashes() {
+        let root = root();
+        let image = root.join(r"a\b\c.png");
+        assert_eq!(relative_key(&image, &[root]).unwrap(), "a/b/c.png");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backslashes_are_part_of_unix_file_names() {
+        let root = root();
+        let image = root.join(r"a\b.png");
+        assert_eq!(relative_key(&image, &[root]).unwrap(), r"a\b.png");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_caller_keys_stay_absolute() {
+        assert_eq!(
+            caller_key("/srv/site/a.png").unwrap(),
+            CacheKey::Absolute("/srv/site/a.png".to_string())
+        );
+        assert_eq!(
+            caller_key("a/b.png").unwrap(),
+            CacheKey::Relative("a/b.png".to_string())
+        );
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn legacy_backslash_keys_are_normalized() {
+        assert_eq!(
+            normalized_legacy_key(r"a\b.png").as_deref(),
+            Some("a/b.png")
+        );
+        assert_eq!(
+            normalized_legacy_key(r"a\b/c\d.png").as_deref(),
+            Some("a/b/c/d.png")
+        );
+        assert_eq!(normalized_legacy_key("a/b.png"), None);
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn legacy_absolute_keys_are_left_alone() {
+        assert_eq!(normalized_legacy_key(r"C:\images\a.png"), None);
+        assert_eq!(normalized_legacy_key("c:/images/a.png"), None);
+        assert_eq!(normalized_legacy_key(r"\\?\C:\images\a.png"), None);
+        assert_eq!(normalized_legacy_key(r"\\server\share\a.png"), None);
+        assert_eq!(normalized_legacy_key("/srv/site/a.png"), None);
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn migration_leaves_backslash_keys_to_normalize_keys() {
+        use diesel::{Connection, SqliteConnection, connection::SimpleConnection, prelude::*};
+
+        use crate::{migrations::MIGRATIONS, schema::blurhash_cache};
+
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        conn.batch_execute(MIGRATIONS[0]).unwrap();
+        for (id, key) in [
+            (1, r"a\b.png"),
+            (2, r"c\d.png"),
+            (3, "c/d.png"),
+            (4, r"C:\images\e.png"),
+            (5, r"\\?\C:\images\f.png"),
+        ] {
+            conn.batch_execute(&format!(
+                "INSERT INTO blurhash_cache (id, relative_path, xxhash, mtime_ms, blurhash, width, height) \
+                 VALUES ({id}, '{key}', 'hash', 0, 'LEHV6nWB2yk8', 1, 1)"
+            ))
+            .unwrap();
+        }
+        conn.batch_execute(MIGRATIONS[1]).unwrap();
+
+        let keys: Vec<(i32, String)> = blurhash_cache::table
+            .select((blurhash_cache::id, blurhash_cache::relative_path))
+            .order(blurhash_cache::id)
+            .load(&mut conn)
+            .unwrap();
+        let keys: Vec<(i32, &str)> = keys.iter().map(|(id, key)| (*id, key.as_str())).collect();
+        // Only `normalize_keys` can tell a legacy key from a Unix file name.
+        assert_eq!(
+            keys,
+            [
+                (1, r"a\b.png"),
+                (2, r"c\d.png"),
+                (3, "c/d.png"),
+                (4, r"C:\images\e.png"),
+                (5, r"\\?\C:\images\f.png"),
+            ]
+        );
+    }
+}
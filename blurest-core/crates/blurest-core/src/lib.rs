@@ -0,0 +1,117 @@
+//! # blurest-core
+//!
+//! Blurhash generation with a SQLite-backed cache, independent of any JavaScript runtime.
+//!
+//! This is the caching engine behind the `@fuuck/blurest-core` Node.js module, usable
+//! directly from Rust services and tools. Images are keyed by their path relative to a
+//! project root; cached entries are validated by modification time and content hash, so
+//! a blurhash is only recomputed when the image actually changed.
+//!
+//! ## Usage
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use blurest_core::{CacheStore, ContextOptions};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let store = CacheStore::open("cache.sqlite3", &["/srv/site/public"], ContextOptions::default())?;
+//! let data = store.get_blurhash(Path::new("/srv/site/public/hero.jpg"))?;
+//! println!("{} ({}x{})", data.blurhash, data.width, data.height);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`CacheStore`] is cheap to clone and safe to share between threads, which makes it
+//! suitable as shared state in a web server. For single-threaded use, an [`AppContext`]
+//! can be driven directly with [`get_blurhash_with_cache`].
+//!
+//! ## Features
+//!
+//! - `cache` (default): the SQLite-backed cache. Without it only [`encode`] and the
+//!   option types remain, which build for targets without SQLite such as `wasm32`.
+//! - `otel`: OpenTelemetry spans for lookups and generations, with an OTLP exporter;
+//!   see [`telemetry`].
+//! - `turbojpeg`: JPEG decoding with libjpeg-turbo, several times faster, at 1/8 size
+//!   where the full resolution is not needed; see [`jpeg`].
+//! - `vips`: decoding through the system's libvips, shrinking images while loading
+//!   them and reading formats such as HEIC; see [`vips`] and [`backend`].
+//! - `test-support`: the `testing` module, scaffolding for tests of integrations
+//!   (enable it under `[dev-dependencies]`).
+
+pub mod backend;
+#[cfg(feature = "cache")]
+pub mod benchmark;
+#[cfg(feature = "cache")]
+pub mod bitmap;
+#[cfg(feature = "cache")]
+pub mod completions;
+#[cfg(feature = "cache")]
+pub mod config;
+#[cfg(feature = "cache")]
+pub mod core;
+pub mod css;
+pub mod encode;
+pub mod error;
+#[cfg(feature = "cache")]
+pub mod handle;
+#[cfg(feature = "cache")]
+pub mod health;
+#[cfg(all(unix, feature = "cache"))]
+pub mod ipc;
+#[cfg(feature = "turbojpeg")]
+pub mod jpeg;
+pub mod keys;
+#[cfg(feature = "cache")]
+pub mod logging;
+#[cfg(feature = "cache")]
+pub mod maintenance;
+#[cfg(feature = "cache")]
+pub mod manifest;
+#[cfg(feature = "cache")]
+pub mod metrics;
+#[cfg(feature = "cache")]
+pub mod migrations;
+#[cfg(feature = "cache")]
+pub mod models;
+pub mod options;
+pub mod placeholder;
+pub mod pool;
+#[cfg(feature = "cache")]
+pub mod project;
+#[cfg(feature = "cache")]
+pub mod refresh;
+pub mod responsive;
+pub mod retry;
+pub mod sandbox;
+#[cfg(feature = "cache")]
+pub mod schema;
+#[cfg(feature = "cache")]
+pub mod similar;
+#[cfg(feature = "cache")]
+pub mod snapshot;
+#[cfg(feature = "cache")]
+pub mod store;
+#[cfg(feature = "cache")]
+pub mod telemetry;
+#[cfg(feature = "test-support")]
+pub mod testing;
+#[cfg(feature = "cache")]
+pub mod thumbnail;
+#[cfg(feature = "cache")]
+pub mod verify;
+#[cfg(feature = "vips")]
+pub mod vips;
+
+#[cfg(feature = "cache")]
+pub use crate::{
+    core::{AppContext, BlurhashData, get_blurhash_with_cache, get_blurhash_with_options},
+    store::CacheStore,
+};
+pub use crate::{
+    error::BlurestError,
+    options::{
+        CacheValidation, ContextOptions, EncodeRule, EncodeSettings, KeyPattern, Output, Quality,
+        RequestOptions, Revalidate, ScheduledSnapshot,
+    },
+};
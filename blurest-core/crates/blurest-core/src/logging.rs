@@ -0,0 +1,176 @@
+//! A process-wide logger for hosts without one of their own, such as the Node.js module.
+//!
+//! Records carry structured fields through the `log` key-value API (`path`,
+//! `cache_status`, `duration_ms`, ...). In [`LogFormat::Json`] every record becomes one
+//! JSON object per line, with those fields at the top level, for log aggregators:
+//!
+//! ```text
+//! {"timestamp":"2024-05-01T12:00:00.000Z","level":"INFO","target":"blurest_core::core","message":"Cache miss: new file a.png","path":"a.png","cache_status":"miss"}
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{Context, Result, anyhow};
+use log::{
+    Log, Metadata, Record,
+    kv::{Error as KvError, Key, Value, VisitSource},
+};
+use serde_json::{Map, Value as JsonValue};
+
+/// Output format of the [`init_logger`] logger.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `[LEVEL target] message key=value ...`
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+    /// Parses `"text"` or `"json"`.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!(
+                "Unknown log format `{value}`, expected \"text\" or \"json\""
+            )),
+        }
+    }
+}
+
+struct Sink {
+    format: LogFormat,
+    /// Log file, or `None` for stderr.
+    file: Option<File>,
+}
+
+struct Logger {
+    sink: Mutex<Sink>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Routes `log` records to stderr, or to the file at `file` (appended to), in `format`.
+///
+/// Installs the logger on first use; later calls only change its format and output.
+/// Fails if another logger was installed in the process first. The level is still
+/// controlled by [`log::set_max_level`].
+pub fn init_logger(format: LogFormat, file: Option<&Path>) -> Result<()> {
+    let file = match file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open log file {path:?}"))?,
+        ),
+        None => None,
+    };
+    let sink = Sink { format, file };
+
+    let mut installed = false;
+    let logger = LOGGER.get_or_init(|| {
+        installed = true;
+        Logger {
+            sink: Mutex::new(Sink { format, file: None }),
+        }
+    });
+    if installed {
+        log::set_logger(logger).map_err(|_| anyhow!("Another logger is already installed"))?;
+    }
+    *logger
+        .sink
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire logger lock"))? = sink;
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let Ok(mut sink) = self.sink.lock() else {
+            return;
+        };
+        let line = match sink.format {
+            LogFormat::Text => text_line(record),
+            LogFormat::Json => json_line(record),
+        };
+        let _ = match &mut sink.file {
+            Some(file) => writeln!(file, "{line}"),
+            None => writeln!(io::stderr().lock(), "{line}"),
+        };
+    }
+
+    fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock()
+            && let Some(file) = &mut sink.file
+        {
+            let _ = file.flush();
+        }
+    }
+}
+
+fn text_line(record: &Record) -> String {
+    let mut line = format!("[{} {}] {}", record.level(), record.target(), record.args());
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+    for (key, value) in fields.0 {
+        line.push_str(&format!(" {key}={value}"));
+    }
+    line
+}
+
+fn json_line(record: &Record) -> String {
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_string(),
+        chrono::Utc::now()
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+            .to_string()
+            .into(),
+    );
+    object.insert("level".to_string(), record.level().as_str().into());
+    object.insert("target".to_string(), record.target().into());
+    object.insert("message".to_string(), record.args().to_string().into());
+    let mut fields = Fields::default();
+    let _ = record.key_values().visit(&mut fields);
+    for (key, value) in fields.0 {
+        object.insert(key, value);
+    }
+    JsonValue::Object(object).to_string()
+}
+
+/// Collects the key-value pairs of a record as JSON values.
+#[derive(Default)]
+struct Fields(Vec<(String, JsonValue)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        let value = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(number) = value.to_f64() {
+            number.into()
+        } else if let Some(flag) = value.to_bool() {
+            flag.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.push((key.as_str().to_string(), value));
+        Ok(())
+    }
+}
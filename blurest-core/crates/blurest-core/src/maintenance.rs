@@ -0,0 +1,725 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    hash::{BuildHasher, RandomState},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    dsl::{max, min, sql},
+    prelude::*,
+    sql_types::{BigInt, Bool},
+};
+use log::{info, warn};
+use serde_json::json;
+
+use crate::{
+    core::{AppContext, find_cached, get_blurhash_with_options, mtime_matches, time_to_ms},
+    encode::is_valid_blurhash,
+    keys::{CONTENT_KEY_PREFIX, image_key, key_path, key_paths, normalized_legacy_key},
+    models::BlurhashCache,
+    options::{RequestOptions, ScheduledMaintenance},
+    refresh::apply_refreshes,
+    schema::blurhash_cache,
+};
+
+/// Aggregate figures about the cache of a context.
+#[derive(Debug, Clone)]
+pub struct CacheStats {
+    /// Number of cached entries.
+    pub entries: i64,
+    /// Entries keyed by file content rather than by path.
+    pub content_keyed: i64,
+    /// When the least recently written entry was written.
+    pub oldest_update: Option<NaiveDateTime>,
+    /// When the most recently written entry was written.
+    pub newest_update: Option<NaiveDateTime>,
+}
+
+/// Collects [`CacheStats`] for the database of `context`.
+pub fn cache_stats(context: &mut AppContext) -> Result<CacheStats> {
+    let conn = &mut context.db_conn;
+    let entries = blurhash_cache::table.count().get_result(conn)?;
+    let content_keyed = blurhash_cache::table
+        .filter(blurhash_cache::relative_path.like(format!("{CONTENT_KEY_PREFIX}%")))
+        .count()
+        .get_result(conn)?;
+    let (oldest_update, newest_update) = blurhash_cache::table
+        .select((
+            min(blurhash_cache::updated_at),
+            max(blurhash_cache::updated_at),
+        ))
+        .first(conn)?;
+
+    Ok(CacheStats {
+        entries,
+        content_keyed,
+        oldest_update,
+        newest_update,
+    })
+}
+
+/// Entry counts and sizes of one group of a [`CacheComposition`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompositionGroup {
+    /// Number of cached entries, crops included.
+    pub entries: u64,
+    /// Total size in bytes of the files on disk, each counted once however many
+    /// crops of it are cached.
+    pub file_size: u64,
+    /// Entries whose file could not be found, e.g. deleted or keyed by content.
+    pub missing_files: u64,
+    /// Average dimensions of the cached images in pixels.
+    pub average_width: f64,
+    pub average_height: f64,
+}
+
+/// Breakdown of a cache by file extension and top-level directory, from
+/// [`cache_composition`].
+#[derive(Debug, Clone, Default)]
+pub struct CacheComposition {
+    /// Groups by lowercase file extension, `""` for keys without one.
+    pub by_extension: BTreeMap<String, CompositionGroup>,
+    /// Groups by the first directory of the key: `"."` for images directly in a
+    /// project root, `""` for keys that are absolute paths or content hashes.
+    pub by_directory: BTreeMap<String, CompositionGroup>,
+}
+
+impl CacheComposition {
+    /// The composition as a JSON object, with camel-cased fields:
+    ///
+    /// ```json
+    /// { "byExtension": { "<ext>": { "entries": 0, "fileSize": 0, "missingFiles": 0, "averageWidth": 0.0, "averageHeight": 0.0 } }, "byDirectory": { … } }
+    /// ```
+    pub fn to_json(&self) -> serde_json::Value {
+        let groups = |groups: &BTreeMap<String, CompositionGroup>| {
+            groups
+                .iter()
+                .map(|(name, group)| {
+                    let group = json!({
+                        "entries": group.entries,
+                        "fileSize": group.file_size,
+                        "missingFiles": group.missing_files,
+                        "averageWidth": group.average_width,
+                        "averageHeight": group.average_height,
+                    });
+                    (name.clone(), group)
+                })
+                .collect::<serde_json::Map<_, _>>()
+        };
+        json!({
+            "byExtension": groups(&self.by_extension),
+            "byDirectory": groups(&self.by_directory),
+        })
+    }
+}
+
+/// Aggregates the cached entries of `context` by file extension and top-level
+/// directory, for capacity planning. File sizes are read from disk, so this stats
+/// every cached image once.
+pub fn cache_composition(context: &mut AppContext) -> Result<CacheComposition> {
+    let rows: Vec<(String, i32, i32)> = blurhash_cache::table
+        .select((
+            blurhash_cache::relative_path,
+            blurhash_cache::width,
+            blurhash_cache::height,
+        ))
+        .load(&mut context.db_conn)?;
+
+    let mut by_extension: BTreeMap<String, GroupTotals> = BTreeMap::new();
+    let mut by_directory: BTreeMap<String, GroupTotals> = BTreeMap::new();
+    let mut file_sizes = BTreeMap::new();
+    for (key, width, height) in &rows {
+        let image = image_key(key);
+        let path = key_path(image);
+        let extension = path
+            .as_ref()
+            .and_then(|path| path.extension())
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let directory = match &path {
+            Some(path) if path.is_relative() => match path.parent() {
+                Some(parent) if parent.as_os_str().is_empty() => ".".to_string(),
+                _ => path
+                    .components()
+                    .next()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            },
+            _ => String::new(),
+        };
+        let file_size = *file_sizes.entry(image).or_insert_with(|| {
+            key_paths(image, &context.project_roots)
+                .unwrap_or_default()
+                .iter()
+                .find_map(|path| {
+                    fs::metadata(path)
+                        .ok()
+                        .filter(|metadata| metadata.is_file())
+                })
+                .map(|metadata| metadata.len())
+        });
+        for totals in [
+            by_extension.entry(extension).or_default(),
+            by_directory.entry(directory.clone()).or_default(),
+        ] {
+            totals.add(image, file_size, *width, *height);
+        }
+    }
+
+    let finish = |groups: BTreeMap<String, GroupTotals>| {
+        groups
+            .into_iter()
+            .map(|(name, totals)| (name, totals.finish()))
+            .collect()
+    };
+    Ok(CacheComposition {
+        by_extension: finish(by_extension),
+        by_directory: finish(by_directory),
+    })
+}
+
+/// Running sums of a [`CompositionGroup`].
+#[derive(Default)]
+struct GroupTotals<'a> {
+    entries: u64,
+    file_size: u64,
+    missing_files: u64,
+    width: u64,
+    height: u64,
+    /// Images whose file size was added already.
+    sized: HashSet<&'a str>,
+}
+
+impl<'a> GroupTotals<'a> {
+    fn add(&mut self, image: &'a str, file_size: Option<u64>, width: i32, height: i32) {
+        self.entries += 1;
+        self.width += width.max(0) as u64;
+        self.height += height.max(0) as u64;
+        match file_size {
+            Some(size) if self.sized.insert(image) => self.file_size += size,
+            Some(_) => {}
+            None => self.missing_files += 1,
+        }
+    }
+
+    fn finish(self) -> CompositionGroup {
+        let entries = self.entries.max(1) as f64;
+        CompositionGroup {
+            entries: self.entries,
+            file_size: self.file_size,
+            missing_files: self.missing_files,
+            average_width: self.width as f64 / entries,
+            average_height: self.height as f64 / entries,
+        }
+    }
+}
+
+/// Returns every cached entry, ordered by key.
+pub fn export_entries(context: &mut AppContext) -> Result<Vec<BlurhashCache>> {
+    Ok(blurhash_cache::table
+        .order(blurhash_cache::relative_path)
+        .select(BlurhashCache::as_select())
+        .load(&mut context.db_conn)?)
+}
+
+/// Removes entries whose image no longer exists under any of the context's roots and
+/// returns their keys. With `dry_run` the entries are only reported.
+///
+/// Content-addressed entries do not name a file and are always kept.
+pub fn prune_cache(context: &mut AppContext, dry_run: bool) -> Result<Vec<String>> {
+    let rows: Vec<(i32, String)> = blurhash_cache::table
+        .select((blurhash_cache::id, blurhash_cache::relative_path))
+        .load(&mut context.db_conn)?;
+
+    let (ids, keys): (Vec<i32>, Vec<String>) = rows
+        .into_iter()
+        .filter(|(_, key)| {
+            key_paths(key, &context.project_roots)
+                .is_some_and(|paths| !paths.iter().any(|path| path.is_file()))
+        })
+        .unzip();
+
+    if !dry_run {
+        delete_entries(context, &ids)?;
+    }
+
+    Ok(keys)
+}
+
+/// Outcome of [`repair_cache`].
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport {
+    /// Number of entries checked.
+    pub checked: usize,
+    /// Keys of the invalid entries regenerated from their file.
+    pub regenerated: Vec<String>,
+    /// Keys of the invalid entries removed because they could not be regenerated.
+    pub deleted: Vec<String>,
+    pub duration: Duration,
+}
+
+/// Finds entries with a malformed blurhash or dimensions below one pixel, e.g. left by
+/// a bad import or a damaged database, and regenerates them from their file. Entries
+/// that cannot be regenerated are removed, so their next lookup starts afresh:
+/// entries of deleted files, cropped entries, content-addressed entries, which name
+/// no file, and entries whose regeneration fails.
+pub fn repair_cache(context: &mut AppContext) -> Result<RepairReport> {
+    let started = Instant::now();
+    let rows: Vec<(i32, String, String, i32, i32)> = blurhash_cache::table
+        .select((
+            blurhash_cache::id,
+            blurhash_cache::relative_path,
+            blurhash_cache::blurhash,
+            blurhash_cache::width,
+            blurhash_cache::height,
+        ))
+        .load(&mut context.db_conn)?;
+
+    let mut report = RepairReport {
+        checked: rows.len(),
+        ..RepairReport::default()
+    };
+    let request = RequestOptions {
+        force: true,
+        ..RequestOptions::default()
+    };
+    let mut unrecoverable = Vec::new();
+    for (id, key, blurhash, width, height) in rows {
+        if is_valid_blurhash(&blurhash) && width > 0 && height > 0 {
+            continue;
+        }
+        warn!(path = key.as_str(); "Cached entry of {key} is invalid: {blurhash:?}, {width}x{height}");
+        let path = key_paths(&key, &context.project_roots)
+            .filter(|_| image_key(&key) == key)
+            .and_then(|paths| paths.into_iter().find(|path| path.is_file()));
+        let regenerated = path.is_some_and(|path| {
+            get_blurhash_with_options(context, &path, &request)
+                .inspect_err(|e| warn!(path = key.as_str(); "Failed to regenerate {key}: {e:#}"))
+                .is_ok_and(|data| data.key == key)
+        });
+        if regenerated {
+            report.regenerated.push(key);
+        } else {
+            unrecoverable.push(id);
+            report.deleted.push(key);
+        }
+    }
+    delete_entries(context, &unrecoverable)?;
+
+    report.duration = started.elapsed();
+    info!(
+        "Checked {} cached entries in {} ms: {} regenerated, {} deleted",
+        report.checked,
+        report.duration.as_millis(),
+        report.regenerated.len(),
+        report.deleted.len()
+    );
+    Ok(report)
+}
+
+/// Outcome of [`normalize_keys`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyNormalization {
+    /// Legacy keys rewritten, with their normalized form.
+    pub rewritten: Vec<(String, String)>,
+    /// Keys of the rows removed because a row with the normalized key exists.
+    pub removed: Vec<String>,
+}
+
+/// Rewrites keys stored by older versions or on other platforms into the form lookups
+/// derive now, so their entries keep being found. Run whenever a context is created.
+///
+/// Relative keys with backslash separators become `/`-separated, unless they name an
+/// existing file with backslashes in its name. With
+/// [`ContextOptions::case_insensitive_keys`], rows whose keys differ only by case are
+/// reduced to the oldest one, which lookups already return. A legacy row whose
+/// normalized key is taken is removed.
+///
+/// [`ContextOptions::case_insensitive_keys`]: crate::ContextOptions::case_insensitive_keys
+pub fn normalize_keys(context: &mut AppContext) -> Result<KeyNormalization> {
+    let case_insensitive = context.options.case_insensitive_keys;
+    let legacy: Vec<(i32, String)> = blurhash_cache::table
+        .filter(blurhash_cache::relative_path.like("%\\%"))
+        .select((blurhash_cache::id, blurhash_cache::relative_path))
+        .order(blurhash_cache::id.asc())
+        .load(&mut context.db_conn)?;
+
+    let mut report = KeyNormalization::default();
+    let mut removed = Vec::new();
+    for (id, key) in legacy {
+        let Some(normalized) = normalized_legacy_key(&key) else {
+            continue;
+        };
+        let literal_file = key_paths(&key, &context.project_roots)
+            .is_some_and(|paths| paths.iter().any(|path| path.is_file()));
+        if literal_file {
+            continue;
+        }
+        if find_cached(&mut context.db_conn, &normalized, case_insensitive)?.is_some() {
+            removed.push(id);
+            report.removed.push(key);
+        } else {
+            diesel::update(blurhash_cache::table.find(id))
+                .set(blurhash_cache::relative_path.eq(&normalized))
+                .execute(&mut context.db_conn)?;
+            report.rewritten.push((key, normalized));
+        }
+    }
+
+    if case_insensitive {
+        let rows: Vec<(i32, String)> = blurhash_cache::table
+            .filter(sql::<Bool>(
+                "relative_path COLLATE NOCASE IN (SELECT relative_path COLLATE NOCASE \
+                 FROM blurhash_cache GROUP BY relative_path COLLATE NOCASE HAVING COUNT(*) > 1)",
+            ))
+            .select((blurhash_cache::id, blurhash_cache::relative_path))
+            .order(blurhash_cache::id.asc())
+            .load(&mut context.db_conn)?;
+        let mut kept = HashSet::new();
+        for (id, key) in rows {
+            if !kept.insert(key.to_ascii_lowercase()) {
+                removed.push(id);
+                report.removed.push(key);
+            }
+        }
+    }
+    delete_entries(context, &removed)?;
+
+    if !report.rewritten.is_empty() || !report.removed.is_empty() {
+        info!(
+            "Normalized cache keys: {} rewritten, {} duplicates removed",
+            report.rewritten.len(),
+            report.removed.len()
+        );
+    }
+    Ok(report)
+}
+
+/// Removes entries that were not written within `max_age`, optionally only those
+/// whose key starts with `prefix`, and returns their keys.
+///
+/// Unlike [`prune_cache`] this removes entries of images that still exist, e.g. of a
+/// content area that is no longer built. An entry is written when it is generated or
+/// revalidated, not when it is served.
+pub fn delete_older_than(
+    context: &mut AppContext,
+    max_age: Duration,
+    prefix: Option<&str>,
+) -> Result<Vec<String>> {
+    let cutoff = chrono::Duration::from_std(max_age)
+        .ok()
+        .and_then(|max_age| Utc::now().naive_utc().checked_sub_signed(max_age))
+        .unwrap_or(NaiveDateTime::MIN);
+    let rows: Vec<(i32, String)> = blurhash_cache::table
+        .filter(blurhash_cache::updated_at.lt(cutoff))
+        .select((blurhash_cache::id, blurhash_cache::relative_path))
+        .load(&mut context.db_conn)?;
+
+    let (ids, keys): (Vec<i32>, Vec<String>) = rows
+        .into_iter()
+        .filter(|(_, key)| prefix.is_none_or(|prefix| key.starts_with(prefix)))
+        .unzip();
+    delete_entries(context, &ids)?;
+
+    Ok(keys)
+}
+
+/// How many entries under a prefix still match their file, from [`staleness_report`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StalenessReport {
+    /// Entries whose file has the cached modification time.
+    pub fresh: u64,
+    /// Entries whose file was modified since it was cached.
+    pub stale: u64,
+    /// Entries whose file no longer exists.
+    pub missing: u64,
+}
+
+/// Compares the modification times cached for the entries whose key starts with
+/// `prefix`, or for every entry, with those of their files, and counts how many are
+/// fresh, stale or missing. Nothing is regenerated or removed.
+///
+/// Only file metadata is read, so a stale entry may still match its file's content.
+/// Content-addressed entries do not name a file and are not counted.
+pub fn staleness_report(context: &mut AppContext, prefix: Option<&str>) -> Result<StalenessReport> {
+    let rows: Vec<(String, i64)> = blurhash_cache::table
+        .select((blurhash_cache::relative_path, blurhash_cache::mtime_ms))
+        .load(&mut context.db_conn)?;
+
+    let mut report = StalenessReport::default();
+    for (key, mtime_ms) in rows {
+        if prefix.is_some_and(|prefix| !key.starts_with(prefix)) {
+            continue;
+        }
+        let Some(paths) = key_paths(&key, &context.project_roots) else {
+            continue;
+        };
+        let current_mtime_ms = paths
+            .iter()
+            .find_map(|path| {
+                fs::metadata(path)
+                    .ok()
+                    .filter(|metadata| metadata.is_file())
+            })
+            .map(|metadata| {
+                metadata
+                    .modified()
+                    .map_err(anyhow::Error::from)
+                    .and_then(time_to_ms)
+            });
+        match current_mtime_ms {
+            None => report.missing += 1,
+            Some(Ok(current))
+                if mtime_matches(current, mtime_ms, context.options.mtime_tolerance_ms) =>
+            {
+                report.fresh += 1
+            }
+            Some(_) => report.stale += 1,
+        }
+    }
+    Ok(report)
+}
+
+/// Cached images with identical content, from [`find_duplicates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Content hash the images share.
+    pub xxhash: String,
+    /// Size of the files in bytes, when verified.
+    pub file_size: Option<u64>,
+    /// Cache keys of the images, sorted.
+    pub keys: Vec<String>,
+}
+
+/// Groups the cached images by content hash and returns the groups of two or more,
+/// largest first, so redundant copies of an asset can be found across the project.
+///
+/// Crops of an image count as the image itself. Entries cached without a content
+/// hash, under [`crate::options::CacheValidation::MtimeOnly`], are not considered.
+/// With `verify_size`, images whose file is gone are left out and groups are split by
+/// file size, ruling out hash collisions and entries of files changed since.
+pub fn find_duplicates(context: &mut AppContext, verify_size: bool) -> Result<Vec<DuplicateGroup>> {
+    let rows: Vec<(String, String)> = blurhash_cache::table
+        .filter(blurhash_cache::xxhash.ne(""))
+        .select((blurhash_cache::xxhash, blurhash_cache::relative_path))
+        .load(&mut context.db_conn)?;
+
+    let mut groups: BTreeMap<(String, Option<u64>), Vec<String>> = BTreeMap::new();
+    for (xxhash, key) in rows {
+        let key = image_key(&key).to_string();
+        let file_size = if verify_size {
+            let size = key_paths(&key, &context.project_roots)
+                .unwrap_or_default()
+                .iter()
+                .find_map(|path| {
+                    fs::metadata(path)
+                        .ok()
+                        .filter(|metadata| metadata.is_file())
+                })
+                .map(|metadata| metadata.len());
+            match size {
+                Some(size) => Some(size),
+                None => continue,
+            }
+        } else {
+            None
+        };
+        groups.entry((xxhash, file_size)).or_default().push(key);
+    }
+
+    let mut duplicates: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .filter_map(|((xxhash, file_size), mut keys)| {
+            keys.sort();
+            keys.dedup();
+            (keys.len() > 1).then_some(DuplicateGroup {
+                xxhash,
+                file_size,
+                keys,
+            })
+        })
+        .collect();
+    duplicates.sort_by_key(|group| std::cmp::Reverse(group.keys.len()));
+    Ok(duplicates)
+}
+
+/// Steps of [`maintain`]; each is skipped unless enabled.
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceOptions {
+    /// Remove entries whose image no longer exists, see [`prune_cache`].
+    pub prune_orphans: bool,
+    /// Remove entries not written within this age, see [`delete_older_than`].
+    pub max_age: Option<Duration>,
+    /// Rebuild the database file to return the space of deleted rows to the system.
+    pub vacuum: bool,
+    /// Refresh the statistics SQLite's query planner relies on.
+    pub analyze: bool,
+}
+
+/// Outcome of [`maintain`].
+#[derive(Debug, Clone)]
+pub struct MaintenanceReport {
+    /// Keys of the entries removed because their image no longer exists.
+    pub orphans: Vec<String>,
+    /// Keys of the entries removed because of their age.
+    pub expired: Vec<String>,
+    pub vacuumed: bool,
+    pub analyzed: bool,
+    /// Size of the database in bytes before and after maintenance.
+    pub size_before: u64,
+    pub size_after: u64,
+    pub duration: Duration,
+}
+
+/// Runs the maintenance steps selected in `options`: removing entries first, so that
+/// vacuuming reclaims their space and the statistics gathered last describe the
+/// remaining rows. Meant for a nightly job.
+///
+/// Vacuuming rewrites the whole database and blocks other connections meanwhile.
+pub fn maintain(
+    context: &mut AppContext,
+    options: &MaintenanceOptions,
+) -> Result<MaintenanceReport> {
+    let started = Instant::now();
+    let size_before = database_size(context)?;
+    let orphans = if options.prune_orphans {
+        prune_cache(context, false)?
+    } else {
+        Vec::new()
+    };
+    let expired = match options.max_age {
+        Some(max_age) => delete_older_than(context, max_age, None)?,
+        None => Vec::new(),
+    };
+    if options.vacuum {
+        diesel::sql_query("VACUUM").execute(&mut context.db_conn)?;
+    }
+    if options.analyze {
+        diesel::sql_query("ANALYZE").execute(&mut context.db_conn)?;
+    }
+    Ok(MaintenanceReport {
+        orphans,
+        expired,
+        vacuumed: options.vacuum,
+        analyzed: options.analyze,
+        size_before,
+        size_after: database_size(context)?,
+        duration: started.elapsed(),
+    })
+}
+
+/// Handle of the thread running the scheduled maintenance of a context; dropping it
+/// stops the thread at its next wakeup without waiting for a run in progress.
+pub struct MaintenanceSchedule {
+    schedule: ScheduledMaintenance,
+    _stop: Sender<()>,
+}
+
+impl MaintenanceSchedule {
+    /// Settings the thread runs with.
+    pub fn schedule(&self) -> ScheduledMaintenance {
+        self.schedule
+    }
+}
+
+/// Starts, restarts or stops the maintenance thread of `context` to match its
+/// [`ContextOptions::maintenance`](crate::ContextOptions::maintenance), leaving a
+/// thread already running with the same settings alone.
+///
+/// The thread cannot hold the context itself, so whoever shares it passes `run`,
+/// which should lock the context and call [`run_scheduled_maintenance`], and return
+/// `false` once the context is gone to end the thread.
+pub fn schedule_maintenance(
+    context: &mut AppContext,
+    mut run: impl FnMut() -> bool + Send + 'static,
+) -> Result<()> {
+    let Some(schedule) = context.options.maintenance else {
+        context.maintenance_schedule = None;
+        return Ok(());
+    };
+    if context
+        .maintenance_schedule
+        .as_ref()
+        .is_some_and(|running| running.schedule == schedule)
+    {
+        return Ok(());
+    }
+    // Replacing the handle stops the previous thread.
+    let (stop, stopped) = mpsc::channel::<()>();
+    thread::Builder::new()
+        .name("blurest-maintenance".to_string())
+        .spawn(move || {
+            let random = RandomState::new();
+            let mut round = 0u64;
+            loop {
+                round += 1;
+                let jitter_ms = schedule.jitter.as_millis() as u64;
+                let jitter = match jitter_ms {
+                    0 => Duration::ZERO,
+                    _ => Duration::from_millis(random.hash_one(round) % (jitter_ms + 1)),
+                };
+                match stopped.recv_timeout(schedule.interval + jitter) {
+                    Err(RecvTimeoutError::Timeout) if run() => {}
+                    _ => break,
+                }
+            }
+        })?;
+    context.maintenance_schedule = Some(MaintenanceSchedule {
+        schedule,
+        _stop: stop,
+    });
+    Ok(())
+}
+
+/// Runs one round of the context's [`ScheduledMaintenance`]: stores the background
+/// refreshes that finished, then runs the selected [`maintain`] steps. Returns `None`
+/// if the context has no scheduled maintenance.
+pub fn run_scheduled_maintenance(context: &mut AppContext) -> Result<Option<MaintenanceReport>> {
+    let Some(schedule) = context.options.maintenance else {
+        return Ok(None);
+    };
+    let options = MaintenanceOptions {
+        prune_orphans: schedule.prune_orphans,
+        max_age: schedule.max_age,
+        vacuum: false,
+        analyze: schedule.analyze,
+    };
+    apply_refreshes(context)?;
+    let report = maintain(context, &options)?;
+    info!(
+        "Scheduled maintenance removed {} orphaned and {} expired entries in {} ms",
+        report.orphans.len(),
+        report.expired.len(),
+        report.duration.as_millis()
+    );
+    Ok(Some(report))
+}
+
+/// Size of the database of `context` in bytes, free pages included.
+fn database_size(context: &mut AppContext) -> Result<u64> {
+    let size: i64 = diesel::select(sql::<BigInt>(
+        "(SELECT page_count FROM pragma_page_count()) * (SELECT page_size FROM pragma_page_size())",
+    ))
+    .get_result(&mut context.db_conn)?;
+    Ok(size.max(0) as u64)
+}
+
+/// Deletes the entries with the given ids in one transaction.
+fn delete_entries(context: &mut AppContext, ids: &[i32]) -> Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    context.db_conn.exclusive_transaction(|conn| {
+        for chunk in ids.chunks(500) {
+            diesel::delete(blurhash_cache::table.filter(blurhash_cache::id.eq_any(chunk)))
+                .execute(conn)?;
+        }
+        Ok::<_, diesel::result::Error>(())
+    })?;
+    Ok(())
+}
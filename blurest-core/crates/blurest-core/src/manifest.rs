@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use diesel::{
+    dsl::sql,
+    prelude::*,
+    sql_types::{Bool, Text},
+};
+use serde_json::json;
+
+use crate::{
+    core::{AppContext, BlurhashData, find_cached, get_blurhash_with_options},
+    keys::{cropped_key_pattern, derive_key, image_key},
+    options::RequestOptions,
+    schema::blurhash_cache,
+};
+
+/// Version of the JSON layout written by [`Manifest::to_json`].
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// A blurhash recorded in a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub blurhash: String,
+    pub width: i32,
+    pub height: i32,
+    /// Decoded pixels of the blurhash, when [`ContextOptions::micro_pixels`] is set.
+    ///
+    /// [`ContextOptions::micro_pixels`]: crate::ContextOptions::micro_pixels
+    pub micro_pixels: Option<Vec<u8>>,
+}
+
+/// Blurhashes handed out during a build, keyed by cache key, for bundler plugins to
+/// emit as an asset.
+///
+/// Entries are kept sorted and carry no timestamps, so the same images always produce
+/// byte-identical output.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    /// Records `data` under its cache key, replacing any previous entry.
+    pub fn record(&mut self, data: &BlurhashData) {
+        self.entries.insert(
+            data.key.clone(),
+            ManifestEntry {
+                blurhash: data.blurhash.clone(),
+                width: data.width,
+                height: data.height,
+                micro_pixels: data.micro_pixels.clone(),
+            },
+        );
+    }
+
+    /// Removes the entry for `key`, returning whether there was one.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key).is_some()
+    }
+
+    /// Removes every entry.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn get(&self, key: &str) -> Option<&ManifestEntry> {
+        self.entries.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over the entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ManifestEntry)> {
+        self.entries
+            .iter()
+            .map(|(key, entry)| (key.as_str(), entry))
+    }
+
+    /// Serializes the manifest as pretty-printed JSON with a trailing newline:
+    ///
+    /// ```json
+    /// { "version": 1, "images": { "<key>": { "blurhash": "…", "width": 0, "height": 0 } } }
+    /// ```
+    ///
+    /// Entries with micro pixels also carry them base64-encoded as `"pixels"`.
+    pub fn to_json(&self) -> String {
+        let images: serde_json::Map<String, serde_json::Value> = self
+            .iter()
+            .map(|(key, entry)| {
+                let mut image = json!({
+                    "blurhash": entry.blurhash,
+                    "width": entry.width,
+                    "height": entry.height,
+                });
+                if let Some(pixels) = &entry.micro_pixels {
+                    image["pixels"] = json!(BASE64.encode(pixels));
+                }
+                (key.to_string(), image)
+            })
+            .collect();
+        let manifest = json!({ "version": MANIFEST_VERSION, "images": images });
+        format!("{manifest:#}\n")
+    }
+}
+
+/// Gets the blurhash for an image like [`get_blurhash_with_options`] and records it
+/// in the context's manifest.
+pub fn get_or_generate(
+    context: &mut AppContext,
+    image_path: &Path,
+    request: &RequestOptions,
+) -> Result<BlurhashData> {
+    let data = get_blurhash_with_options(context, image_path, request)?;
+    context.manifest.record(&data);
+    Ok(data)
+}
+
+/// Starts a build session: from now on, every blurhash the context hands out is
+/// recorded, whichever API it was requested through, until [`finalize_session`].
+///
+/// Starting a session while one is in progress discards what it recorded so far.
+pub fn begin_session(context: &mut AppContext) {
+    context.session = Some(Manifest::default());
+}
+
+/// Ends the build session and returns the blurhashes handed out during it, or `None`
+/// if no session was in progress.
+///
+/// Unlike the context's [`Manifest`], which only holds what bundler plugins asked for
+/// through [`get_or_generate`], this covers exactly the placeholders used by one build.
+pub fn finalize_session(context: &mut AppContext) -> Option<Manifest> {
+    context.session.take()
+}
+
+/// Forgets an image that changed or was deleted: drops its manifest and session
+/// entries and its cached rows, cropped ones included, so the next lookup
+/// regenerates it.
+///
+/// Works for deleted files too, as long as their path is inside a project root.
+/// Returns the cache key, or `None` if no key can be derived for `image_path`.
+pub fn invalidate(context: &mut AppContext, image_path: &Path) -> Result<Option<String>> {
+    let absolute_path = match fs::canonicalize(image_path) {
+        Ok(path) => path,
+        Err(_) => std::path::absolute(image_path)?,
+    };
+    let Ok(key) = derive_key(&absolute_path, &context.project_roots, &context.options) else {
+        return Ok(None);
+    };
+    let key = key.as_str();
+
+    if let Some(cached) = find_cached(
+        &mut context.db_conn,
+        key,
+        context.options.case_insensitive_keys,
+    )? {
+        diesel::delete(&cached).execute(&mut context.db_conn)?;
+        forget(context, &cached.relative_path);
+        forget_crops(context, &cached.relative_path)?;
+    }
+    forget(context, key);
+    forget_crops(context, key)?;
+    Ok(Some(key.to_string()))
+}
+
+/// Deletes the cached rows of every crop of the image `key` and forgets their keys.
+fn forget_crops(context: &mut AppContext, key: &str) -> Result<()> {
+    let (ids, keys): (Vec<i32>, Vec<String>) = blurhash_cache::table
+        .filter(sql::<Bool>("relative_path GLOB ").bind::<Text, _>(cropped_key_pattern(key)))
+        .select((blurhash_cache::id, blurhash_cache::relative_path))
+        .load::<(i32, String)>(&mut context.db_conn)?
+        .into_iter()
+        .filter(|(_, cropped)| image_key(cropped) == key)
+        .unzip();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    diesel::delete(blurhash_cache::table.filter(blurhash_cache::id.eq_any(&ids)))
+        .execute(&mut context.db_conn)?;
+    for key in &keys {
+        forget(context, key);
+    }
+    Ok(())
+}
+
+/// Drops `key` from the manifest and the build session.
+fn forget(context: &mut AppContext, key: &str) {
+    context.manifest.remove(key);
+    if let Some(session) = &mut context.session {
+        session.remove(key);
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the decode time histogram buckets.
+pub const DECODE_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Cache counters of one context, updated lock-free from any thread.
+///
+/// Counters only grow until [`Metrics::reset`], so they can be scraped as Prometheus
+/// counters; see [`render_prometheus`]. They live in memory only and start from zero
+/// with every context.
+#[derive(Default)]
+pub struct Metrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale_refreshes: AtomicU64,
+    db_errors: AtomicU64,
+    /// Decodes per bucket of [`DECODE_BUCKETS`], not cumulative; the last slot
+    /// counts decodes slower than every bound.
+    decode_buckets: [AtomicU64; DECODE_BUCKETS.len() + 1],
+    decode_micros: AtomicU64,
+    decode_count: AtomicU64,
+}
+
+/// Point-in-time copy of a [`Metrics`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Lookups answered from the cache, including entries revalidated by content hash.
+    pub hits: u64,
+    /// Images generated because they had no cached entry.
+    pub misses: u64,
+    /// Images regenerated because their cached entry was outdated.
+    pub stale_refreshes: u64,
+    /// Failed database queries.
+    pub db_errors: u64,
+    /// Cumulative decode counts per bucket of [`DECODE_BUCKETS`].
+    pub decode_buckets: Vec<u64>,
+    pub decode_seconds: f64,
+    pub decode_count: u64,
+}
+
+impl Metrics {
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stale_refresh(&self) {
+        self.stale_refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_db_error(&self) {
+        self.db_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds one decode taking `elapsed` to the histogram.
+    pub fn record_decode(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = DECODE_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(DECODE_BUCKETS.len());
+        self.decode_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.decode_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.decode_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Zeroes every counter, e.g. at the start of a deployment or build run, so later
+    /// snapshots cover only what happened since. Prometheus treats this as a counter
+    /// reset. Lookups finishing during the reset may be counted on either side of it.
+    pub fn reset(&self) {
+        let counters = [
+            &self.hits,
+            &self.misses,
+            &self.stale_refreshes,
+            &self.db_errors,
+            &self.decode_micros,
+            &self.decode_count,
+        ];
+        for counter in counters.into_iter().chain(&self.decode_buckets) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut cumulative = 0;
+        let decode_buckets = self.decode_buckets[..DECODE_BUCKETS.len()]
+            .iter()
+            .map(|bucket| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                cumulative
+            })
+            .collect();
+        MetricsSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stale_refreshes: self.stale_refreshes.load(Ordering::Relaxed),
+            db_errors: self.db_errors.load(Ordering::Relaxed),
+            decode_buckets,
+            decode_seconds: self.decode_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            decode_count: self.decode_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    /// Share of lookups answered from the cache, between 0 and 1, or `None` before
+    /// the first lookup.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses + self.stale_refreshes;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+/// Renders the metrics of named contexts in the Prometheus text exposition format,
+/// one series per context labelled `cache="<name>"`.
+pub fn render_prometheus<S: AsRef<str>>(contexts: &[(S, MetricsSnapshot)]) -> String {
+    let mut out = String::new();
+    write_counter(
+        &mut out,
+        "blurest_cache_hits_total",
+        "Lookups answered from the cache.",
+        contexts,
+        |m| m.hits,
+    );
+    write_counter(
+        &mut out,
+        "blurest_cache_misses_total",
+        "Images generated because they had no cached entry.",
+        contexts,
+        |m| m.misses,
+    );
+    write_counter(
+        &mut out,
+        "blurest_cache_stale_refreshes_total",
+        "Images regenerated because their cached entry was outdated.",
+        contexts,
+        |m| m.stale_refreshes,
+    );
+    write_counter(
+        &mut out,
+        "blurest_db_errors_total",
+        "Failed database queries.",
+        contexts,
+        |m| m.db_errors,
+    );
+
+    let name = "blurest_decode_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Time spent decoding images and encoding blurhashes.\n# TYPE {name} histogram"
+    );
+    for (cache, metrics) in contexts {
+        let cache = escape_label(cache.as_ref());
+        for (bound, count) in DECODE_BUCKETS.iter().zip(&metrics.decode_buckets) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{cache=\"{cache}\",le=\"{bound}\"}} {count}"
+            );
+        }
+        let count = metrics.decode_count;
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{cache=\"{cache}\",le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            out,
+            "{name}_sum{{cache=\"{cache}\"}} {}",
+            metrics.decode_seconds
+        );
+        let _ = writeln!(out, "{name}_count{{cache=\"{cache}\"}} {count}");
+    }
+    out
+}
+
+/// Writes one counter family with a sample per context.
+fn write_counter<S: AsRef<str>>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    contexts: &[(S, MetricsSnapshot)],
+    value: impl Fn(&MetricsSnapshot) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+    for (cache, metrics) in contexts {
+        let cache = escape_label(cache.as_ref());
+        let _ = writeln!(out, "{name}{{cache=\"{cache}\"}} {}", value(metrics));
+    }
+}
+
+/// Escapes a label value as required by the text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
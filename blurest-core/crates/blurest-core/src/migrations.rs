@@ -0,0 +1,233 @@
+use std::time::Duration;
+
+use anyhow::{Context as AnyhowContext, Result, anyhow};
+use diesel::{
+    SqliteConnection, connection::SimpleConnection, prelude::*, sql_query, sql_types::Integer,
+};
+use log::info;
+
+/// Schema migrations in order; applying entry `i` upgrades a database from version `i`
+/// to version `i + 1`. The version is tracked in SQLite's `user_version` pragma.
+pub(crate) const MIGRATIONS: &[&str] = &[
+    // 1: initial blurhash cache table and `updated_at` trigger
+    r#"
+CREATE TABLE blurhash_cache (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    relative_path TEXT NOT NULL UNIQUE,
+    xxhash TEXT NOT NULL,
+    mtime_ms BIGINT NOT NULL,
+    blurhash TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TRIGGER trigger_blurhash_cache_updated_at
+AFTER UPDATE ON blurhash_cache
+FOR EACH ROW
+BEGIN
+    UPDATE blurhash_cache SET updated_at = CURRENT_TIMESTAMP WHERE id = OLD.id;
+END;
+"#,
+    // 2: none. Relative keys written on Windows are rewritten to forward slashes by
+    // `maintenance::normalize_keys`, which unlike SQL can tell them from Unix file
+    // names containing backslashes.
+    r#"
+-- Intentionally empty.
+"#,
+    // 3: index for case-insensitive key lookups
+    r#"
+CREATE INDEX idx_blurhash_cache_relative_path_nocase
+ON blurhash_cache (relative_path COLLATE NOCASE);
+"#,
+    // 4: tiny placeholder image for Next.js `blurDataURL`, filled in on demand
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN blur_data_url TEXT;
+"#,
+    // 5: encoder version of each entry; existing entries come from the first one
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN algo_version INTEGER NOT NULL DEFAULT 1;
+"#,
+    // 6: payloads of custom placeholder generators, removed along with their entry
+    r#"
+CREATE TABLE placeholder_cache (
+    entry_id INTEGER NOT NULL,
+    generator TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    payloads TEXT NOT NULL,
+    PRIMARY KEY (entry_id, generator)
+);
+
+CREATE TRIGGER trigger_blurhash_cache_delete_placeholders
+AFTER DELETE ON blurhash_cache
+FOR EACH ROW
+BEGIN
+    DELETE FROM placeholder_cache WHERE entry_id = OLD.id;
+END;
+"#,
+    // 7: EXIF orientation of each entry, unknown for existing entries, and whether it
+    // was applied before encoding
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN orientation INTEGER;
+ALTER TABLE blurhash_cache ADD COLUMN orientation_applied BOOLEAN NOT NULL DEFAULT 0;
+"#,
+    // 8: tiny WebP preview, filled in on demand when previews are persisted
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN webp_preview BLOB;
+"#,
+    // 9: size the stored previews were rendered at, unknown for existing entries
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN preview_size INTEGER;
+"#,
+    // 10: whether the encoded image has transparent pixels, unknown for existing entries
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN has_alpha BOOLEAN;
+"#,
+    // 11: container format and bit depth of the file, unknown for existing entries
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN format TEXT;
+ALTER TABLE blurhash_cache ADD COLUMN bit_depth INTEGER;
+"#,
+    // 12: difference hash of the encoded image, unknown for existing entries
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN perceptual_hash BIGINT;
+"#,
+    // 13: color space and ICC profile name of the file, unknown for existing entries
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN color_space TEXT;
+ALTER TABLE blurhash_cache ADD COLUMN color_profile TEXT;
+"#,
+    // 14: raster thumbnails, stored when enabled and removed along with their entry
+    r#"
+CREATE TABLE thumbnail_cache (
+    entry_id INTEGER PRIMARY KEY NOT NULL,
+    size INTEGER NOT NULL,
+    thumbnail BLOB NOT NULL
+);
+
+CREATE TRIGGER trigger_blurhash_cache_delete_thumbnails
+AFTER DELETE ON blurhash_cache
+FOR EACH ROW
+BEGIN
+    DELETE FROM thumbnail_cache WHERE entry_id = OLD.id;
+END;
+"#,
+    // 15: decoded pixels of the blurhash, filled in on demand when enabled
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN micro_pixels BLOB;
+"#,
+    // 16: file size next to the content hash, unknown for existing entries until
+    // they are revalidated
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN file_size BIGINT;
+"#,
+    // 17: decoder and scale the pixels came from, `image` at full size for existing
+    // entries
+    r#"
+ALTER TABLE blurhash_cache ADD COLUMN decoded_with TEXT;
+"#,
+];
+
+/// Schema version this build creates and expects.
+pub const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32;
+
+/// How long [`run_migrations`] waits for another process migrating the same database.
+pub const MIGRATION_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(QueryableByName)]
+struct UserVersion {
+    #[diesel(sql_type = Integer)]
+    user_version: i32,
+}
+
+#[derive(QueryableByName)]
+struct BusyTimeout {
+    #[diesel(sql_type = Integer)]
+    timeout: i32,
+}
+
+#[derive(QueryableByName)]
+struct TableCount {
+    #[diesel(sql_type = Integer)]
+    count: i32,
+}
+
+/// Returns the schema version recorded in the database.
+///
+/// Databases created before versioning have `user_version` 0 but already contain the
+/// cache table, so they are reported as version 1.
+pub fn schema_version(conn: &mut SqliteConnection) -> Result<i32> {
+    let version = sql_query("PRAGMA user_version")
+        .get_result::<UserVersion>(conn)?
+        .user_version;
+    if version > 0 {
+        return Ok(version);
+    }
+
+    let tables = sql_query(
+        "SELECT COUNT(*) AS count FROM sqlite_master WHERE type = 'table' AND name = 'blurhash_cache'",
+    )
+    .get_result::<TableCount>(conn)?
+    .count;
+    Ok(if tables > 0 { 1 } else { 0 })
+}
+
+/// Brings the database schema up to [`SCHEMA_VERSION`], applying each pending
+/// migration in its own exclusive transaction.
+///
+/// Processes starting together on the same database, typically a fresh one, would
+/// otherwise all apply the pending migrations. Each transaction takes SQLite's
+/// exclusive lock on the database, waiting up to [`MIGRATION_LOCK_TIMEOUT`] while
+/// another process holds it, and reads the version again under the lock, so every
+/// migration runs exactly once and later processes find the schema current.
+pub fn run_migrations(conn: &mut SqliteConnection) -> Result<()> {
+    let busy_timeout = sql_query("PRAGMA busy_timeout")
+        .get_result::<BusyTimeout>(conn)?
+        .timeout;
+    conn.batch_execute(&format!(
+        "PRAGMA busy_timeout = {}",
+        MIGRATION_LOCK_TIMEOUT.as_millis()
+    ))?;
+    let migrated = migrate_locked(conn);
+    conn.batch_execute(&format!("PRAGMA busy_timeout = {busy_timeout}"))?;
+    migrated
+}
+
+/// Applies pending migrations one exclusive transaction at a time, until the version
+/// read under the lock is current.
+fn migrate_locked(conn: &mut SqliteConnection) -> Result<()> {
+    // Databases are usually current already, which needs no exclusive lock.
+    if check_version(schema_version(conn)?)? {
+        return Ok(());
+    }
+    loop {
+        let migrated = conn.exclusive_transaction(|conn| -> Result<Option<i32>> {
+            let current = schema_version(conn)?;
+            if check_version(current)? {
+                return Ok(None);
+            }
+            let version = current + 1;
+            info!("Migrating database schema to version {version}");
+            conn.batch_execute(MIGRATIONS[current as usize])
+                .and_then(|()| conn.batch_execute(&format!("PRAGMA user_version = {version}")))
+                .with_context(|| {
+                    format!("Failed to migrate database schema to version {version}")
+                })?;
+            Ok(Some(version))
+        })?;
+        if migrated.is_none() {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether the schema at `version` is current; fails if it is newer than this build.
+fn check_version(version: i32) -> Result<bool> {
+    if version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "Database schema version {version} is newer than the supported version {SCHEMA_VERSION}"
+        ));
+    }
+    Ok(version == SCHEMA_VERSION)
+}
@@ -0,0 +1,485 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use glob::{MatchOptions, Pattern};
+
+use crate::{
+    backend::Decoder,
+    encode::DEFAULT_PREVIEW_SIZE,
+    keys::{NonUtf8Policy, OutsideRootPolicy},
+    placeholder::PlaceholderGenerator,
+    pool::default_worker_count,
+};
+
+/// Blurhash components `(x, y)` used unless configured otherwise.
+pub const DEFAULT_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Tunable options for an application context, supplied at initialization.
+#[derive(Debug, Clone)]
+pub struct ContextOptions {
+    /// Number of worker threads used by the async and batch APIs.
+    pub workers: usize,
+    /// Blurhash components `(x, y)`, each between 1 and 9. Cached entries encoded
+    /// with different components are regenerated.
+    pub components: (u32, u32),
+    /// Longer side, in pixels, images are shrunk to before the blurhash is encoded,
+    /// or `None` to encode them as decoded. Shrinking is much faster on large images
+    /// and barely changes the blurhash; cached entries are not regenerated, except
+    /// those of decoders that scale down to it.
+    pub encode_size: Option<u32>,
+    /// Settings for the images matching a pattern, e.g. fewer components for icons,
+    /// overriding `components`, `encode_size` and `preview_size`. Every matching rule
+    /// applies, in order, so later rules override earlier ones.
+    pub encode_rules: Vec<EncodeRule>,
+    /// Maximum number of images decoded at the same time across all APIs,
+    /// or `None` for no limit.
+    pub max_concurrent_decodes: Option<usize>,
+    /// Upper bound in bytes on the estimated memory of all in-progress decodes,
+    /// or `None` for no limit. Decodes over budget wait until memory is released.
+    pub decode_memory_budget: Option<u64>,
+    /// Library images are decoded with; see [`crate::backend`]. Entries decoded by
+    /// another library, or at another scale, are regenerated.
+    pub decoder: Decoder,
+    /// Largest difference in milliseconds between the file and cached mtimes that
+    /// still counts as a match. Absorbs coarse filesystem timestamps and copies
+    /// (archives, rsync) that round mtimes.
+    pub mtime_tolerance_ms: u64,
+    /// Times a file read or stat failing with a transient error (`EBUSY`, `EAGAIN`,
+    /// `ESTALE`) is retried, with exponential backoff from
+    /// [`RETRY_BACKOFF`](crate::retry::RETRY_BACKOFF), before the error is returned.
+    pub io_retries: u32,
+    /// How a cached entry is checked against the file.
+    pub validation: CacheValidation,
+    /// What lookups do with a cached entry that fails `validation`.
+    pub revalidate: Revalidate,
+    /// How images outside every project root are keyed.
+    pub outside_root: OutsideRootPolicy,
+    /// Match cache keys regardless of ASCII case, for case-insensitive filesystems
+    /// where one file can be referenced with differing case.
+    pub case_insensitive_keys: bool,
+    /// How paths that are not valid UTF-8 are keyed.
+    pub non_utf8_keys: NonUtf8Policy,
+    /// Reject paths that escape the project roots, through `..` or symlinks,
+    /// regardless of `outside_root`.
+    pub sandbox: bool,
+    /// Lowercase file extensions (without the dot) that may be processed,
+    /// or `None` to allow any.
+    pub allowed_extensions: Option<Vec<String>>,
+    /// Detected image MIME types that may be processed, or `None` to allow any.
+    pub allowed_mime_types: Option<Vec<String>>,
+    /// Include a tiny base64 PNG of the blurhash in results, cached with the entry,
+    /// for Next.js `placeholder="blur"`.
+    pub blur_data_url: bool,
+    /// Cache the WebP previews of [`Output::WebP`] lookups with their entries instead
+    /// of rendering them on every lookup.
+    pub persist_webp_previews: bool,
+    /// Include the RGB pixels of the blurhash decoded at 8x8 in results and manifests,
+    /// cached with the entry, for clients that upscale them with CSS instead of
+    /// decoding blurhashes; see [`crate::encode::micro_pixels`].
+    pub micro_pixels: bool,
+    /// Longer side, in pixels, of blur data URLs and WebP previews, at most
+    /// [`MAX_PREVIEW_SIZE`](crate::encode::MAX_PREVIEW_SIZE). Larger previews look closer to the image but weigh more.
+    pub preview_size: u32,
+    /// Longer side, in pixels, of a raster thumbnail of the image stored with each
+    /// generated entry, at most [`MAX_PREVIEW_SIZE`](crate::encode::MAX_PREVIEW_SIZE),
+    /// or `None` to store none. See [`crate::thumbnail`].
+    pub thumbnail_size: Option<u32>,
+    /// Custom placeholder formats generated from every decoded image and cached with
+    /// the entry; see [`crate::placeholder`].
+    pub generators: Vec<Arc<dyn PlaceholderGenerator>>,
+    /// Target widths of responsive renditions; results then carry the rendition sizes
+    /// computed from the image dimensions. See [`crate::responsive`].
+    pub rendition_widths: Vec<u32>,
+    /// Rotate and flip images according to their EXIF orientation before encoding, so
+    /// placeholders and dimensions match how browsers display them. Cached entries
+    /// encoded the other way are regenerated.
+    pub apply_orientation: bool,
+    /// Maintenance run on an interval while the context is alive, or `None` for none;
+    /// see [`crate::maintenance::schedule_maintenance`].
+    pub maintenance: Option<ScheduledMaintenance>,
+    /// Snapshot of the cache written on an interval while the context is alive, for
+    /// read-only processes to map, or `None` for none; see
+    /// [`crate::snapshot::schedule_snapshots`].
+    pub snapshot: Option<ScheduledSnapshot>,
+}
+
+impl Default for ContextOptions {
+    fn default() -> Self {
+        Self {
+            workers: default_worker_count(),
+            components: DEFAULT_COMPONENTS,
+            encode_size: None,
+            encode_rules: Vec::new(),
+            max_concurrent_decodes: None,
+            decode_memory_budget: None,
+            decoder: Decoder::default(),
+            mtime_tolerance_ms: 0,
+            io_retries: 3,
+            validation: CacheValidation::default(),
+            revalidate: Revalidate::default(),
+            outside_root: OutsideRootPolicy::default(),
+            case_insensitive_keys: false,
+            non_utf8_keys: NonUtf8Policy::default(),
+            sandbox: false,
+            allowed_extensions: None,
+            allowed_mime_types: None,
+            blur_data_url: false,
+            persist_webp_previews: false,
+            micro_pixels: false,
+            preview_size: DEFAULT_PREVIEW_SIZE,
+            thumbnail_size: None,
+            generators: Vec::new(),
+            rendition_widths: Vec::new(),
+            apply_orientation: false,
+            maintenance: None,
+            snapshot: None,
+        }
+    }
+}
+
+impl ContextOptions {
+    /// The encode settings of the image keyed `key`: the context's, overridden by the
+    /// [`ContextOptions::encode_rules`] matching it.
+    pub fn encode_settings(&self, key: &str) -> EncodeSettings {
+        let mut settings = EncodeSettings {
+            components: self.components,
+            encode_size: self.encode_size,
+            preview_size: self.preview_size,
+        };
+        for rule in self
+            .encode_rules
+            .iter()
+            .filter(|rule| rule.pattern.matches(key))
+        {
+            settings.components.0 = rule.components_x.unwrap_or(settings.components.0);
+            settings.components.1 = rule.components_y.unwrap_or(settings.components.1);
+            settings.encode_size = rule.encode_size.or(settings.encode_size);
+            settings.preview_size = rule.preview_size.unwrap_or(settings.preview_size);
+        }
+        settings
+    }
+}
+
+/// Settings an image is encoded with, see [`ContextOptions::encode_settings`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeSettings {
+    pub components: (u32, u32),
+    pub encode_size: Option<u32>,
+    pub preview_size: u32,
+}
+
+/// Encode settings for the images matching [`EncodeRule::pattern`]. Unset fields
+/// keep the value of the context or of earlier rules.
+#[derive(Debug, Clone)]
+pub struct EncodeRule {
+    pub pattern: KeyPattern,
+    pub components_x: Option<u32>,
+    pub components_y: Option<u32>,
+    pub encode_size: Option<u32>,
+    pub preview_size: Option<u32>,
+}
+
+/// Images an [`EncodeRule`] applies to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPattern {
+    /// Files with this extension, lowercase and without the dot, in any case.
+    Extension(String),
+    /// Cache keys matching a glob, e.g. `icons/**` or `*.svg`, where `*` also
+    /// matches `/`.
+    Glob(Pattern),
+}
+
+impl KeyPattern {
+    /// Parses a bare extension such as `png` or `.png`, or else a glob.
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let extension = pattern.strip_prefix('.').unwrap_or(pattern);
+        if !extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Ok(Self::Extension(extension.to_ascii_lowercase()));
+        }
+        Pattern::new(pattern)
+            .map(Self::Glob)
+            .with_context(|| format!("Invalid pattern `{pattern}`"))
+    }
+
+    /// Whether the image keyed `key` matches.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Self::Extension(extension) => Path::new(key)
+                .extension()
+                .is_some_and(|found| found.eq_ignore_ascii_case(extension)),
+            Self::Glob(pattern) => pattern.matches_with(
+                key,
+                MatchOptions {
+                    require_literal_separator: false,
+                    ..MatchOptions::new()
+                },
+            ),
+        }
+    }
+}
+
+/// Maintenance a context runs on its own, for long-running servers that never call
+/// [`maintain`](crate::maintenance::maintain). Each run first stores finished
+/// background refreshes, then removes entries and analyzes as selected here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledMaintenance {
+    /// Time between the end of one run and the start of the next.
+    pub interval: Duration,
+    /// Up to this much is added at random to each interval, so processes sharing a
+    /// database do not all run at once.
+    pub jitter: Duration,
+    /// Remove entries whose image no longer exists.
+    pub prune_orphans: bool,
+    /// Remove entries not written within this age.
+    pub max_age: Option<Duration>,
+    /// Refresh the statistics SQLite's query planner relies on.
+    pub analyze: bool,
+}
+
+impl ScheduledMaintenance {
+    /// Runs every `interval` with a tenth of it as jitter, pruning orphans and
+    /// analyzing.
+    pub fn every(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: interval / 10,
+            prune_orphans: true,
+            max_age: None,
+            analyze: true,
+        }
+    }
+}
+
+/// Where and how often a context writes its [snapshot](crate::snapshot).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledSnapshot {
+    /// File the snapshot is written to, replaced atomically on every write.
+    pub path: PathBuf,
+    /// Time between the end of one write and the next check for changes.
+    pub interval: Duration,
+}
+
+/// Preset for the settings that trade placeholder detail for size and speed, so
+/// they can be chosen with one knob; see [`Quality::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// 3x3 components from images shrunk to 32 pixels, 16-pixel previews.
+    Low,
+    /// 4x3 components from images shrunk to 64 pixels, 32-pixel previews, matching
+    /// the defaults apart from the shrinking.
+    Medium,
+    /// 6x5 components from images shrunk to 128 pixels, 64-pixel previews.
+    High,
+}
+
+impl Quality {
+    /// Parses a quality name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            other => Err(anyhow!(
+                "Unknown quality `{other}`, expected \"low\", \"medium\" or \"high\""
+            )),
+        }
+    }
+
+    /// Sets the [`ContextOptions::components`], [`ContextOptions::encode_size`] and
+    /// [`ContextOptions::preview_size`] of the preset. Options set afterwards
+    /// override it.
+    pub fn apply(self, options: &mut ContextOptions) {
+        let (components, encode_size, preview_size) = match self {
+            Self::Low => ((3, 3), 32, 16),
+            Self::Medium => (DEFAULT_COMPONENTS, 64, DEFAULT_PREVIEW_SIZE),
+            Self::High => ((6, 5), 128, 64),
+        };
+        options.components = components;
+        options.encode_size = Some(encode_size);
+        options.preview_size = preview_size;
+    }
+}
+
+/// Representation of the placeholder a lookup returns in addition to the blurhash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Output {
+    /// The blurhash alone, plus whatever the context options add.
+    #[default]
+    Blurhash,
+    /// A tiny PNG data URI of the decoded blurhash, ready for an `<img>` placeholder,
+    /// as with [`ContextOptions::blur_data_url`] for this lookup.
+    DataUri,
+    /// The same image as a WebP, lossy with the `libwebp` feature, for previews inlined
+    /// into HTML, see [`crate::encode::blur_webp`]. Cached with the entry when
+    /// [`ContextOptions::persist_webp_previews`] is set.
+    WebP,
+}
+
+impl Output {
+    /// Parses an output name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "blurhash" => Ok(Self::Blurhash),
+            "dataUri" => Ok(Self::DataUri),
+            "webp" => Ok(Self::WebP),
+            other => Err(anyhow!(
+                "Unknown output `{other}`, expected \"blurhash\", \"dataUri\" or \"webp\""
+            )),
+        }
+    }
+}
+
+/// Strategy for deciding whether a cached entry still matches its file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheValidation {
+    /// Trust a matching mtime; on mismatch compare content hashes before regenerating.
+    #[default]
+    MtimeThenHash,
+    /// Trust a matching mtime; on mismatch regenerate without hashing. Rows written
+    /// in this mode carry no content hash.
+    MtimeOnly,
+    /// Ignore mtimes and compare content hashes on every lookup, for environments
+    /// where mtimes are meaningless (container layers, restored CI caches).
+    HashOnly,
+    /// Regenerate on every lookup and overwrite the cached entry.
+    Always,
+    /// Never validate: serve any cached entry without looking at the file, for
+    /// immutable inputs.
+    Never,
+}
+
+impl CacheValidation {
+    /// Parses a validation mode name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(Self::MtimeThenHash),
+            "mtime" => Ok(Self::MtimeOnly),
+            "hash" => Ok(Self::HashOnly),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            other => Err(anyhow!(
+                "Unknown validation mode `{other}`, expected \"auto\", \"mtime\", \"hash\", \"always\" or \"never\""
+            )),
+        }
+    }
+
+    /// Name of the mode as accepted by [`CacheValidation::parse`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::MtimeThenHash => "auto",
+            Self::MtimeOnly => "mtime",
+            Self::HashOnly => "hash",
+            Self::Always => "always",
+            Self::Never => "never",
+        }
+    }
+
+    /// Whether a cached entry with a matching mtime is returned without reading the file.
+    pub fn trusts_mtime(self) -> bool {
+        matches!(self, Self::MtimeThenHash | Self::MtimeOnly)
+    }
+
+    /// Whether a file whose mtime changed is compared by content before regenerating.
+    pub fn compares_content(self) -> bool {
+        matches!(self, Self::MtimeThenHash | Self::HashOnly)
+    }
+}
+
+/// What a lookup does with a cached entry that fails validation, e.g. because its
+/// file changed.
+///
+/// Only entries encoded with the current settings qualify; entries of other
+/// components, encoder versions or generators are always regenerated first. Modes
+/// that compare content hash a touched file first, so an unchanged one is a hit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Revalidate {
+    /// Regenerate the entry before answering.
+    #[default]
+    Blocking,
+    /// Answer with the cached entry at once and regenerate it on the worker pool;
+    /// later lookups see the fresh entry (stale-while-revalidate).
+    Background,
+    /// Answer with the cached entry and leave it as is, until the image is
+    /// invalidated.
+    Never,
+}
+
+impl Revalidate {
+    /// Parses a revalidation policy name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "blocking" => Ok(Self::Blocking),
+            "background" => Ok(Self::Background),
+            "never" => Ok(Self::Never),
+            other => Err(anyhow!(
+                "Unknown revalidate policy `{other}`, expected \"blocking\", \"background\" or \"never\""
+            )),
+        }
+    }
+}
+
+/// Per-call overrides of context settings.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    /// Root to derive the cache key against instead of the context's project root.
+    /// Relative image paths are resolved against this root as well.
+    pub project_root: Option<PathBuf>,
+    /// Validation strategy for this call instead of the context's.
+    pub validation: Option<CacheValidation>,
+    /// Handling of an entry failing validation for this call instead of the context's.
+    pub revalidate: Option<Revalidate>,
+    /// W3C `traceparent` header of the request this call serves; spans recorded with
+    /// the `otel` feature become its children. Ignored without the feature.
+    pub traceparent: Option<String>,
+    /// Region of the image to encode instead of the full frame, so the placeholder
+    /// matches a cropped display. Results report the dimensions of the region, and
+    /// each crop is cached as an entry of its own.
+    pub crop: Option<Crop>,
+    /// Width-to-height ratio of the rendition the placeholder is for. The image, or
+    /// its [`RequestOptions::crop`] region, is cropped to this ratio around
+    /// `focal_point` before encoding, and cached per ratio and focal point.
+    pub aspect_ratio: Option<f64>,
+    /// Point to keep in view when cropping to `aspect_ratio`; the center by default.
+    /// Ignored without an aspect ratio.
+    pub focal_point: Option<FocalPoint>,
+    /// Rendition widths for this call instead of the context's.
+    pub rendition_widths: Option<Vec<u32>>,
+    /// Regenerate the placeholder and overwrite the cached entry, even when it is
+    /// current. For changes to the pixels the cache cannot detect, such as a tool
+    /// rewriting a file with its original bytes and mtime, or encoder changes.
+    pub force: bool,
+    /// Representation to return in addition to the blurhash.
+    pub output: Output,
+    /// Preview size for this call instead of the context's.
+    pub preview_size: Option<u32>,
+    /// Longest a generation may run. It is checked between the stages of a generation
+    /// (read, decode, crop, encode), which are not interrupted themselves, and fails
+    /// with [`BlurestError::Timeout`](crate::error::BlurestError::Timeout).
+    pub timeout: Option<Duration>,
+}
+
+/// Rectangle of an image in pixels, from its top-left corner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Position in an image as fractions of its width and height, each between 0 and 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FocalPoint {
+    pub fx: f64,
+    pub fy: f64,
+}
+
+impl Default for FocalPoint {
+    fn default() -> Self {
+        Self { fx: 0.5, fy: 0.5 }
+    }
+}
@@ -0,0 +1,116 @@
+//! Custom placeholder formats, generated and cached alongside the blurhash.
+//!
+//! Implement [`PlaceholderGenerator`] and register it in
+//! [`ContextOptions::generators`](crate::options::ContextOptions::generators). Whenever
+//! an image is decoded, each generator receives a downscaled copy of it and returns
+//! named string payloads, which the cache stores next to the blurhash and returns in
+//! `BlurhashData::placeholders` under the generator's name.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use blurest_core::{
+//!     ContextOptions,
+//!     placeholder::{Payloads, PlaceholderGenerator},
+//! };
+//! use image::RgbaImage;
+//!
+//! struct Dominant;
+//!
+//! impl PlaceholderGenerator for Dominant {
+//!     fn name(&self) -> &str {
+//!         "dominant"
+//!     }
+//!
+//!     fn generate(&self, image: &RgbaImage) -> anyhow::Result<Payloads> {
+//!         let [r, g, b, _] = image.get_pixel(0, 0).0;
+//!         Ok(Payloads::from([(
+//!             "color".to_string(),
+//!             format!("#{r:02x}{g:02x}{b:02x}"),
+//!         )]))
+//!     }
+//! }
+//!
+//! let options = ContextOptions {
+//!     generators: vec![Arc::new(Dominant)],
+//!     ..ContextOptions::default()
+//! };
+//! ```
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use anyhow::{Context, Result, anyhow};
+use image::{DynamicImage, GenericImageView, RgbaImage};
+
+use crate::encode::catch_panic;
+
+/// Longest side, in pixels, of the image handed to generators.
+pub const GENERATOR_INPUT_SIZE: u32 = 64;
+
+/// Named payloads produced by one generator for one image.
+pub type Payloads = BTreeMap<String, String>;
+
+/// A custom placeholder format, computed from every decoded image.
+pub trait PlaceholderGenerator: Send + Sync {
+    /// Name the payloads are stored and returned under, unique among the registered
+    /// generators.
+    fn name(&self) -> &str;
+
+    /// Version of the output. Bump it whenever the payloads for the same image change;
+    /// entries cached with another version are regenerated on their next lookup.
+    fn version(&self) -> i32 {
+        1
+    }
+
+    /// Computes the payloads for `image`, the decoded image downscaled to at most
+    /// [`GENERATOR_INPUT_SIZE`] pixels on its longest side.
+    fn generate(&self, image: &RgbaImage) -> Result<Payloads>;
+}
+
+impl fmt::Debug for dyn PlaceholderGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.name(), self.version())
+    }
+}
+
+/// Fails if two generators share a name.
+pub fn check_generators(generators: &[Arc<dyn PlaceholderGenerator>]) -> Result<()> {
+    for (index, generator) in generators.iter().enumerate() {
+        if generators[..index]
+            .iter()
+            .any(|other| other.name() == generator.name())
+        {
+            return Err(anyhow!(
+                "Placeholder generator `{}` is registered more than once",
+                generator.name()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs every generator on a downscaled copy of `image`, keyed by generator name.
+pub fn generate_placeholders(
+    generators: &[Arc<dyn PlaceholderGenerator>],
+    image: &DynamicImage,
+) -> Result<BTreeMap<String, Payloads>> {
+    if generators.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let (width, height) = image.dimensions();
+    let input = if width.max(height) > GENERATOR_INPUT_SIZE {
+        image
+            .thumbnail(GENERATOR_INPUT_SIZE, GENERATOR_INPUT_SIZE)
+            .to_rgba8()
+    } else {
+        image.to_rgba8()
+    };
+    generators
+        .iter()
+        .map(|generator| {
+            let payloads = catch_panic(|| generator.generate(&input))
+                .with_context(|| format!("Placeholder generator `{}` failed", generator.name()))?;
+            Ok((generator.name().to_string(), payloads))
+        })
+        .collect()
+}
@@ -0,0 +1,365 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use log::{debug, error};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Scheduling priority of a queued job. Higher priorities are always dequeued first;
+/// jobs of equal priority run in submission order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JobPriority {
+    /// Bulk work such as build-time cache warming.
+    Background,
+    #[default]
+    Normal,
+    /// User-facing requests that something is actively waiting on.
+    Interactive,
+}
+
+impl JobPriority {
+    /// Parses a priority name as accepted by the JavaScript API.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "background" => Ok(Self::Background),
+            "normal" => Ok(Self::Normal),
+            "interactive" => Ok(Self::Interactive),
+            other => Err(anyhow!(
+                "Unknown priority `{other}`, expected \"background\", \"normal\" or \"interactive\""
+            )),
+        }
+    }
+}
+
+struct QueuedJob {
+    priority: JobPriority,
+    sequence: u64,
+    job: Job,
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap: higher priority first, then lower sequence number first.
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Returns the default worker count, which is the available parallelism of the process.
+///
+/// On Linux this honours cgroup CPU quotas, so containers with CPU limits get a pool
+/// sized to their quota rather than to the host's core count.
+pub fn default_worker_count() -> usize {
+    thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+struct QueueState {
+    jobs: BinaryHeap<QueuedJob>,
+    next_sequence: u64,
+    shutdown: bool,
+    /// Jobs taken off the queue that have not returned yet.
+    running: usize,
+    completed: u64,
+    panicked: u64,
+}
+
+/// Point-in-time counts of a [`WorkerPool`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Number of worker threads.
+    pub size: usize,
+    /// Jobs waiting for a worker.
+    pub queued: usize,
+    /// Jobs the workers are running.
+    pub running: usize,
+    /// Jobs that returned since the pool started, including those that panicked.
+    pub completed: u64,
+    /// Jobs that panicked since the pool started.
+    pub panicked: u64,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    available: Condvar,
+    /// Signalled whenever a job finishes.
+    finished: Condvar,
+}
+
+/// A fixed-size pool of worker threads owned by a context, running all of its
+/// generations off the calling thread: the async and batch APIs, background
+/// revalidation, cache warming and scheduled completions.
+///
+/// Workers pull jobs from a shared priority queue. Dropping the pool stops it from
+/// accepting new jobs; workers finish whatever is already queued and then exit,
+/// so pending callers are always settled.
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+    size: usize,
+}
+
+impl WorkerPool {
+    /// Spawns a pool with `size` worker threads.
+    pub fn new(size: usize) -> Result<Self> {
+        if size == 0 {
+            return Err(anyhow!("Worker pool size must be at least 1"));
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState {
+                jobs: BinaryHeap::new(),
+                next_sequence: 0,
+                shutdown: false,
+                running: 0,
+                completed: 0,
+                panicked: 0,
+            }),
+            available: Condvar::new(),
+            finished: Condvar::new(),
+        });
+
+        for index in 0..size {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("blurest-worker-{index}"))
+                .spawn(move || worker_loop(&shared))?;
+        }
+
+        debug!("Started worker pool with {size} threads");
+        Ok(Self { shared, size })
+    }
+
+    /// Number of worker threads in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of jobs waiting for a worker.
+    pub fn queued(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .map(|state| state.jobs.len())
+            .unwrap_or(0)
+    }
+
+    /// Number of jobs the workers are running.
+    pub fn running(&self) -> usize {
+        self.shared
+            .state
+            .lock()
+            .map(|state| state.running)
+            .unwrap_or(0)
+    }
+
+    /// Current counts of the pool.
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.state.lock();
+        let (queued, running, completed, panicked) = state.map_or((0, 0, 0, 0), |state| {
+            (
+                state.jobs.len(),
+                state.running,
+                state.completed,
+                state.panicked,
+            )
+        });
+        PoolStats {
+            size: self.size,
+            queued,
+            running,
+            completed,
+            panicked,
+        }
+    }
+
+    /// Queues a job with [`JobPriority::Normal`] for execution on one of the workers.
+    pub fn execute<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(JobPriority::Normal, job)
+    }
+
+    /// Queues a job for execution on one of the workers, ahead of any lower-priority jobs.
+    pub fn execute_with_priority<F>(&self, priority: JobPriority, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self
+            .shared
+            .state
+            .lock()
+            .map_err(|_| anyhow!("Worker pool queue lock was poisoned"))?;
+        if state.shutdown {
+            return Err(anyhow!("Worker pool is shut down"));
+        }
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.jobs.push(QueuedJob {
+            priority,
+            sequence,
+            job: Box::new(job),
+        });
+        self.shared.available.notify_one();
+        Ok(())
+    }
+
+    /// Runs `job` on every item on the workers at `priority`, keeping at most `limit`
+    /// queued or running at once so other work on the pool is not starved, and hands
+    /// each result to `on_result` on the calling thread as it finishes. Returns once
+    /// every job queued has finished; a job that panics yields an error result.
+    ///
+    /// Must not be called from a job of the same pool, which could wait on itself.
+    pub fn run_each<T, R>(
+        &self,
+        priority: JobPriority,
+        limit: usize,
+        items: impl IntoIterator<Item = T>,
+        job: impl Fn(T) -> R + Send + Sync + 'static,
+        mut on_result: impl FnMut(Result<R>),
+    ) -> Result<()>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+    {
+        let job = Arc::new(job);
+        let (sender, results) = mpsc::channel();
+        let mut items = items.into_iter();
+        let mut in_flight = 0;
+        let mut queue_error = None;
+        loop {
+            while queue_error.is_none() && in_flight < limit.max(1) {
+                let Some(item) = items.next() else { break };
+                let (job, sender) = (Arc::clone(&job), sender.clone());
+                let queued = self.execute_with_priority(priority, move || {
+                    match panic::catch_unwind(AssertUnwindSafe(|| job(item))) {
+                        Ok(result) => {
+                            let _ = sender.send(Ok(result));
+                        }
+                        Err(payload) => {
+                            let _ = sender.send(Err(anyhow!("Worker job panicked")));
+                            // Let the worker log and count the panic.
+                            panic::resume_unwind(payload);
+                        }
+                    }
+                });
+                match queued {
+                    Ok(()) => in_flight += 1,
+                    Err(e) => queue_error = Some(e),
+                }
+            }
+            if in_flight == 0 {
+                break;
+            }
+            // Every queued job sends exactly one result, and `sender` is still held.
+            let Ok(result) = results.recv() else { break };
+            on_result(result);
+            in_flight -= 1;
+        }
+        queue_error.map_or(Ok(()), Err)
+    }
+
+    /// Stops accepting new jobs. Jobs already queued still run, after which the
+    /// workers exit.
+    pub fn close(&self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.shutdown = true;
+        }
+        self.shared.available.notify_all();
+    }
+
+    /// Blocks until no job is queued or running, or until `timeout` elapses.
+    ///
+    /// Returns `true` if the pool became idle. Without a preceding [`WorkerPool::close`]
+    /// new jobs may be queued while waiting, so this may never return `true`.
+    pub fn wait_idle(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let Ok(mut state) = self.shared.state.lock() else {
+            return false;
+        };
+        while !state.jobs.is_empty() || state.running > 0 {
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return false;
+                    }
+                    match self.shared.finished.wait_timeout(state, remaining) {
+                        Ok((state, _)) => state,
+                        Err(_) => return false,
+                    }
+                }
+                None => match self.shared.finished.wait(state) {
+                    Ok(state) => state,
+                    Err(_) => return false,
+                },
+            };
+        }
+        true
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn worker_loop(shared: &Shared) {
+    loop {
+        let job = {
+            let Ok(mut state) = shared.state.lock() else {
+                error!("Worker pool queue lock was poisoned, stopping worker");
+                return;
+            };
+            loop {
+                if let Some(queued) = state.jobs.pop() {
+                    state.running += 1;
+                    break queued.job;
+                }
+                if state.shutdown {
+                    return;
+                }
+                state = match shared.available.wait(state) {
+                    Ok(state) => state,
+                    Err(_) => return,
+                };
+            }
+        };
+
+        let panicked = panic::catch_unwind(AssertUnwindSafe(job)).is_err();
+        if panicked {
+            error!("Worker job panicked");
+        }
+
+        if let Ok(mut state) = shared.state.lock() {
+            state.running -= 1;
+            state.completed += 1;
+            state.panicked += u64::from(panicked);
+        }
+        shared.finished.notify_all();
+    }
+}
@@ -0,0 +1,193 @@
+//! Zero-config layout for small projects: the cache lives in `<projectRoot>/.blurest/`,
+//! next to the images it describes, and one process at a time writes to it.
+//!
+//! ```text
+//! .blurest/
+//!   .gitignore      ignores the whole directory
+//!   cache.sqlite3   the database, with its journal files
+//!   lock            identifies the process holding the cache
+//! ```
+//!
+//! The owning process holds an exclusive advisory lock on `lock` for as long as its
+//! context lives, see [`ProjectLock`]. The operating system releases it when the
+//! process exits, even after a crash, so a lock is never left behind. Other processes
+//! wait for it up to a timeout, then fail with [`ProjectLocked`] naming the owner;
+//! processes that only read can share the owner's cache through
+//! [`crate::snapshot`] or a daemon instead.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+use crate::core::time_to_ms;
+
+/// Directory of the cache, inside the project root.
+pub const PROJECT_DIR: &str = ".blurest";
+/// File name of the database in [`PROJECT_DIR`].
+pub const DATABASE_FILE: &str = "cache.sqlite3";
+/// File name of the lock in [`PROJECT_DIR`].
+pub const LOCK_FILE: &str = "lock";
+
+/// Interval at which a busy lock is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The cache directory of the project at `root`.
+pub fn project_dir(root: &Path) -> PathBuf {
+    root.join(PROJECT_DIR)
+}
+
+/// Exclusive lock on the cache of a project, released when dropped or when the
+/// process exits.
+pub struct ProjectLock {
+    file: File,
+    dir: PathBuf,
+}
+
+impl ProjectLock {
+    /// Creates the cache directory of the project at `root` if needed and locks it,
+    /// trying again for up to `timeout` while another process holds it. The lock file
+    /// then names this process.
+    pub fn acquire(root: &Path, timeout: Duration) -> Result<Self> {
+        let dir = project_dir(root);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create the cache directory {}", dir.display()))?;
+        let gitignore = dir.join(".gitignore");
+        if !gitignore.exists() {
+            fs::write(&gitignore, "*\n")
+                .with_context(|| format!("Failed to write {}", gitignore.display()))?;
+        }
+
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open the lock file {}", path.display()))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(ProjectLocked {
+                        owner: read_owner(&path),
+                        dir,
+                    }
+                    .into());
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+                }
+            }
+        }
+
+        let owner = LockOwner::current();
+        file.set_len(0)?;
+        file.write_all(owner.to_json().to_string().as_bytes())?;
+        file.sync_data()?;
+        Ok(Self { file, dir })
+    }
+
+    /// The cache directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the database in the cache directory.
+    pub fn database_path(&self) -> PathBuf {
+        self.dir.join(DATABASE_FILE)
+    }
+}
+
+impl Drop for ProjectLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock; the emptied file no longer names us.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// The process holding a [`ProjectLock`], as written to its lock file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    pub pid: u32,
+    /// File name of the owner's executable, e.g. `node`.
+    pub program: Option<String>,
+    /// When the lock was taken, in milliseconds since the Unix epoch.
+    pub locked_at_ms: i64,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: process::id(),
+            program: std::env::current_exe().ok().and_then(|exe| {
+                exe.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            }),
+            locked_at_ms: time_to_ms(SystemTime::now()).unwrap_or(0),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "pid": self.pid,
+            "program": self.program,
+            "lockedAtMs": self.locked_at_ms,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            pid: u32::try_from(value.get("pid")?.as_u64()?).ok()?,
+            program: value
+                .get("program")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            locked_at_ms: value.get("lockedAtMs")?.as_i64()?,
+        })
+    }
+}
+
+/// Reads the owner named by the lock file at `path`. `None` if it names none, as
+/// while nobody holds the lock, or cannot be read, as on Windows while it is held.
+pub fn read_owner(path: &Path) -> Option<LockOwner> {
+    let contents = fs::read_to_string(path).ok()?;
+    LockOwner::from_json(&serde_json::from_str(&contents).ok()?)
+}
+
+/// Error of [`ProjectLock::acquire`] when another process kept the cache locked.
+#[derive(Debug)]
+pub struct ProjectLocked {
+    /// The cache directory.
+    pub dir: PathBuf,
+    pub owner: Option<LockOwner>,
+}
+
+impl fmt::Display for ProjectLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The cache in {} is in use by ", self.dir.display())?;
+        match &self.owner {
+            Some(LockOwner {
+                pid,
+                program: Some(program),
+                ..
+            }) => write!(f, "process {pid} ({program})"),
+            Some(LockOwner { pid, .. }) => write!(f, "process {pid}"),
+            None => f.write_str("another process"),
+        }
+    }
+}
+
+impl std::error::Error for ProjectLocked {}
@@ -0,0 +1,446 @@
+//! Background refreshes of stale entries, for [`Revalidate::Background`].
+//!
+//! A lookup that finds a stale entry answers with it and queues the regeneration on
+//! the context's worker pool. Workers cannot reach the context, so finished
+//! refreshes wait in the [`RefreshQueue`] until the next lookup through the context
+//! stores them with [`apply_refreshes`].
+//!
+//! [`revalidate_entries`] instead refreshes every changed entry at once, e.g. at
+//! startup.
+//!
+//! [`Revalidate::Background`]: crate::options::Revalidate::Background
+
+use std::{
+    collections::HashSet,
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use diesel::prelude::*;
+use log::{debug, info, warn};
+
+use crate::{
+    core::{
+        AppContext, CacheLookup, ComputedEntry, PendingEntry, compute_blurhash, lookup_blurhash,
+        mtime_matches, store_blurhash, time_to_ms,
+    },
+    keys::{image_key, key_paths},
+    options::{RequestOptions, Revalidate},
+    pool::JobPriority,
+    schema::blurhash_cache,
+};
+
+/// Refreshes in flight on the worker pool, and their results until stored.
+///
+/// A key is refreshed at most once at a time, so repeated lookups of a stale entry
+/// do not queue duplicate work.
+#[derive(Default)]
+pub struct RefreshQueue {
+    state: Mutex<RefreshQueueState>,
+}
+
+#[derive(Default)]
+struct RefreshQueueState {
+    in_flight: HashSet<String>,
+    done: Vec<(PendingEntry, Result<ComputedEntry>)>,
+}
+
+impl RefreshQueue {
+    /// Marks `key` as in flight. Returns `false` if it already was.
+    fn schedule(&self, key: &str) -> bool {
+        match self.state.lock() {
+            Ok(mut state) => state.in_flight.insert(key.to_string()),
+            Err(_) => false,
+        }
+    }
+
+    /// Stores the result of a refresh started with [`RefreshQueue::schedule`].
+    fn complete(&self, pending: PendingEntry, computed: Result<ComputedEntry>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.in_flight.remove(&pending.relative_key);
+            state.done.push((pending, computed));
+        }
+    }
+
+    /// Takes every finished refresh, oldest first.
+    fn drain(&self) -> Vec<(PendingEntry, Result<ComputedEntry>)> {
+        match self.state.lock() {
+            Ok(mut state) => std::mem::take(&mut state.done),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Number of refreshes running or queued on the worker pool.
+    pub fn in_flight(&self) -> usize {
+        self.state
+            .lock()
+            .map(|state| state.in_flight.len())
+            .unwrap_or(0)
+    }
+}
+
+/// Queues the regeneration of a stale entry on the worker pool at background
+/// priority, unless its key is already being refreshed.
+pub(crate) fn schedule_refresh(context: &AppContext, pending: PendingEntry) {
+    if !context.refreshes.schedule(&pending.relative_key) {
+        return;
+    }
+    let refreshes = Arc::clone(&context.refreshes);
+    let key = pending.relative_key.clone();
+    let queued = context
+        .pool
+        .execute_with_priority(JobPriority::Background, move || {
+            let computed = compute_blurhash(&pending);
+            refreshes.complete(pending, computed);
+        });
+    if let Err(e) = queued {
+        warn!(path = key.as_str(); "Failed to queue refresh of {key}: {e:#}");
+        if let Some(hook) = &context.error_hook {
+            hook(&key, &e);
+        }
+        if let Ok(mut state) = context.refreshes.state.lock() {
+            state.in_flight.remove(&key);
+        }
+    }
+}
+
+/// Stores the refreshes that finished since the last call, returning how many were
+/// stored. Lookups call this first, so it only needs calling directly to persist
+/// refreshes without another lookup, e.g. before shutting down.
+///
+/// A refresh is dropped when its file changed again while it ran, as it no longer
+/// reflects the file; the next lookup refreshes the entry anew. Failed refreshes
+/// leave the stale entry in place.
+pub fn apply_refreshes(context: &mut AppContext) -> Result<usize> {
+    let mut stored = 0;
+    for (pending, computed) in context.refreshes.drain() {
+        let key = pending.relative_key.clone();
+        let computed = match computed {
+            Ok(computed) => computed,
+            Err(e) => {
+                warn!(path = key.as_str(); "Background refresh of {key} failed: {e:#}");
+                if let Some(hook) = &context.error_hook {
+                    hook(&key, &e);
+                }
+                continue;
+            }
+        };
+        let current_mtime_ms = fs::metadata(&pending.absolute_path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(anyhow::Error::from)
+            .and_then(time_to_ms);
+        if current_mtime_ms.ok() != Some(pending.mtime_ms) {
+            debug!(path = key.as_str(); "Dropping outdated background refresh of {key}");
+            continue;
+        }
+        match computed {
+            ComputedEntry::Unchanged => {
+                if let Some(cache) = &pending.cached {
+                    diesel::update(cache)
+                        .set((
+                            blurhash_cache::mtime_ms.eq(pending.mtime_ms),
+                            blurhash_cache::file_size.eq(pending.file_size as i64),
+                        ))
+                        .execute(&mut context.db_conn)
+                        .inspect_err(|_| context.metrics.record_db_error())?;
+                }
+            }
+            generated @ ComputedEntry::Generated { .. } => {
+                store_blurhash(context, pending, generated)?;
+            }
+        }
+        debug!(path = key.as_str(); "Stored background refresh of {key}");
+        stored += 1;
+    }
+    Ok(stored)
+}
+
+/// Outcome of [`revalidate_entries`].
+#[derive(Debug, Clone, Default)]
+pub struct RevalidationReport {
+    /// Number of entries whose file was checked.
+    pub checked: usize,
+    /// Keys of the entries regenerated because their file changed.
+    pub regenerated: Vec<String>,
+    /// Keys of the entries whose file was touched without changing its content.
+    pub unchanged: Vec<String>,
+    /// Keys of the entries that failed to regenerate; they are left as they were.
+    pub failed: Vec<String>,
+    pub duration: Duration,
+}
+
+/// Checks every cached entry against its file and refreshes those whose file changed,
+/// e.g. while the application was down, decoding up to `concurrency` images at once
+/// on the context's worker pool at [`JobPriority::Background`].
+/// Meant to run right after connecting, so the first lookups after a deploy find
+/// their entries current instead of all regenerating them.
+///
+/// Files still at their cached mtime are not read, and others are validated like a
+/// lookup would, so a checkout that only touched files costs a content hash each.
+/// Entries of deleted images are left to [`prune_cache`], and cropped entries to
+/// their next lookup. Failures are logged and passed to the context's error hook.
+///
+/// [`prune_cache`]: crate::maintenance::prune_cache
+pub fn revalidate_entries(
+    context: &mut AppContext,
+    concurrency: usize,
+) -> Result<RevalidationReport> {
+    let started = Instant::now();
+    let rows: Vec<(String, i64)> = blurhash_cache::table
+        .select((blurhash_cache::relative_path, blurhash_cache::mtime_ms))
+        .load(&mut context.db_conn)
+        .inspect_err(|_| context.metrics.record_db_error())?;
+
+    let mut report = RevalidationReport::default();
+    let request = RequestOptions {
+        revalidate: Some(Revalidate::Blocking),
+        ..RequestOptions::default()
+    };
+    let mut changed = Vec::new();
+    for (key, mtime_ms) in rows {
+        if image_key(&key) != key {
+            continue;
+        }
+        let Some(path) = key_paths(&key, &context.project_roots)
+            .and_then(|paths| paths.into_iter().find(|path| path.is_file()))
+        else {
+            continue;
+        };
+        report.checked += 1;
+        let current_mtime_ms = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(anyhow::Error::from)
+            .and_then(time_to_ms);
+        if current_mtime_ms.is_ok_and(|current| {
+            mtime_matches(current, mtime_ms, context.options.mtime_tolerance_ms)
+        }) {
+            continue;
+        }
+        match lookup_blurhash(context, &path, &request) {
+            Ok(CacheLookup::Pending(pending)) if pending.relative_key == key => {
+                changed.push(*pending);
+            }
+            Ok(_) => {}
+            Err(e) => revalidation_failed(context, &mut report, key, &e),
+        }
+    }
+
+    let pool = Arc::clone(&context.pool);
+    pool.run_each(
+        JobPriority::Background,
+        concurrency,
+        changed,
+        |pending| {
+            let computed = compute_blurhash(&pending);
+            (pending, computed)
+        },
+        |result| match result {
+            Ok((pending, computed)) => {
+                let key = pending.relative_key.clone();
+                let unchanged = matches!(computed, Ok(ComputedEntry::Unchanged));
+                match computed.and_then(|computed| store_blurhash(context, pending, computed)) {
+                    Ok(_) if unchanged => report.unchanged.push(key),
+                    Ok(_) => report.regenerated.push(key),
+                    Err(e) => revalidation_failed(context, &mut report, key, &e),
+                }
+            }
+            Err(e) => warn!("Revalidation job failed: {e:#}"),
+        },
+    )?;
+
+    report.duration = started.elapsed();
+    info!(
+        "Revalidated {} cached entries in {} ms: {} regenerated, {} unchanged, {} failed",
+        report.checked,
+        report.duration.as_millis(),
+        report.regenerated.len(),
+        report.unchanged.len(),
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// Logs and reports an entry [`revalidate_entries`] could not refresh.
+fn revalidation_failed(
+    context: &AppContext,
+    report: &mut RevalidationReport,
+    key: String,
+    error: &anyhow::Error,
+) {
+    warn!(path = key.as_str(); "Revalidation of {key} failed: {error:#}");
+    if let Some(hook) = &context.error_hook {
+        hook(&key, error);
+    }
+    report.failed.push(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        path::{Path, PathBuf},
+        sync::mpsc,
+        thread,
+        time::SystemTime,
+    };
+
+    use image::{ImageFormat, Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        core::{get_blurhash_with_options, initialize_and_connect_db},
+        options::ContextOptions,
+    };
+
+    fn write_bitmap(path: &Path, rgb: [u8; 3]) {
+        RgbImage::from_pixel(4, 4, Rgb(rgb))
+            .save_with_format(path, ImageFormat::Bmp)
+            .unwrap();
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    /// A context with one worker that revalidates in the background, and an image
+    /// cached in red.
+    fn cached_image(dir: &TempDir) -> (AppContext, PathBuf, SystemTime) {
+        let root = dir.path().canonicalize().unwrap();
+        let options = ContextOptions {
+            workers: 1,
+            revalidate: Revalidate::Background,
+            ..ContextOptions::default()
+        };
+        let conn = initialize_and_connect_db(":memory:").unwrap();
+        let mut context = AppContext::new(conn, vec![root.clone()], options).unwrap();
+        let path = root.join("image.bmp");
+        write_bitmap(&path, [200, 30, 30]);
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        get_blurhash_with_options(&mut context, &path, &RequestOptions::default()).unwrap();
+        (context, path, mtime)
+    }
+
+    /// Occupies the only worker until the returned sender is dropped.
+    fn block_pool(context: &AppContext) -> mpsc::Sender<()> {
+        let (release, released) = mpsc::channel::<()>();
+        context
+            .pool
+            .execute_with_priority(JobPriority::Interactive, move || {
+                let _ = released.recv();
+            })
+            .unwrap();
+        release
+    }
+
+    fn wait_for_refreshes(context: &AppContext) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while context.refreshes.in_flight() > 0 {
+            assert!(Instant::now() < deadline, "refreshes did not finish");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn cached_row(context: &mut AppContext) -> (String, i64) {
+        blurhash_cache::table
+            .select((blurhash_cache::blurhash, blurhash_cache::mtime_ms))
+            .first(&mut context.db_conn)
+            .unwrap()
+    }
+
+    fn lookup(context: &mut AppContext, path: &Path) -> String {
+        get_blurhash_with_options(context, path, &RequestOptions::default())
+            .unwrap()
+            .blurhash
+    }
+
+    #[test]
+    fn refreshes_of_one_key_coalesce() {
+        let dir = TempDir::new().unwrap();
+        let (mut context, path, mtime) = cached_image(&dir);
+        let cached = cached_row(&mut context).0;
+        write_bitmap(&path, [30, 30, 200]);
+        set_mtime(&path, mtime + Duration::from_secs(10));
+
+        let release = block_pool(&context);
+        assert_eq!(lookup(&mut context, &path), cached);
+        assert_eq!(lookup(&mut context, &path), cached);
+        assert_eq!(context.refreshes.in_flight(), 1);
+        drop(release);
+        wait_for_refreshes(&context);
+
+        assert_eq!(context.refreshes.drain().len(), 1);
+    }
+
+    #[test]
+    fn refresh_does_not_overwrite_a_newer_store() {
+        let dir = TempDir::new().unwrap();
+        let (mut context, path, mtime) = cached_image(&dir);
+        write_bitmap(&path, [30, 200, 30]);
+        set_mtime(&path, mtime + Duration::from_secs(10));
+
+        let release = block_pool(&context);
+        // Serves the stale entry and queues a refresh behind the blocked worker.
+        lookup(&mut context, &path);
+        // The file changes again and a foreground lookup stores it first.
+        write_bitmap(&path, [30, 30, 200]);
+        let newest_mtime = mtime + Duration::from_secs(20);
+        set_mtime(&path, newest_mtime);
+        let blocking = RequestOptions {
+            revalidate: Some(Revalidate::Blocking),
+            ..RequestOptions::default()
+        };
+        let newest = get_blurhash_with_options(&mut context, &path, &blocking)
+            .unwrap()
+            .blurhash;
+        drop(release);
+        wait_for_refreshes(&context);
+
+        assert_eq!(apply_refreshes(&mut context).unwrap(), 0);
+        assert_eq!(
+            cached_row(&mut context),
+            (newest, time_to_ms(newest_mtime).unwrap())
+        );
+    }
+
+    #[test]
+    fn refreshes_are_applied_in_completion_order() {
+        let dir = TempDir::new().unwrap();
+        let (mut context, path, mtime) = cached_image(&dir);
+        let blocking = RequestOptions {
+            revalidate: Some(Revalidate::Blocking),
+            ..RequestOptions::default()
+        };
+        let mut computed = Vec::new();
+        for rgb in [[30, 200, 30], [30, 30, 200]] {
+            // Same mtime, so both refreshes are still current when applied.
+            write_bitmap(&path, rgb);
+            set_mtime(&path, mtime + Duration::from_secs(10));
+            let CacheLookup::Pending(pending) =
+                lookup_blurhash(&mut context, &path, &blocking).unwrap()
+            else {
+                panic!("changed image was served from the cache");
+            };
+            let result = compute_blurhash(&pending);
+            computed.push((*pending, result));
+        }
+        let last = match &computed[1].1 {
+            Ok(ComputedEntry::Generated { blurhash, .. }) => blurhash.clone(),
+            _ => panic!("changed image was not regenerated"),
+        };
+        for (pending, result) in computed {
+            context.refreshes.complete(pending, result);
+        }
+
+        assert_eq!(apply_refreshes(&mut context).unwrap(), 2);
+        assert_eq!(cached_row(&mut context).0, last);
+        assert!(context.refreshes.drain().is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+//! Rendition sizes for responsive images, derived from the intrinsic dimensions so
+//! templates need no aspect math of their own.
+//!
+//! Given the target widths of an image's renditions, [`responsive`] computes each
+//! rendition's height and a `sizes` attribute. Targets wider than the image are
+//! replaced by the intrinsic width, as upscaled renditions add bytes without detail.
+
+/// One rendition of an image, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rendition {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Rendition sizes of one image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Responsive {
+    /// Renditions by ascending width, without duplicates.
+    pub renditions: Vec<Rendition>,
+    /// `sizes` attribute filling the viewport up to the widest rendition, e.g.
+    /// `(max-width: 1280px) 100vw, 1280px`.
+    pub sizes: String,
+}
+
+impl Responsive {
+    /// The `srcset` attribute for these renditions, with `url` giving the URL of the
+    /// rendition of a given width.
+    pub fn srcset(&self, url: impl Fn(&Rendition) -> String) -> String {
+        self.renditions
+            .iter()
+            .map(|rendition| format!("{} {}w", url(rendition), rendition.width))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Computes the renditions of a `width` by `height` image at `target_widths`, or
+/// `None` without any target width.
+pub fn responsive(width: i32, height: i32, target_widths: &[u32]) -> Option<Responsive> {
+    if target_widths.is_empty() {
+        return None;
+    }
+    let (width, height) = (width.max(1) as u32, height.max(1) as u32);
+    let mut widths: Vec<u32> = target_widths
+        .iter()
+        .map(|&target| target.clamp(1, width))
+        .collect();
+    widths.sort_unstable();
+    widths.dedup();
+
+    let renditions: Vec<Rendition> = widths
+        .into_iter()
+        .map(|rendition_width| Rendition {
+            width: rendition_width,
+            height: ((u64::from(rendition_width) * u64::from(height) + u64::from(width) / 2)
+                / u64::from(width))
+            .max(1) as u32,
+        })
+        .collect();
+    let widest = renditions.last().map_or(width, |rendition| rendition.width);
+    Some(Responsive {
+        renditions,
+        sizes: format!("(max-width: {widest}px) 100vw, {widest}px"),
+    })
+}
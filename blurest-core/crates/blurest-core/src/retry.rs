@@ -0,0 +1,46 @@
+//! Retries of filesystem calls that fail transiently, as on network filesystems or
+//! while a file is being written.
+
+use std::{
+    io::{self, ErrorKind},
+    thread,
+    time::Duration,
+};
+
+use log::debug;
+
+/// Delay before the first retry; each further retry waits twice as long.
+pub const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Whether `error` may go away on its own: the file is busy (`EBUSY`), the call would
+/// block (`EAGAIN`), it was interrupted, or a network filesystem handle went stale
+/// (`ESTALE`).
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ResourceBusy
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+/// Runs `op`, retrying it up to `retries` times with exponential backoff while it fails
+/// with a transient error. Other errors are returned at once.
+pub fn retry_io<T>(retries: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let delay = RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(attempt));
+                debug!(
+                    "Retrying in {} ms after transient IO error: {e}",
+                    delay.as_millis()
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Component, Path},
+};
+
+use anyhow::Result;
+
+use crate::{error::BlurestError, options::ContextOptions, retry::retry_io};
+
+/// Number of leading bytes read to detect the image type.
+const SNIFF_LEN: usize = 64;
+
+/// Rejects relative paths whose `..` components climb above the directory they
+/// are resolved from. Purely lexical, so it never touches the filesystem.
+pub fn check_traversal(image_path: &Path) -> Result<()> {
+    if image_path.is_absolute() {
+        return Ok(());
+    }
+
+    let mut depth = 0usize;
+    for component in image_path.components() {
+        match component {
+            Component::ParentDir => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| BlurestError::PathEscapesRoot(image_path.to_path_buf()))?;
+            }
+            Component::Normal(_) => depth += 1,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Applies the extension and image type allow-lists of `options` to a canonical path.
+pub fn check_allowed(absolute_path: &Path, options: &ContextOptions) -> Result<()> {
+    if let Some(allowed) = &options.allowed_extensions {
+        let extension = absolute_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        if !extension.is_some_and(|extension| allowed.contains(&extension)) {
+            return Err(BlurestError::ExtensionNotAllowed(absolute_path.to_path_buf()).into());
+        }
+    }
+
+    if let Some(allowed) = &options.allowed_mime_types {
+        let header = retry_io(options.io_retries, || read_header(absolute_path))?;
+        let mime_type = image::guess_format(&header)
+            .map(|format| format.to_mime_type().to_string())
+            .unwrap_or_else(|_| "application/octet-stream".to_string());
+        if !allowed.contains(&mime_type) {
+            return Err(BlurestError::MimeTypeNotAllowed {
+                path: absolute_path.to_path_buf(),
+                mime_type,
+            }
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the file at `path` starts like an image format the `image` crate reads.
+/// Files that cannot be read count as images, so their errors are reported as such.
+pub fn is_supported_image(path: &Path) -> bool {
+    match read_header(path) {
+        Ok(header) => image::guess_format(&header).is_ok_and(|format| format.reading_enabled()),
+        Err(_) => true,
+    }
+}
+
+/// Reads the leading bytes of the file at `path` that image types are detected from.
+fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
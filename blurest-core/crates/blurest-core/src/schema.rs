@@ -0,0 +1,45 @@
+diesel::table! {
+    blurhash_cache (id) {
+        id -> Integer,
+        relative_path -> Text,
+        xxhash -> Text,
+        mtime_ms -> BigInt,
+        blurhash -> Text,
+        width -> Integer,
+        height -> Integer,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        blur_data_url -> Nullable<Text>,
+        algo_version -> Integer,
+        orientation -> Nullable<Integer>,
+        orientation_applied -> Bool,
+        webp_preview -> Nullable<Binary>,
+        preview_size -> Nullable<Integer>,
+        has_alpha -> Nullable<Bool>,
+        format -> Nullable<Text>,
+        bit_depth -> Nullable<Integer>,
+        perceptual_hash -> Nullable<BigInt>,
+        color_space -> Nullable<Text>,
+        color_profile -> Nullable<Text>,
+        micro_pixels -> Nullable<Binary>,
+        file_size -> Nullable<BigInt>,
+        decoded_with -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    placeholder_cache (entry_id, generator) {
+        entry_id -> Integer,
+        generator -> Text,
+        version -> Integer,
+        payloads -> Text,
+    }
+}
+
+diesel::table! {
+    thumbnail_cache (entry_id) {
+        entry_id -> Integer,
+        size -> Integer,
+        thumbnail -> Binary,
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use diesel::prelude::*;
+
+use crate::{
+    core::{AppContext, get_blurhash_with_options},
+    options::RequestOptions,
+    schema::blurhash_cache,
+};
+
+/// Largest Hamming distance between two 64-bit perceptual hashes.
+pub const MAX_DISTANCE: u32 = 64;
+
+/// A cached image found by [`find_similar`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimilarImage {
+    /// Cache key of the image.
+    pub key: String,
+    /// Number of bits its perceptual hash differs in, from 0 (the same picture) to
+    /// [`MAX_DISTANCE`].
+    pub distance: u32,
+}
+
+/// Finds the cached images whose perceptual hash differs from that of the image at
+/// `image_path` in at most `max_distance` bits, closest first.
+///
+/// The image itself is looked up, and generated if needed, like any other lookup;
+/// an entry cached before perceptual hashes were recorded is regenerated. Other
+/// entries without a hash are not considered. Distances up to about 10 usually mean
+/// the same picture resized, recompressed or slightly edited.
+///
+/// Every hashed entry is compared, so the cost grows with the size of the cache.
+pub fn find_similar(
+    context: &mut AppContext,
+    image_path: &Path,
+    max_distance: u32,
+) -> Result<Vec<SimilarImage>> {
+    let mut data = get_blurhash_with_options(context, image_path, &RequestOptions::default())?;
+    if data.perceptual_hash.is_none() {
+        let request = RequestOptions {
+            force: true,
+            ..RequestOptions::default()
+        };
+        data = get_blurhash_with_options(context, image_path, &request)?;
+    }
+    let hash = data
+        .perceptual_hash
+        .ok_or_else(|| anyhow!("No perceptual hash was computed for {}", data.key))?;
+
+    let rows: Vec<(String, Option<i64>)> = blurhash_cache::table
+        .filter(blurhash_cache::perceptual_hash.is_not_null())
+        .filter(blurhash_cache::relative_path.ne(&data.key))
+        .select((
+            blurhash_cache::relative_path,
+            blurhash_cache::perceptual_hash,
+        ))
+        .load(&mut context.db_conn)
+        .inspect_err(|_| context.metrics.record_db_error())?;
+
+    let mut similar: Vec<SimilarImage> = rows
+        .into_iter()
+        .filter_map(|(key, other)| {
+            let distance = (hash ^ other? as u64).count_ones();
+            (distance <= max_distance).then_some(SimilarImage { key, distance })
+        })
+        .collect();
+    similar.sort_by(|a, b| a.distance.cmp(&b.distance).then_with(|| a.key.cmp(&b.key)));
+    Ok(similar)
+}
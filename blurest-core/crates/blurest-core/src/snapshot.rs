@@ -0,0 +1,403 @@
+//! Compact read-only snapshots of the cache, mapping each key to its blurhash and
+//! dimensions, for processes that only read: they map the file with [`Snapshot`]
+//! and look keys up without touching SQLite or taking any lock. The database stays
+//! the source of truth; a context writes snapshots on an interval with
+//! [`ContextOptions::snapshot`](crate::ContextOptions::snapshot), or once with
+//! [`write_snapshot`].
+//!
+//! Snapshots are written to a temporary file that then replaces the previous one, so
+//! a reader never sees a partial file and keeps its mapping of the old one until it
+//! reopens. The format, all integers little-endian:
+//!
+//! ```text
+//! magic     b"BLRSNAP1"
+//! count     u32
+//! offsets   count × u32, of the entries sorted by key bytes
+//! entries   key length u16, key, blurhash length u16, blurhash, width u32, height u32
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow};
+use diesel::{dsl::sql, prelude::*, sql_types::BigInt};
+use log::info;
+use memmap2::Mmap;
+
+use crate::{core::AppContext, options::ScheduledSnapshot, schema::blurhash_cache};
+
+const MAGIC: &[u8; 8] = b"BLRSNAP1";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+/// A snapshot file mapped into memory. Lookups read the mapping directly and never
+/// block, so one snapshot can be shared by every thread of a process.
+pub struct Snapshot {
+    map: Mmap,
+    count: usize,
+    path: PathBuf,
+    identity: FileIdentity,
+}
+
+/// An entry of a [`Snapshot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotEntry<'a> {
+    pub blurhash: &'a str,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Snapshot {
+    /// Maps the snapshot at `path`, checking its header.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open snapshot {}", path.display()))?;
+        let identity = file_identity(&file.metadata()?);
+        // SAFETY: snapshots are only ever replaced by renaming a new file over them,
+        // never written in place, so the mapped file does not change under us.
+        let map = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Failed to map snapshot {}", path.display()))?;
+        if map.len() < HEADER_LEN || &map[..MAGIC.len()] != MAGIC {
+            return Err(anyhow!("{} is not a blurest snapshot", path.display()));
+        }
+        let count = read_u32(&map, MAGIC.len()).unwrap_or(0) as usize;
+        if HEADER_LEN + count * 4 > map.len() {
+            return Err(anyhow!("Snapshot {} is truncated", path.display()));
+        }
+        Ok(Self {
+            map,
+            count,
+            path: path.to_path_buf(),
+            identity,
+        })
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Whether the file at the snapshot's path was replaced since it was opened, in
+    /// which case [`Snapshot::open`] again reads the newer one.
+    pub fn is_stale(&self) -> bool {
+        fs::metadata(&self.path).map_or(true, |metadata| file_identity(&metadata) != self.identity)
+    }
+
+    /// The entry of the cache key `key`, or `None` if the snapshot has none.
+    pub fn get(&self, key: &str) -> Option<SnapshotEntry<'_>> {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (entry_key, rest) = self.entry(middle)?;
+            match entry_key.cmp(key.as_bytes()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return read_record(&self.map, rest),
+            }
+        }
+        None
+    }
+
+    /// The key of entry `index` and the offset of its record. `None` for a corrupted
+    /// file.
+    fn entry(&self, index: usize) -> Option<(&[u8], usize)> {
+        let offset = read_u32(&self.map, HEADER_LEN + index * 4)? as usize;
+        let length = read_u16(&self.map, offset)? as usize;
+        let key = self.map.get(offset + 2..offset + 2 + length)?;
+        Some((key, offset + 2 + length))
+    }
+}
+
+/// Modification time, size and inode of a file. A replaced snapshot has a new inode
+/// on Unix, and elsewhere almost surely a new modification time or size.
+type FileIdentity = (Option<SystemTime>, u64, u64);
+
+fn file_identity(metadata: &fs::Metadata) -> FileIdentity {
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    (metadata.modified().ok(), metadata.len(), inode)
+}
+
+fn read_record(map: &[u8], offset: usize) -> Option<SnapshotEntry<'_>> {
+    let length = read_u16(map, offset)? as usize;
+    let blurhash = std::str::from_utf8(map.get(offset + 2..offset + 2 + length)?).ok()?;
+    Some(SnapshotEntry {
+        blurhash,
+        width: read_u32(map, offset + 2 + length)?,
+        height: read_u32(map, offset + 6 + length)?,
+    })
+}
+
+fn read_u16(map: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        map.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(map: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        map.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Outcome of [`write_snapshot`].
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotReport {
+    /// Number of entries written.
+    pub entries: usize,
+    /// Size of the file in bytes.
+    pub bytes: u64,
+}
+
+/// Writes a snapshot of every cached entry of `context` to `path`, replacing the
+/// file there. Keys longer than 65535 bytes, which no file system produces, are left
+/// out.
+pub fn write_snapshot(context: &mut AppContext, path: &Path) -> Result<SnapshotReport> {
+    let mut rows: Vec<(String, String, i32, i32)> = blurhash_cache::table
+        .select((
+            blurhash_cache::relative_path,
+            blurhash_cache::blurhash,
+            blurhash_cache::width,
+            blurhash_cache::height,
+        ))
+        .load(&mut context.db_conn)
+        .inspect_err(|_| context.metrics.record_db_error())?;
+    rows.retain(|(key, blurhash, ..)| {
+        u16::try_from(key.len()).is_ok() && u16::try_from(blurhash.len()).is_ok()
+    });
+    rows.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    rows.dedup_by(|a, b| a.0 == b.0);
+
+    let mut offsets = Vec::with_capacity(rows.len());
+    let mut offset = HEADER_LEN + rows.len() * 4;
+    for (key, blurhash, ..) in &rows {
+        offsets.push(u32::try_from(offset).map_err(|_| anyhow!("Snapshot exceeds 4 GiB"))?);
+        offset += 2 + key.len() + 2 + blurhash.len() + 8;
+    }
+
+    let mut file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid snapshot path {}", path.display()))?
+        .to_os_string();
+    file_name.push(format!(".{}.tmp", process::id()));
+    let temporary = path.with_file_name(file_name);
+    let written = (|| -> Result<()> {
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&(rows.len() as u32).to_le_bytes())?;
+        for offset in &offsets {
+            out.write_all(&offset.to_le_bytes())?;
+        }
+        for (key, blurhash, width, height) in &rows {
+            out.write_all(&(key.len() as u16).to_le_bytes())?;
+            out.write_all(key.as_bytes())?;
+            out.write_all(&(blurhash.len() as u16).to_le_bytes())?;
+            out.write_all(blurhash.as_bytes())?;
+            out.write_all(&(*width).max(0).cast_unsigned().to_le_bytes())?;
+            out.write_all(&(*height).max(0).cast_unsigned().to_le_bytes())?;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temporary);
+        return Err(e.context(format!("Failed to write snapshot {}", path.display())));
+    }
+
+    Ok(SnapshotReport {
+        entries: rows.len(),
+        bytes: offset as u64,
+    })
+}
+
+/// Handle of the thread writing the snapshots of a context; dropping it stops the
+/// thread at its next wakeup without waiting for a write in progress.
+pub struct SnapshotSchedule {
+    schedule: ScheduledSnapshot,
+    /// [`change_marker`] of the database when the last snapshot was written, to skip
+    /// unchanged caches.
+    written: Option<(i64, i64)>,
+    _stop: Sender<()>,
+}
+
+impl SnapshotSchedule {
+    /// Settings the thread runs with.
+    pub fn schedule(&self) -> &ScheduledSnapshot {
+        &self.schedule
+    }
+
+    /// Makes the next run write a snapshot even if the database looks unchanged, as
+    /// after the context connected anew and its change marker started over.
+    pub(crate) fn forget_written(&mut self) {
+        self.written = None;
+    }
+}
+
+/// Starts, restarts or stops the snapshot thread of `context` to match its
+/// [`ContextOptions::snapshot`](crate::ContextOptions::snapshot), leaving a thread
+/// already running with the same settings alone. The first snapshot is written
+/// right away.
+///
+/// Like [`crate::maintenance::schedule_maintenance`], the thread calls `run`, which
+/// should lock the context and call [`run_scheduled_snapshot`], and return `false`
+/// once the context is gone to end the thread.
+pub fn schedule_snapshots(
+    context: &mut AppContext,
+    mut run: impl FnMut() -> bool + Send + 'static,
+) -> Result<()> {
+    let Some(schedule) = context.options.snapshot.clone() else {
+        context.snapshot_schedule = None;
+        return Ok(());
+    };
+    if context
+        .snapshot_schedule
+        .as_ref()
+        .is_some_and(|running| running.schedule == schedule)
+    {
+        return Ok(());
+    }
+    // Replacing the handle stops the previous thread.
+    let (stop, stopped) = mpsc::channel::<()>();
+    let interval = schedule.interval;
+    thread::Builder::new()
+        .name("blurest-snapshot".to_string())
+        .spawn(move || {
+            if !run() {
+                return;
+            }
+            loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) if run() => {}
+                    _ => break,
+                }
+            }
+        })?;
+    context.snapshot_schedule = Some(SnapshotSchedule {
+        schedule,
+        written: None,
+        _stop: stop,
+    });
+    Ok(())
+}
+
+/// Writes the snapshot of the context's [`ScheduledSnapshot`] unless the database
+/// did not change since the last one. Returns `None` if nothing was written.
+pub fn run_scheduled_snapshot(context: &mut AppContext) -> Result<Option<SnapshotReport>> {
+    let Some(path) = context
+        .snapshot_schedule
+        .as_ref()
+        .map(|schedule| schedule.schedule.path.clone())
+    else {
+        return Ok(None);
+    };
+    let marker = change_marker(context)?;
+    let unchanged = context
+        .snapshot_schedule
+        .as_ref()
+        .is_some_and(|schedule| schedule.written == Some(marker));
+    if unchanged && path.exists() {
+        return Ok(None);
+    }
+
+    let started = Instant::now();
+    let report = write_snapshot(context, &path)?;
+    if let Some(schedule) = &mut context.snapshot_schedule {
+        schedule.written = Some(marker);
+    }
+    info!(
+        "Wrote a snapshot of {} entries to {} in {} ms",
+        report.entries,
+        path.display(),
+        started.elapsed().as_millis()
+    );
+    Ok(Some(report))
+}
+
+/// A value that changes whenever the database is written to: SQLite's data version,
+/// bumped by commits of other connections, and the rows changed through the
+/// context's own connection. Timestamps would miss writes within their resolution.
+fn change_marker(context: &mut AppContext) -> Result<(i64, i64)> {
+    diesel::select((
+        sql::<BigInt>("(SELECT data_version FROM pragma_data_version())"),
+        sql::<BigInt>("total_changes()"),
+    ))
+    .get_result(&mut context.db_conn)
+    .inspect_err(|_| context.metrics.record_db_error())
+    .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{ImageFormat, Rgb, RgbImage};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        core::{get_blurhash_with_cache, initialize_and_connect_db},
+        options::ContextOptions,
+    };
+
+    fn write_bitmap(path: &Path, rgb: [u8; 3]) {
+        RgbImage::from_pixel(4, 4, Rgb(rgb))
+            .save_with_format(path, ImageFormat::Bmp)
+            .unwrap();
+    }
+
+    #[test]
+    fn writes_within_one_second_reach_the_next_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let path = root.join("snapshot.bin");
+        let options = ContextOptions {
+            snapshot: Some(ScheduledSnapshot {
+                path: path.clone(),
+                interval: Duration::from_secs(3600),
+            }),
+            ..ContextOptions::default()
+        };
+        let conn = initialize_and_connect_db(":memory:").unwrap();
+        let mut context = AppContext::new(conn, vec![root.clone()], options).unwrap();
+        // Runs are driven by the test; the thread ends right away.
+        schedule_snapshots(&mut context, || false).unwrap();
+        let image = root.join("image.bmp");
+
+        write_bitmap(&image, [200, 30, 30]);
+        let first = get_blurhash_with_cache(&mut context, &image).unwrap();
+        assert!(run_scheduled_snapshot(&mut context).unwrap().is_some());
+        assert!(run_scheduled_snapshot(&mut context).unwrap().is_none());
+
+        write_bitmap(&image, [30, 30, 200]);
+        let mtime = fs::metadata(&image).unwrap().modified().unwrap();
+        File::options()
+            .write(true)
+            .open(&image)
+            .unwrap()
+            .set_modified(mtime + Duration::from_secs(10))
+            .unwrap();
+        let second = get_blurhash_with_cache(&mut context, &image).unwrap();
+        assert_ne!(first.blurhash, second.blurhash);
+        assert!(run_scheduled_snapshot(&mut context).unwrap().is_some());
+
+        let snapshot = Snapshot::open(&path).unwrap();
+        assert_eq!(
+            snapshot.get("image.bmp").map(|entry| entry.blurhash),
+            Some(second.blurhash.as_str())
+        );
+    }
+}
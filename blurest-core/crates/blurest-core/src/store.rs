@@ -0,0 +1,196 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context as AnyhowContext, Result, anyhow};
+use log::warn;
+
+use crate::{
+    core::{
+        AppContext, BatchItem, BlurhashData, CacheLookup, compute_blurhash, lookup_blurhash,
+        skip_unsupported, store_blurhash,
+    },
+    maintenance::{run_scheduled_maintenance, schedule_maintenance},
+    options::{ContextOptions, RequestOptions},
+    pool::JobPriority,
+    snapshot::{run_scheduled_snapshot, schedule_snapshots},
+};
+
+/// Thread-safe handle to an [`AppContext`], cheap to clone and share between threads.
+///
+/// The context is locked only while the cache is read or written; reading and
+/// decoding images happens without the lock, so concurrent callers do not serialize
+/// on image work.
+#[derive(Clone)]
+pub struct CacheStore {
+    context: Arc<Mutex<AppContext>>,
+}
+
+impl CacheStore {
+    /// Opens (creating and migrating if needed) the database at `database_url` and
+    /// creates a context for `project_roots`, which are canonicalized first.
+    pub fn open<P: AsRef<Path>>(
+        database_url: &str,
+        project_roots: &[P],
+        options: ContextOptions,
+    ) -> Result<Self> {
+        Ok(Self::from_context(AppContext::open(
+            database_url,
+            canonical_roots(project_roots)?,
+            options,
+        )?))
+    }
+
+    /// Opens the project-local cache of the first of `project_roots`, which are
+    /// canonicalized first, like [`AppContext::open_project`]. The cache stays locked
+    /// until the last clone of the store is dropped.
+    pub fn open_project<P: AsRef<Path>>(
+        project_roots: &[P],
+        options: ContextOptions,
+        lock_timeout: Duration,
+    ) -> Result<Self> {
+        Ok(Self::from_context(AppContext::open_project(
+            canonical_roots(project_roots)?,
+            options,
+            lock_timeout,
+        )?))
+    }
+
+    /// Wraps an existing context, starting its scheduled maintenance and snapshots if
+    /// it has any.
+    pub fn from_context(context: AppContext) -> Self {
+        let store = Self {
+            context: Arc::new(Mutex::new(context)),
+        };
+        if let Err(e) = store.schedule_maintenance() {
+            warn!("Failed to start scheduled maintenance: {e:#}");
+        }
+        if let Err(e) = store.schedule_snapshots() {
+            warn!("Failed to start scheduled snapshots: {e:#}");
+        }
+        store
+    }
+
+    /// Gets the blurhash for an image, generating and caching it if needed.
+    pub fn get_blurhash(&self, image_path: &Path) -> Result<BlurhashData> {
+        self.get_blurhash_with_options(image_path, &RequestOptions::default())
+    }
+
+    /// Same as [`CacheStore::get_blurhash`], applying per-call overrides from `request`.
+    pub fn get_blurhash_with_options(
+        &self,
+        image_path: &Path,
+        request: &RequestOptions,
+    ) -> Result<BlurhashData> {
+        let pending =
+            match self.with_context(|context| lookup_blurhash(context, image_path, request))? {
+                CacheLookup::Hit(data) => return Ok(data),
+                CacheLookup::Pending(pending) => *pending,
+            };
+        let computed = compute_blurhash(&pending)?;
+        self.with_context(|context| store_blurhash(context, pending, computed))
+    }
+
+    /// Gets the blurhashes of `paths` on the context's worker pool at
+    /// [`JobPriority::Background`], as many at once as the pool has workers, passing
+    /// each path and its result to `on_result` on this thread as it finishes. Files
+    /// that are not images are skipped, see [`skip_unsupported`].
+    pub fn get_blurhashes(
+        &self,
+        paths: impl IntoIterator<Item = PathBuf>,
+        mut on_result: impl FnMut(PathBuf, Result<BatchItem>),
+    ) -> Result<()> {
+        let pool = self.with_context(|context| Ok(Arc::clone(&context.pool)))?;
+        let store = self.clone();
+        pool.run_each(
+            JobPriority::Background,
+            pool.size(),
+            paths,
+            move |path| {
+                let result =
+                    skip_unsupported(&path, &RequestOptions::default(), store.get_blurhash(&path));
+                (path, result)
+            },
+            |result| match result {
+                Ok((path, result)) => on_result(path, result),
+                Err(e) => warn!("{e:#}"),
+            },
+        )
+    }
+
+    /// Applies new options to the live context, see [`AppContext::reconfigure`], and
+    /// starts or restarts its scheduled maintenance and snapshots to match.
+    pub fn reconfigure(&self, options: ContextOptions) -> Result<()> {
+        self.with_context(|context| context.reconfigure(options))?;
+        self.schedule_maintenance()?;
+        self.schedule_snapshots()
+    }
+
+    /// Replaces the database connection with a fresh one, see [`AppContext::reconnect`].
+    pub fn reconnect(&self) -> Result<()> {
+        self.with_context(AppContext::reconnect)
+    }
+
+    /// Matches the maintenance thread to the context's options. The thread only holds
+    /// a weak reference, so it ends once every clone of the store is dropped.
+    fn schedule_maintenance(&self) -> Result<()> {
+        let weak = Arc::downgrade(&self.context);
+        self.with_context(|context| {
+            schedule_maintenance(context, move || {
+                let Some(context) = weak.upgrade() else {
+                    return false;
+                };
+                let Ok(mut context) = context.lock() else {
+                    return false;
+                };
+                if let Err(e) = run_scheduled_maintenance(&mut context) {
+                    warn!("Scheduled maintenance failed: {e:#}");
+                }
+                true
+            })
+        })
+    }
+
+    /// Matches the snapshot thread to the context's options, like
+    /// [`CacheStore::schedule_maintenance`].
+    fn schedule_snapshots(&self) -> Result<()> {
+        let weak = Arc::downgrade(&self.context);
+        self.with_context(|context| {
+            schedule_snapshots(context, move || {
+                let Some(context) = weak.upgrade() else {
+                    return false;
+                };
+                let Ok(mut context) = context.lock() else {
+                    return false;
+                };
+                if let Err(e) = run_scheduled_snapshot(&mut context) {
+                    warn!("Writing the snapshot failed: {e:#}");
+                }
+                true
+            })
+        })
+    }
+
+    /// Runs `f` with exclusive access to the context.
+    pub fn with_context<T>(&self, f: impl FnOnce(&mut AppContext) -> Result<T>) -> Result<T> {
+        let mut context = self
+            .context
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire context lock"))?;
+        f(&mut context)
+    }
+}
+
+/// Canonicalizes every project root, naming the first that cannot be resolved.
+fn canonical_roots<P: AsRef<Path>>(project_roots: &[P]) -> Result<Vec<PathBuf>> {
+    project_roots
+        .iter()
+        .map(|root| {
+            let root = root.as_ref();
+            root.canonicalize()
+                .with_context(|| format!("Failed to resolve project root path {root:?}"))
+        })
+        .collect()
+}
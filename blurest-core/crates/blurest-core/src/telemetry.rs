@@ -0,0 +1,156 @@
+//! OpenTelemetry spans for cache lookups and generations.
+//!
+//! With the `otel` feature, every cache hit and every generation is recorded as a span
+//! through the global tracer provider, which applications either install themselves or
+//! set up with [`init_otlp`]. Spans are children of the W3C `traceparent` passed in
+//! [`RequestOptions::traceparent`](crate::options::RequestOptions::traceparent), so
+//! they join the trace of the request that needed the image.
+//!
+//! Without the feature, [`Span`] compiles to nothing.
+
+#[cfg(feature = "otel")]
+use std::sync::Mutex;
+
+#[cfg(feature = "otel")]
+use anyhow::Result;
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    KeyValue, global,
+    propagation::TextMapPropagator,
+    trace::{Span as _, Status, Tracer},
+};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+
+/// Name of the instrumentation scope spans are recorded under.
+#[cfg(feature = "otel")]
+pub const TRACER_NAME: &str = "blurest";
+
+/// Provider installed by [`init_otlp`], kept to flush it on [`shutdown`].
+#[cfg(feature = "otel")]
+static PROVIDER: Mutex<Option<SdkTracerProvider>> = Mutex::new(None);
+
+/// Options for [`init_otlp`].
+#[cfg(feature = "otel")]
+#[derive(Debug, Clone)]
+pub struct OtlpOptions {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`. Defaults to
+    /// the `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` / `OTEL_EXPORTER_OTLP_ENDPOINT`
+    /// environment variables, then to a local collector.
+    pub endpoint: Option<String>,
+    /// `service.name` resource attribute.
+    pub service_name: String,
+}
+
+#[cfg(feature = "otel")]
+impl Default for OtlpOptions {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "blurest".to_string(),
+        }
+    }
+}
+
+/// Installs a global tracer provider exporting spans in batches over OTLP/HTTP.
+///
+/// Only needed when the application does not install a provider of its own. Calling
+/// it again replaces the provider, flushing the previous one.
+#[cfg(feature = "otel")]
+pub fn init_otlp(options: &OtlpOptions) -> Result<()> {
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+
+    let mut exporter = SpanExporter::builder().with_http();
+    if let Some(endpoint) = &options.endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter.build()?)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(options.service_name.clone())
+                .build(),
+        )
+        .build();
+    global::set_tracer_provider(provider.clone());
+
+    let previous = PROVIDER
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to acquire telemetry lock"))?
+        .replace(provider);
+    if let Some(previous) = previous {
+        previous.shutdown()?;
+    }
+    Ok(())
+}
+
+/// Flushes and shuts down the provider installed by [`init_otlp`], if any.
+#[cfg(feature = "otel")]
+pub fn shutdown() -> Result<()> {
+    let provider = PROVIDER
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Failed to acquire telemetry lock"))?
+        .take();
+    if let Some(provider) = provider {
+        provider.shutdown()?;
+    }
+    Ok(())
+}
+
+/// A span in progress, ended when dropped.
+pub(crate) struct Span {
+    #[cfg(feature = "otel")]
+    inner: global::BoxedSpan,
+}
+
+impl Span {
+    /// Starts a span named `name`, as a child of `traceparent` if it is a valid W3C
+    /// trace context header.
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub(crate) fn start(name: &'static str, traceparent: Option<&str>) -> Self {
+        #[cfg(feature = "otel")]
+        {
+            let parent = match traceparent {
+                Some(traceparent) => {
+                    TraceContextPropagator::new().extract(&std::collections::HashMap::from([(
+                        "traceparent".to_string(),
+                        traceparent.to_string(),
+                    )]))
+                }
+                None => opentelemetry::Context::current(),
+            };
+            let inner = global::tracer(TRACER_NAME).start_with_context(name, &parent);
+            Self { inner }
+        }
+        #[cfg(not(feature = "otel"))]
+        Self {}
+    }
+
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub(crate) fn set_str(&mut self, key: &'static str, value: &str) {
+        #[cfg(feature = "otel")]
+        self.inner
+            .set_attribute(KeyValue::new(key, value.to_string()));
+    }
+
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub(crate) fn set_i64(&mut self, key: &'static str, value: i64) {
+        #[cfg(feature = "otel")]
+        self.inner.set_attribute(KeyValue::new(key, value));
+    }
+
+    /// Marks the span as failed with `error`.
+    #[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+    pub(crate) fn set_error(&mut self, error: &anyhow::Error) {
+        #[cfg(feature = "otel")]
+        self.inner.set_status(Status::error(format!("{error:#}")));
+    }
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.inner.end();
+    }
+}
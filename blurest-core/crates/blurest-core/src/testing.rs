@@ -0,0 +1,267 @@
+//! Scaffolding for testing integrations against a real cache, behind the
+//! `test-support` feature.
+//!
+//! ```no_run
+//! use blurest_core::{get_blurhash_with_cache, testing::TestContext};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut test = TestContext::new()?;
+//! let image = test.solid_image("red.png", 32, 16, [255, 0, 0])?;
+//! get_blurhash_with_cache(&mut test.context, &image)?;
+//! test.assert_cached("red.png");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use image::{Rgb, RgbImage};
+use tempfile::TempDir;
+
+use crate::{
+    core::{AppContext, find_cached, initialize_and_connect_db},
+    maintenance::export_entries,
+    models::BlurhashCache,
+    options::ContextOptions,
+};
+
+/// A context over an in-memory database, whose project root is a fresh temporary
+/// directory that is deleted on drop.
+pub struct TestContext {
+    pub context: AppContext,
+    dir: TempDir,
+}
+
+impl TestContext {
+    /// Creates a test context with default options.
+    pub fn new() -> Result<Self> {
+        Self::with_options(ContextOptions::default())
+    }
+
+    /// Creates a test context with `options`.
+    pub fn with_options(options: ContextOptions) -> Result<Self> {
+        let dir = tempfile::tempdir().context("Failed to create a temporary project root")?;
+        let root = dir.path().canonicalize()?;
+        let conn = initialize_and_connect_db(":memory:")?;
+        let context = AppContext::new(conn, vec![root], options)?;
+        Ok(Self { context, dir })
+    }
+
+    /// The project root, in canonical form.
+    pub fn root(&self) -> &Path {
+        &self.context.project_roots[0]
+    }
+
+    /// Path of the temporary directory, as created.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes a single-color image to `name` under the root; see [`write_solid_image`].
+    pub fn solid_image(
+        &self,
+        name: &str,
+        width: u32,
+        height: u32,
+        rgb: [u8; 3],
+    ) -> Result<PathBuf> {
+        let path = self.root().join(name);
+        write_solid_image(&path, width, height, rgb)?;
+        Ok(path)
+    }
+
+    /// Writes a gradient image to `name` under the root; see [`write_gradient_image`].
+    pub fn gradient_image(&self, name: &str, width: u32, height: u32) -> Result<PathBuf> {
+        let path = self.root().join(name);
+        write_gradient_image(&path, width, height)?;
+        Ok(path)
+    }
+
+    /// The cached row stored under `key`, if any.
+    pub fn cached(&mut self, key: &str) -> Result<Option<BlurhashCache>> {
+        find_cached(
+            &mut self.context.db_conn,
+            key,
+            self.context.options.case_insensitive_keys,
+        )
+    }
+
+    /// Every cached key, sorted.
+    pub fn cached_keys(&mut self) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = export_entries(&mut self.context)?
+            .into_iter()
+            .map(|entry| entry.relative_path)
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Panics unless an entry is cached under `key`, returning it.
+    #[track_caller]
+    pub fn assert_cached(&mut self, key: &str) -> BlurhashCache {
+        match self.cached(key) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => panic!(
+                "expected `{key}` to be cached, cached keys: {:?}",
+                self.cached_keys().unwrap_or_default()
+            ),
+            Err(e) => panic!("failed to read the cache: {e:#}"),
+        }
+    }
+
+    /// Panics if an entry is cached under `key`.
+    #[track_caller]
+    pub fn assert_not_cached(&mut self, key: &str) {
+        match self.cached(key) {
+            Ok(None) => {}
+            Ok(Some(entry)) => panic!("expected `{key}` not to be cached, found {entry:?}"),
+            Err(e) => panic!("failed to read the cache: {e:#}"),
+        }
+    }
+}
+
+/// Writes a `width`×`height` image filled with `rgb`, in the format implied by the
+/// extension of `path`. Parent directories are created as needed.
+pub fn write_solid_image(path: &Path, width: u32, height: u32, rgb: [u8; 3]) -> Result<()> {
+    save(path, RgbImage::from_pixel(width, height, Rgb(rgb)))
+}
+
+/// Writes a `width`×`height` image with red increasing left to right and green top
+/// to bottom, in the format implied by the extension of `path`. The same arguments
+/// always produce the same pixels, and so the same blurhash.
+pub fn write_gradient_image(path: &Path, width: u32, height: u32) -> Result<()> {
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        let scale = |value: u32, size: u32| (value * 255 / size.saturating_sub(1).max(1)) as u8;
+        Rgb([scale(x, width), scale(y, height), 128])
+    });
+    save(path, image)
+}
+
+fn save(path: &Path, image: RgbImage) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    image
+        .save(path)
+        .with_context(|| format!("Failed to write fixture image {path:?}"))
+}
+
+#[cfg(all(test, feature = "test-support"))]
+mod tests {
+    use image::GenericImageView;
+
+    use super::*;
+    use crate::{
+        core::{CacheLookup, get_blurhash_with_cache, lookup_blurhash},
+        options::RequestOptions,
+    };
+
+    fn is_hit(test: &mut TestContext, image: &Path) -> bool {
+        let lookup = lookup_blurhash(&mut test.context, image, &RequestOptions::default());
+        matches!(lookup.unwrap(), CacheLookup::Hit(_))
+    }
+
+    #[test]
+    fn contexts_use_a_fresh_root_with_the_given_options() {
+        let test = TestContext::with_options(ContextOptions {
+            components: (3, 2),
+            ..ContextOptions::default()
+        })
+        .unwrap();
+
+        assert_eq!(test.root(), test.dir().canonicalize().unwrap());
+        assert_eq!(std::fs::read_dir(test.root()).unwrap().count(), 0);
+        assert_eq!(test.context.options.components, (3, 2));
+        assert_ne!(TestContext::new().unwrap().root(), test.root());
+    }
+
+    #[test]
+    fn root_is_deleted_on_drop() {
+        let test = TestContext::new().unwrap();
+        let root = test.root().to_path_buf();
+        drop(test);
+
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn solid_images_have_one_color() {
+        let test = TestContext::new().unwrap();
+        let path = test
+            .solid_image("nested/red.png", 5, 3, [255, 0, 0])
+            .unwrap();
+
+        assert_eq!(path, test.root().join("nested/red.png"));
+        let image = image::open(&path).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (5, 3));
+        assert!(image.pixels().all(|pixel| pixel.0 == [255, 0, 0]));
+    }
+
+    #[test]
+    fn gradient_images_are_deterministic() {
+        let test = TestContext::new().unwrap();
+        let first = test.gradient_image("a.png", 8, 4).unwrap();
+        let second = test.gradient_image("b.png", 8, 4).unwrap();
+
+        assert_eq!(
+            std::fs::read(&first).unwrap(),
+            std::fs::read(&second).unwrap()
+        );
+        let image = image::open(&first).unwrap();
+        assert_eq!(image.dimensions(), (8, 4));
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 128, 255]);
+        assert_eq!(image.get_pixel(7, 3).0, [255, 255, 128, 255]);
+    }
+
+    #[test]
+    fn write_functions_create_parent_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let solid = dir.path().join("a/b/solid.bmp");
+        let gradient = dir.path().join("c/gradient.bmp");
+        write_solid_image(&solid, 2, 2, [0, 0, 255]).unwrap();
+        write_gradient_image(&gradient, 2, 2).unwrap();
+
+        assert!(solid.is_file());
+        assert!(gradient.is_file());
+    }
+
+    #[test]
+    fn cache_assertions_follow_lookups() {
+        let mut test = TestContext::new().unwrap();
+        let image = test.gradient_image("photos/hero.png", 16, 8).unwrap();
+        test.assert_not_cached("photos/hero.png");
+        assert!(test.cached_keys().unwrap().is_empty());
+        assert!(!is_hit(&mut test, &image));
+
+        let generated = get_blurhash_with_cache(&mut test.context, &image).unwrap();
+
+        assert!(is_hit(&mut test, &image));
+        let entry = test.assert_cached("photos/hero.png");
+        assert_eq!(entry.blurhash, generated.blurhash);
+        assert_eq!(
+            test.cached("photos/hero.png")
+                .unwrap()
+                .map(|cached| cached.id),
+            Some(entry.id)
+        );
+        assert_eq!(test.cached_keys().unwrap(), ["photos/hero.png"]);
+        let hit = get_blurhash_with_cache(&mut test.context, &image).unwrap();
+        assert_eq!(hit.blurhash, generated.blurhash);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `missing.png` to be cached")]
+    fn assert_cached_panics_on_a_miss() {
+        TestContext::new().unwrap().assert_cached("missing.png");
+    }
+
+    #[test]
+    #[should_panic(expected = "expected `red.png` not to be cached")]
+    fn assert_not_cached_panics_on_a_hit() {
+        let mut test = TestContext::new().unwrap();
+        let image = test.solid_image("red.png", 4, 4, [255, 0, 0]).unwrap();
+        get_blurhash_with_cache(&mut test.context, &image).unwrap();
+        test.assert_not_cached("red.png");
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use diesel::prelude::*;
+
+use crate::{
+    core::{AppContext, get_blurhash_with_options},
+    options::RequestOptions,
+    schema::{blurhash_cache, thumbnail_cache},
+};
+
+/// Returns the raster thumbnail stored for the image at `image_path`, a lossless WebP
+/// [`ContextOptions::thumbnail_size`](crate::ContextOptions::thumbnail_size) pixels on
+/// its longer side.
+///
+/// The image is looked up, and generated if needed, like any other lookup. An entry
+/// cached before thumbnails were enabled, or with a thumbnail of another size, is
+/// regenerated, so the file is decoded once for both its blurhash and its thumbnail.
+pub fn get_thumbnail(context: &mut AppContext, image_path: &Path) -> Result<Vec<u8>> {
+    let size = context
+        .options
+        .thumbnail_size
+        .ok_or_else(|| anyhow!("Thumbnails are disabled; set the `thumbnailSize` option"))?;
+
+    let data = get_blurhash_with_options(context, image_path, &RequestOptions::default())?;
+    if let Some(thumbnail) = stored_thumbnail(context, &data.key, size)? {
+        return Ok(thumbnail);
+    }
+    let request = RequestOptions {
+        force: true,
+        ..RequestOptions::default()
+    };
+    let data = get_blurhash_with_options(context, image_path, &request)?;
+    stored_thumbnail(context, &data.key, size)?
+        .ok_or_else(|| anyhow!("No thumbnail was stored for {}", data.key))
+}
+
+/// Thumbnail of the entry `key` if it was stored at `size`.
+fn stored_thumbnail(context: &mut AppContext, key: &str, size: u32) -> Result<Option<Vec<u8>>> {
+    context
+        .db_conn
+        .transaction(|conn| {
+            let Some(entry_id) = blurhash_cache::table
+                .filter(blurhash_cache::relative_path.eq(key))
+                .select(blurhash_cache::id)
+                .first::<i32>(conn)
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            thumbnail_cache::table
+                .filter(thumbnail_cache::entry_id.eq(entry_id))
+                .filter(thumbnail_cache::size.eq(size as i32))
+                .select(thumbnail_cache::thumbnail)
+                .first::<Vec<u8>>(conn)
+                .optional()
+        })
+        .inspect_err(|_| context.metrics.record_db_error())
+        .map_err(Into::into)
+}
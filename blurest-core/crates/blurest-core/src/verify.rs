@@ -0,0 +1,261 @@
+//! Verification of cached entries against a fresh encode of their files, to catch
+//! corrupted rows, encoder drift after an upgrade or bad imports before users see
+//! wrong placeholders. Nothing is written back; see [`verify_entries`].
+//!
+//! Like lookups, verification has a phase that needs the context,
+//! [`prepare_verification`], and one that does not, [`PendingVerification::run`], so
+//! bindings can decode the images without holding the context.
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use diesel::prelude::*;
+use log::{info, warn};
+
+use crate::{
+    core::{
+        AppContext, CacheLookup, ComputedEntry, ErrorHook, PendingEntry, compute_blurhash,
+        lookup_blurhash,
+    },
+    encode::blurhash_components,
+    keys::{image_key, key_paths},
+    models::BlurhashCache,
+    options::{CacheValidation, RequestOptions, Revalidate},
+    pool::{JobPriority, WorkerPool},
+    schema::blurhash_cache,
+};
+
+/// A cached entry whose recomputed blurhash or dimensions differ, from
+/// [`verify_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationMismatch {
+    /// Cache key of the entry.
+    pub key: String,
+    /// Blurhash and dimensions as cached.
+    pub cached_blurhash: String,
+    pub cached_width: i32,
+    pub cached_height: i32,
+    /// Blurhash and dimensions recomputed from the file.
+    pub blurhash: String,
+    pub width: u32,
+    pub height: u32,
+    /// Whether the content of the file differs from the one cached, in which case
+    /// the entry is merely stale rather than wrong. Always `false` for entries cached
+    /// without a content hash.
+    pub content_changed: bool,
+}
+
+/// Outcome of [`verify_entries`].
+#[derive(Debug, Clone, Default)]
+pub struct VerificationReport {
+    /// Number of entries recomputed and compared.
+    pub checked: usize,
+    /// Number of entries that matched their recomputation.
+    pub matched: usize,
+    /// Entries that did not.
+    pub mismatched: Vec<VerificationMismatch>,
+    /// Keys of the sampled entries whose file no longer exists.
+    pub missing: Vec<String>,
+    /// Keys of the entries that could not be recomputed.
+    pub failed: Vec<String>,
+    /// Number of entries under the prefix that cannot be verified: cropped entries
+    /// and entries keyed by content, which name no file.
+    pub skipped: usize,
+    pub duration: Duration,
+}
+
+/// Recomputes the blurhashes of the cached entries whose key starts with `prefix`, or
+/// of every entry, and reports those that differ from what is stored. With `sample`,
+/// only that many entries, picked at random, are recomputed.
+///
+/// Each entry is encoded with the components of its cached blurhash and its
+/// orientation handling, and otherwise with the context's current settings, on the
+/// context's worker pool at [`JobPriority::Background`]. The cache is left as it is,
+/// stale entries included; look the mismatched keys up with
+/// [`RequestOptions::force`] to regenerate them.
+pub fn verify_entries(
+    context: &mut AppContext,
+    prefix: Option<&str>,
+    sample: Option<usize>,
+) -> Result<VerificationReport> {
+    prepare_verification(context, prefix, sample)?.run()
+}
+
+/// Entries selected by [`prepare_verification`], ready to be recomputed.
+pub struct PendingVerification {
+    entries: Vec<(BlurhashCache, PendingEntry)>,
+    report: VerificationReport,
+    pool: Arc<WorkerPool>,
+    error_hook: Option<ErrorHook>,
+    started: Instant,
+}
+
+/// Selects and looks up the entries [`verify_entries`] recomputes, reading only the
+/// database and file metadata.
+pub fn prepare_verification(
+    context: &mut AppContext,
+    prefix: Option<&str>,
+    sample: Option<usize>,
+) -> Result<PendingVerification> {
+    let started = Instant::now();
+    let rows: Vec<BlurhashCache> = blurhash_cache::table
+        .select(BlurhashCache::as_select())
+        .load(&mut context.db_conn)
+        .inspect_err(|_| context.metrics.record_db_error())?;
+
+    let mut report = VerificationReport::default();
+    let mut rows: Vec<_> = rows
+        .into_iter()
+        .filter(|row| prefix.is_none_or(|prefix| row.relative_path.starts_with(prefix)))
+        .filter_map(|row| {
+            let path = key_paths(&row.relative_path, &context.project_roots)
+                .filter(|_| image_key(&row.relative_path) == row.relative_path);
+            if path.is_none() {
+                report.skipped += 1;
+            }
+            Some((row, path?))
+        })
+        .collect();
+    if let Some(sample) = sample.filter(|&sample| sample < rows.len()) {
+        let random = RandomState::new();
+        rows.sort_by_cached_key(|(row, _)| random.hash_one(&row.relative_path));
+        rows.truncate(sample);
+    }
+
+    // Always recomputes every entry read, without trusting its mtime or content hash.
+    let request = RequestOptions {
+        validation: Some(CacheValidation::Always),
+        revalidate: Some(Revalidate::Blocking),
+        ..RequestOptions::default()
+    };
+    let mut pending = Vec::new();
+    for (row, paths) in rows {
+        let key = row.relative_path.clone();
+        let Some(path) = paths.into_iter().find(|path| path.is_file()) else {
+            report.missing.push(key);
+            continue;
+        };
+        match lookup_blurhash(context, &path, &request) {
+            Ok(CacheLookup::Pending(mut entry)) if entry.relative_key == key => {
+                if let Some(components) = blurhash_components(&row.blurhash) {
+                    entry.components = components;
+                }
+                entry.apply_orientation = row.orientation_applied;
+                entry.cached = None;
+                entry.generators = Vec::new();
+                entry.thumbnail_size = None;
+                pending.push((row, *entry));
+            }
+            Ok(_) => report.skipped += 1,
+            Err(e) => verification_failed(context.error_hook.as_ref(), &mut report, key, &e),
+        }
+    }
+    Ok(PendingVerification {
+        entries: pending,
+        report,
+        pool: Arc::clone(&context.pool),
+        error_hook: context.error_hook.clone(),
+        started,
+    })
+}
+
+impl PendingVerification {
+    /// Recomputes the entries and compares them with the cache. Needs no context, so
+    /// it can run on any thread.
+    pub fn run(self) -> Result<VerificationReport> {
+        let Self {
+            entries,
+            mut report,
+            pool,
+            error_hook,
+            started,
+        } = self;
+        let error_hook = error_hook.as_ref();
+        pool.run_each(
+            JobPriority::Background,
+            pool.size(),
+            entries,
+            |(row, entry)| {
+                let computed = compute_blurhash(&entry);
+                (row, computed)
+            },
+            |result| match result {
+                Ok((row, Ok(computed))) => compare_entry(error_hook, &mut report, row, computed),
+                Ok((row, Err(e))) => {
+                    verification_failed(error_hook, &mut report, row.relative_path, &e)
+                }
+                Err(e) => warn!("Verification job failed: {e:#}"),
+            },
+        )?;
+
+        report.duration = started.elapsed();
+        info!(
+            "Verified {} cached entries in {} ms: {} matched, {} mismatched, {} missing, {} failed",
+            report.checked,
+            report.duration.as_millis(),
+            report.matched,
+            report.mismatched.len(),
+            report.missing.len(),
+            report.failed.len()
+        );
+        Ok(report)
+    }
+}
+
+/// Compares the recomputation of an entry with its cached `row`.
+fn compare_entry(
+    error_hook: Option<&ErrorHook>,
+    report: &mut VerificationReport,
+    row: BlurhashCache,
+    computed: ComputedEntry,
+) {
+    let ComputedEntry::Generated {
+        xxhash,
+        blurhash,
+        width,
+        height,
+        ..
+    } = computed
+    else {
+        let error = anyhow!("Entry was not recomputed");
+        return verification_failed(error_hook, report, row.relative_path, &error);
+    };
+    report.checked += 1;
+    if blurhash == row.blurhash
+        && i64::from(width) == i64::from(row.width)
+        && i64::from(height) == i64::from(row.height)
+    {
+        report.matched += 1;
+        return;
+    }
+    let key = row.relative_path;
+    warn!(path = key.as_str(); "Cached entry of {key} does not match its file");
+    report.mismatched.push(VerificationMismatch {
+        content_changed: !row.xxhash.is_empty() && xxhash != row.xxhash,
+        key,
+        cached_blurhash: row.blurhash,
+        cached_width: row.width,
+        cached_height: row.height,
+        blurhash,
+        width,
+        height,
+    });
+}
+
+/// Logs and reports an entry [`verify_entries`] could not recompute.
+fn verification_failed(
+    error_hook: Option<&ErrorHook>,
+    report: &mut VerificationReport,
+    key: String,
+    error: &anyhow::Error,
+) {
+    warn!(path = key.as_str(); "Verification of {key} failed: {error:#}");
+    if let Some(hook) = error_hook {
+        hook(&key, error);
+    }
+    report.failed.push(key);
+}
@@ -0,0 +1,302 @@
+//! Decoding through libvips, behind the `vips` feature, for installations that already
+//! ship it, e.g. alongside sharp. libvips shrinks JPEG, WebP and HEIF files while
+//! loading them, and reads formats the `image` crate does not, such as HEIC or JPEG XL.
+//!
+//! The library is linked through its C API and found with pkg-config at build time.
+
+use std::{
+    ffi::{CStr, c_char, c_int, c_void},
+    ptr, slice,
+    sync::OnceLock,
+};
+
+use anyhow::{Result, anyhow};
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+use crate::encode::{DecodedImage, decode_image_with_metadata, icc_color_space, icc_profile_name};
+
+#[repr(C)]
+struct VipsImage {
+    _private: [u8; 0],
+}
+
+// Values of the libvips enums used below.
+const VIPS_INTERPRETATION_B_W: c_int = 1;
+const VIPS_INTERPRETATION_CMYK: c_int = 15;
+const VIPS_INTERPRETATION_SRGB: c_int = 22;
+const VIPS_INTERPRETATION_GREY16: c_int = 26;
+const VIPS_FORMAT_UCHAR: c_int = 0;
+const VIPS_FORMAT_CHAR: c_int = 1;
+const VIPS_FORMAT_USHORT: c_int = 2;
+const VIPS_FORMAT_SHORT: c_int = 3;
+const VIPS_FORMAT_DOUBLE: c_int = 8;
+const VIPS_SIZE_DOWN: c_int = 2;
+
+unsafe extern "C" {
+    fn vips_init(argv0: *const c_char) -> c_int;
+    fn vips_error_buffer() -> *const c_char;
+    fn vips_error_clear();
+    fn vips_image_new_from_buffer(
+        buf: *const c_void,
+        len: usize,
+        option_string: *const c_char,
+        ...
+    ) -> *mut VipsImage;
+    fn vips_thumbnail_buffer(
+        buf: *mut c_void,
+        len: usize,
+        out: *mut *mut VipsImage,
+        width: c_int,
+        ...
+    ) -> c_int;
+    fn vips_colourspace(
+        input: *mut VipsImage,
+        out: *mut *mut VipsImage,
+        space: c_int,
+        ...
+    ) -> c_int;
+    fn vips_cast(input: *mut VipsImage, out: *mut *mut VipsImage, format: c_int, ...) -> c_int;
+    fn vips_image_write_to_memory(image: *mut VipsImage, size: *mut usize) -> *mut c_void;
+    fn vips_image_get_width(image: *const VipsImage) -> c_int;
+    fn vips_image_get_height(image: *const VipsImage) -> c_int;
+    fn vips_image_get_bands(image: *const VipsImage) -> c_int;
+    fn vips_image_get_format(image: *const VipsImage) -> c_int;
+    fn vips_image_get_interpretation(image: *const VipsImage) -> c_int;
+    fn vips_image_get_typeof(image: *const VipsImage, name: *const c_char) -> usize;
+    fn vips_image_get_int(image: *const VipsImage, name: *const c_char, out: *mut c_int) -> c_int;
+    fn vips_image_get_string(
+        image: *const VipsImage,
+        name: *const c_char,
+        out: *mut *const c_char,
+    ) -> c_int;
+    fn vips_image_get_blob(
+        image: *const VipsImage,
+        name: *const c_char,
+        data: *mut *const c_void,
+        length: *mut usize,
+    ) -> c_int;
+    fn g_object_unref(object: *mut c_void);
+    fn g_free(mem: *mut c_void);
+}
+
+/// An owned reference to a libvips image, released when dropped.
+struct Image(*mut VipsImage);
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        // SAFETY: `Image` holds the only reference it was handed.
+        unsafe { g_object_unref(self.0.cast()) }
+    }
+}
+
+impl Image {
+    /// Wraps the output of an operation returning 0 on success.
+    fn from_operation(
+        what: &str,
+        operation: impl FnOnce(*mut *mut VipsImage) -> c_int,
+    ) -> Result<Self> {
+        let mut out = ptr::null_mut();
+        if operation(&mut out) != 0 || out.is_null() {
+            return Err(vips_error(what));
+        }
+        Ok(Self(out))
+    }
+
+    fn size(&self) -> (u32, u32) {
+        // SAFETY: `self.0` is a valid image for the lifetime of `self`.
+        let (width, height) =
+            unsafe { (vips_image_get_width(self.0), vips_image_get_height(self.0)) };
+        (width.max(0) as u32, height.max(0) as u32)
+    }
+
+    /// Whether the loader set the metadata field `name`.
+    fn has_field(&self, name: &CStr) -> bool {
+        // SAFETY: `self.0` is a valid image and `name` is NUL-terminated.
+        unsafe { vips_image_get_typeof(self.0, name.as_ptr()) != 0 }
+    }
+
+    fn int_field(&self, name: &CStr) -> Option<i32> {
+        let mut value = 0;
+        // SAFETY: as above; `value` outlives the call.
+        (self.has_field(name)
+            && unsafe { vips_image_get_int(self.0, name.as_ptr(), &mut value) } == 0)
+            .then_some(value)
+    }
+
+    fn string_field(&self, name: &CStr) -> Option<String> {
+        let mut value = ptr::null();
+        // SAFETY: as above; the returned string belongs to the image.
+        let found = self.has_field(name)
+            && unsafe { vips_image_get_string(self.0, name.as_ptr(), &mut value) } == 0
+            && !value.is_null();
+        found.then(|| {
+            unsafe { CStr::from_ptr(value) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    fn blob_field(&self, name: &CStr) -> Option<Vec<u8>> {
+        let (mut data, mut length) = (ptr::null(), 0);
+        // SAFETY: as above; the blob belongs to the image and is copied out.
+        let found = self.has_field(name)
+            && unsafe { vips_image_get_blob(self.0, name.as_ptr(), &mut data, &mut length) } == 0
+            && !data.is_null();
+        found.then(|| unsafe { slice::from_raw_parts(data.cast::<u8>(), length) }.to_vec())
+    }
+
+    /// The pixels as 8-bit sRGB, with alpha if the image has it.
+    fn to_image(&self) -> Result<DynamicImage> {
+        // SAFETY: every pointer passed is a valid image or output slot, and the
+        // variadic option lists are terminated with NULL.
+        let srgb = Self::from_operation("Failed to convert to sRGB", |out| unsafe {
+            vips_colourspace(self.0, out, VIPS_INTERPRETATION_SRGB, ptr::null::<c_char>())
+        })?;
+        let pixels = Self::from_operation("Failed to convert to 8 bits", |out| unsafe {
+            vips_cast(srgb.0, out, VIPS_FORMAT_UCHAR, ptr::null::<c_char>())
+        })?;
+        let mut size = 0;
+        // SAFETY: `pixels` is valid; the returned buffer is copied, then freed.
+        let data = unsafe { vips_image_write_to_memory(pixels.0, &mut size) };
+        if data.is_null() {
+            return Err(vips_error("Failed to decode pixels"));
+        }
+        let bytes = unsafe { slice::from_raw_parts(data.cast::<u8>(), size) }.to_vec();
+        unsafe { g_free(data) };
+
+        let (width, height) = pixels.size();
+        // SAFETY: `pixels` is valid.
+        let image = match unsafe { vips_image_get_bands(pixels.0) } {
+            3 => RgbImage::from_raw(width, height, bytes).map(DynamicImage::ImageRgb8),
+            4 => RgbaImage::from_raw(width, height, bytes).map(DynamicImage::ImageRgba8),
+            bands => return Err(anyhow!("Unsupported sRGB image with {bands} bands")),
+        };
+        image.ok_or_else(|| anyhow!("libvips decoded fewer pixels than {width}x{height}"))
+    }
+}
+
+/// The libvips error messages so far, clearing them.
+fn vips_error(what: &str) -> anyhow::Error {
+    // SAFETY: libvips always returns a NUL-terminated buffer.
+    let message = unsafe { CStr::from_ptr(vips_error_buffer()) }
+        .to_string_lossy()
+        .trim()
+        .to_string();
+    unsafe { vips_error_clear() };
+    anyhow!("{what}: {message}")
+}
+
+fn init() -> Result<()> {
+    static INITIALIZED: OnceLock<bool> = OnceLock::new();
+    // SAFETY: called once, before any other libvips function.
+    let initialized = *INITIALIZED.get_or_init(|| unsafe { vips_init(c"blurest".as_ptr()) } == 0);
+    if initialized {
+        Ok(())
+    } else {
+        Err(anyhow!("Failed to initialize libvips"))
+    }
+}
+
+/// Format name reported in results for a libvips loader, e.g. `"jpeg"` for
+/// `jpegload_buffer`.
+fn loader_format(loader: &str) -> Option<&'static str> {
+    let name = loader.split("load").next()?;
+    [
+        "jpeg", "png", "webp", "gif", "tiff", "heif", "jxl", "jp2k", "svg", "pdf",
+    ]
+    .into_iter()
+    .find(|&format| format == name)
+}
+
+/// Decodes `file_bytes` like [`decode_image_with_metadata`], using libvips. Given
+/// `min_size`, the image is shrunk while loading so its longer side is that long, or
+/// left at its size if smaller; [`DecodedImage::full_size`] still reports the
+/// dimensions as stored. Files libvips does not recognize are decoded by the `image`
+/// crate.
+pub fn decode_vips_with_metadata(file_bytes: &[u8], min_size: Option<u32>) -> Result<DecodedImage> {
+    init()?;
+    // Loading only reads the header; pixels are decoded on demand.
+    // SAFETY: `file_bytes` outlives the image, which is dropped before returning.
+    let header = unsafe {
+        vips_image_new_from_buffer(
+            file_bytes.as_ptr().cast(),
+            file_bytes.len(),
+            c"".as_ptr(),
+            ptr::null::<c_char>(),
+        )
+    };
+    if header.is_null() {
+        // SAFETY: only clears the message of the failed load.
+        unsafe { vips_error_clear() };
+        return decode_image_with_metadata(file_bytes);
+    }
+    let header = Image(header);
+
+    let full_size = header.size();
+    let image = match min_size {
+        Some(min_size) => {
+            let size = min_size.min(i32::MAX as u32) as c_int;
+            // SAFETY: the buffer is only read; options are name/value pairs ending
+            // in NULL. `no_rotate` keeps the pixels as stored, as the orientation is
+            // applied later.
+            let thumbnail = Image::from_operation("Failed to shrink image", |out| unsafe {
+                vips_thumbnail_buffer(
+                    file_bytes.as_ptr().cast_mut().cast(),
+                    file_bytes.len(),
+                    out,
+                    size,
+                    c"height".as_ptr(),
+                    size,
+                    c"size".as_ptr(),
+                    VIPS_SIZE_DOWN,
+                    c"no_rotate".as_ptr(),
+                    1 as c_int,
+                    ptr::null::<c_char>(),
+                )
+            })?;
+            thumbnail.to_image()?
+        }
+        None => header.to_image()?,
+    };
+
+    // SAFETY: `header` is valid.
+    let (format, interpretation) = unsafe {
+        (
+            vips_image_get_format(header.0),
+            vips_image_get_interpretation(header.0),
+        )
+    };
+    let bit_depth = match format {
+        VIPS_FORMAT_UCHAR | VIPS_FORMAT_CHAR => 8,
+        VIPS_FORMAT_USHORT | VIPS_FORMAT_SHORT => 16,
+        VIPS_FORMAT_DOUBLE => 64,
+        _ => 32,
+    };
+    let icc_profile = header.blob_field(c"icc-profile-data");
+    let color_space = icc_profile
+        .as_deref()
+        .and_then(icc_color_space)
+        .unwrap_or_else(|| {
+            match interpretation {
+                VIPS_INTERPRETATION_B_W | VIPS_INTERPRETATION_GREY16 => "GRAY",
+                VIPS_INTERPRETATION_CMYK => "CMYK",
+                _ => "RGB",
+            }
+            .to_string()
+        });
+    Ok(DecodedImage {
+        image,
+        full_size,
+        orientation: header
+            .int_field(c"orientation")
+            .filter(|orientation| (1..=8).contains(orientation))
+            .unwrap_or(1) as u8,
+        format: header
+            .string_field(c"vips-loader")
+            .as_deref()
+            .and_then(loader_format),
+        bit_depth,
+        color_space,
+        color_profile: icc_profile.as_deref().and_then(icc_profile_name),
+    })
+}
@@ -0,0 +1,44 @@
+#ifndef BLUREST_H
+#define BLUREST_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Opaque cache handle, safe to use from any thread. */
+typedef struct BlurestCache BlurestCache;
+
+/* A generated blurhash with the dimensions of the source image. */
+typedef struct BlurestResult {
+    char *blurhash;
+    int32_t width;
+    int32_t height;
+} BlurestResult;
+
+/* Opens (creating and migrating if needed) the cache database for the given
+ * project roots. Returns NULL on failure; see blurest_last_error(). */
+BlurestCache *blurest_init(const char *database_url, const char *const *roots, size_t root_count);
+
+/* Gets the blurhash for an image, generating and caching it if needed.
+ * Returns NULL on failure; release results with blurest_result_free(). */
+BlurestResult *blurest_get(const BlurestCache *cache, const char *path);
+
+/* Releases a result of blurest_get(). Accepts NULL. */
+void blurest_result_free(BlurestResult *result);
+
+/* Closes the database and releases the handle. Accepts NULL. */
+void blurest_clear(BlurestCache *cache);
+
+/* Message of the error of the last call on the calling thread, or NULL if
+ * it succeeded. Owned by the library and valid until the next call on this
+ * thread. */
+const char *blurest_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BLUREST_H */
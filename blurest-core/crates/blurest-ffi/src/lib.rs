@@ -0,0 +1,170 @@
+//! # blurest-ffi
+//!
+//! C ABI over [`CacheStore`], so applications outside Node.js (Swift, C, C++) can link
+//! the caching engine as `libblurest`. The declarations are in `include/blurest.h`.
+//!
+//! ## Conventions
+//!
+//! - Strings are NUL-terminated UTF-8, borrowed for the duration of the call.
+//! - A cache handle from [`blurest_init`] may be used from any thread and must be
+//!   released with [`blurest_clear`].
+//! - Functions that fail return `NULL` and record a message, available on the same
+//!   thread through [`blurest_last_error`] until the next call. Every other call
+//!   clears it, so `NULL` there means the last call succeeded.
+//! - Results from [`blurest_get`] are owned by the caller and released with
+//!   [`blurest_result_free`].
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char},
+    path::PathBuf,
+    ptr,
+};
+
+use anyhow::{Result, anyhow};
+use blurest_core::{CacheStore, ContextOptions, error::error_code};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque cache handle.
+pub struct BlurestCache {
+    store: CacheStore,
+}
+
+/// A generated blurhash with the dimensions of the source image.
+#[repr(C)]
+pub struct BlurestResult {
+    pub blurhash: *mut c_char,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Opens the cache database at `database_url` for `root_count` project roots.
+///
+/// Returns `NULL` on failure.
+///
+/// # Safety
+///
+/// `database_url` must be a valid C string and `roots` must point to `root_count`
+/// valid C strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blurest_init(
+    database_url: *const c_char,
+    roots: *const *const c_char,
+    root_count: usize,
+) -> *mut BlurestCache {
+    clear_last_error();
+    let result = (|| {
+        let database_url = unsafe { c_str(database_url) }?;
+        if roots.is_null() {
+            return Err(anyhow!("Project roots must not be null"));
+        }
+        let roots = (0..root_count)
+            .map(|index| unsafe { c_str(*roots.add(index)) }.map(PathBuf::from))
+            .collect::<Result<Vec<_>>>()?;
+        let store = CacheStore::open(database_url, &roots, ContextOptions::default())?;
+        Ok(Box::into_raw(Box::new(BlurestCache { store })))
+    })();
+    result.unwrap_or_else(fail)
+}
+
+/// Gets the blurhash for the image at `path`, generating and caching it if needed.
+///
+/// Returns `NULL` on failure.
+///
+/// # Safety
+///
+/// `cache` must be a live handle from [`blurest_init`] and `path` a valid C string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blurest_get(
+    cache: *const BlurestCache,
+    path: *const c_char,
+) -> *mut BlurestResult {
+    clear_last_error();
+    let result = (|| {
+        let cache = unsafe { cache.as_ref() }.ok_or_else(|| anyhow!("Cache must not be null"))?;
+        let path = unsafe { c_str(path) }?;
+        let data = cache.store.get_blurhash(path.as_ref())?;
+        Ok(Box::into_raw(Box::new(BlurestResult {
+            blurhash: CString::new(data.blurhash)?.into_raw(),
+            width: data.width,
+            height: data.height,
+        })))
+    })();
+    result.unwrap_or_else(fail)
+}
+
+/// Releases a result returned by [`blurest_get`]. Accepts `NULL`.
+///
+/// # Safety
+///
+/// `result` must come from [`blurest_get`] and not have been released already.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blurest_result_free(result: *mut BlurestResult) {
+    clear_last_error();
+    if result.is_null() {
+        return;
+    }
+    let result = unsafe { Box::from_raw(result) };
+    if !result.blurhash.is_null() {
+        drop(unsafe { CString::from_raw(result.blurhash) });
+    }
+}
+
+/// Closes the database and releases the handle. Accepts `NULL`.
+///
+/// # Safety
+///
+/// `cache` must come from [`blurest_init`], not have been released already, and not
+/// be in use on another thread.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blurest_clear(cache: *mut BlurestCache) {
+    clear_last_error();
+    if !cache.is_null() {
+        drop(unsafe { Box::from_raw(cache) });
+    }
+}
+
+/// Message of the error of the last call on the calling thread, or `NULL` if that
+/// call succeeded.
+///
+/// The string is owned by the library and valid until the next call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn blurest_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Forgets the last error of this thread, at the start of every exported function
+/// but [`blurest_last_error`].
+fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Records `error` as the last error of this thread and returns `NULL`.
+fn fail<T>(error: anyhow::Error) -> *mut T {
+    let message = match error_code(&error) {
+        Some(code) => format!("{code}: {error:#}"),
+        None => format!("{error:#}"),
+    };
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    ptr::null_mut()
+}
+
+/// Borrows a C string as UTF-8.
+///
+/// # Safety
+///
+/// `value` must be null or a valid C string that outlives the returned reference.
+unsafe fn c_str<'a>(value: *const c_char) -> Result<&'a str> {
+    if value.is_null() {
+        return Err(anyhow!("String argument must not be null"));
+    }
+    Ok(unsafe { CStr::from_ptr(value) }.to_str()?)
+}
@@ -0,0 +1,168 @@
+//! # blurest-wasm
+//!
+//! The blurhash encoder of `blurest-core` compiled to WebAssembly, for edge runtimes
+//! such as Cloudflare Workers that cannot load native Node.js addons.
+//!
+//! There is no cache: every call decodes and encodes. Output is identical to the
+//! native module for the same image and components.
+//!
+//! ```bash
+//! wasm-pack build crates/blurest-wasm --target web
+//! ```
+//!
+//! ```javascript
+//! import init, { encode, averageColor } from "./pkg/blurest_wasm.js";
+//!
+//! await init();
+//! const result = encode(new Uint8Array(await response.arrayBuffer()));
+//! console.log(result.blurhash, result.width, result.height, averageColor(result.blurhash));
+//! ```
+
+use blurest_core::{
+    css::blurhash_to_css,
+    encode::{average_color, calculate_blurhash, color_to_hex, decode_rgba, encode_rgba},
+    options::DEFAULT_COMPONENTS,
+};
+use wasm_bindgen::{Clamped, prelude::*};
+
+/// A generated blurhash with the dimensions of the source image.
+#[wasm_bindgen]
+pub struct Blurhash {
+    blurhash: String,
+    width: u32,
+    height: u32,
+}
+
+#[wasm_bindgen]
+impl Blurhash {
+    #[wasm_bindgen(getter)]
+    pub fn blurhash(&self) -> String {
+        self.blurhash.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// A decoded blurhash, shaped for `new ImageData(data, width, height)`.
+#[wasm_bindgen]
+pub struct DecodedImage {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+#[wasm_bindgen]
+impl DecodedImage {
+    /// Straight-alpha RGBA8 pixels, all opaque, as a `Uint8ClampedArray`.
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Clamped<Vec<u8>> {
+        Clamped(self.data.clone())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// Decodes an encoded image file and generates its blurhash.
+///
+/// Components default to the same 4x3 as the native module.
+#[wasm_bindgen]
+pub fn encode(
+    file_bytes: &[u8],
+    components_x: Option<u32>,
+    components_y: Option<u32>,
+) -> Result<Blurhash, JsError> {
+    let components = components(components_x, components_y);
+    let (blurhash, width, height) = calculate_blurhash(components, file_bytes).map_err(js_error)?;
+    Ok(Blurhash {
+        blurhash,
+        width,
+        height,
+    })
+}
+
+/// Generates the blurhash of an RGBA8 pixel buffer, e.g. from `ImageData.data`.
+#[wasm_bindgen(js_name = encodeRgba)]
+pub fn encode_rgba_pixels(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    components_x: Option<u32>,
+    components_y: Option<u32>,
+) -> Result<String, JsError> {
+    let components = components(components_x, components_y);
+    encode_rgba(components, width, height, rgba).map_err(js_error)
+}
+
+/// Decodes a blurhash into an RGBA8 pixel buffer of `width` by `height`.
+#[wasm_bindgen]
+pub fn decode(
+    blurhash: &str,
+    width: u32,
+    height: u32,
+    punch: Option<f32>,
+) -> Result<Vec<u8>, JsError> {
+    decode_rgba(blurhash, width, height, punch.unwrap_or(1.0)).map_err(js_error)
+}
+
+/// Decodes a blurhash like [`decode`], shaped for `new ImageData(...)`.
+#[wasm_bindgen(js_name = decodeImageData)]
+pub fn decode_image_data(
+    blurhash: &str,
+    width: u32,
+    height: u32,
+    punch: Option<f32>,
+) -> Result<DecodedImage, JsError> {
+    let data = decode(blurhash, width, height, punch)?;
+    Ok(DecodedImage {
+        data,
+        width,
+        height,
+    })
+}
+
+/// Returns the average color of a blurhash as a CSS hex color, or `undefined` if
+/// the blurhash is malformed.
+#[wasm_bindgen(js_name = averageColor)]
+pub fn average_color_hex(blurhash: &str) -> Option<String> {
+    average_color(blurhash).map(color_to_hex)
+}
+
+/// Approximates a blurhash with CSS backgrounds for an element with the aspect ratio
+/// of a `width` by `height` image, returned as declarations for a `style` attribute.
+#[wasm_bindgen(js_name = blurhashToCss)]
+pub fn blurhash_to_css_declarations(
+    blurhash: &str,
+    width: u32,
+    height: u32,
+) -> Result<String, JsError> {
+    blurhash_to_css(blurhash, width, height)
+        .map(|background| background.to_declarations())
+        .map_err(js_error)
+}
+
+fn components(x: Option<u32>, y: Option<u32>) -> (u32, u32) {
+    (
+        x.unwrap_or(DEFAULT_COMPONENTS.0),
+        y.unwrap_or(DEFAULT_COMPONENTS.1),
+    )
+}
+
+fn js_error(error: anyhow::Error) -> JsError {
+    JsError::new(&format!("{error:#}"))
+}
@@ -3,38 +3,1126 @@ import fs from "node:fs";
 
 import * as addon from "./load.cjs";
 
+/**
+ * Sources `getManyIfCachedAsync` looks up between yields to the event loop.
+ */
+const SYNC_BATCH_CHUNK_SIZE = 250;
+
 /**
  * Core plugin options for initializing the Blurhash cache module.
  */
 export interface BlurhashCoreOptions {
   /**
-   * Database connection string
+   * Database connection string
+   */
+  databasePath: string;
+
+  /**
+   * Absolute path to the project root directory, or several of them.
+   * Used to resolve relative image paths; with several roots, relative paths
+   * resolve against the first one and cache keys are derived from the most
+   * specific root containing the image.
+   */
+  projectRoot: string | string[];
+
+  /**
+   * Number of worker threads used by the async and batch APIs.
+   * Defaults to the available parallelism of the process.
+   */
+  workers?: number;
+
+  /**
+   * Preset for `componentsX`/`componentsY`, `encodeSize` and `previewSize`:
+   * `"low"` is 3x3 components from images shrunk to 32 pixels with 16-pixel
+   * previews, `"medium"` 4x3, 64 and 32, `"high"` 6x5, 128 and 64. Any of
+   * those options given as well overrides the preset.
+   */
+  quality?: Quality;
+
+  /**
+   * Horizontal blurhash components, 1 to 9. Defaults to 4.
+   */
+  componentsX?: number;
+
+  /**
+   * Vertical blurhash components, 1 to 9. Defaults to 3.
+   */
+  componentsY?: number;
+
+  /**
+   * Longer side, in pixels, images are shrunk to before the blurhash is
+   * encoded, much faster on large images. Defaults to encoding them as decoded.
+   */
+  encodeSize?: number;
+
+  /**
+   * Settings for the images matching a pattern, e.g. fewer components for PNG
+   * icons or a larger `encodeSize` for SVGs. Every matching rule applies, in
+   * order, so later rules override earlier ones. Defaults to none.
+   */
+  encodeRules?: EncodeRule[];
+
+  /**
+   * Maximum number of images decoded at the same time across all APIs.
+   * Defaults to no limit.
+   */
+  maxConcurrentDecodes?: number;
+
+  /**
+   * Budget in bytes for the estimated decoded size of all images being decoded
+   * at once. Decodes that would exceed it wait. Defaults to no limit.
+   */
+  decodeMemoryBudget?: number;
+
+  /**
+   * Library images are decoded with. `"auto"` picks the fastest one built in;
+   * `"turbojpeg"` and `"vips"` need the matching build features. Defaults to
+   * `"auto"`.
+   */
+  decoder?: Decoder;
+
+  /**
+   * Largest difference in milliseconds between a file's mtime and the cached
+   * mtime that still counts as unchanged. Use e.g. `1000` for filesystems or
+   * copies (archives, rsync) with second granularity. Defaults to `0`.
+   */
+  mtimeToleranceMs?: number;
+
+  /**
+   * Times a file read or stat failing with a transient error (`EBUSY`,
+   * `EAGAIN`, `ESTALE`, common on network filesystems and while files are
+   * being written) is retried, waiting 10 ms, then 20 ms, and so on.
+   * Defaults to `3`.
+   */
+  ioRetries?: number;
+
+  /**
+   * How cached entries are validated. `"auto"` trusts a matching mtime and
+   * compares content hashes on mismatch; `"mtime"` regenerates on any mtime
+   * mismatch without hashing; `"hash"` ignores mtimes and compares content
+   * hashes on every lookup; `"always"` regenerates every time; `"never"`
+   * never validates and serves any cached entry. Defaults to `"auto"`.
+   */
+  validation?: CacheValidation;
+
+  /**
+   * What lookups do with a cached entry that fails `validation`, e.g. because
+   * its file changed. `"blocking"` regenerates it before answering;
+   * `"background"` answers with the cached entry at once and regenerates it on
+   * the worker pool, so later lookups see the fresh one
+   * (stale-while-revalidate); `"never"` answers with the cached entry and
+   * keeps it until the image is invalidated. Defaults to `"blocking"`.
+   */
+  revalidate?: RevalidatePolicy;
+
+  /**
+   * How images outside every project root are cached. `"error"` skips them,
+   * `"absolute"` keys them by absolute path and `"hash"` by content hash.
+   * Defaults to `"error"`.
+   */
+  outsideRoot?: OutsideRootPolicy;
+
+  /**
+   * Match cache keys regardless of ASCII case, for case-insensitive
+   * filesystems (macOS, Windows) where one file can be referenced with
+   * differing case. Defaults to `false`.
+   */
+  caseInsensitiveKeys?: boolean;
+
+  /**
+   * How paths that are not valid UTF-8 are keyed. `"error"` rejects them,
+   * `"lossy"` replaces invalid bytes (distinct names may collide) and
+   * `"bytes"` hex-encodes the raw path. Defaults to `"error"`.
+   */
+  nonUtf8Keys?: NonUtf8Policy;
+
+  /**
+   * Reject paths that escape the project roots through `..` or symlinks,
+   * regardless of `outsideRoot`. Defaults to `false`.
+   */
+  sandbox?: boolean;
+
+  /**
+   * File extensions that may be processed, case-insensitive, e.g.
+   * `["jpg", "png"]`. Defaults to any.
+   */
+  allowedExtensions?: string[];
+
+  /**
+   * Image MIME types, detected from the file header, that may be processed,
+   * e.g. `["image/jpeg", "image/png"]`. Defaults to any.
+   */
+  allowedMimeTypes?: string[];
+
+  /**
+   * Also generate and cache a tiny base64 PNG of each blurhash, returned as
+   * `placeholder: "blur"` and `blurDataURL` so results can be spread into
+   * `next/image`. Defaults to `false`.
+   */
+  blurDataUrl?: boolean;
+
+  /**
+   * Cache the WebP previews of `output: "webp"` calls with their entries
+   * instead of rendering them on every call. Defaults to `false`.
+   */
+  persistWebpPreviews?: boolean;
+
+  /**
+   * Also return the RGB pixels of each blurhash decoded at 8x8 as
+   * `microPixels`, cached with the entry and included in manifests as
+   * `pixels`, so clients can upscale them with CSS instead of shipping a
+   * blurhash decoder. Defaults to `false`.
+   */
+  microPixels?: boolean;
+
+  /**
+   * Longer side, in pixels, of blur data URLs and WebP previews, between 1 and
+   * 256. Larger previews look closer to the image but weigh more. Can be
+   * overridden per call. Defaults to 32.
+   */
+  previewSize?: number;
+
+  /**
+   * Longer side, in pixels, of a raster thumbnail of the image stored with each
+   * generated entry, between 1 and 256, for `getThumbnail`. Thumbnails are
+   * made in the same decode as the blurhash. None are stored by default.
+   */
+  thumbnailSize?: number;
+
+  /**
+   * Rotate and flip images according to their EXIF orientation before
+   * encoding, so placeholders and `width`/`height` match how browsers display
+   * them. Defaults to `false`.
+   */
+  applyOrientation?: boolean;
+
+  /**
+   * Target widths of responsive renditions, e.g. `[640, 1280, 1920]`.
+   * Results then include each rendition's height and a `sizes` hint; see
+   * `buildSrcset`. Widths above the image's own width are capped to it.
+   */
+  renditionWidths?: number[];
+
+  /**
+   * Run maintenance on a timer thread while the cache is alive, for servers
+   * that never call `maintain`. `null` in `updateConfig` turns it off. Off by
+   * default.
+   */
+  scheduledMaintenance?: ScheduledMaintenanceOptions | null;
+
+  /**
+   * Write a snapshot of the cache on a timer thread while it is alive, for
+   * read-only processes using `readSnapshot`. `null` in `updateConfig` turns
+   * it off. Off by default.
+   */
+  snapshot?: SnapshotOptions | null;
+
+  /**
+   * When initializing, check every cached entry against its file and
+   * regenerate those that changed while the application was down, so the
+   * first requests after a deploy do not all pay for it. `true` decodes on
+   * every worker of the pool, a number on at most that many. `initialize`
+   * blocks until done, `initializeAsync` resolves once done. Defaults to
+   * `false`.
+   */
+  revalidateOnInit?: boolean | number;
+
+  /**
+   * Maximum level of log records emitted by the native module. Applies to
+   * the whole process.
+   */
+  logLevel?: LogLevel;
+
+  /**
+   * Write native log records to stderr (or `logFile`), as plain text or as
+   * JSON lines with `path`, `cache_status` and `duration_ms` fields for log
+   * aggregation. Applies to the whole process. Defaults to no output.
+   */
+  logFormat?: LogFormat;
+
+  /**
+   * Append native log records to this file instead of stderr.
+   */
+  logFile?: string;
+
+  /**
+   * Name of the native cache context. Instances with different names keep
+   * separate databases, roots and worker pools in the same process.
+   * Defaults to `"default"`.
+   */
+  cache?: string;
+
+  /**
+   * Which JavaScript threads see the native context. `"process"` shares it
+   * with the main thread and every `worker_threads` worker; `"worker"` keeps
+   * it private to the initializing thread, shadowing a shared context of the
+   * same name there, and closes it when the thread exits. Defaults to
+   * `"process"`.
+   */
+  scope?: ContextScope;
+}
+
+/**
+ * Maximum level of log records emitted by the native module.
+ */
+export type LogLevel = "off" | "error" | "warn" | "info" | "debug" | "trace";
+
+/**
+ * Output format of native log records.
+ */
+export type LogFormat = "text" | "json";
+
+/**
+ * Visibility of a native context across `worker_threads`.
+ */
+export type ContextScope = "process" | "worker";
+
+/**
+ * Strategy for validating cached entries against their files.
+ */
+export type CacheValidation = "auto" | "mtime" | "hash" | "always" | "never";
+
+/**
+ * What lookups do with cached entries that fail validation.
+ */
+export type RevalidatePolicy = "blocking" | "background" | "never";
+
+/**
+ * Cache key strategy for images outside every project root.
+ */
+export type OutsideRootPolicy = "error" | "absolute" | "hash";
+
+/**
+ * Cache key strategy for paths that are not valid UTF-8.
+ */
+export type NonUtf8Policy = "error" | "lossy" | "bytes";
+
+/**
+ * Library images are decoded with.
+ */
+export type Decoder = "auto" | "image" | "turbojpeg" | "vips";
+
+/**
+ * Preset trading placeholder detail for size and speed.
+ */
+export type Quality = "low" | "medium" | "high";
+
+/**
+ * Encode settings for the images matching `pattern`, see
+ * {@link BlurhashCoreOptions.encodeRules}.
+ */
+export interface EncodeRule {
+  /**
+   * An extension such as `"png"` or `".png"`, matched in any case, or a glob
+   * of cache keys such as `"icons/**"`, where `*` also matches `/`.
+   */
+  pattern: string;
+  /** Horizontal blurhash components, 1 to 9 */
+  componentsX?: number;
+  /** Vertical blurhash components, 1 to 9 */
+  componentsY?: number;
+  /** Longer side images are shrunk to before encoding */
+  encodeSize?: number;
+  /** Longer side of generated previews */
+  previewSize?: number;
+}
+
+/**
+ * Tunables passed to the native module at initialization.
+ */
+export interface NativeContextOptions {
+  /** Number of worker threads used by the async and batch APIs */
+  workers?: number;
+  /** Preset for the components, encode size and preview size */
+  quality?: Quality;
+  /** Horizontal blurhash components, 1 to 9 */
+  componentsX?: number;
+  /** Vertical blurhash components, 1 to 9 */
+  componentsY?: number;
+  /** Longer side images are shrunk to before encoding */
+  encodeSize?: number;
+  /** Settings for the images matching a pattern */
+  encodeRules?: EncodeRule[];
+  /** Maximum number of simultaneous image decodes across all APIs, `null` for no limit */
+  maxConcurrentDecodes?: number | null;
+  /** Budget in bytes for the estimated decoded size of in-flight decodes, `null` for no limit */
+  decodeMemoryBudget?: number | null;
+  /** Library images are decoded with */
+  decoder?: Decoder;
+  /** Largest mtime difference in milliseconds still treated as unchanged */
+  mtimeToleranceMs?: number;
+  /** Retries of file reads and stats failing with a transient error */
+  ioRetries?: number;
+  /** How cached entries are validated */
+  validation?: CacheValidation;
+  /** What lookups do with cached entries that fail validation */
+  revalidate?: RevalidatePolicy;
+  /** Cache key strategy for images outside every project root */
+  outsideRoot?: OutsideRootPolicy;
+  /** Match cache keys regardless of ASCII case */
+  caseInsensitiveKeys?: boolean;
+  /** Cache key strategy for paths that are not valid UTF-8 */
+  nonUtf8Keys?: NonUtf8Policy;
+  /** Reject paths that escape the project roots */
+  sandbox?: boolean;
+  /** File extensions that may be processed */
+  allowedExtensions?: string[];
+  /** Detected image MIME types that may be processed */
+  allowedMimeTypes?: string[];
+  /** Also return cached `next/image` blur placeholders */
+  blurDataUrl?: boolean;
+  /** Cache the WebP previews of `output: "webp"` calls */
+  persistWebpPreviews?: boolean;
+  /** Also return the blurhash decoded to 8x8 RGB pixels */
+  microPixels?: boolean;
+  /** Longer side of blur data URLs and WebP previews in pixels */
+  previewSize?: number;
+  /** Longer side of the raster thumbnails stored with entries in pixels */
+  thumbnailSize?: number;
+  /** Apply EXIF orientations before encoding */
+  applyOrientation?: boolean;
+  /** Target widths of responsive renditions to report on results */
+  renditionWidths?: number[];
+  /** Maintenance run on a timer thread, `null` for none */
+  scheduledMaintenance?: ScheduledMaintenanceOptions | null;
+  /** Snapshot written on a timer thread, `null` for none */
+  snapshot?: SnapshotOptions | null;
+  /** Revalidate changed entries after connecting, on this many threads */
+  revalidateOnInit?: boolean | number;
+  /** Maximum level of native log records, process-wide */
+  logLevel?: LogLevel;
+  /** Native log output format, process-wide */
+  logFormat?: LogFormat;
+  /** File to append native log records to instead of stderr */
+  logFile?: string;
+  /** Name to register the context under, defaults to `"default"` */
+  cache?: string;
+  /** Threads that see the context, defaults to `"process"` */
+  scope?: ContextScope;
+}
+
+/**
+ * Connection retries of `initialize_blurhash_cache_async`.
+ */
+export interface ConnectRetryOptions {
+  /** Further connection attempts after a failure, defaults to 0 */
+  connectRetries?: number;
+  /** Delay before the first retry in milliseconds, doubled for each further one, defaults to 100 */
+  connectRetryDelayMs?: number;
+}
+
+/**
+ * Options of `BlurhashCore.forProject`.
+ */
+export interface ProjectLockOptions {
+  /**
+   * Milliseconds to wait for another process to release the cache, blocking
+   * the event loop meanwhile. Defaults to 0, throwing at once
+   */
+  lockTimeoutMs?: number;
+}
+
+/**
+ * Cache counters of a context, from `get_stats`.
+ */
+export interface RuntimeStats {
+  /** Lookups answered from the cache */
+  hits: number;
+  /** Images generated because they had no cached entry */
+  misses: number;
+  /** Images regenerated because their cached entry was outdated */
+  staleRefreshes: number;
+  /** Failed database queries */
+  dbErrors: number;
+  /** Images decoded */
+  decodeCount: number;
+  /** Time spent decoding and encoding, in seconds */
+  decodeSeconds: number;
+  /** Share of lookups answered from the cache, `null` before the first lookup */
+  hitRatio: number | null;
+}
+
+/**
+ * Job counts of a context's worker pool, from `get_pool_stats`.
+ */
+export interface PoolStats {
+  /** Number of worker threads */
+  size: number;
+  /** Jobs waiting for a worker */
+  queued: number;
+  /** Jobs the workers are running */
+  running: number;
+  /** Jobs that returned since the pool started, including those that panicked */
+  completed: number;
+  /** Jobs that panicked since the pool started */
+  panicked: number;
+}
+
+/**
+ * A cached image found by `findSimilar`.
+ */
+export interface SimilarImage {
+  /** Cache key of the image */
+  key: string;
+  /** Bits in which the perceptual hashes differ, 0 for the same picture */
+  distance: number;
+}
+
+/**
+ * Cached images with identical content, from `findDuplicates`.
+ */
+export interface DuplicateGroup {
+  /** Content hash the images share */
+  xxhash: string;
+  /** Cache keys of the images, sorted */
+  keys: string[];
+  /** Size of the files in bytes, present with `verifySize` */
+  fileSize?: number;
+}
+
+/**
+ * Entries under a prefix by whether they match their file, from
+ * `stalenessReport`.
+ */
+export interface StalenessReport {
+  /** Entries whose file has the cached mtime */
+  fresh: number;
+  /** Entries whose file was modified since it was cached */
+  stale: number;
+  /** Entries whose file no longer exists */
+  missing: number;
+}
+
+/**
+ * A cached entry that differs from its recomputation, from `verify`.
+ */
+export interface VerificationMismatch {
+  /** Cache key of the entry */
+  key: string;
+  /** Blurhash and dimensions as cached */
+  cachedBlurhash: string;
+  cachedWidth: number;
+  cachedHeight: number;
+  /** Blurhash and dimensions recomputed from the file */
+  blurhash: string;
+  width: number;
+  height: number;
+  /** Whether the file changed since it was cached, making the entry stale rather than wrong */
+  contentChanged: boolean;
+}
+
+/**
+ * Outcome of `verify`.
+ */
+export interface VerificationReport {
+  /** Entries recomputed and compared */
+  checked: number;
+  /** Entries that matched their recomputation */
+  matched: number;
+  /** Entries that did not */
+  mismatched: VerificationMismatch[];
+  /** Keys of the entries whose file no longer exists */
+  missing: string[];
+  /** Keys of the entries that could not be recomputed */
+  failed: string[];
+  /** Cropped and content-addressed entries, which cannot be verified */
+  skipped: number;
+  durationMs: number;
+}
+
+/**
+ * Entry counts and sizes of one group of a `CacheComposition`.
+ */
+export interface CompositionGroup {
+  /** Cached entries, crops included */
+  entries: number;
+  /** Total size of the files on disk in bytes, each counted once */
+  fileSize: number;
+  /** Entries whose file was not found, e.g. deleted or keyed by content */
+  missingFiles: number;
+  /** Average dimensions of the cached images in pixels */
+  averageWidth: number;
+  averageHeight: number;
+}
+
+/**
+ * Breakdown of a cache from `getComposition`.
+ */
+export interface CacheComposition {
+  /** Groups by lowercase file extension, `""` for keys without one */
+  byExtension: Record<string, CompositionGroup>;
+  /**
+   * Groups by the first directory of the key: `"."` for images directly in a
+   * project root, `""` for absolute and content keys
+   */
+  byDirectory: Record<string, CompositionGroup>;
+}
+
+/**
+ * Steps of `maintain`; each is skipped unless set.
+ */
+export interface MaintenanceOptions extends Pick<CallOptions, "cache"> {
+  /** Remove entries whose image no longer exists */
+  pruneOrphans?: boolean;
+  /** Remove entries not written within this many milliseconds */
+  pruneExpired?: number;
+  /** Rebuild the database file to reclaim the space of removed entries */
+  vacuum?: boolean;
+  /** Refresh the statistics of SQLite's query planner */
+  analyze?: boolean;
+}
+
+/**
+ * Maintenance a cache runs on its own. Each run first stores the background
+ * refreshes that finished, then removes entries and analyzes as selected.
+ */
+export interface ScheduledMaintenanceOptions {
+  /** Milliseconds between runs */
+  intervalMs: number;
+  /**
+   * Up to this many milliseconds are added at random to each interval, so
+   * processes sharing a database do not run at once. Defaults to a tenth of
+   * `intervalMs`
+   */
+  jitterMs?: number;
+  /** Remove entries whose image no longer exists, defaults to `true` */
+  pruneOrphans?: boolean;
+  /** Remove entries not written within this many milliseconds */
+  pruneExpired?: number;
+  /** Refresh the statistics of SQLite's query planner, defaults to `true` */
+  analyze?: boolean;
+}
+
+/**
+ * Snapshot a cache writes on its own. Runs where no entry changed since the
+ * previous snapshot are skipped.
+ */
+export interface SnapshotOptions {
+  /** File the snapshot is written to, replaced on each run */
+  path: string;
+  /** Milliseconds between runs */
+  intervalMs: number;
+}
+
+/**
+ * Report returned by `writeSnapshot`.
+ */
+export interface SnapshotReport {
+  /** Number of entries written */
+  entries: number;
+  /** Size of the snapshot in bytes */
+  bytes: number;
+}
+
+/**
+ * Entry of a snapshot, returned by `readSnapshot`.
+ */
+export interface SnapshotEntry {
+  blurhash: string;
+  width: number;
+  height: number;
+}
+
+/**
+ * Report returned by `maintain`.
+ */
+export interface MaintenanceReport {
+  /** Cache keys of the entries removed because their image is gone */
+  orphans: string[];
+  /** Cache keys of the entries removed because of their age */
+  expired: string[];
+  vacuumed: boolean;
+  analyzed: boolean;
+  /** Database size in bytes before maintenance */
+  sizeBefore: number;
+  /** Database size in bytes after maintenance */
+  sizeAfter: number;
+  durationMs: number;
+}
+
+/**
+ * Report returned by `repair`.
+ */
+export interface RepairReport {
+  /** Number of entries checked */
+  checked: number;
+  /** Cache keys of the invalid entries regenerated from their file */
+  regenerated: string[];
+  /** Cache keys of the invalid entries deleted because they could not be regenerated */
+  deleted: string[];
+  durationMs: number;
+}
+
+/**
+ * Build information of the native module, from `getVersionInfo`.
+ */
+export interface VersionInfo {
+  /** Version of the native module */
+  version: string;
+  /** Optional cargo features it was built with, e.g. `"otel"` or `"vips"` */
+  features: string[];
+  /**
+   * Encoder version stored with cached entries; entries of other versions are
+   * regenerated on their next lookup
+   */
+  algoVersion: number;
+  /** Version of the database schema the module migrates to */
+  schemaVersion: number;
+  /** Image formats it decodes, e.g. `"jpeg"`, `"webp"` or `"avif"` */
+  formats: string[];
+}
+
+/**
+ * Outcome of one check of a `HealthReport`.
+ */
+export interface HealthCheckResult {
+  ok: boolean;
+  /** Why the check failed */
+  error?: string;
+  durationMs: number;
+}
+
+/**
+ * Readiness of a context, from `health_check`.
+ */
+export interface HealthReport {
+  /** Whether every check passed */
+  healthy: boolean;
+  /** Why no checks ran, e.g. the context is not initialized */
+  error?: string;
+  /** The cache table answers a query */
+  database?: HealthCheckResult;
+  /** Each project root can be listed */
+  projectRoots?: (HealthCheckResult & { path: string })[];
+  /** A tiny in-memory image encodes and decodes back to its color */
+  encoder?: HealthCheckResult;
+}
+
+/**
+ * Settings a context was created with by `initialize_from_config`.
+ */
+export interface ConfigFileSettings {
+  /** Database path, resolved against the config file's directory */
+  databaseUrl: string;
+  /** Canonical project roots */
+  projectRoots: string[];
+}
+
+/**
+ * Options accepted by every native lookup function.
+ */
+export interface CallOptions {
+  /** Name of the context to use, defaults to `"default"` */
+  cache?: string;
+  /**
+   * Root to derive the cache key against for this call only, instead of the
+   * root configured at initialization. Relative image paths are resolved
+   * against it.
+   */
+  projectRoot?: string;
+  /** Validation strategy for this call instead of the configured one */
+  validation?: CacheValidation;
+  /**
+   * W3C `traceparent` of the request this call serves. With a module built
+   * with the `otel` feature, the spans of this call join that trace.
+   */
+  traceparent?: string;
+  /**
+   * Region of the image to encode instead of the full frame, so the
+   * placeholder matches a cropped display. Results report the region's
+   * dimensions; each crop is cached separately.
+   */
+  crop?: CropRegion;
+  /**
+   * Width-to-height ratio of the rendition the placeholder is for. The image
+   * (or `crop` region) is cropped to it around `focalPoint` before encoding,
+   * and each rendition is cached separately.
+   */
+  aspectRatio?: number;
+  /** Point to keep in view when cropping to `aspectRatio`, defaults to the center */
+  focalPoint?: FocalPoint;
+  /** Rendition widths for this call instead of the configured ones */
+  renditionWidths?: number[];
+  /**
+   * Regenerate the placeholder and overwrite the cached entry even when it is
+   * current, e.g. after a tool changed the pixels without changing the bytes
+   * or modification time the cache checks.
+   */
+  force?: boolean;
+  /**
+   * Representation to return in addition to the blurhash. `"dataUri"` adds a
+   * tiny PNG data URI of the blurhash as `blurDataURL`, cached with the entry,
+   * so clients can show it without decoding the blurhash themselves.
+   * `"webp"` adds the same image as a lossy WebP `Buffer` in `webp`, for
+   * previews inlined into HTML (lossless in builds without the `libwebp`
+   * feature).
+   */
+  output?: PlaceholderOutput;
+  /** Longer side of the data URI or WebP preview in pixels, up to 256 */
+  previewSize?: number;
+  /**
+   * Abort a generation that runs longer than this many milliseconds, with
+   * error code `TIMEOUT`. Checked between the read, decode, crop and encode
+   * stages, so a stage in progress finishes first.
+   */
+  timeoutMs?: number;
+  /**
+   * Return results serialized as JSON strings instead of objects, with `webp`
+   * as a base64 string. Batches resolve with one string for the whole array,
+   * which is much cheaper to hand over than thousands of objects.
+   */
+  json?: boolean;
+}
+
+/**
+ * Representation of the placeholder returned in addition to the blurhash.
+ */
+export type PlaceholderOutput = "blurhash" | "dataUri" | "webp";
+
+/**
+ * Rectangle of an image in pixels, from its top-left corner.
+ */
+export interface CropRegion {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+/**
+ * Position in an image as fractions of its width and height, each between 0 and 1.
+ */
+export interface FocalPoint {
+  fx: number;
+  fy: number;
+}
+
+/**
+ * Options for `init_telemetry`.
+ */
+export interface TelemetryOptions {
+  /** OTLP/HTTP traces endpoint, defaults to `OTEL_EXPORTER_OTLP_ENDPOINT` */
+  endpoint?: string;
+  /** `service.name` of the exported spans, defaults to `"blurest"` */
+  serviceName?: string;
+}
+
+/**
+ * Result of a generation scheduled by `get_blurhash_cached_sync` on a miss.
+ */
+export type BlurhashCompletion = BlurhashResult & {
+  /** Image path as passed to `get_blurhash_cached_sync` */
+  path: string;
+};
+
+/**
+ * Result of `get_many_if_cached`, keyed by image path as passed.
+ */
+export interface CachedLookups {
+  /** Results of images with a valid cached entry */
+  hits: Record<string, BlurhashSuccessResult>;
+  /** Images without a valid cached entry, in the order they were passed */
+  misses: string[];
+  /** Images that could not be looked up, e.g. missing files */
+  errors: Record<string, BlurhashErrorResult>;
+}
+
+/**
+ * Options for `emit_manifest`.
+ */
+export interface EmitManifestOptions {
+  /** Name of the context to use, defaults to `"default"` */
+  cache?: string;
+  /** Also write the manifest to this path */
+  outFile?: string;
+  /** Clear the manifest after emitting it, e.g. at the end of each watch build */
+  reset?: boolean;
+}
+
+/**
+ * Options for `shutdown`.
+ */
+export interface ShutdownOptions {
+  /** Name of the context to shut down, defaults to `"default"` */
+  cache?: string;
+  /** Longest time to wait for pending work, defaults to no limit */
+  timeoutMs?: number;
+}
+
+/**
+ * Options for `finalize_session`.
+ */
+export interface FinalizeSessionOptions {
+  /** Name of the context to use, defaults to `"default"` */
+  cache?: string;
+  /** Also write the session manifest to this path */
+  outFile?: string;
+}
+
+/**
+ * Build manifest written by `emit_manifest` and `finalize_session`, with keys in sorted order.
+ */
+export interface BlurhashManifest {
+  version: 1;
+  images: Record<
+    string,
+    {
+      blurhash: string;
+      width: number;
+      height: number;
+      /** Base64 of the 8x8 RGB pixels, when `microPixels` is enabled */
+      pixels?: string;
+    }
+  >;
+}
+
+/**
+ * Queue priority for jobs running on the native worker pool.
+ * Higher priorities are always started first.
+ */
+export type JobPriority = "interactive" | "normal" | "background";
+
+/**
+ * Options for the async and batch native functions.
+ */
+export interface AsyncJobOptions extends CallOptions {
+  /** Queue priority, defaults to `"normal"` */
+  priority?: JobPriority;
+}
+
+/**
+ * Uncompressed pixels passed to `BlurhashCore.getBlurhashFromBitmap`, such as
+ * an Electron `nativeImage.toBitmap()` capture.
+ */
+export interface BlurhashBitmap {
+  /** Four bytes per pixel, row by row from the top */
+  data: Uint8Array;
+  width: number;
+  height: number;
+  /** Bytes from one row to the next, defaults to `width * 4` */
+  rowStride?: number;
+  /** Channel order, defaults to `"bgra"` as returned by `toBitmap()` */
+  format?: "bgra" | "rgba";
+}
+
+/**
+ * One result yielded by `BlurhashCore.streamBatch`.
+ */
+export interface BatchStreamItem {
+  src: string;
+  /** Position of `src` in the sources passed to `streamBatch` */
+  index: number;
+  /** `null` if the source was skipped, like `processImage` */
+  result: BlurhashResult | null;
+}
+
+/**
+ * Success result type for `get_blurhash` function.
+ */
+export interface BlurhashSuccessResult {
+  success: true;
+  /** Cache key of the image, also its entry in the build manifest */
+  key: string;
+  /**
+   * Key derived from the requested path: relative to the containing project
+   * root, before matching stored keys regardless of case and without the
+   * suffixes of a cropped entry. Compare it with `key` to see which cache
+   * entry a lookup used.
+   */
+  relativeKey: string;
+  blurhash: string;
+  width: number;
+  height: number;
+  /** Present when `blurDataUrl` is enabled or `output` is `"dataUri"` */
+  placeholder?: "blur";
+  /**
+   * Tiny base64 PNG of the blurhash, present when `blurDataUrl` is enabled or
+   * `output` is `"dataUri"`
+   */
+  blurDataURL?: string;
+  /** Tiny WebP of the blurhash, present when `output` is `"webp"` */
+  webp?: Buffer;
+  /**
+   * RGB pixels of the blurhash decoded at 8x8, row by row (192 bytes),
+   * present when `microPixels` is enabled
+   */
+  microPixels?: Buffer;
+  /**
+   * EXIF orientation of the file, 1 (upright) to 8; absent for entries cached
+   * before orientations were recorded
+   */
+  orientation?: number;
+  /**
+   * Whether the orientation was applied before encoding, making `width` and
+   * `height` the displayed dimensions
+   */
+  orientationApplied: boolean;
+  /** Width of the pixels as stored in the file */
+  rawWidth: number;
+  /** Height of the pixels as stored in the file */
+  rawHeight: number;
+  /** Width as displayed, with the orientation applied */
+  displayWidth: number;
+  /** Height as displayed, with the orientation applied */
+  displayHeight: number;
+  /**
+   * Whether the image, or its cropped region, has transparent pixels; absent
+   * for entries cached before transparency was recorded
+   */
+  hasAlpha?: boolean;
+  /**
+   * Container format detected from the file contents, e.g. `"jpeg"`, `"png"`
+   * or `"webp"`; absent for entries cached before formats were recorded
    */
-  databasePath: string;
-
+  format?: string;
+  /** Bits per channel of the file, e.g. 8 or 16; absent with `format` */
+  bitDepth?: number;
+  /**
+   * Difference hash of the encoded image as 16 hex digits; images that look
+   * alike differ in few bits. Absent for entries cached before perceptual
+   * hashes were recorded
+   */
+  perceptualHash?: string;
+  /**
+   * Color space of the file, e.g. `"RGB"`, `"GRAY"` or `"CMYK"`. Absent for
+   * entries cached before color spaces were recorded
+   */
+  colorSpace?: string;
+  /**
+   * Name of the ICC profile embedded in the file, e.g. `"Display P3"` or
+   * `"Adobe RGB (1998)"`; wide-gamut profiles look different on sRGB displays.
+   * Absent for untagged files, which are usually sRGB
+   */
+  colorProfile?: string;
+  /** Conditions that did not stop the lookup; absent when there are none */
+  warnings?: BlurhashWarning[];
+  /**
+   * Text color for captions overlaid on the image, whichever of black and
+   * white has the higher WCAG contrast with its average color
+   */
+  overlayColor?: "#000" | "#fff";
+  /** Renditions by ascending width, present with `renditionWidths` */
+  renditions?: Rendition[];
   /**
-   * Absolute path to the project root directory.
-   * Used to resolve relative image paths.
+   * `sizes` attribute filling the viewport up to the widest rendition,
+   * present with `renditionWidths`
    */
-  projectRoot: string;
+  sizes?: string;
 }
 
 /**
- * Success result type for `get_blurhash` function.
+ * Dimensions of one responsive rendition of an image.
  */
-export interface BlurhashSuccessResult {
-  success: true;
-  blurhash: string;
+export interface Rendition {
+  width: number;
+  height: number;
+}
+
+/**
+ * CSS backgrounds approximating a blurhash: one horizontal gradient per row of
+ * a small grid, stacked top to bottom.
+ */
+export interface CssPlaceholder {
+  backgroundImage: string;
+  backgroundPosition: string;
+  backgroundSize: string;
+  backgroundRepeat: string;
+  /** All of the above as CSS declarations, e.g. for a `style` attribute */
+  css: string;
+}
+
+/**
+ * Options of `decodeBlurhash`.
+ */
+export interface DecodeOptions {
+  /** Width in pixels, at most 2048 */
+  width: number;
+  /** Height in pixels, at most 2048 */
+  height: number;
+  /** Contrast factor, defaults to `1` */
+  punch?: number;
+}
+
+/**
+ * A decoded blurhash, shaped for `new ImageData(data, width, height)`.
+ */
+export interface DecodedImageData {
+  /** RGBA pixels with straight alpha, all opaque */
+  data: Uint8ClampedArray;
   width: number;
   height: number;
 }
 
+/**
+ * Non-fatal condition noticed while looking up an image.
+ */
+export interface BlurhashWarning {
+  /**
+   * `STALE_ENTRY`: an outdated cached entry was served under `revalidate`;
+   * `MTIME_REGRESSION`: the file is older than its cached entry;
+   * `LARGE_IMAGE`: the image has more than 50 megapixels;
+   * `UNSUPPORTED_FILE`: a batch skipped a file that is not an image
+   */
+  code: "STALE_ENTRY" | "MTIME_REGRESSION" | "LARGE_IMAGE" | "UNSUPPORTED_FILE";
+  message: string;
+}
+
 /**
  * Error result type for `get_blurhash` function.
  */
 export interface BlurhashErrorResult {
   success: false;
   error: string;
+  /** Machine-readable code, for errors that have one */
+  code?: BlurhashErrorCode;
+  /**
+   * Set by batches for files that are not images, which are skipped with an
+   * `UNSUPPORTED_FILE` warning rather than reported as errors
+   */
+  skipped?: true;
+  /** Why the file was skipped */
+  warnings?: BlurhashWarning[];
+}
+
+/**
+ * Codes of errors that callers may want to tell apart. Compare against
+ * `ErrorCodes` rather than error messages, which change between versions.
+ */
+export type BlurhashErrorCode =
+  | "PATH_ESCAPES_ROOT"
+  | "EXTENSION_NOT_ALLOWED"
+  | "MIME_TYPE_NOT_ALLOWED"
+  | "DECODE_PANIC"
+  | "CROP_OUT_OF_BOUNDS"
+  | "TIMEOUT";
+
+/**
+ * Every error code results can carry, mapped to itself, as exported by the
+ * native module's error table.
+ *
+ * ```ts
+ * if (!result.success && result.code === ErrorCodes.TIMEOUT) retryLater(src);
+ * ```
+ */
+export const ErrorCodes: { readonly [Code in BlurhashErrorCode]: Code } =
+  Object.freeze(addon.ErrorCodes);
+
+/**
+ * Error passed to the handler of `setErrorHandler`.
+ */
+export interface BlurhashErrorEvent {
+  /** Machine-readable code, for errors that have one */
+  code?: BlurhashErrorCode;
+  message: string;
+  /** Image path as passed, or the cache key for background refreshes */
+  path?: string;
+  /** Name of the context the error happened in */
+  cache: string;
+  /**
+   * `"lookup"` for error results, `"refresh"` for background refreshes and
+   * `"maintenance"` for scheduled maintenance runs, which have no `path`
+   */
+  origin: "lookup" | "refresh" | "maintenance";
 }
 
 /**
@@ -42,6 +1130,39 @@ export interface BlurhashErrorResult {
  */
 export type BlurhashResult = BlurhashSuccessResult | BlurhashErrorResult;
 
+/**
+ * Timing statistics of one benchmarked operation, in milliseconds.
+ */
+export interface BenchmarkTiming {
+  meanMs: number;
+  minMs: number;
+  maxMs: number;
+}
+
+/**
+ * Report returned by `benchmark`.
+ */
+export interface BenchmarkReport {
+  iterations: number;
+  width: number;
+  height: number;
+  fileSize: number;
+  /** Full uncached generation: read, hash, decode and encode */
+  cold: BenchmarkTiming;
+  /** Cached lookup of an up-to-date entry */
+  warm: BenchmarkTiming;
+  /** Individual stages of the cold path */
+  stages: {
+    read: BenchmarkTiming;
+    hash: BenchmarkTiming;
+    decode: BenchmarkTiming;
+    toRgba: BenchmarkTiming;
+    encode: BenchmarkTiming;
+  };
+  /** Decoding with each backend built in, by name, e.g. `image` and `turbojpeg` */
+  decoders: Record<string, BenchmarkTiming>;
+}
+
 /**
  * Parsed image source information.
  */
@@ -70,33 +1191,632 @@ export interface FileValidationResult {
 declare module "./load.cjs" {
   /**
    * Initialize the Blurhash cache system. Must be called before all other functions.
-   * @param databasePath Database connection string
-   * @param projectRoot Project root directory path
+   * Omitted arguments and options fall back to `BLUREST_*` environment variables.
+   * @param databasePath Database connection string, defaults to `BLUREST_DATABASE_URL`
+   * @param projectRoot Project root directory path, or several of them, defaults to
+   * `BLUREST_PROJECT_ROOT`
+   * @param options Optional tunables
    * @returns `true` if initialization succeeds, otherwise throws an error
    */
   function initialize_blurhash_cache(
-    databasePath: string,
-    projectRoot: string
+    databasePath?: string,
+    projectRoot?: string | string[],
+    options?: NativeContextOptions
   ): boolean;
 
+  /**
+   * Like `initialize_blurhash_cache`, but connects to the database and runs
+   * migrations off the event loop, retrying failed connections.
+   * @param databasePath Database connection string, defaults to `BLUREST_DATABASE_URL`
+   * @param projectRoot Project root directory path, or several of them, defaults to
+   * `BLUREST_PROJECT_ROOT`
+   * @param options Optional tunables and connection retries
+   * @returns A promise resolving to `true` once initialized, or rejecting with the
+   *   last connection error
+   */
+  function initialize_blurhash_cache_async(
+    databasePath?: string,
+    projectRoot?: string | string[],
+    options?: NativeContextOptions & ConnectRetryOptions
+  ): Promise<true>;
+
+  /**
+   * Initialize the Blurhash cache system from a `blurest.config.json` or
+   * `blurest.config.toml` file, as shared with the `blurest` CLI, and `BLUREST_*`
+   * environment variables, which take precedence over the file.
+   * @param configPath Config file path, defaults to `BLUREST_CONFIG`, then to one
+   * in the working directory
+   * @param options Optional tunables, taking precedence over the file
+   * @returns The database path and project roots the context was created with
+   */
+  function initialize_from_config(
+    configPath?: string,
+    options?: NativeContextOptions
+  ): ConfigFileSettings;
+
+  /**
+   * Initialize the Blurhash cache system on a database in
+   * `<projectRoot>/.blurest/`, locked against other processes while the
+   * context lives.
+   * @param projectRoot Project root directory path, or several of them,
+   * defaults to `BLUREST_PROJECT_ROOT`, then to the working directory
+   * @param options Optional tunables and how long to wait for the lock
+   * @returns The database path and project roots the context was created with
+   */
+  function initialize_project_cache(
+    projectRoot?: string | string[],
+    options?: NativeContextOptions & ProjectLockOptions
+  ): ConfigFileSettings;
+
   /**
    * Generate or retrieve cached blurhash, width and height for the specified image.
    * @param imagePath Image file path (can be absolute or relative to projectRoot)
+   * @param options Optional call options
    * @returns An object containing blurhash data or error information
    */
-  function get_blurhash(imagePath: string): BlurhashResult;
+  function get_blurhash(
+    imagePath: string,
+    options: CallOptions & { json: true }
+  ): string;
+  function get_blurhash(
+    imagePath: string,
+    options?: CallOptions
+  ): BlurhashResult;
+
+  /**
+   * Same as `get_blurhash`, but reads and decodes the image on the worker pool.
+   * @param imagePath Image file path (can be absolute or relative to projectRoot)
+   * @param options Optional job options
+   * @returns A promise resolving to the blurhash data or error information
+   */
+  function get_blurhash_async(
+    imagePath: string,
+    options: AsyncJobOptions & { json: true }
+  ): Promise<string>;
+  function get_blurhash_async(
+    imagePath: string,
+    options?: AsyncJobOptions
+  ): Promise<BlurhashResult>;
+
+  /**
+   * Generate or retrieve blurhash data for many images in parallel on the worker pool.
+   * @param imagePaths Image file paths
+   * @param options Optional job options
+   * @returns A promise resolving to one result per path, in input order, or
+   *   with `json` to the JSON of that array
+   */
+  function get_blurhash_batch(
+    imagePaths: string[],
+    options: AsyncJobOptions & { json: true }
+  ): Promise<string>;
+  function get_blurhash_batch(
+    imagePaths: string[],
+    options?: AsyncJobOptions
+  ): Promise<BlurhashResult[]>;
+
+  /**
+   * Like `get_blurhash_batch`, but calls `onResult` with each result as soon
+   * as it is ready, in completion order, instead of collecting them.
+   * @param imagePaths Image file paths
+   * @param onResult Receives each result with its index in `imagePaths`
+   * @param options Optional job options
+   * @returns A promise resolving to the number of results once all were
+   *   delivered, or rejecting with the error thrown by `onResult`
+   */
+  function stream_blurhash_batch(
+    imagePaths: string[],
+    onResult: (result: string, index: number, path: string) => void,
+    options: AsyncJobOptions & { json: true }
+  ): Promise<number>;
+  function stream_blurhash_batch(
+    imagePaths: string[],
+    onResult: (result: BlurhashResult, index: number, path: string) => void,
+    options?: AsyncJobOptions
+  ): Promise<number>;
+
+  /**
+   * Same as `get_blurhash_async`, additionally recording the result in the
+   * context's manifest for `emit_manifest`.
+   * @param imagePath Image file path (can be absolute or relative to projectRoot)
+   * @param options Optional job options
+   * @returns A promise resolving to the blurhash data or error information
+   */
+  function get_or_generate(
+    imagePath: string,
+    options: AsyncJobOptions & { json: true }
+  ): Promise<string>;
+  function get_or_generate(
+    imagePath: string,
+    options?: AsyncJobOptions
+  ): Promise<BlurhashResult>;
+
+  /**
+   * Blurhash of a raw bitmap, cached under a hash of its pixels.
+   * @param bitmap Pixels, dimensions, row stride and channel order
+   * @param options Optional job options; `projectRoot` is ignored
+   * @returns A promise resolving to the blurhash data or error information
+   */
+  function get_blurhash_from_bitmap(
+    bitmap: BlurhashBitmap,
+    options: AsyncJobOptions & { json: true }
+  ): Promise<string>;
+  function get_blurhash_from_bitmap(
+    bitmap: BlurhashBitmap,
+    options?: AsyncJobOptions
+  ): Promise<BlurhashResult>;
+
+  /**
+   * Blurhash of a file opened by the caller, cached under `cacheKey`. Unix only.
+   * @param fd Open file descriptor; it is duplicated and may be closed once
+   *   the call returns
+   * @param cacheKey Key to cache the image under
+   * @param options Optional job options; `projectRoot` is ignored
+   * @returns A promise resolving to the blurhash data or error information
+   */
+  function get_blurhash_from_fd(
+    fd: number,
+    cacheKey: string,
+    options: AsyncJobOptions & { json: true }
+  ): Promise<string>;
+  function get_blurhash_from_fd(
+    fd: number,
+    cacheKey: string,
+    options?: AsyncJobOptions
+  ): Promise<BlurhashResult>;
+
+  /**
+   * Return a cached result without reading or decoding the image. On a miss,
+   * returns `null` immediately and schedules the generation on the worker
+   * pool; collect it with `take_completions` or `flush_completions`.
+   * @param imagePath Image file path (can be absolute or relative to projectRoot)
+   * @param options Optional job options; `priority` applies to the scheduled generation
+   * @returns The result on a hit or error, `null` on a miss
+   */
+  function get_blurhash_cached_sync(
+    imagePath: string,
+    options?: AsyncJobOptions
+  ): BlurhashResult | null;
+
+  /**
+   * Look up many images in the cache with a single query, never decoding and
+   * never scheduling generations.
+   * @param imagePaths Image file path or paths
+   * @param options Optional call options
+   * @returns Hits by path, misses in order, and error results by path
+   */
+  function get_many_if_cached(
+    imagePaths: string | string[],
+    options?: CallOptions
+  ): CachedLookups;
+
+  /**
+   * Collect generations scheduled on misses that finished since the last call.
+   * @param options Optional call options
+   */
+  function take_completions(
+    options?: Pick<CallOptions, "cache">
+  ): BlurhashCompletion[];
+
+  /**
+   * Wait until every generation scheduled on a miss finished, then collect them.
+   * @param options Optional call options
+   */
+  function flush_completions(
+    options?: Pick<CallOptions, "cache">
+  ): Promise<BlurhashCompletion[]>;
+
+  /**
+   * Forget images that changed or were deleted: drop their cached entries and
+   * manifest entries so the next lookup regenerates them.
+   * @param imagePaths Image file path or paths; deleted files are accepted
+   * @param options Optional call options
+   * @returns Cache keys of the invalidated images
+   */
+  function invalidate(
+    imagePaths: string | string[],
+    options?: Pick<CallOptions, "cache">
+  ): string[];
+
+  /**
+   * Find cached images whose perceptual hash differs from that of an image in
+   * at most `maxDistance` bits, generating the image's entry if needed.
+   * @param imagePath Image file path
+   * @param maxDistance Most differing bits, 0 to 64
+   * @param options Optional call options
+   * @returns Similar images but the image itself, closest first
+   */
+  function find_similar(
+    imagePath: string,
+    maxDistance: number,
+    options?: Pick<CallOptions, "cache">
+  ): SimilarImage[];
+
+  /**
+   * Raster thumbnail stored with an image's entry, generating it if needed.
+   * @param imagePath Image file path
+   * @param options Optional call options
+   * @returns Lossless WebP `thumbnailSize` pixels on its longer side
+   */
+  function get_thumbnail(
+    imagePath: string,
+    options?: Pick<CallOptions, "cache">
+  ): Buffer;
+
+  /**
+   * Group cached images with identical content.
+   * @param options Optional context name and `verifySize`
+   * @returns Groups of two or more images, largest first
+   */
+  function find_duplicates(
+    options?: Pick<CallOptions, "cache"> & { verifySize?: boolean }
+  ): DuplicateGroup[];
+
+  /**
+   * Break the cache down by file extension and top-level directory.
+   * @param options Optional context name
+   * @returns `CacheComposition` as JSON
+   */
+  function cache_composition(options?: Pick<CallOptions, "cache">): string;
+
+  /**
+   * Prune orphaned and expired entries, then vacuum and analyze the database.
+   * @param options Steps to run and optional context name
+   * @returns Removed keys, database sizes and duration
+   */
+  function maintain(options?: MaintenanceOptions): MaintenanceReport;
+
+  /**
+   * Regenerate or delete entries with an invalid blurhash or dimensions.
+   * @param options Optional context name
+   * @returns Regenerated and deleted keys and duration
+   */
+  function repair(options?: Pick<CallOptions, "cache">): RepairReport;
+
+  /**
+   * Count cached entries by whether their file still has the cached mtime.
+   * @param prefix Cache key prefix; every entry if omitted
+   * @param options Optional context name
+   * @returns Fresh, stale and missing entry counts
+   */
+  function staleness_report(
+    prefix?: string,
+    options?: Pick<CallOptions, "cache">
+  ): StalenessReport;
+
+  /**
+   * Recompute cached entries and report those that differ from the cache.
+   * @param prefix Cache key prefix; every entry if omitted
+   * @param options Optional context name and `sample` size
+   * @returns Promise resolving with the comparison
+   */
+  function verify(
+    prefix?: string,
+    options?: Pick<CallOptions, "cache"> & { sample?: number }
+  ): Promise<VerificationReport>;
+
+  /**
+   * Delete cached entries not written within `durationMs`.
+   * @param durationMs Age beyond which entries are deleted
+   * @param options Optional context name and key `prefix`
+   * @returns Cache keys of the deleted entries
+   */
+  function delete_older_than(
+    durationMs: number,
+    options?: Pick<CallOptions, "cache"> & { prefix?: string }
+  ): string[];
+
+  /**
+   * Serialize the manifest of results recorded by `get_or_generate` as JSON
+   * (see `BlurhashManifest`). Identical inputs produce identical output.
+   * @param options Optional output options
+   * @returns The manifest JSON
+   */
+  function emit_manifest(options?: EmitManifestOptions): string;
+
+  /**
+   * Start recording every result the context hands out, through any lookup
+   * function, until `finalize_session`.
+   * @param options Optional call options
+   * @returns true once the session started
+   */
+  function begin_session(options?: Pick<CallOptions, "cache">): boolean;
+
+  /**
+   * End the build session and serialize what it recorded, in the same format
+   * as `emit_manifest`. Throws if no session was in progress.
+   * @param options Optional output options
+   * @returns The session manifest JSON
+   */
+  function finalize_session(options?: FinalizeSessionOptions): string;
+
+  /**
+   * Benchmark blurhash generation for one image on this machine.
+   * @param imagePath Image file path (can be absolute or relative to projectRoot)
+   * @param options Number of measured runs per operation (default 10)
+   * @returns A promise resolving to the benchmark report
+   */
+  function benchmark(
+    imagePath: string,
+    options?: CallOptions & { iterations?: number }
+  ): Promise<BenchmarkReport>;
+
+  /**
+   * Change the options of a live context without closing its database or
+   * clearing cached entries. Only the given fields change.
+   * @param options Tunables to change, plus the `cache` to apply them to
+   * @returns `true` if the options were applied, otherwise throws an error
+   */
+  function update_config(options: NativeContextOptions): boolean;
 
   /**
    * Check if the Blurhash cache system is initialized.
+   * @param cache Context name, defaults to `"default"`
    * @returns `true` if initialized
    */
-  function is_initialized(): boolean;
+  function is_initialized(cache?: string): boolean;
+
+  /**
+   * Check that a context can serve lookups: the database answers a query,
+   * the project roots are readable and the encoder round-trips an image.
+   * @param cache Context name, defaults to `"default"`
+   * @returns The report of each check
+   */
+  function health_check(cache?: string): HealthReport;
+
+  /** Every error code results can carry, mapped to itself */
+  const ErrorCodes: { readonly [Code in BlurhashErrorCode]: Code };
+
+  /**
+   * List the names of all initialized contexts.
+   */
+  function list_contexts(): string[];
+
+  /**
+   * Render the cache metrics of all visible contexts in the Prometheus text
+   * exposition format, labelled by context name.
+   */
+  function get_metrics_prometheus(): string;
+
+  /**
+   * Read the cache counters of a context since it was created or last reset.
+   * @param cache Context name, defaults to `"default"`
+   */
+  function get_stats(cache?: string): RuntimeStats;
+
+  /**
+   * Zero the cache counters of a context.
+   * @param cache Context name, defaults to `"default"`
+   */
+  function reset_stats(cache?: string): boolean;
+
+  /**
+   * Read the job counts of a context's worker pool.
+   * @param cache Context name, defaults to `"default"`
+   */
+  function get_pool_stats(cache?: string): PoolStats;
+
+  /**
+   * Approximate a blurhash with CSS backgrounds.
+   * @param blurhash The blurhash to approximate
+   * @param size Image dimensions, which set the aspect ratio
+   * @returns The background properties, also joined as declarations in `css`
+   */
+  function blurhash_to_css(
+    blurhash: string,
+    size: { width: number; height: number }
+  ): CssPlaceholder;
+
+  /**
+   * Decode a blurhash into pixels for `new ImageData(...)`.
+   * @param blurhash The blurhash to decode
+   * @param options Output dimensions and contrast
+   * @returns Straight-alpha RGBA pixels with their dimensions
+   */
+  function decode_blurhash(
+    blurhash: string,
+    options: DecodeOptions
+  ): DecodedImageData;
+
+  /**
+   * Read the average color of a blurhash without decoding any pixels.
+   * @param blurhash The blurhash to read
+   * @returns CSS hex color, or null if the blurhash is malformed
+   */
+  function average_color_from_blurhash(blurhash: string): string | null;
+
+  /**
+   * Replace the database connection of a context with a fresh one, keeping
+   * its options, worker pool, metrics and manifest.
+   * @param cache Context name, defaults to `"default"`
+   * @returns `true` once reconnected, otherwise throws an error
+   */
+  function reconnect(cache?: string): boolean;
+
+  /**
+   * Forward `get_blurhash`, `get_blurhash_async`, `get_or_generate` and
+   * `invalidate` calls of a cache to the daemon listening on a Unix socket.
+   * Unix only.
+   * @param socketPath Path of the daemon's socket
+   * @returns `true` once connected, otherwise throws an error
+   */
+  function connect_to_daemon(
+    socketPath: string,
+    options?: { cache?: string; projectRoot?: string }
+  ): boolean;
+
+  /**
+   * Stop forwarding the calls of a cache to its daemon.
+   * @param cache Cache name, defaults to `"default"`
+   * @returns Whether the cache was connected to a daemon
+   */
+  function disconnect_from_daemon(cache?: string): boolean;
+
+  /**
+   * Write a snapshot of every entry of a cache, replacing the file.
+   * @param snapshotPath File to write
+   * @param cache Context name, defaults to `"default"`
+   */
+  function write_snapshot(snapshotPath: string, cache?: string): SnapshotReport;
+
+  /**
+   * Look a cache key up in a snapshot, mapped once per process.
+   * @param snapshotPath Path of the snapshot
+   * @param key Cache key, relative to the writer's project root
+   * @returns The entry, or null if the snapshot has none for `key`
+   */
+  function read_snapshot(
+    snapshotPath: string,
+    key: string
+  ): SnapshotEntry | null;
+
+  /**
+   * Register the one callback receiving every error returned as an error
+   * result or raised by background work, or remove it with `null`.
+   * @param handler Called asynchronously, without keeping the process alive
+   */
+  function set_error_handler(
+    handler: ((event: BlurhashErrorEvent) => void) | null
+  ): void;
+
+  /**
+   * Describe the native state of every visible context as indented JSON.
+   * @returns Options, pool and in-flight work per context, and recent errors
+   */
+  function debug_dump(): string;
 
   /**
-   * Clean up global context and close database connections.
+   * Describe this build of the module.
+   * @returns Version, cargo features, encoder and schema versions, formats
+   */
+  function get_version_info(): VersionInfo;
+
+  /**
+   * Clean up a context and close its database connection.
+   * @param cache Context name, defaults to `"default"`
    * @returns `true` if cleanup succeeds
    */
-  function clear_context(): boolean;
+  function clear_context(cache?: string): boolean;
+
+  /**
+   * Stop accepting jobs, wait for pending generations to be cached, then
+   * close the database connection and clear the context.
+   * @param options Optional context name and `timeoutMs`
+   * @returns Whether all pending work finished before the timeout
+   */
+  function shutdown(options?: ShutdownOptions): Promise<boolean>;
+
+  /**
+   * Export OpenTelemetry spans of lookups and generations over OTLP/HTTP.
+   * Only present when the module is built with the `otel` feature.
+   */
+  const init_telemetry: ((options?: TelemetryOptions) => boolean) | undefined;
+
+  /**
+   * Flush spans and stop the exporter installed by `init_telemetry`.
+   * Only present when the module is built with the `otel` feature.
+   */
+  const shutdown_telemetry: (() => boolean) | undefined;
+}
+
+/**
+ * Build a `srcset` attribute from the `renditions` of a result.
+ * @param renditions Renditions of a result with `renditionWidths`
+ * @param url Returns the URL of a rendition, e.g. of an image CDN
+ * @returns `srcset` with a width descriptor per rendition
+ */
+export function buildSrcset(
+  renditions: Rendition[],
+  url: (rendition: Rendition) => string
+): string {
+  return renditions
+    .map((rendition) => `${url(rendition)} ${rendition.width}w`)
+    .join(", ");
+}
+
+/**
+ * Receive every error that does not throw: error results of any context, and
+ * failed background refreshes that no caller ever sees. There is one handler
+ * per process; registering another replaces it.
+ * @param handler Called asynchronously with each error, or `null` to remove it
+ */
+export function setErrorHandler(
+  handler: ((event: BlurhashErrorEvent) => void) | null
+): void {
+  addon.set_error_handler(handler);
+}
+
+/**
+ * Snapshot the native state for a bug report: the options, worker pool, work
+ * in flight, manifest sizes and counters of every context visible to this
+ * thread, and the latest 50 errors of any context.
+ * @returns Indented JSON, meant to be logged or written to a file as is
+ */
+export function debugDump(): string {
+  return addon.debug_dump();
+}
+
+/**
+ * Describe this build of the native module, to check for a capability, such
+ * as telemetry or an image format, before relying on it.
+ * @returns Version, cargo features, encoder and schema versions, formats
+ */
+export function getVersionInfo(): VersionInfo {
+  return addon.get_version_info();
+}
+
+/**
+ * Approximate a blurhash with pure CSS, so placeholders need no client-side
+ * decoder.
+ * @param blurhash The blurhash to approximate
+ * @param size Dimensions of the image, e.g. a result of `processImage`
+ * @returns Background properties for the placeholder element
+ */
+export function blurhashToCss(
+  blurhash: string,
+  size: { width: number; height: number }
+): CssPlaceholder {
+  return addon.blurhash_to_css(blurhash, size);
+}
+
+/**
+ * Decode a blurhash into pixels that paint on a canvas without conversion, e.g.
+ * `ctx.putImageData(new ImageData(data, width, height), 0, 0)`.
+ * @param blurhash The blurhash to decode
+ * @param options Output dimensions and optional `punch`
+ * @returns Straight-alpha RGBA pixels with their dimensions
+ */
+export function decodeBlurhash(
+  blurhash: string,
+  options: DecodeOptions
+): DecodedImageData {
+  return addon.decode_blurhash(blurhash, options);
+}
+
+/**
+ * Read the average color of a blurhash, e.g. for a background fill. Only the
+ * color digits are decoded, so this is much cheaper than `decodeBlurhash`.
+ * @param blurhash The blurhash to read
+ * @returns CSS hex color such as `"#979695"`, or null if the blurhash is malformed
+ */
+export function averageColorFromBlurhash(blurhash: string): string | null {
+  return addon.average_color_from_blurhash(blurhash);
+}
+
+/**
+ * Look an image up in a snapshot written by another process with the
+ * `snapshot` option or `writeSnapshot`, without initializing a cache. The
+ * file is memory-mapped once per process and read without SQLite or locks,
+ * and mapped again once the writer replaces it.
+ * @param snapshotPath Path of the snapshot
+ * @param key Cache key of the image, relative to the writer's project root,
+ *   e.g. `"images/hero.jpg"`
+ * @returns The entry, or null if the snapshot has none for `key`
+ */
+export function readSnapshot(
+  snapshotPath: string,
+  key: string
+): SnapshotEntry | null {
+  return addon.read_snapshot(snapshotPath, key);
 }
 
 /**
@@ -118,16 +1838,54 @@ export function isSvgFile(filePath: string): boolean {
   return ext === ".svg";
 }
 
+/**
+ * Render cache hit, miss, stale refresh and database error counters and a
+ * decode time histogram for every context in the Prometheus text format,
+ * e.g. to serve from a `/metrics` endpoint.
+ * @returns The metrics, labelled by context name
+ */
+export function getMetricsPrometheus(): string {
+  return addon.get_metrics_prometheus();
+}
+
+/**
+ * Export OpenTelemetry spans of cache lookups and generations over OTLP/HTTP.
+ * Pass a request's `traceparent` in the call options to make its spans part
+ * of that trace.
+ * @param options Exporter options
+ * @throws If the native module was built without the `otel` feature
+ */
+export function initTelemetry(options: TelemetryOptions = {}): void {
+  if (!addon.init_telemetry) {
+    throw new Error(
+      "[blurhash-core] Tracing requires a native module built with the `otel` feature."
+    );
+  }
+  addon.init_telemetry(options);
+}
+
+/**
+ * Flush pending spans and stop the exporter installed by `initTelemetry()`.
+ * Does nothing when the native module was built without the `otel` feature.
+ */
+export function shutdownTelemetry(): void {
+  addon.shutdown_telemetry?.();
+}
+
 /**
  * Validate if the file should be processed by the native module.
  * @param src Image source path
- * @param projectRoot Project root directory
+ * @param projectRoot Project root directory, or several of them. Relative
+ * sources resolve against the first root.
+ * @param allowOutsideRoot Accept files outside every project root
  * @returns Validation result with processing decision
  */
 export function validateFile(
   src: string,
-  projectRoot: string
+  projectRoot: string | string[],
+  allowOutsideRoot = false
 ): FileValidationResult {
+  const projectRoots = Array.isArray(projectRoot) ? projectRoot : [projectRoot];
   // Skip network URLs
   if (isNetworkUrl(src)) {
     return {
@@ -151,15 +1909,17 @@ export function validateFile(
     if (path.isAbsolute(src)) {
       resolvedPath = src;
     } else {
-      resolvedPath = path.resolve(projectRoot, src);
+      resolvedPath = path.resolve(projectRoots[0], src);
     }
 
     // Normalize paths for comparison
-    const normalizedProjectRoot = path.normalize(projectRoot);
     const normalizedResolvedPath = path.normalize(resolvedPath);
 
-    // Check if file is within project root
-    if (!normalizedResolvedPath.startsWith(normalizedProjectRoot)) {
+    // Check if file is within any project root
+    const withinRoot = projectRoots.some((root) =>
+      normalizedResolvedPath.startsWith(path.normalize(root))
+    );
+    if (!withinRoot && !allowOutsideRoot) {
       return {
         shouldProcess: false,
         reason: "File is outside project root",
@@ -254,7 +2014,11 @@ export class BlurhashCore {
    * Initialize the Blurhash cache system
    */
   initialize(): void {
-    if (!this.options.databasePath || !this.options.projectRoot) {
+    if (
+      !this.options.databasePath ||
+      !this.options.projectRoot ||
+      this.getProjectRoots().length === 0
+    ) {
       throw new Error(
         "[blurhash-core] `databasePath` and `projectRoot` options are required."
       );
@@ -263,7 +2027,8 @@ export class BlurhashCore {
     try {
       const initialized = addon.initialize_blurhash_cache(
         this.options.databasePath,
-        this.options.projectRoot
+        this.options.projectRoot,
+        this.nativeOptions()
       );
       if (!initialized) {
         throw new Error("Native module initialization returned false.");
@@ -282,19 +2047,193 @@ export class BlurhashCore {
     }
   }
 
+  /**
+   * Initialize the Blurhash cache system without blocking the event loop on
+   * the database connection and migrations, retrying the connection while the
+   * database is unreachable.
+   * @param options Connection retries
+   */
+  async initializeAsync(options: ConnectRetryOptions = {}): Promise<void> {
+    if (
+      !this.options.databasePath ||
+      !this.options.projectRoot ||
+      this.getProjectRoots().length === 0
+    ) {
+      throw new Error(
+        "[blurhash-core] `databasePath` and `projectRoot` options are required."
+      );
+    }
+
+    try {
+      await addon.initialize_blurhash_cache_async(
+        this.options.databasePath,
+        this.options.projectRoot,
+        { ...this.nativeOptions(), ...options }
+      );
+      this.initialized = true;
+    } catch (error) {
+      throw new Error(
+        `[blurhash-core] Initialization failed. Please check your options and native module setup. Details: ${
+          error instanceof Error ? error.message : String(error)
+        }`
+      );
+    }
+  }
+
+  /**
+   * Native context options derived from the constructor options.
+   */
+  private nativeOptions(): NativeContextOptions {
+    return {
+      workers: this.options.workers,
+      quality: this.options.quality,
+      componentsX: this.options.componentsX,
+      componentsY: this.options.componentsY,
+      encodeSize: this.options.encodeSize,
+      encodeRules: this.options.encodeRules,
+      maxConcurrentDecodes: this.options.maxConcurrentDecodes,
+      decodeMemoryBudget: this.options.decodeMemoryBudget,
+      decoder: this.options.decoder,
+      mtimeToleranceMs: this.options.mtimeToleranceMs,
+      ioRetries: this.options.ioRetries,
+      validation: this.options.validation,
+      revalidate: this.options.revalidate,
+      outsideRoot: this.options.outsideRoot,
+      caseInsensitiveKeys: this.options.caseInsensitiveKeys,
+      nonUtf8Keys: this.options.nonUtf8Keys,
+      sandbox: this.options.sandbox,
+      allowedExtensions: this.options.allowedExtensions,
+      allowedMimeTypes: this.options.allowedMimeTypes,
+      blurDataUrl: this.options.blurDataUrl,
+      persistWebpPreviews: this.options.persistWebpPreviews,
+      microPixels: this.options.microPixels,
+      previewSize: this.options.previewSize,
+      thumbnailSize: this.options.thumbnailSize,
+      applyOrientation: this.options.applyOrientation,
+      renditionWidths: this.options.renditionWidths,
+      scheduledMaintenance: this.options.scheduledMaintenance,
+      snapshot: this.options.snapshot,
+      revalidateOnInit: this.options.revalidateOnInit,
+      logLevel: this.options.logLevel,
+      logFormat: this.options.logFormat,
+      logFile: this.options.logFile,
+      cache: this.options.cache,
+      scope: this.options.scope,
+    };
+  }
+
+  /**
+   * Create and initialize a core from a `blurest.config.json` or
+   * `blurest.config.toml` file, shared with the `blurest` CLI, and `BLUREST_*`
+   * environment variables.
+   * @param configPath Config file path, defaults to `BLUREST_CONFIG`, then to one
+   * in the working directory
+   * @param options Options taking precedence over the file
+   */
+  static fromConfig(
+    configPath?: string,
+    options: Omit<Partial<BlurhashCoreOptions>, "databasePath" | "projectRoot"> = {}
+  ): BlurhashCore {
+    const { databaseUrl, projectRoots } = addon.initialize_from_config(
+      configPath,
+      options
+    );
+    const core = new BlurhashCore({
+      ...options,
+      databasePath: databaseUrl,
+      projectRoot: projectRoots,
+    });
+    core.initialized = true;
+    return core;
+  }
+
+  /**
+   * Create and initialize a core whose database lives in
+   * `<projectRoot>/.blurest/`, with no database path to manage. The cache is
+   * locked to this process until `cleanup()` or exit; other processes wait up
+   * to `lockTimeoutMs`, then throw naming the owner.
+   * @param projectRoot Project root, or several; the cache is placed in the
+   * first. Defaults to `BLUREST_PROJECT_ROOT`, then to the working directory
+   * @param options Options of the core and how long to wait for the lock
+   */
+  static forProject(
+    projectRoot?: string | string[],
+    options: Omit<Partial<BlurhashCoreOptions>, "databasePath" | "projectRoot"> &
+      ProjectLockOptions = {}
+  ): BlurhashCore {
+    const { databaseUrl, projectRoots } = addon.initialize_project_cache(
+      projectRoot,
+      options
+    );
+    const core = new BlurhashCore({
+      ...options,
+      databasePath: databaseUrl,
+      projectRoot: projectRoots,
+    });
+    core.initialized = true;
+    return core;
+  }
+
+  /**
+   * Change tunables of the live cache, e.g. after a config file change.
+   * Cached entries and the database connection are kept.
+   * @param options Options to change; omitted fields keep their current value
+   */
+  updateConfig(
+    options: Omit<
+      Partial<BlurhashCoreOptions>,
+      "databasePath" | "projectRoot" | "cache" | "scope"
+    >
+  ): void {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    addon.update_config({ ...options, cache: this.options.cache });
+    this.options = { ...this.options, ...options };
+  }
+
   /**
    * Check if the core is initialized
    */
   isInitialized(): boolean {
-    return this.initialized && addon.is_initialized();
+    return this.initialized && addon.is_initialized(this.options.cache);
+  }
+
+  /**
+   * Check that the core can serve lookups, for readiness probes: the database
+   * answers a query, every project root is readable and the encoder
+   * round-trips an image held in memory. No image files are read.
+   * @returns The report of each check, `healthy` if all passed
+   */
+  healthCheck(): HealthReport {
+    return addon.health_check(this.options.cache);
   }
 
   /**
    * Process an image and get blurhash data
    * @param src Clean image source path (without size definitions)
+   * @param options `crop`, or `aspectRatio` and `focalPoint`, to encode part of
+   *   the image only; `force` to regenerate a cached entry; `output` for a data URI
+   *   or WebP preview; `timeoutMs` to bound generation time
    * @returns Blurhash result or null if processing should be skipped
    */
-  processImage(src: string): BlurhashResult | null {
+  processImage(
+    src: string,
+    options: Pick<
+      CallOptions,
+      | "crop"
+      | "aspectRatio"
+      | "focalPoint"
+      | "renditionWidths"
+      | "force"
+      | "output"
+      | "previewSize"
+      | "timeoutMs"
+    > = {}
+  ): BlurhashResult | null {
     if (!this.initialized) {
       throw new Error(
         "[blurhash-core] Core not initialized. Call initialize() first."
@@ -302,7 +2241,11 @@ export class BlurhashCore {
     }
 
     // Validate file before processing
-    const validation = validateFile(src, this.options.projectRoot);
+    const validation = validateFile(
+      src,
+      this.options.projectRoot,
+      (this.options.outsideRoot ?? "error") !== "error"
+    );
 
     if (!validation.shouldProcess) {
       console.debug(
@@ -312,7 +2255,571 @@ export class BlurhashCore {
     }
 
     // Get blurhash and original dimensions from native module
-    return addon.get_blurhash(src);
+    return addon.get_blurhash(src, {
+      cache: this.options.cache,
+      crop: options.crop,
+      aspectRatio: options.aspectRatio,
+      focalPoint: options.focalPoint,
+      renditionWidths: options.renditionWidths,
+      force: options.force,
+      output: options.output,
+      previewSize: options.previewSize,
+      timeoutMs: options.timeoutMs,
+    });
+  }
+
+  /**
+   * Get blurhash data for an image and record it in the build manifest.
+   * Designed for bundler plugins: call from a transform hook, then emit the
+   * manifest at the end of the build with `emitManifest()`.
+   * @param src Clean image source path (without size definitions)
+   * @param options `crop`, or `aspectRatio` and `focalPoint`, to encode part of
+   *   the image only; `force` to regenerate a cached entry; `output` for a data URI
+   *   or WebP preview; `timeoutMs` to bound generation time
+   * @returns Blurhash result or null if processing should be skipped
+   */
+  async getOrGenerate(
+    src: string,
+    options: Pick<
+      CallOptions,
+      | "crop"
+      | "aspectRatio"
+      | "focalPoint"
+      | "renditionWidths"
+      | "force"
+      | "output"
+      | "previewSize"
+      | "timeoutMs"
+    > = {}
+  ): Promise<BlurhashResult | null> {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    const validation = validateFile(
+      src,
+      this.options.projectRoot,
+      (this.options.outsideRoot ?? "error") !== "error"
+    );
+
+    if (!validation.shouldProcess) {
+      console.debug(
+        `[blurhash-core] Skipping blurhash processing for "${src}": ${validation.reason}`
+      );
+      return null;
+    }
+
+    return addon.get_or_generate(src, {
+      cache: this.options.cache,
+      crop: options.crop,
+      aspectRatio: options.aspectRatio,
+      focalPoint: options.focalPoint,
+      renditionWidths: options.renditionWidths,
+      force: options.force,
+      output: options.output,
+      previewSize: options.previewSize,
+      timeoutMs: options.timeoutMs,
+    });
+  }
+
+  /**
+   * Get blurhash data for a raw bitmap that has no file, such as a window
+   * captured with Electron's `capturePage()`. Bitmaps are cached under a hash
+   * of their pixels, so the same capture is only encoded once.
+   * @param bitmap Pixels in BGRA order unless `format` says otherwise
+   * @param options `crop`, or `aspectRatio` and `focalPoint`, to encode part of
+   *   the bitmap only; `force`, `output`, `previewSize` and `timeoutMs` as for
+   *   `getOrGenerate`
+   * @returns Blurhash result, whose `key` is the pixel hash
+   */
+  async getBlurhashFromBitmap(
+    bitmap: BlurhashBitmap,
+    options: Pick<
+      AsyncJobOptions,
+      | "priority"
+      | "crop"
+      | "aspectRatio"
+      | "focalPoint"
+      | "force"
+      | "output"
+      | "previewSize"
+      | "timeoutMs"
+    > = {}
+  ): Promise<BlurhashResult> {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    return addon.get_blurhash_from_bitmap(bitmap, {
+      cache: this.options.cache,
+      priority: options.priority,
+      crop: options.crop,
+      aspectRatio: options.aspectRatio,
+      focalPoint: options.focalPoint,
+      force: options.force,
+      output: options.output,
+      previewSize: options.previewSize,
+      timeoutMs: options.timeoutMs,
+    });
+  }
+
+  /**
+   * Get blurhash data for a file the caller already opened, e.g. with a
+   * security-scoped open, without passing its path. The file is read from its
+   * start, without moving the descriptor's offset, and cached under
+   * `cacheKey`; entries are validated by the file's modification time and
+   * content like those of paths. Only supported on Unix.
+   * @param fd Open file descriptor, such as one from `fs.promises.open`'s
+   *   `handle.fd`; it may be closed once the call returns
+   * @param cacheKey Key to cache the image under, e.g. its path relative to
+   *   the project root
+   * @param options The options of `getBlurhashFromBitmap`
+   * @returns Blurhash result
+   */
+  async getBlurhashFromFd(
+    fd: number,
+    cacheKey: string,
+    options: Pick<
+      AsyncJobOptions,
+      | "priority"
+      | "crop"
+      | "aspectRatio"
+      | "focalPoint"
+      | "force"
+      | "output"
+      | "previewSize"
+      | "timeoutMs"
+    > = {}
+  ): Promise<BlurhashResult> {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    return addon.get_blurhash_from_fd(fd, cacheKey, {
+      cache: this.options.cache,
+      priority: options.priority,
+      crop: options.crop,
+      aspectRatio: options.aspectRatio,
+      focalPoint: options.focalPoint,
+      force: options.force,
+      output: options.output,
+      previewSize: options.previewSize,
+      timeoutMs: options.timeoutMs,
+    });
+  }
+
+  /**
+   * Generate or retrieve blurhash data for many images on the worker pool and
+   * return all results as one JSON string of a `BlurhashResult` array, in the
+   * order of `srcs`. Serializing natively avoids building an object per image,
+   * which dominates large batches; parse the string or write it out as is.
+   * Unlike `streamBatch`, sources are not filtered, so skipped ones come back
+   * as error results; files that are not images come back with `skipped: true`.
+   * @param srcs Clean image source paths
+   * @param options Queue priority, `force`, `output`, `previewSize` and `timeoutMs`
+   * @returns JSON array of the results
+   */
+  async batchJson(
+    srcs: string[],
+    options: Pick<
+      AsyncJobOptions,
+      "priority" | "force" | "output" | "previewSize" | "timeoutMs"
+    > = {}
+  ): Promise<string> {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    return addon.get_blurhash_batch(srcs, {
+      cache: this.options.cache,
+      priority: options.priority,
+      force: options.force,
+      output: options.output,
+      previewSize: options.previewSize,
+      timeoutMs: options.timeoutMs,
+      json: true,
+    });
+  }
+
+  /**
+   * Generate or retrieve blurhash data for many images on the worker pool,
+   * yielding each result as soon as it is ready rather than all of them at
+   * the end, so large warm-ups can write output while work continues.
+   * Results come in completion order; use `index` to match them to `srcs`.
+   * @param srcs Clean image source paths
+   * @param options Queue priority of the generations
+   */
+  async *streamBatch(
+    srcs: string[],
+    options: Pick<AsyncJobOptions, "priority"> = {}
+  ): AsyncGenerator<BatchStreamItem> {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    const allowOutsideRoot = (this.options.outsideRoot ?? "error") !== "error";
+    const processable: string[] = [];
+    const indices: number[] = [];
+    for (const [index, src] of srcs.entries()) {
+      if (validateFile(src, this.options.projectRoot, allowOutsideRoot).shouldProcess) {
+        processable.push(src);
+        indices.push(index);
+      } else {
+        yield { src, index, result: null };
+      }
+    }
+
+    const ready: BatchStreamItem[] = [];
+    let wake: (() => void) | undefined;
+    let finished = false;
+    let failure: unknown;
+    addon
+      .stream_blurhash_batch(
+        processable,
+        (result, index, src) => {
+          ready.push({ src, index: indices[index], result });
+          wake?.();
+        },
+        { cache: this.options.cache, priority: options.priority }
+      )
+      .then(
+        () => {
+          finished = true;
+          wake?.();
+        },
+        (error) => {
+          failure = error;
+          finished = true;
+          wake?.();
+        }
+      );
+
+    while (true) {
+      const item = ready.shift();
+      if (item) {
+        yield item;
+      } else if (finished) {
+        if (failure !== undefined) {
+          throw failure;
+        }
+        return;
+      } else {
+        await new Promise<void>((resolve) => (wake = resolve));
+        wake = undefined;
+      }
+    }
+  }
+
+  /**
+   * Get cached blurhash data without ever decoding, for synchronous loaders.
+   * Returns `null` on a miss and generates the image in the background;
+   * see `flushCompletions()`.
+   * @param src Clean image source path (without size definitions)
+   * @returns Blurhash result, or null if skipped or not cached yet
+   */
+  getCachedSync(src: string): BlurhashResult | null {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    const validation = validateFile(
+      src,
+      this.options.projectRoot,
+      (this.options.outsideRoot ?? "error") !== "error"
+    );
+
+    if (!validation.shouldProcess) {
+      return null;
+    }
+
+    return addon.get_blurhash_cached_sync(src, { cache: this.options.cache });
+  }
+
+  /**
+   * Look up many images in the cache at once, with one database query instead
+   * of one per image, e.g. for every image of a page being rendered. Never
+   * decodes; generate the misses with `getOrGenerate`. Sources rejected by
+   * `validateFile`, such as network URLs, are left out. Runs on the calling
+   * thread, so at most 1000 sources are accepted; use
+   * `getManyIfCachedAsync` for more.
+   * @param srcs Image source paths
+   * @returns Hits by source, misses in order, and errors by source
+   */
+  getManyIfCached(srcs: string[]): CachedLookups {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+
+    const allowOutsideRoot = (this.options.outsideRoot ?? "error") !== "error";
+    const processable = srcs.filter(
+      (src) =>
+        validateFile(src, this.options.projectRoot, allowOutsideRoot)
+          .shouldProcess
+    );
+    return addon.get_many_if_cached(processable, { cache: this.options.cache });
+  }
+
+  /**
+   * Like `getManyIfCached`, for any number of sources: looks them up in chunks
+   * and yields to the event loop between chunks, so huge batches do not
+   * freeze the process.
+   * @param srcs Image source paths
+   * @param options `chunkSize`, the sources looked up at once, 1 to 1000
+   *   (defaults to 250)
+   * @returns Hits by source, misses in order, and errors by source
+   */
+  async getManyIfCachedAsync(
+    srcs: string[],
+    options: { chunkSize?: number } = {}
+  ): Promise<CachedLookups> {
+    const chunkSize = options.chunkSize ?? SYNC_BATCH_CHUNK_SIZE;
+    if (!Number.isInteger(chunkSize) || chunkSize < 1 || chunkSize > 1000) {
+      throw new RangeError("chunkSize must be an integer from 1 to 1000");
+    }
+
+    const merged: CachedLookups = { hits: {}, misses: [], errors: {} };
+    for (let start = 0; start < srcs.length; start += chunkSize) {
+      if (start > 0) {
+        await new Promise<void>((resolve) => setImmediate(resolve));
+      }
+      const chunk = this.getManyIfCached(srcs.slice(start, start + chunkSize));
+      Object.assign(merged.hits, chunk.hits);
+      merged.misses.push(...chunk.misses);
+      Object.assign(merged.errors, chunk.errors);
+    }
+    return merged;
+  }
+
+  /**
+   * Collect background generations started by `getCachedSync` that finished.
+   */
+  takeCompletions(): BlurhashCompletion[] {
+    return addon.take_completions({ cache: this.options.cache });
+  }
+
+  /**
+   * Wait for every background generation started by `getCachedSync`.
+   */
+  flushCompletions(): Promise<BlurhashCompletion[]> {
+    return addon.flush_completions({ cache: this.options.cache });
+  }
+
+  /**
+   * Forget images that changed or were deleted, e.g. from a bundler's watch
+   * hook. Their next lookup regenerates them.
+   * @param paths Image file path or paths
+   * @returns Cache keys of the invalidated images
+   */
+  invalidate(paths: string | string[]): string[] {
+    return addon.invalidate(paths, { cache: this.options.cache });
+  }
+
+  /**
+   * Find cached images that look like an image, e.g. to flag near-duplicate
+   * uploads. Only hashes recorded when images were encoded are compared.
+   * @param src Image file path
+   * @param maxDistance Most differing bits, 0 to 64; around 10 matches
+   *   resized, recompressed or lightly edited copies
+   * @returns Similar images but the image itself, closest first
+   */
+  findSimilar(src: string, maxDistance = 10): SimilarImage[] {
+    return addon.find_similar(src, maxDistance, { cache: this.options.cache });
+  }
+
+  /**
+   * Get the raster thumbnail stored with an image's entry, for UIs that need
+   * an actual micro-image. Requires the `thumbnailSize` option; entries cached
+   * without a thumbnail are regenerated.
+   * @param src Image file path
+   * @returns Lossless WebP `thumbnailSize` pixels on its longer side
+   */
+  getThumbnail(src: string): Buffer {
+    return addon.get_thumbnail(src, { cache: this.options.cache });
+  }
+
+  /**
+   * Group cached images with identical content, to find redundant copies of
+   * the same asset. Crops count as their image.
+   * @param options `verifySize` also compares file sizes on disk and leaves
+   *   out images whose file is gone
+   * @returns Groups of two or more images, largest first
+   */
+  findDuplicates(options: { verifySize?: boolean } = {}): DuplicateGroup[] {
+    return addon.find_duplicates({ ...options, cache: this.options.cache });
+  }
+
+  /**
+   * Break the cache down by file extension and top-level directory, with
+   * entry counts, file sizes on disk and average dimensions, for capacity
+   * planning.
+   */
+  getComposition(): CacheComposition {
+    return JSON.parse(
+      addon.cache_composition({ cache: this.options.cache })
+    ) as CacheComposition;
+  }
+
+  /**
+   * Run the selected maintenance steps in one call, e.g. nightly: prune
+   * orphaned and expired entries, then vacuum and analyze the database.
+   * @param options Steps to run; each is skipped unless set
+   * @returns Removed keys, database sizes and duration
+   */
+  maintain(
+    options: Omit<MaintenanceOptions, "cache"> = {}
+  ): MaintenanceReport {
+    return addon.maintain({ ...options, cache: this.options.cache });
+  }
+
+  /**
+   * Find cached entries with a malformed blurhash or dimensions below one
+   * pixel, regenerate them from their file, and delete those that cannot be
+   * regenerated.
+   * @returns Regenerated and deleted keys and duration
+   */
+  repair(): RepairReport {
+    return addon.repair({ cache: this.options.cache });
+  }
+
+  /**
+   * Count the cached entries whose key starts with `prefix` by whether their
+   * file still has the cached mtime, without regenerating anything.
+   * @param prefix Cache key prefix, e.g. `"blog/"`; every entry if omitted
+   */
+  stalenessReport(prefix?: string): StalenessReport {
+    return addon.staleness_report(prefix, { cache: this.options.cache });
+  }
+
+  /**
+   * Recompute the blurhashes of the cached entries whose key starts with
+   * `prefix` from their files and report those that differ, catching
+   * corrupted rows, encoder drift or bad imports. Nothing is written back.
+   * @param prefix Cache key prefix, e.g. `"blog/"`; every entry if omitted
+   * @param options Recompute only `sample` entries, picked at random
+   */
+  verify(
+    prefix?: string,
+    options: { sample?: number } = {}
+  ): Promise<VerificationReport> {
+    return addon.verify(prefix, { ...options, cache: this.options.cache });
+  }
+
+  /**
+   * Delete cached entries not written within `durationMs`, e.g. those of a
+   * content area that is no longer built. Entries are written when generated
+   * or revalidated, not when served.
+   * @param durationMs Age beyond which entries are deleted
+   * @param options Only delete entries whose cache key starts with `prefix`
+   * @returns Cache keys of the deleted entries
+   */
+  deleteOlderThan(
+    durationMs: number,
+    options: { prefix?: string } = {}
+  ): string[] {
+    return addon.delete_older_than(durationMs, {
+      ...options,
+      cache: this.options.cache,
+    });
+  }
+
+  /**
+   * Serialize everything recorded by `getOrGenerate` as a stable JSON
+   * manifest, optionally writing it to `outFile`.
+   * @param options Output options
+   * @returns The manifest JSON
+   */
+  emitManifest(options: Omit<EmitManifestOptions, "cache"> = {}): string {
+    return addon.emit_manifest({ ...options, cache: this.options.cache });
+  }
+
+  /**
+   * Start a build session: every result handed out from now on, by any
+   * method, is recorded until `finalizeSession()`. Meant for static site
+   * generators, which should emit exactly the placeholders a build used.
+   */
+  beginSession(): void {
+    addon.begin_session({ cache: this.options.cache });
+  }
+
+  /**
+   * End the build session and serialize what it recorded as a stable JSON
+   * manifest, optionally writing it to `outFile`.
+   * @param options Output options
+   * @returns The session manifest JSON
+   */
+  finalizeSession(
+    options: Omit<FinalizeSessionOptions, "cache"> = {}
+  ): string {
+    return addon.finalize_session({ ...options, cache: this.options.cache });
+  }
+
+  /**
+   * Reopen the database connection after a transient failure, e.g. the
+   * volume holding it was remounted, without losing pending work, metrics or
+   * the build manifest as `cleanup()` and `initialize()` would.
+   */
+  reconnect(): void {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+    addon.reconnect(this.options.cache);
+  }
+
+  /**
+   * Forward lookups to the daemon listening on `socketPath`, started with
+   * `blurest daemon --socket <path>`, instead of opening the database in this
+   * process, e.g. in every Node.js cluster worker. Replaces `initialize()`
+   * for `processImage`, `getOrGenerate` and `invalidate`, which then only
+   * take `force`. Unix only.
+   * @param socketPath Path of the daemon's socket
+   */
+  connectToDaemon(socketPath: string): void {
+    addon.connect_to_daemon(socketPath, {
+      cache: this.options.cache,
+      projectRoot: this.getProjectRoots()[0],
+    });
+    this.initialized = true;
+  }
+
+  /**
+   * Write a snapshot of every cached entry to `snapshotPath` for processes
+   * using `readSnapshot`, replacing the file. See the `snapshot` option to
+   * write one on a timer.
+   * @param snapshotPath File to write
+   */
+  writeSnapshot(snapshotPath: string): SnapshotReport {
+    if (!this.initialized) {
+      throw new Error(
+        "[blurhash-core] Core not initialized. Call initialize() first."
+      );
+    }
+    return addon.write_snapshot(snapshotPath, this.options.cache);
+  }
+
+  /**
+   * Stop forwarding lookups to the daemon.
+   * @returns Whether lookups were forwarded to a daemon
+   */
+  disconnectFromDaemon(): boolean {
+    const connected = addon.disconnect_from_daemon(this.options.cache);
+    this.initialized = addon.is_initialized(this.options.cache);
+    return connected;
   }
 
   /**
@@ -320,7 +2827,7 @@ export class BlurhashCore {
    */
   cleanup(): boolean {
     try {
-      const result = addon.clear_context();
+      const result = addon.clear_context(this.options.cache);
       this.initialized = false;
       return result;
     } catch (error) {
@@ -330,9 +2837,54 @@ export class BlurhashCore {
   }
 
   /**
-   * Get project root
+   * Gracefully shut down: stop accepting new jobs, wait for pending
+   * generations to be cached, then close the database connection. Unlike
+   * `cleanup()`, pending `getOrGenerate` calls still resolve with results.
+   * @param options Longest time to wait for pending work
+   * @returns Whether all pending work finished before the timeout
+   */
+  async shutdown(
+    options: Omit<ShutdownOptions, "cache"> = {}
+  ): Promise<boolean> {
+    this.initialized = false;
+    return addon.shutdown({ ...options, cache: this.options.cache });
+  }
+
+  /**
+   * Read the cache counters since initialization or the last `resetStats()`
+   */
+  getStats(): RuntimeStats {
+    return addon.get_stats(this.options.cache);
+  }
+
+  /**
+   * Zero the cache counters, e.g. at the start of a build run, so
+   * `getStats()` and the Prometheus metrics cover only what follows
+   */
+  resetStats(): void {
+    addon.reset_stats(this.options.cache);
+  }
+
+  /**
+   * Read the job counts of the worker pool that runs every generation made
+   * off the calling thread
+   */
+  getPoolStats(): PoolStats {
+    return addon.get_pool_stats(this.options.cache);
+  }
+
+  /**
+   * Get the primary project root
    */
   getProjectRoot(): string {
-    return this.options.projectRoot;
+    return this.getProjectRoots()[0];
+  }
+
+  /**
+   * Get all configured project roots
+   */
+  getProjectRoots(): string[] {
+    const { projectRoot } = this.options;
+    return Array.isArray(projectRoot) ? projectRoot : [projectRoot];
   }
 }
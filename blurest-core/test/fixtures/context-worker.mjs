@@ -0,0 +1,18 @@
+// Worker for worker-contexts.test.mjs: initializes a worker-scoped context from
+// `workerData` and answers `{ method, args }` messages by calling that method.
+
+import { parentPort, workerData } from "node:worker_threads";
+
+import { BlurhashCore } from "../../lib/index.mjs";
+
+const blurhash = new BlurhashCore({ ...workerData, scope: "worker" });
+blurhash.initialize();
+
+parentPort.on("message", async ({ method, args }) => {
+  try {
+    parentPort.postMessage({ value: await blurhash[method](...args) });
+  } catch (error) {
+    parentPort.postMessage({ error: String(error) });
+  }
+});
+parentPort.postMessage({ ready: true });
@@ -0,0 +1,96 @@
+import assert from "node:assert/strict";
+import { once } from "node:events";
+import fs from "node:fs";
+import os from "node:os";
+import path from "node:path";
+import { test } from "node:test";
+import { Worker } from "node:worker_threads";
+
+const CACHE = "worker-contexts";
+
+/** Writes a 4x4 24-bit bitmap of one color. */
+function writeBitmap(file, [red, green, blue]) {
+  const size = 4;
+  const pixels = Buffer.alloc(size * size * 3);
+  for (let offset = 0; offset < pixels.length; offset += 3) {
+    pixels.set([blue, green, red], offset);
+  }
+  const header = Buffer.alloc(54);
+  header.write("BM", 0, "ascii");
+  header.writeUInt32LE(header.length + pixels.length, 2);
+  header.writeUInt32LE(header.length, 10);
+  header.writeUInt32LE(40, 14);
+  header.writeInt32LE(size, 18);
+  header.writeInt32LE(size, 22);
+  header.writeUInt16LE(1, 26);
+  header.writeUInt16LE(24, 28);
+  header.writeUInt32LE(pixels.length, 34);
+  fs.writeFileSync(file, Buffer.concat([header, pixels]));
+}
+
+/** A project of its own holding `image.bmp` in one color. */
+function project(t, color) {
+  const dir = fs.realpathSync(
+    fs.mkdtempSync(path.join(os.tmpdir(), "blurest-worker-"))
+  );
+  t.after(() => fs.rmSync(dir, { recursive: true, force: true }));
+  const projectRoot = path.join(dir, "public");
+  fs.mkdirSync(projectRoot);
+  writeBitmap(path.join(projectRoot, "image.bmp"), color);
+  return {
+    databasePath: path.join(dir, "cache.sqlite3"),
+    projectRoot,
+    cache: CACHE,
+  };
+}
+
+/** Starts a worker that initializes a worker-scoped context with `options`. */
+async function startWorker(t, options) {
+  const worker = new Worker(
+    new URL("./fixtures/context-worker.mjs", import.meta.url),
+    { workerData: options }
+  );
+  t.after(() => worker.terminate());
+  await once(worker, "message");
+  return {
+    worker,
+    async call(method, ...args) {
+      worker.postMessage({ method, args });
+      const [{ value, error }] = await once(worker, "message");
+      if (error) {
+        throw new Error(error);
+      }
+      return value;
+    },
+  };
+}
+
+/** The blurhash of `image.bmp` in the worker's cache, generating it if needed. */
+async function blurhashOf(worker, method = "getOrGenerate") {
+  const result = await worker.call(method, "image.bmp");
+  assert.equal(result?.success, true, JSON.stringify(result));
+  return result.blurhash;
+}
+
+test("workers keep separate contexts of the same name", async (t) => {
+  const red = await startWorker(t, project(t, [200, 30, 30]));
+  const blue = await startWorker(t, project(t, [30, 30, 200]));
+
+  const redHash = await blurhashOf(red);
+  const blueHash = await blurhashOf(blue);
+
+  assert.notEqual(redHash, blueHash);
+  assert.equal(await blurhashOf(red, "getCachedSync"), redHash);
+  assert.equal(await blurhashOf(blue, "getCachedSync"), blueHash);
+});
+
+test("terminating a worker keeps the contexts of other workers", async (t) => {
+  const red = await startWorker(t, project(t, [200, 30, 30]));
+  const blue = await startWorker(t, project(t, [30, 30, 200]));
+  const blueHash = await blurhashOf(blue);
+
+  await red.worker.terminate();
+
+  assert.equal(await blue.call("isInitialized"), true);
+  assert.equal(await blurhashOf(blue, "getCachedSync"), blueHash);
+});